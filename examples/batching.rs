@@ -69,7 +69,7 @@ async fn main() -> Result<(), pulsar::Error> {
             v.push(receipt_rx);
             println!("sent");
             counter += 1;
-            if counter % 4 == 0 {
+            if counter.is_multiple_of(4) {
                 //producer.send_batch().await.unwrap();
                 println!("sent {} messages", counter);
                 break;
@@ -97,7 +97,7 @@ async fn main() -> Result<(), pulsar::Error> {
         }
         println!("got message: {:?}", data);
         counter += 1;
-        if counter % 4 == 0 {
+        if counter.is_multiple_of(4) {
             println!("sent {} messages", counter);
             break;
         }
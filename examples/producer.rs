@@ -35,12 +35,7 @@ async fn main() -> Result<(), pulsar::Error> {
     let mut builder = Pulsar::builder(addr, TokioExecutor);
 
     if let Ok(token) = env::var("PULSAR_TOKEN") {
-        let authentication = Authentication {
-            name: "token".to_string(),
-            data: token.into_bytes(),
-        };
-
-        builder = builder.with_auth(authentication);
+        builder = builder.with_auth(Authentication::token(token));
     }
 
     let pulsar: Pulsar<_> = builder.build().await?;
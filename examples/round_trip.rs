@@ -61,7 +61,7 @@ async fn main() -> Result<(), pulsar::Error> {
                 .await
                 .unwrap();
             counter += 1;
-            if counter % 1000 == 0 {
+            if counter.is_multiple_of(1000) {
                 println!("sent {} messages", counter);
             }
         }
@@ -87,7 +87,7 @@ async fn main() -> Result<(), pulsar::Error> {
             panic!("Unexpected payload: {}", &data.data);
         }
         counter += 1;
-        if counter % 1000 == 0 {
+        if counter.is_multiple_of(1000) {
             println!("received {} messages", counter);
         }
     }
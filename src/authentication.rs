@@ -0,0 +1,316 @@
+//! Authentication providers refreshing their credentials
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::connection::Authentication;
+use crate::error::ConnectionError;
+use crate::executor::Executor;
+
+/// authentication data obtained from a provider for every new connection
+/// and authentication challenge, for credentials that expire
+///
+/// ```rust,no_run
+/// use pulsar::{authentication::DynamicAuthentication, Pulsar, TokioExecutor};
+///
+/// # async fn run() -> Result<(), pulsar::Error> {
+/// let pulsar: Pulsar<_> = Pulsar::builder("pulsar://127.0.0.1:6650", TokioExecutor)
+///     .with_dynamic_auth(DynamicAuthentication::token_from_file("/run/secrets/token"))
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct DynamicAuthentication {
+    name: String,
+    provider: Arc<dyn AuthenticationProvider>,
+}
+
+impl DynamicAuthentication {
+    /// JWT authentication with a token read from a file
+    ///
+    /// the file is read again for every new connection, so tokens rotated
+    /// on disk are used without restarting the process
+    pub fn token_from_file<P: Into<PathBuf>>(path: P) -> Self {
+        DynamicAuthentication {
+            name: "token".to_string(),
+            provider: Arc::new(TokenFileProvider::new(path.into())),
+        }
+    }
+
+    /// token authentication with the OAuth2 client credentials flow
+    ///
+    /// the bearer token is cached and refreshed before it expires, for new
+    /// connections and when the broker asks for new credentials
+    #[cfg(feature = "auth-oauth2")]
+    pub fn oauth2(params: OAuth2Params) -> Result<Self, ConnectionError> {
+        Ok(DynamicAuthentication {
+            name: "token".to_string(),
+            provider: Arc::new(oauth2::OAuth2Provider::new(params)?),
+        })
+    }
+}
+
+/// credentials sent by the connections
+#[derive(Clone)]
+pub(crate) enum Credentials {
+    Static(Authentication),
+    Dynamic(DynamicAuthentication),
+}
+
+impl Credentials {
+    /// returns up to date authentication data, calling the provider if
+    /// there is one
+    pub(crate) async fn current<Exe: Executor>(
+        &self,
+        executor: &Exe,
+    ) -> Result<Authentication, ConnectionError> {
+        match self {
+            Credentials::Static(auth) => Ok(auth.clone()),
+            Credentials::Dynamic(auth) => {
+                let provider = auth.provider.clone();
+                let data = executor
+                    .spawn_blocking(move || provider.auth_data())
+                    .await
+                    .ok_or(ConnectionError::Shutdown)??;
+                Ok(Authentication {
+                    name: auth.name.clone(),
+                    data,
+                })
+            }
+        }
+    }
+}
+
+/// source of authentication data that can change over time
+///
+/// providers are called before every new connection and when the broker
+/// sends an authentication challenge. They may block, so they are run
+/// with [Executor::spawn_blocking](crate::executor::Executor::spawn_blocking)
+pub(crate) trait AuthenticationProvider: Send + Sync {
+    /// returns the current authentication data, refreshing it if needed
+    fn auth_data(&self) -> Result<Vec<u8>, ConnectionError>;
+}
+
+/// reads a token from a file every time a connection is created, so
+/// tokens rotated on disk are picked up without restarting the process
+pub(crate) struct TokenFileProvider {
+    path: PathBuf,
+}
+
+impl TokenFileProvider {
+    pub fn new(path: PathBuf) -> Self {
+        TokenFileProvider { path }
+    }
+}
+
+impl AuthenticationProvider for TokenFileProvider {
+    fn auth_data(&self) -> Result<Vec<u8>, ConnectionError> {
+        let token = std::fs::read_to_string(&self.path).map_err(|e| {
+            ConnectionError::TokenFile(format!("could not read {}: {}", self.path.display(), e))
+        })?;
+
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(ConnectionError::TokenFile(format!(
+                "{} is empty",
+                self.path.display()
+            )));
+        }
+        Ok(token.as_bytes().to_vec())
+    }
+}
+
+#[cfg(feature = "auth-oauth2")]
+pub use oauth2::OAuth2Params;
+
+#[cfg(feature = "auth-oauth2")]
+pub(crate) mod oauth2 {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::AuthenticationProvider;
+    use crate::error::ConnectionError;
+
+    /// tokens are refreshed when they expire in less than this delay
+    const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+    /// parameters of the OAuth2 client credentials flow
+    #[derive(Debug, Clone, Default)]
+    pub struct OAuth2Params {
+        /// URL of the token issuer. The token endpoint is discovered from
+        /// `<issuer_url>/.well-known/openid-configuration`
+        pub issuer_url: String,
+        /// client identifier
+        pub client_id: String,
+        /// client secret
+        pub client_secret: String,
+        /// audience of the token, usually the Pulsar cluster
+        pub audience: Option<String>,
+        /// space separated list of scopes requested for the token
+        pub scope: Option<String>,
+    }
+
+    struct CachedToken {
+        access_token: String,
+        expires_at: Option<Instant>,
+    }
+
+    impl CachedToken {
+        fn is_valid(&self) -> bool {
+            self.expires_at
+                .map(|expires_at| Instant::now() + REFRESH_MARGIN < expires_at)
+                .unwrap_or(true)
+        }
+    }
+
+    /// fetches bearer tokens from an OAuth2 issuer and caches them until
+    /// they are about to expire
+    pub(crate) struct OAuth2Provider {
+        params: OAuth2Params,
+        agent: ureq::Agent,
+        token_endpoint: Mutex<Option<String>>,
+        token: Mutex<Option<CachedToken>>,
+    }
+
+    impl OAuth2Provider {
+        pub fn new(params: OAuth2Params) -> Result<Self, ConnectionError> {
+            let connector = native_tls::TlsConnector::new()?;
+            let agent = ureq::AgentBuilder::new()
+                .tls_connector(Arc::new(connector))
+                .build();
+
+            Ok(OAuth2Provider {
+                params,
+                agent,
+                token_endpoint: Mutex::new(None),
+                token: Mutex::new(None),
+            })
+        }
+
+        fn token_endpoint(&self) -> Result<String, ConnectionError> {
+            let mut token_endpoint = self.token_endpoint.lock().unwrap();
+            if let Some(endpoint) = token_endpoint.as_ref() {
+                return Ok(endpoint.clone());
+            }
+
+            let url = format!(
+                "{}/.well-known/openid-configuration",
+                self.params.issuer_url.trim_end_matches('/')
+            );
+            let metadata: serde_json::Value = self
+                .agent
+                .get(&url)
+                .call()
+                .map_err(|e| {
+                    ConnectionError::Authentication(format!(
+                        "could not get the OAuth2 issuer metadata: {}",
+                        e
+                    ))
+                })?
+                .into_json()?;
+
+            let endpoint = metadata["token_endpoint"]
+                .as_str()
+                .ok_or_else(|| {
+                    ConnectionError::Authentication(
+                        "missing token_endpoint in the OAuth2 issuer metadata".to_string(),
+                    )
+                })?
+                .to_string();
+            *token_endpoint = Some(endpoint.clone());
+            Ok(endpoint)
+        }
+
+        fn fetch_token(&self) -> Result<CachedToken, ConnectionError> {
+            let endpoint = self.token_endpoint()?;
+
+            let mut form = vec![
+                ("grant_type", "client_credentials"),
+                ("client_id", self.params.client_id.as_str()),
+                ("client_secret", self.params.client_secret.as_str()),
+            ];
+            if let Some(audience) = self.params.audience.as_ref() {
+                form.push(("audience", audience.as_str()));
+            }
+            if let Some(scope) = self.params.scope.as_ref() {
+                form.push(("scope", scope.as_str()));
+            }
+
+            let requested_at = Instant::now();
+            let response: serde_json::Value = self
+                .agent
+                .post(&endpoint)
+                .send_form(&form)
+                .map_err(|e| {
+                    ConnectionError::Authentication(format!("could not get an OAuth2 token: {}", e))
+                })?
+                .into_json()?;
+
+            let access_token = response["access_token"]
+                .as_str()
+                .ok_or_else(|| {
+                    ConnectionError::Authentication(
+                        "missing access_token in the OAuth2 token response".to_string(),
+                    )
+                })?
+                .to_string();
+            let expires_at = response["expires_in"]
+                .as_u64()
+                .map(|expires_in| requested_at + Duration::from_secs(expires_in));
+
+            Ok(CachedToken {
+                access_token,
+                expires_at,
+            })
+        }
+    }
+
+    impl AuthenticationProvider for OAuth2Provider {
+        fn auth_data(&self) -> Result<Vec<u8>, ConnectionError> {
+            let mut token = self.token.lock().unwrap();
+            match token.as_ref() {
+                Some(cached) if cached.is_valid() => {}
+                _ => {
+                    debug!("requesting a new OAuth2 token");
+                    *token = Some(self.fetch_token()?);
+                }
+            }
+
+            Ok(token
+                .as_ref()
+                .map(|cached| cached.access_token.clone().into_bytes())
+                .unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_file() {
+        let path = std::env::temp_dir().join(format!("pulsar_token_{}", rand::random::<u32>()));
+        let provider = TokenFileProvider::new(path.clone());
+
+        assert!(matches!(
+            provider.auth_data(),
+            Err(ConnectionError::TokenFile(_))
+        ));
+
+        std::fs::write(&path, "  \n").unwrap();
+        assert!(matches!(
+            provider.auth_data(),
+            Err(ConnectionError::TokenFile(_))
+        ));
+
+        std::fs::write(&path, "first\n").unwrap();
+        assert_eq!(provider.auth_data().unwrap(), b"first".to_vec());
+
+        std::fs::write(&path, "second").unwrap();
+        assert_eq!(provider.auth_data().unwrap(), b"second".to_vec());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
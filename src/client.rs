@@ -1,22 +1,64 @@
 use std::string::FromUtf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::channel::{mpsc, oneshot};
+use futures::lock::Mutex;
 
+use crate::authentication::{Credentials, DynamicAuthentication};
 use crate::connection::Authentication;
 use crate::connection_manager::{
-    BrokerAddress, ConnectionManager, ConnectionRetryOptions, OperationRetryOptions, TlsOptions,
+    AddressOrder, BrokerAddress, ConnectionManager, ConnectionRetryOptions, OperationRetryOptions,
+    RetryPolicy, TlsOptions,
 };
-use crate::consumer::ConsumerBuilder;
-use crate::error::Error;
+use crate::consumer::{Consumer, ConsumerBuilder};
+use crate::error::{ConnectionError, Error, ProducerError};
 use crate::executor::Executor;
-use crate::message::proto::{self, CommandSendReceipt};
+use crate::message::proto::{self, command_subscribe::SubType, CommandSendReceipt};
 use crate::message::Payload;
-use crate::producer::{self, ProducerBuilder, SendFuture};
+use crate::producer::{self, ProducerBuilder, ProducerOptions, SendFuture};
+use crate::reader::{Reader, StartMessageId};
+use crate::schema::RegisteredSchema;
+#[cfg(feature = "json-schema")]
+use crate::schema::{JsonSchema, SchemaDefinition};
 use crate::service_discovery::ServiceDiscovery;
-use futures::StreamExt;
+#[cfg(feature = "transactions")]
+use crate::transaction::Transaction;
+use futures::{SinkExt, Stream, StreamExt};
+use regex::Regex;
+#[cfg(feature = "json-schema")]
+use serde::Serialize;
 
 /// Helper trait for consumer deserialization
+///
+/// the payload gives access to the message metadata, including the
+/// properties set by the producer:
+///
+/// ```rust
+/// use pulsar::{message::Payload, schema::CONTENT_TYPE, DeserializeMessage};
+///
+/// enum Content {
+///     Json(serde_json::Value),
+///     Text(String),
+///     Other(Vec<u8>),
+/// }
+///
+/// impl DeserializeMessage for Content {
+///     type Output = Content;
+///
+///     fn deserialize_message(payload: &Payload) -> Content {
+///         match payload.property(CONTENT_TYPE) {
+///             Some("application/json") => serde_json::from_slice(&payload.data)
+///                 .map(Content::Json)
+///                 .unwrap_or_else(|_| Content::Other(payload.data.clone())),
+///             Some("text/plain") => String::from_utf8(payload.data.clone())
+///                 .map(Content::Text)
+///                 .unwrap_or_else(|e| Content::Other(e.into_bytes())),
+///             _ => Content::Other(payload.data.clone()),
+///         }
+///     }
+/// }
+/// ```
 pub trait DeserializeMessage {
     /// type produced from the message
     type Output: Sized;
@@ -52,7 +94,7 @@ impl SerializeMessage for producer::Message {
     }
 }
 
-impl<'a> SerializeMessage for &'a [u8] {
+impl SerializeMessage for &[u8] {
     fn serialize_message(input: Self) -> Result<producer::Message, Error> {
         Ok(producer::Message {
             payload: input.to_vec(),
@@ -80,7 +122,7 @@ impl SerializeMessage for String {
     }
 }
 
-impl<'a> SerializeMessage for &String {
+impl SerializeMessage for &String {
     fn serialize_message(input: Self) -> Result<producer::Message, Error> {
         let payload = input.as_bytes().to_vec();
         Ok(producer::Message {
@@ -90,7 +132,7 @@ impl<'a> SerializeMessage for &String {
     }
 }
 
-impl<'a> SerializeMessage for &'a str {
+impl SerializeMessage for &str {
     fn serialize_message(input: Self) -> Result<producer::Message, Error> {
         let payload = input.as_bytes().to_vec();
         Ok(producer::Message {
@@ -141,8 +183,10 @@ pub struct Pulsar<Exe: Executor> {
     // o,solve this, we create a client without this sender, use it in
     // run_producer, then fill in the producer field afterwards in the
     // main Pulsar instance
-    producer: Option<mpsc::UnboundedSender<SendMessage>>,
+    producer: Option<SendQueue>,
     pub(crate) operation_retry_options: OperationRetryOptions,
+    // retries the creation of producers on transient errors
+    pub(crate) retry_policy: Option<RetryPolicy>,
     pub(crate) executor: Arc<Exe>,
 }
 
@@ -150,10 +194,14 @@ impl<Exe: Executor> Pulsar<Exe> {
     /// creates a new client
     pub(crate) async fn new<S: Into<String>>(
         url: S,
-        auth: Option<Authentication>,
+        auth: Option<Credentials>,
         connection_retry_parameters: Option<ConnectionRetryOptions>,
         operation_retry_parameters: Option<OperationRetryOptions>,
         tls_options: Option<TlsOptions>,
+        send_queue_capacity: Option<usize>,
+        producer_options: Option<ProducerOptions>,
+        proxy: bool,
+        retry_policy: Option<RetryPolicy>,
         executor: Exe,
     ) -> Result<Self, Error> {
         let url: String = url.into();
@@ -172,7 +220,7 @@ impl<Exe: Executor> Pulsar<Exe> {
 
         // set up a regular connection check
         let weak_manager = Arc::downgrade(&manager);
-        let mut interval = executor.interval(std::time::Duration::from_secs(60));
+        let mut interval = executor.interval(Duration::from_secs(60));
         let res = executor.spawn(Box::pin(async move {
             while let Some(()) = interval.next().await {
                 if let Some(strong_manager) = weak_manager.upgrade() {
@@ -189,20 +237,39 @@ impl<Exe: Executor> Pulsar<Exe> {
             return Err(crate::error::ConnectionError::Shutdown.into());
         }
 
-        let service_discovery = Arc::new(ServiceDiscovery::with_manager(manager.clone()));
-        let (producer, producer_rx) = mpsc::unbounded();
+        let service_discovery =
+            Arc::new(ServiceDiscovery::with_manager(manager.clone()).with_proxy(proxy));
 
         let mut client = Pulsar {
             manager,
             service_discovery,
             producer: None,
             operation_retry_options,
+            retry_policy,
             executor,
         };
 
-        let _ = client
-            .executor
-            .spawn(Box::pin(run_producer(client.clone(), producer_rx)));
+        let producer_options = producer_options.unwrap_or_default();
+        let producer = match send_queue_capacity {
+            None => {
+                let (producer, producer_rx) = mpsc::unbounded();
+                let _ = client.executor.spawn(Box::pin(run_producer(
+                    client.clone(),
+                    producer_options,
+                    producer_rx,
+                )));
+                SendQueue::Unbounded(producer)
+            }
+            Some(capacity) => {
+                let (producer, producer_rx) = mpsc::channel(capacity);
+                let _ = client.executor.spawn(Box::pin(run_producer(
+                    client.clone(),
+                    producer_options,
+                    producer_rx,
+                )));
+                SendQueue::Bounded(Arc::new(Mutex::new(producer)))
+            }
+        };
         client.producer = Some(producer);
         Ok(client)
     }
@@ -228,10 +295,38 @@ impl<Exe: Executor> Pulsar<Exe> {
             connection_retry_options: None,
             operation_retry_options: None,
             tls_options: None,
+            send_queue_capacity: None,
+            producer_options: None,
+            proxy: false,
+            retry_policy: None,
             executor,
         }
     }
 
+    /// creates a client builder connecting through a Pulsar proxy
+    ///
+    /// lookups, producers and consumers all use connections to the proxy,
+    /// which forwards the commands to the broker indicated by
+    /// `proxy_to_broker_url`, instead of connecting to the broker addresses
+    /// returned by the lookups, that may not be reachable from the client
+    ///
+    /// ```rust,no_run
+    /// use pulsar::{Pulsar, TokioExecutor};
+    ///
+    /// # async fn run() -> Result<(), pulsar::Error> {
+    /// let pulsar: Pulsar<_> = Pulsar::with_proxy("pulsar://proxy.example.com:6650", TokioExecutor)
+    ///     .build()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_proxy<S: Into<String>>(url: S, executor: Exe) -> PulsarBuilder<Exe> {
+        PulsarBuilder {
+            proxy: true,
+            ..Pulsar::builder(url, executor)
+        }
+    }
+
     /// creates a consumer builder
     ///
     /// ```rust,no_run
@@ -254,6 +349,108 @@ impl<Exe: Executor> Pulsar<Exe> {
         ConsumerBuilder::new(self)
     }
 
+    /// subscribes to a topic with the default consumer options
+    ///
+    /// this is a shortcut for [Pulsar::consumer], use the builder to set
+    /// other consumer options
+    ///
+    /// ```rust,no_run
+    /// use pulsar::{SubType, Consumer};
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut consumer: Consumer<String, _> = pulsar
+    ///     .subscribe("non-persistent://public/default/test", "test_subscription", SubType::Shared)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn subscribe<T, S1, S2>(
+        &self,
+        topic: S1,
+        subscription: S2,
+        sub_type: SubType,
+    ) -> Result<Consumer<T, Exe>, Error>
+    where
+        T: DeserializeMessage,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.consumer()
+            .with_topic(topic)
+            .with_subscription(subscription)
+            .with_subscription_type(sub_type)
+            .build()
+            .await
+    }
+
+    /// subscribes to all the topics matching a regular expression
+    ///
+    /// the pattern must match the full topic name. The topics are looked up
+    /// in the namespace of the pattern (or `public/default`), and this list
+    /// is refreshed every 30 seconds: the consumer subscribes to the new
+    /// matching topics, and closes its consumers on deleted topics. Use
+    /// [ConsumerBuilder::with_topic_regex] to set other consumer options
+    ///
+    /// ```rust,no_run
+    /// use pulsar::Consumer;
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut consumer: Consumer<String, _> = pulsar
+    ///     .subscribe_with_regex("persistent://tenant/ns/events-.*", "test_subscription")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn subscribe_with_regex<T, S>(
+        &self,
+        pattern: &str,
+        subscription: S,
+    ) -> Result<Consumer<T, Exe>, Error>
+    where
+        T: DeserializeMessage,
+        S: Into<String>,
+    {
+        // the pattern must match the whole topic name, `events` should not
+        // subscribe to `events-old`
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::Custom(format!("invalid topic pattern {}: {}", pattern, e)))?;
+        let mut builder = self
+            .consumer()
+            .with_topic_regex(regex)
+            .with_subscription(subscription);
+
+        if let Some((_, name)) = pattern.split_once("://") {
+            let mut parts = name.splitn(3, '/');
+            if let (Some(tenant), Some(namespace), Some(_)) =
+                (parts.next(), parts.next(), parts.next())
+            {
+                builder = builder.with_lookup_namespace(format!("{}/{}", tenant, namespace));
+            }
+        }
+
+        builder.build().await
+    }
+
+    /// creates a reader on a topic, starting at `start`
+    ///
+    /// ```rust,no_run
+    /// use pulsar::reader::{Reader, StartMessageId};
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut reader: Reader<String, _> = pulsar
+    ///     .create_reader("persistent://public/default/test", StartMessageId::Earliest)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn create_reader<T: DeserializeMessage, S: Into<String>>(
+        &self,
+        topic: S,
+        start: StartMessageId,
+    ) -> Result<Reader<T, Exe>, Error> {
+        Reader::new(self, topic, start).await
+    }
+
     /// creates a producer builder
     ///
     /// ```rust,no_run
@@ -271,6 +468,41 @@ impl<Exe: Executor> Pulsar<Exe> {
         ProducerBuilder::new(self)
     }
 
+    /// creates a producer registering the JSON schema of `T` on the topic
+    ///
+    /// the broker checks that the schema is compatible with the schema of the
+    /// topic. Messages are sent with [Json](crate::schema::Json)
+    ///
+    /// ```rust,no_run
+    /// # use pulsar::schema::{Json, JsonSchema};
+    /// # #[derive(serde::Serialize)]
+    /// # struct Temperature { value: f64 }
+    /// # impl JsonSchema for Temperature {
+    /// #     fn schema_definition() -> String { String::new() }
+    /// # }
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let mut producer = pulsar
+    ///     .create_producer_with_schema::<Temperature>("persistent://public/default/temperatures")
+    ///     .await?;
+    /// producer.send(Json(Temperature { value: 21.5 })).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "json-schema")]
+    pub async fn create_producer_with_schema<T: JsonSchema + Serialize>(
+        &self,
+        topic: impl Into<String>,
+    ) -> Result<producer::Producer<Exe>, Error> {
+        self.producer()
+            .with_topic(topic)
+            .with_options(producer::ProducerOptions {
+                schema: Some(SchemaDefinition::json::<T>().into_schema(T::schema_name())),
+                ..Default::default()
+            })
+            .build()
+            .await
+    }
+
     /// gets the address of a broker handling the topic
     ///
     /// ```rust,no_run
@@ -286,6 +518,26 @@ impl<Exe: Executor> Pulsar<Exe> {
             .map_err(|e| e.into())
     }
 
+    /// removes the cached broker addresses of all topics
+    ///
+    /// the address found by [Pulsar::lookup_topic] is reused for producers and
+    /// consumers on the same topic for a minute, unless the connection fails
+    pub fn clear_lookup_cache(&self) {
+        self.service_discovery.clear_cache();
+    }
+
+    /// looks up a topic again, ignoring the cached broker address
+    ///
+    /// used when reconnecting, because the topic might have moved to another broker
+    pub(crate) async fn lookup_topic_again<S: Into<String>>(
+        &self,
+        topic: S,
+    ) -> Result<BrokerAddress, Error> {
+        let topic = topic.into();
+        self.service_discovery.invalidate(&topic);
+        self.lookup_topic(topic).await
+    }
+
     /// gets the number of partitions for a partitioned topic
     ///
     /// ```rust,no_run
@@ -304,6 +556,24 @@ impl<Exe: Executor> Pulsar<Exe> {
             .map_err(|e| e.into())
     }
 
+    /// gets the number of partitions of a topic, 0 if it is not partitioned
+    ///
+    /// the request is sent on the client's pooled connection to the service
+    /// URL. Same as [Pulsar::lookup_partitioned_topic_number]
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let partitions = pulsar.get_partitioned_topic_metadata("persistent://public/default/test").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_partitioned_topic_metadata<S: Into<String>>(
+        &self,
+        topic: S,
+    ) -> Result<u32, Error> {
+        self.lookup_partitioned_topic_number(topic).await
+    }
+
     /// gets the address of brokers handling the topic's partitions. If the topic is not
     /// a partitioned topic, result will be a single element containing the topic and address
     /// of the non-partitioned topic provided.
@@ -347,6 +617,103 @@ impl<Exe: Executor> Pulsar<Exe> {
         Ok(topics.topics)
     }
 
+    /// gets the schema registered on a topic
+    ///
+    /// returns the latest version of the schema, or the one given by
+    /// `version`, and `None` if the topic has no schema
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// if let Some(registered) = pulsar.get_schema("persistent://public/default/users", None).await? {
+    ///     println!("schema version {:?}: {:?}", registered.version, registered.schema);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_schema<S: Into<String>>(
+        &self,
+        topic: S,
+        version: Option<Vec<u8>>,
+    ) -> Result<Option<RegisteredSchema>, Error> {
+        let topic = topic.into();
+        let broker_address = self.lookup_topic(topic.clone()).await?;
+        let conn = self.manager.get_connection(&broker_address).await?;
+        match conn.sender().get_schema(topic, version).await {
+            Ok(proto::CommandGetSchemaResponse {
+                schema,
+                schema_version,
+                ..
+            }) => Ok(schema.map(|schema| RegisteredSchema {
+                schema,
+                version: schema_version,
+            })),
+            // the broker answers TopicNotFound for topics without schema
+            Err(ConnectionError::PulsarError(Some(proto::ServerError::TopicNotFound), _)) => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// sends a command that the client does not support yet to a broker, and
+    /// returns the broker's answer
+    ///
+    /// `command` receives the request id that the command must carry, and the
+    /// answer with the same request id is returned. Errors sent by the broker
+    /// are returned as [ConnectionError::PulsarError]
+    ///
+    /// this is a low level escape hatch: the command is sent as is and its
+    /// effects are not tracked by the client, and this method may change
+    /// without a major version
+    ///
+    /// ```rust,no_run
+    /// use pulsar::proto::{self, base_command::Type as CommandType};
+    ///
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let topic = "persistent://public/default/test";
+    /// let broker = pulsar.lookup_topic(topic).await?;
+    /// let response = pulsar
+    ///     .send_raw_command(&broker, |request_id| proto::BaseCommand {
+    ///         r#type: CommandType::GetLastMessageId as i32,
+    ///         get_last_message_id: Some(proto::CommandGetLastMessageId {
+    ///             consumer_id: 1,
+    ///             request_id,
+    ///         }),
+    ///         ..Default::default()
+    ///     })
+    ///     .await?;
+    /// println!("{:?}", response.get_last_message_id_response);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_raw_command<F>(
+        &self,
+        broker: &BrokerAddress,
+        command: F,
+    ) -> Result<proto::BaseCommand, Error>
+    where
+        F: FnOnce(u64) -> proto::BaseCommand,
+    {
+        let conn = self.manager.get_connection(broker).await?;
+        Ok(conn.sender().send_raw_command(command).await?)
+    }
+
+    /// starts a transaction on one of the cluster's transaction coordinators
+    ///
+    /// the broker aborts the transaction if it is not committed before `timeout`
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let txn = pulsar.new_transaction(std::time::Duration::from_secs(60)).await?;
+    /// txn.abort().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "transactions")]
+    pub async fn new_transaction(&self, timeout: Duration) -> Result<Transaction<Exe>, Error> {
+        Transaction::new(self, timeout).await
+    }
+
     /// Sends a message on a topic.
     ///
     /// This function will lazily initialize and re-use producers as needed. For better
@@ -375,15 +742,21 @@ impl<Exe: Executor> Pulsar<Exe> {
         topic: S,
     ) -> Result<SendFuture, Error> {
         let (resolver, future) = oneshot::channel();
-        self.producer
+        let message = SendMessage {
+            topic: topic.into(),
+            message,
+            resolver,
+        };
+        let res = match self
+            .producer
             .as_ref()
             .expect("a client without the producer channel should only be used internally")
-            .unbounded_send(SendMessage {
-                topic: topic.into(),
-                message,
-                resolver,
-            })
-            .map_err(|_| Error::Custom("producer unexpectedly disconnected".into()))?;
+        {
+            SendQueue::Unbounded(producer) => producer.unbounded_send(message).map_err(drop),
+            // waits until there is room in the queue
+            SendQueue::Bounded(producer) => producer.lock().await.send(message).await.map_err(drop),
+        };
+        res.map_err(|_| ProducerError::EngineShutdown)?;
         Ok(SendFuture(future))
     }
 }
@@ -391,17 +764,47 @@ impl<Exe: Executor> Pulsar<Exe> {
 /// Helper structure to generate a [Pulsar] client
 pub struct PulsarBuilder<Exe: Executor> {
     url: String,
-    auth: Option<Authentication>,
+    auth: Option<Credentials>,
     connection_retry_options: Option<ConnectionRetryOptions>,
     operation_retry_options: Option<OperationRetryOptions>,
     tls_options: Option<TlsOptions>,
+    send_queue_capacity: Option<usize>,
+    producer_options: Option<ProducerOptions>,
+    proxy: bool,
+    retry_policy: Option<RetryPolicy>,
     executor: Exe,
 }
 
 impl<Exe: Executor> PulsarBuilder<Exe> {
+    /// replaces the executor, keeping the other options
+    #[cfg(feature = "sync")]
+    pub(crate) fn with_executor<E: Executor>(self, executor: E) -> PulsarBuilder<E> {
+        PulsarBuilder {
+            url: self.url,
+            auth: self.auth,
+            connection_retry_options: self.connection_retry_options,
+            operation_retry_options: self.operation_retry_options,
+            tls_options: self.tls_options,
+            send_queue_capacity: self.send_queue_capacity,
+            producer_options: self.producer_options,
+            proxy: self.proxy,
+            retry_policy: self.retry_policy,
+            executor,
+        }
+    }
+
     /// Authentication parameters (JWT, Biscuit, etc)
     pub fn with_auth(mut self, auth: Authentication) -> Self {
-        self.auth = Some(auth);
+        self.auth = Some(Credentials::Static(auth));
+        self
+    }
+
+    /// Authentication parameters obtained from a provider, like an OAuth2
+    /// issuer, for every new connection
+    ///
+    /// this replaces the parameters set with [PulsarBuilder::with_auth]
+    pub fn with_dynamic_auth(mut self, auth: DynamicAuthentication) -> Self {
+        self.auth = Some(Credentials::Dynamic(auth));
         self
     }
 
@@ -414,6 +817,28 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// order in which the addresses of a broker are tried, when its host
+    /// name resolves to several addresses
+    ///
+    /// default: [AddressOrder::Shuffled]
+    pub fn with_address_order(mut self, address_order: AddressOrder) -> Self {
+        self.connection_retry_options
+            .get_or_insert_with(Default::default)
+            .address_order = address_order;
+        self
+    }
+
+    /// maximum size of the frames received from the brokers, larger frames
+    /// are rejected instead of being buffered
+    ///
+    /// default: the maximum message size advertised by the broker, plus 10KB
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.connection_retry_options
+            .get_or_insert_with(Default::default)
+            .max_frame_size = Some(max_frame_size);
+        self
+    }
+
     /// Retry parameters for Pulsar operations
     pub fn with_operation_retry_options(
         mut self,
@@ -423,11 +848,41 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         self
     }
 
+    /// time limit to receive an answer to a Pulsar operation, shared by
+    /// all the producers and consumers of the client
+    ///
+    /// default: 30 seconds
+    pub fn with_operation_timeout(mut self, operation_timeout: Duration) -> Self {
+        self.operation_retry_options
+            .get_or_insert_with(Default::default)
+            .operation_timeout = operation_timeout;
+        self
+    }
+
+    /// maximum number of redirects followed by a topic lookup, to stop
+    /// brokers redirecting to each other
+    ///
+    /// default: 20
+    pub fn with_max_lookup_redirects(mut self, max_lookup_redirects: u32) -> Self {
+        self.operation_retry_options
+            .get_or_insert_with(Default::default)
+            .max_lookup_redirects = max_lookup_redirects;
+        self
+    }
+
+    /// TLS parameters of the connections
+    ///
+    /// this replaces the options set with the other TLS methods of the builder
+    pub fn with_tls(mut self, tls_options: TlsOptions) -> Self {
+        self.tls_options = Some(tls_options);
+        self
+    }
+
     /// add a custom certificate chain to authenticate the server in TLS connections
     pub fn with_certificate_chain(mut self, certificate_chain: Vec<u8>) -> Self {
-        self.tls_options = Some(TlsOptions {
-            certificate_chain: Some(certificate_chain),
-        });
+        self.tls_options
+            .get_or_insert_with(Default::default)
+            .certificate_chain = Some(certificate_chain);
         self
     }
 
@@ -445,6 +900,67 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
         Ok(self.with_certificate_chain(v))
     }
 
+    /// sets the PEM encoded client certificate and PKCS #8 private key used for mutual TLS
+    pub fn with_client_certificate(mut self, certificate: Vec<u8>, key: Vec<u8>) -> Self {
+        let tls_options = self.tls_options.get_or_insert_with(Default::default);
+        tls_options.client_certificate = Some(certificate);
+        tls_options.client_key = Some(key);
+        self
+    }
+
+    /// loads the PEM encoded client certificate and PKCS #8 private key used for mutual TLS
+    pub fn with_client_certificate_files<P1: AsRef<std::path::Path>, P2: AsRef<std::path::Path>>(
+        self,
+        certificate_path: P1,
+        key_path: P2,
+    ) -> Result<Self, std::io::Error> {
+        let certificate = std::fs::read(certificate_path)?;
+        let key = std::fs::read(key_path)?;
+
+        Ok(self.with_client_certificate(certificate, key))
+    }
+
+    /// accept invalid server certificates in TLS connections
+    ///
+    /// this disables certificate validation and should only be used for testing
+    pub fn with_allow_insecure(mut self, allow_insecure: bool) -> Self {
+        self.tls_options
+            .get_or_insert_with(Default::default)
+            .allow_insecure = allow_insecure;
+        self
+    }
+
+    /// bounds the queue of messages sent with [Pulsar::send]
+    ///
+    /// by default the queue is unbounded. With a capacity, [Pulsar::send] stays
+    /// pending while the queue is full, instead of buffering messages until
+    /// the brokers catch up
+    pub fn with_send_queue_capacity(mut self, capacity: usize) -> Self {
+        self.send_queue_capacity = Some(capacity);
+        self
+    }
+
+    /// options of the producers created by [Pulsar::send], one per topic
+    ///
+    /// they apply to every topic, so batching or compression can be used
+    /// with [Pulsar::send], like with a [MultiTopicProducer](crate::MultiTopicProducer)
+    /// built from a [ProducerBuilder]
+    pub fn with_producer_options(mut self, producer_options: ProducerOptions) -> Self {
+        self.producer_options = Some(producer_options);
+        self
+    }
+
+    /// retries the lookup and creation of producers that fail with a
+    /// transient error, like a refused connection or a broker answering
+    /// ServiceNotReady. Other errors are returned right away
+    ///
+    /// by default, producer creation is not retried, besides the
+    /// [operation retries](PulsarBuilder::with_operation_retry_options)
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
     /// creates the Pulsar client and connects it
     pub async fn build(self) -> Result<Pulsar<Exe>, Error> {
         let PulsarBuilder {
@@ -453,6 +969,10 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
             connection_retry_options,
             operation_retry_options,
             tls_options,
+            send_queue_capacity,
+            producer_options,
+            proxy,
+            retry_policy,
             executor,
         } = self;
         Pulsar::new(
@@ -461,38 +981,54 @@ impl<Exe: Executor> PulsarBuilder<Exe> {
             connection_retry_options,
             operation_retry_options,
             tls_options,
+            send_queue_capacity,
+            producer_options,
+            proxy,
+            retry_policy,
             executor,
         )
         .await
     }
 }
 
+/// queue of the messages sent through the client's internal producer
+#[derive(Clone)]
+enum SendQueue {
+    Unbounded(mpsc::UnboundedSender<SendMessage>),
+    // the sender is shared behind a lock, because each clone of a bounded
+    // sender gets its own slot in the channel
+    Bounded(Arc<Mutex<mpsc::Sender<SendMessage>>>),
+}
+
 struct SendMessage {
     topic: String,
     message: producer::Message,
     resolver: oneshot::Sender<Result<CommandSendReceipt, Error>>,
 }
 
-async fn run_producer<Exe: Executor>(
+async fn run_producer<Exe: Executor, S: Stream<Item = SendMessage> + Unpin>(
     client: Pulsar<Exe>,
-    mut messages: mpsc::UnboundedReceiver<SendMessage>,
+    options: ProducerOptions,
+    mut messages: S,
 ) {
-    let mut producer = client.producer().build_multi_topic();
+    let mut producer = client.producer().with_options(options).build_multi_topic();
     while let Some(SendMessage {
         topic,
         message: payload,
         resolver,
     }) = messages.next().await
     {
-        match producer.send(topic, payload).await {
+        match producer.send(topic.clone(), payload).await {
             Ok(future) => {
                 let _ = client.executor.spawn(Box::pin(async move {
-                    let _ = resolver.send(future.await);
+                    let _ = resolver.send(future.receipt().await);
                 }));
             }
             Err(e) => {
+                warn!("could not send a message on {}: {}", topic, e);
                 let _ = resolver.send(Err(e));
             }
         }
     }
+    debug!("the client was dropped, stopping its producer task");
 }
@@ -1,7 +1,6 @@
-use native_tls::Certificate;
+use native_tls::{Certificate, Identity};
 use proto::MessageIdData;
-use rand::{thread_rng, Rng};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::Debug;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -21,6 +20,8 @@ use futures::{
 };
 use url::Url;
 
+use crate::authentication::Credentials;
+use crate::connection_manager::AddressOrder;
 use crate::consumer::ConsumerOptions;
 use crate::error::{ConnectionError, SharedError};
 use crate::executor::{Executor, ExecutorKind};
@@ -29,6 +30,8 @@ use crate::message::{
     BaseCommand, Codec, Message,
 };
 use crate::producer::{self, ProducerOptions};
+#[cfg(feature = "transactions")]
+use crate::transaction::TxnId;
 
 pub(crate) enum Register {
     Request {
@@ -42,6 +45,14 @@ pub(crate) enum Register {
     Ping {
         resolver: oneshot::Sender<()>,
     },
+    Producer {
+        producer_id: u64,
+        resolver: oneshot::Sender<()>,
+    },
+    /// forgets a request that timed out
+    Cancel {
+        key: RequestKey,
+    },
 }
 
 /// identifier for a message
@@ -51,9 +62,14 @@ pub enum RequestKey {
     ProducerSend { producer_id: u64, sequence_id: u64 },
     Consumer { consumer_id: u64 },
     CloseConsumer { consumer_id: u64, request_id: u64 },
+    CloseProducer { producer_id: u64, request_id: u64 },
 }
 
 /// Authentication parameters
+///
+/// the data is sent as is by every connection. Use
+/// [DynamicAuthentication](crate::authentication::DynamicAuthentication)
+/// for credentials that expire
 #[derive(Clone)]
 pub struct Authentication {
     /// Authentication kid. Use "token" for JWT
@@ -62,16 +78,48 @@ pub struct Authentication {
     pub data: Vec<u8>,
 }
 
+impl Authentication {
+    /// static authentication data
+    ///
+    /// `name` is the authentication method, like "token" for JWT, and
+    /// `data` the credentials expected by that method
+    pub fn new<S: Into<String>>(name: S, data: Vec<u8>) -> Self {
+        Authentication {
+            name: name.into(),
+            data,
+        }
+    }
+
+    /// JWT authentication with a static token
+    pub fn token<S: Into<String>>(token: S) -> Self {
+        Self::new("token", token.into().into_bytes())
+    }
+}
+
+/// number of canceled requests whose late answer is dropped
+const MAX_CANCELED_REQUESTS: usize = 1000;
+
 pub(crate) struct Receiver<S: Stream<Item = Result<Message, ConnectionError>>> {
     inbound: Pin<Box<S>>,
     outbound: mpsc::UnboundedSender<Message>,
     error: SharedError,
+    // only the receiver task accesses this map: senders register their
+    // requests through the `registrations` channel, so there is no lock
+    // shared between sending a request and resolving its answer
     pending_requests: BTreeMap<RequestKey, oneshot::Sender<Message>>,
+    // requests that timed out, whose answer is dropped if it comes later
+    canceled_requests: BTreeSet<RequestKey>,
+    // order of cancellation, to forget the oldest ones
+    canceled_order: VecDeque<RequestKey>,
     consumers: BTreeMap<u64, mpsc::UnboundedSender<Message>>,
+    // producers notified when the broker closes them
+    producers: BTreeMap<u64, oneshot::Sender<()>>,
     received_messages: BTreeMap<RequestKey, Message>,
     registrations: Pin<Box<mpsc::UnboundedReceiver<Register>>>,
     shutdown: Pin<Box<oneshot::Receiver<()>>>,
-    ping: Option<oneshot::Sender<()>>,
+    // pings waiting for a pong, the broker answers them in order
+    pings: VecDeque<oneshot::Sender<()>>,
+    auth_challenges: mpsc::UnboundedSender<()>,
 }
 
 impl<S: Stream<Item = Result<Message, ConnectionError>>> Receiver<S> {
@@ -81,17 +129,38 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Receiver<S> {
         error: SharedError,
         registrations: mpsc::UnboundedReceiver<Register>,
         shutdown: oneshot::Receiver<()>,
+        auth_challenges: mpsc::UnboundedSender<()>,
     ) -> Receiver<S> {
         Receiver {
             inbound: Box::pin(inbound),
             outbound,
             error,
             pending_requests: BTreeMap::new(),
+            canceled_requests: BTreeSet::new(),
+            canceled_order: VecDeque::new(),
             received_messages: BTreeMap::new(),
             consumers: BTreeMap::new(),
+            producers: BTreeMap::new(),
             registrations: Box::pin(registrations),
             shutdown: Box::pin(shutdown),
-            ping: None,
+            pings: VecDeque::new(),
+            auth_challenges,
+        }
+    }
+
+    /// forgets a pending request, and drops its answer if it comes later.
+    /// The answer to some requests never comes, so only the last
+    /// `MAX_CANCELED_REQUESTS` are remembered
+    fn cancel(&mut self, key: RequestKey) {
+        if self.pending_requests.remove(&key).is_none() {
+            return;
+        }
+        self.canceled_requests.insert(key.clone());
+        self.canceled_order.push_back(key);
+        if self.canceled_order.len() > MAX_CANCELED_REQUESTS {
+            if let Some(oldest) = self.canceled_order.pop_front() {
+                self.canceled_requests.remove(&oldest);
+            }
         }
     }
 }
@@ -127,7 +196,18 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Future for Receiver<S>
                     self.consumers.insert(consumer_id, resolver);
                 }
                 Poll::Ready(Some(Register::Ping { resolver })) => {
-                    self.ping = Some(resolver);
+                    self.pings.push_back(resolver);
+                }
+                Poll::Ready(Some(Register::Producer {
+                    producer_id,
+                    resolver,
+                })) => {
+                    // forget the producers that were dropped
+                    self.producers.retain(|_, producer| !producer.is_canceled());
+                    self.producers.insert(producer_id, resolver);
+                }
+                Poll::Ready(Some(Register::Cancel { key })) => {
+                    self.cancel(key);
                 }
                 Poll::Ready(None) => {
                     self.error.set(ConnectionError::Disconnected);
@@ -150,16 +230,29 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Future for Receiver<S>
                         command: BaseCommand { pong: Some(_), .. },
                         ..
                     } => {
-                        if let Some(sender) = self.ping.take() {
-                            let _ = sender.send(());
+                        if let Some(resolver) = self.pings.pop_front() {
+                            let _ = resolver.send(());
                         }
                     }
+                    Message {
+                        command:
+                            BaseCommand {
+                                auth_challenge: Some(_),
+                                ..
+                            },
+                        ..
+                    } => {
+                        debug!("received an authentication challenge");
+                        let _ = self.auth_challenges.unbounded_send(());
+                    }
                     msg => match msg.request_key() {
                         Some(key @ RequestKey::RequestId(_))
                         | Some(key @ RequestKey::ProducerSend { .. }) => {
                             if let Some(resolver) = self.pending_requests.remove(&key) {
                                 // We don't care if the receiver has dropped their future
                                 let _ = resolver.send(msg);
+                            } else if self.canceled_requests.remove(&key) {
+                                debug!("ignoring the late answer to request {:?}", key);
                             } else {
                                 self.received_messages.insert(key, msg);
                             }
@@ -193,6 +286,40 @@ impl<S: Stream<Item = Result<Message, ConnectionError>>> Future for Receiver<S>
                                 }
                             }
                         }
+                        Some(RequestKey::CloseProducer {
+                            producer_id,
+                            request_id,
+                        }) => {
+                            if let Some(resolver) = self
+                                .pending_requests
+                                .remove(&RequestKey::RequestId(request_id))
+                            {
+                                let _ = resolver.send(msg);
+                            } else if let Some(producer) = self.producers.remove(&producer_id) {
+                                debug!("the broker closed producer {}", producer_id);
+                                let _ = producer.send(());
+                                // the receipts of the pending sends will not come,
+                                // they are answered with the close command
+                                let sends: Vec<RequestKey> = self
+                                    .pending_requests
+                                    .keys()
+                                    .filter(|key| match key {
+                                        RequestKey::ProducerSend {
+                                            producer_id: id, ..
+                                        } => *id == producer_id,
+                                        _ => false,
+                                    })
+                                    .cloned()
+                                    .collect();
+                                for key in sends {
+                                    if let Some(resolver) = self.pending_requests.remove(&key) {
+                                        let _ = resolver.send(msg.clone());
+                                    }
+                                }
+                            } else {
+                                warn!("the broker closed unknown producer {}", producer_id);
+                            }
+                        }
                         None => {
                             warn!(
                                 "Received unexpected message; dropping. Message {:?}",
@@ -233,6 +360,25 @@ impl SerialId {
     }
 }
 
+/// maximum message size used when the broker does not advertise one
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 5 * 1024 * 1024;
+
+/// room left above the maximum message size for the command and metadata of
+/// a frame, like the Java client's `MESSAGE_SIZE_FRAME_PADDING`
+pub(crate) const FRAME_PADDING: usize = 10 * 1024;
+
+/// maximum size of the frames received until the broker advertises its
+/// maximum message size
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = DEFAULT_MAX_MESSAGE_SIZE + FRAME_PADDING;
+
+/// client version sent in `CommandConnect` and `CommandAuthResponse`
+pub(crate) const CLIENT_VERSION: &str = "2.0.1-incubating";
+
+/// protocol version sent in `CommandConnect`. The broker answers with the
+/// lowest of this one and its own, and the commands introduced by later
+/// versions fail on connections that negotiated an older one
+pub(crate) const PROTOCOL_VERSION: i32 = proto::ProtocolVersion::V15 as i32;
+
 /// An owned type that can send messages like a connection
 //#[derive(Clone)]
 pub struct ConnectionSender<Exe: Executor> {
@@ -243,6 +389,8 @@ pub struct ConnectionSender<Exe: Executor> {
     error: SharedError,
     executor: Arc<Exe>,
     operation_timeout: Duration,
+    max_message_size: usize,
+    protocol_version: i32,
 }
 
 impl<Exe: Executor> ConnectionSender<Exe> {
@@ -254,6 +402,8 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         error: SharedError,
         executor: Arc<Exe>,
         operation_timeout: Duration,
+        max_message_size: usize,
+        protocol_version: i32,
     ) -> ConnectionSender<Exe> {
         ConnectionSender {
             tx,
@@ -263,7 +413,26 @@ impl<Exe: Executor> ConnectionSender<Exe> {
             error,
             executor,
             operation_timeout,
+            max_message_size,
+            protocol_version,
+        }
+    }
+
+    /// fails if the protocol version negotiated with the broker is lower
+    /// than the one introducing a command
+    fn check_protocol_version(
+        &self,
+        required: proto::ProtocolVersion,
+        command: &'static str,
+    ) -> Result<(), ConnectionError> {
+        if self.protocol_version < required as i32 {
+            return Err(ConnectionError::UnsupportedProtocolVersion {
+                command,
+                required: required as i32,
+                actual: self.protocol_version,
+            });
         }
+        Ok(())
     }
 
     pub(crate) async fn send(
@@ -282,7 +451,32 @@ impl<Exe: Executor> ConnectionSender<Exe> {
             .await
     }
 
+    /// sends a message, and forgets it if the receipt does not come back
+    /// before `timeout`, instead of the operation timeout
+    pub(crate) async fn send_with_timeout(
+        &self,
+        producer_id: u64,
+        producer_name: String,
+        sequence_id: u64,
+        message: producer::ProducerMessage,
+        timeout: Duration,
+    ) -> Result<proto::CommandSendReceipt, ConnectionError> {
+        let key = RequestKey::ProducerSend {
+            producer_id,
+            sequence_id,
+        };
+        let msg = messages::send(producer_id, producer_name, sequence_id, message);
+        self.send_message_with_timeout(msg, key, |resp| resp.command.send_receipt, timeout)
+            .await
+    }
+
     pub async fn send_ping(&self) -> Result<(), ConnectionError> {
+        self.send_ping_with_timeout(self.operation_timeout).await
+    }
+
+    /// sends a ping, and marks the connection as failed if the pong does
+    /// not come back before `timeout`
+    pub async fn send_ping_with_timeout(&self, timeout: Duration) -> Result<(), ConnectionError> {
         let (resolver, response) = oneshot::channel();
         trace!("sending ping");
 
@@ -292,7 +486,7 @@ impl<Exe: Executor> ConnectionSender<Exe> {
             self.tx.unbounded_send(messages::ping()),
         ) {
             (Ok(_), Ok(_)) => {
-                let delay_f = self.executor.delay(self.operation_timeout);
+                let delay_f = self.executor.delay(timeout);
                 pin_mut!(response);
                 pin_mut!(delay_f);
 
@@ -369,6 +563,22 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    /// returns a receiver resolved when the broker closes the producer,
+    /// for example when the topic is moved to another broker
+    pub(crate) fn watch_producer_close(
+        &self,
+        producer_id: u64,
+    ) -> Result<oneshot::Receiver<()>, ConnectionError> {
+        let (resolver, closed) = oneshot::channel();
+        self.registrations
+            .unbounded_send(Register::Producer {
+                producer_id,
+                resolver,
+            })
+            .map_err(|_| ConnectionError::Disconnected)?;
+        Ok(closed)
+    }
+
     pub async fn close_producer(
         &self,
         producer_id: u64,
@@ -459,6 +669,18 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    pub async fn unsubscribe(
+        &self,
+        consumer_id: u64,
+    ) -> Result<proto::CommandSuccess, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::unsubscribe(consumer_id, request_id);
+        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
+            resp.command.success
+        })
+        .await
+    }
+
     pub async fn seek(
         &self,
         consumer_id: u64,
@@ -473,12 +695,199 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         .await
     }
 
+    pub async fn consumer_stats(
+        &self,
+        consumer_id: u64,
+    ) -> Result<proto::CommandConsumerStatsResponse, ConnectionError> {
+        self.check_protocol_version(proto::ProtocolVersion::V8, "ConsumerStats")?;
+        let request_id = self.request_id.get();
+        let msg = messages::consumer_stats(consumer_id, request_id);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.consumer_stats_response
+            })
+            .await?;
+
+        match response.error_code {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.error_message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    pub async fn get_schema(
+        &self,
+        topic: String,
+        version: Option<Vec<u8>>,
+    ) -> Result<proto::CommandGetSchemaResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::get_schema(request_id, topic, version);
+        let response = self
+            .send_message(msg, RequestKey::RequestId(request_id), |resp| {
+                resp.command.get_schema_response
+            })
+            .await?;
+
+        match response.error_code {
+            Some(error) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                response.error_message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub async fn new_txn(
+        &self,
+        tc_id: u64,
+        timeout: Duration,
+    ) -> Result<proto::CommandNewTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::new_txn(request_id, tc_id, timeout);
+        self.send_txn_request("NewTxn", msg, request_id, |resp| {
+            resp.command.new_txn_response
+        })
+        .await
+    }
+
+    #[cfg(feature = "transactions")]
+    pub async fn add_partition_to_txn(
+        &self,
+        txn_id: TxnId,
+        partitions: Vec<String>,
+    ) -> Result<proto::CommandAddPartitionToTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::add_partition_to_txn(request_id, txn_id, partitions);
+        self.send_txn_request("AddPartitionToTxn", msg, request_id, |resp| {
+            resp.command.add_partition_to_txn_response
+        })
+        .await
+    }
+
+    #[cfg(feature = "transactions")]
+    pub async fn add_subscription_to_txn(
+        &self,
+        txn_id: TxnId,
+        topic: String,
+        subscription: String,
+    ) -> Result<proto::CommandAddSubscriptionToTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::add_subscription_to_txn(request_id, txn_id, topic, subscription);
+        self.send_txn_request("AddSubscriptionToTxn", msg, request_id, |resp| {
+            resp.command.add_subscription_to_txn_response
+        })
+        .await
+    }
+
+    #[cfg(feature = "transactions")]
+    pub async fn end_txn(
+        &self,
+        txn_id: TxnId,
+        action: proto::TxnAction,
+    ) -> Result<proto::CommandEndTxnResponse, ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::end_txn(request_id, txn_id, action);
+        self.send_txn_request("EndTxn", msg, request_id, |resp| {
+            resp.command.end_txn_response
+        })
+        .await
+    }
+
+    /// acknowledges messages in a transaction, and waits for the broker's answer
+    #[cfg(feature = "transactions")]
+    pub async fn ack_in_txn(
+        &self,
+        consumer_id: u64,
+        message_ids: Vec<proto::MessageIdData>,
+        txn_id: TxnId,
+    ) -> Result<(), ConnectionError> {
+        let request_id = self.request_id.get();
+        let msg = messages::ack_in_txn(consumer_id, request_id, message_ids, txn_id);
+        self.send_txn_request("Ack", msg, request_id, |resp| resp.command.ack_response)
+            .await
+            .map(drop)
+    }
+
+    /// sends a transaction command, and turns the error of its response
+    /// into a [ConnectionError::PulsarError]
+    #[cfg(feature = "transactions")]
+    async fn send_txn_request<R: TxnResponse + Debug, F>(
+        &self,
+        command: &'static str,
+        msg: Message,
+        request_id: u64,
+        extract: F,
+    ) -> Result<R, ConnectionError>
+    where
+        F: FnOnce(Message) -> Option<R>,
+    {
+        self.check_protocol_version(proto::ProtocolVersion::V15, command)?;
+        let mut response = self
+            .send_message(msg, RequestKey::RequestId(request_id), extract)
+            .await?;
+
+        match response.take_error() {
+            Some((error, message)) => Err(ConnectionError::PulsarError(
+                crate::error::server_error(error),
+                message,
+            )),
+            None => Ok(response),
+        }
+    }
+
+    pub async fn get_last_message_id(
+        &self,
+        consumer_id: u64,
+    ) -> Result<proto::CommandGetLastMessageIdResponse, ConnectionError> {
+        self.check_protocol_version(proto::ProtocolVersion::V12, "GetLastMessageId")?;
+        let request_id = self.request_id.get();
+        let msg = messages::get_last_message_id(consumer_id, request_id);
+        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
+            resp.command.get_last_message_id_response
+        })
+        .await
+    }
+
+    /// sends a command built by `command` from a new request id, and returns
+    /// the broker's answer with the same request id
+    pub async fn send_raw_command<F>(&self, command: F) -> Result<BaseCommand, ConnectionError>
+    where
+        F: FnOnce(u64) -> BaseCommand,
+    {
+        let request_id = self.request_id.get();
+        let msg = Message {
+            command: command(request_id),
+            payload: None,
+        };
+        self.send_message(msg, RequestKey::RequestId(request_id), |resp| {
+            Some(resp.command)
+        })
+        .await
+    }
+
     async fn send_message<R: Debug, F>(
         &self,
         msg: Message,
         key: RequestKey,
         extract: F,
     ) -> Result<R, ConnectionError>
+    where
+        F: FnOnce(Message) -> Option<R>,
+    {
+        self.send_message_with_timeout(msg, key, extract, self.operation_timeout)
+            .await
+    }
+
+    async fn send_message_with_timeout<R: Debug, F>(
+        &self,
+        msg: Message,
+        key: RequestKey,
+        extract: F,
+        timeout: Duration,
+    ) -> Result<R, ConnectionError>
     where
         F: FnOnce(Message) -> Option<R>,
     {
@@ -486,12 +895,17 @@ impl<Exe: Executor> ConnectionSender<Exe> {
         trace!("sending message(key = {:?}): {:?}", key, msg);
 
         let k = key.clone();
+        let key_for_cancel = key.clone();
         let response = async {
             response
                 .await
-                .map_err(|oneshot::Canceled| {
-                    self.error.set(ConnectionError::Disconnected);
-                    ConnectionError::Disconnected
+                .map_err(|oneshot::Canceled| match self.error.get() {
+                    // the connection was closed by a frame above the limit
+                    Some(e @ ConnectionError::FrameTooLarge { .. }) => e,
+                    _ => {
+                        self.error.set(ConnectionError::Disconnected);
+                        ConnectionError::Disconnected
+                    }
                 })
                 .map(move |message: Message| {
                     trace!("received message(key = {:?}): {:?}", k, message);
@@ -505,16 +919,23 @@ impl<Exe: Executor> ConnectionSender<Exe> {
             self.tx.unbounded_send(msg),
         ) {
             (Ok(_), Ok(_)) => {
-                let delay_f = self.executor.delay(self.operation_timeout);
+                let delay_f = self.executor.delay(timeout);
                 pin_mut!(response);
                 pin_mut!(delay_f);
 
                 match select(response, delay_f).await {
                     Either::Left((res, _)) => res,
-                    Either::Right(_) => Err(ConnectionError::Io(std::io::Error::new(
-                        std::io::ErrorKind::TimedOut,
-                        "timeout sending message to the Pulsar server",
-                    ))),
+                    Either::Right(_) => {
+                        warn!(
+                            "no answer to request {:?} after {}ms",
+                            key_for_cancel,
+                            timeout.as_millis()
+                        );
+                        let _ = self.registrations.unbounded_send(Register::Cancel {
+                            key: key_for_cancel,
+                        });
+                        Err(ConnectionError::OperationTimeout)
+                    }
                 }
             }
             _ => Err(ConnectionError::Disconnected),
@@ -522,6 +943,43 @@ impl<Exe: Executor> ConnectionSender<Exe> {
     }
 }
 
+/// tries to connect to each address in turn, until one of them connects or
+/// the timeout is reached, and returns the first connection, or the last error
+async fn connect_any<Exe, T, F, Fut>(
+    executor: &Exe,
+    addresses: &[SocketAddr],
+    connection_timeout: Duration,
+    mut connect: F,
+) -> Result<T, ConnectionError>
+where
+    Exe: Executor,
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, ConnectionError>>,
+{
+    let mut last_error = ConnectionError::NotFound;
+    // the timeout is shared by all the addresses
+    let delay_f = executor.delay(connection_timeout);
+    pin_mut!(delay_f);
+    for address in addresses {
+        let connection = connect(*address);
+        pin_mut!(connection);
+
+        last_error = match select(connection, delay_f.as_mut()).await {
+            Either::Left((Ok(connection), _)) => return Ok(connection),
+            Either::Left((Err(e), _)) => e,
+            Either::Right(_) => {
+                warn!("timeout connecting to {}", address);
+                return Err(ConnectionError::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timeout connecting to the Pulsar server",
+                )));
+            }
+        };
+        warn!("could not connect to {}: {}", address, last_error);
+    }
+    Err(last_error)
+}
+
 pub struct Connection<Exe: Executor> {
     id: i64,
     url: Url,
@@ -531,10 +989,14 @@ pub struct Connection<Exe: Executor> {
 impl<Exe: Executor> Connection<Exe> {
     pub async fn new(
         url: Url,
-        auth_data: Option<Authentication>,
+        auth_data: Option<Credentials>,
         proxy_to_broker_url: Option<String>,
         certificate_chain: &[Certificate],
+        client_identity: Option<&Identity>,
+        allow_insecure: bool,
         connection_timeout: Duration,
+        address_order: AddressOrder,
+        max_frame_size: Option<usize>,
         operation_timeout: Duration,
         executor: Arc<Exe>,
     ) -> Result<Connection<Exe>, ConnectionError> {
@@ -554,7 +1016,7 @@ impl<Exe: Executor> Connection<Exe> {
         };
 
         let u = url.clone();
-        let address: SocketAddr = match executor
+        let mut addresses: Vec<SocketAddr> = match executor
             .spawn_blocking(move || {
                 u.socket_addrs(|| match u.scheme() {
                     "pulsar" => Some(6650),
@@ -566,50 +1028,32 @@ impl<Exe: Executor> Connection<Exe> {
                     e
                 })
                 .ok()
-                .and_then(|v| {
-                    let mut rng = thread_rng();
-                    let index: usize = rng.gen_range(0..v.len());
-                    v.get(index).copied()
-                })
             })
             .await
         {
-            Some(Some(address)) => address,
-            _ =>
-            //return Err(Error::Custom(format!("could not query address: {}", url))),
-            {
-                return Err(ConnectionError::NotFound)
-            }
-        };
-
-        let hostname = hostname.unwrap_or_else(|| address.ip().to_string());
-
-        debug!("Connecting to {}: {}", url, address);
-        let sender_prepare = Connection::prepare_stream(
-            address,
-            hostname,
-            tls,
-            auth_data,
-            proxy_to_broker_url,
-            certificate_chain,
-            executor.clone(),
-            operation_timeout,
-        );
-        let delay_f = executor.delay(connection_timeout);
-
-        pin_mut!(sender_prepare);
-        pin_mut!(delay_f);
-
-        let sender;
-        match select(sender_prepare, delay_f).await {
-            Either::Left((res, _)) => sender = res?,
-            Either::Right(_) => {
-                return Err(ConnectionError::Io(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "timeout connecting to the Pulsar server",
-                )));
-            }
+            Some(Some(addresses)) if !addresses.is_empty() => addresses,
+            _ => return Err(ConnectionError::NotFound),
         };
+        address_order.arrange(&mut addresses);
+
+        let sender = connect_any(&*executor, &addresses, connection_timeout, |address| {
+            let hostname = hostname.clone().unwrap_or_else(|| address.ip().to_string());
+            debug!("Connecting to {}: {}", url, address);
+            Connection::prepare_stream(
+                address,
+                hostname,
+                tls,
+                auth_data.clone(),
+                proxy_to_broker_url.clone(),
+                certificate_chain,
+                client_identity,
+                allow_insecure,
+                max_frame_size,
+                executor.clone(),
+                operation_timeout,
+            )
+        })
+        .await?;
 
         let id = rand::random();
         Ok(Connection { id, url, sender })
@@ -619,12 +1063,22 @@ impl<Exe: Executor> Connection<Exe> {
         address: SocketAddr,
         hostname: String,
         tls: bool,
-        auth_data: Option<Authentication>,
+        auth_data: Option<Credentials>,
         proxy_to_broker_url: Option<String>,
         certificate_chain: &[Certificate],
+        client_identity: Option<&Identity>,
+        allow_insecure: bool,
+        max_frame_size: Option<usize>,
         executor: Arc<Exe>,
         operation_timeout: Duration,
     ) -> Result<ConnectionSender<Exe>, ConnectionError> {
+        let codec = Codec::new(max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE));
+        // without a configured limit, it follows the maximum message size
+        // advertised by the broker
+        let frame_limit = match max_frame_size {
+            None => Some(codec.frame_limit_handle()),
+            Some(_) => None,
+        };
         match executor.kind() {
             #[cfg(feature = "tokio-runtime")]
             ExecutorKind::Tokio => {
@@ -635,17 +1089,22 @@ impl<Exe: Executor> Connection<Exe> {
                     for certificate in certificate_chain {
                         builder.add_root_certificate(certificate.clone());
                     }
+                    if let Some(identity) = client_identity {
+                        builder.identity(identity.clone());
+                    }
+                    builder.danger_accept_invalid_certs(allow_insecure);
                     let cx = builder.build()?;
                     let cx = tokio_native_tls::TlsConnector::from(cx);
                     let stream = cx
                         .connect(&hostname, stream)
                         .await
-                        .map(|stream| tokio_util::codec::Framed::new(stream, Codec))?;
+                        .map(|stream| tokio_util::codec::Framed::new(stream, codec))?;
 
                     Connection::connect(
                         stream,
                         auth_data,
                         proxy_to_broker_url,
+                        frame_limit,
                         executor,
                         operation_timeout,
                     )
@@ -653,12 +1112,13 @@ impl<Exe: Executor> Connection<Exe> {
                 } else {
                     let stream = tokio::net::TcpStream::connect(&address)
                         .await
-                        .map(|stream| tokio_util::codec::Framed::new(stream, Codec))?;
+                        .map(|stream| tokio_util::codec::Framed::new(stream, codec))?;
 
                     Connection::connect(
                         stream,
                         auth_data,
                         proxy_to_broker_url,
+                        frame_limit,
                         executor,
                         operation_timeout,
                     )
@@ -677,15 +1137,20 @@ impl<Exe: Executor> Connection<Exe> {
                     for certificate in certificate_chain {
                         connector = connector.add_root_certificate(certificate.clone());
                     }
+                    if let Some(identity) = client_identity {
+                        connector = connector.identity(identity.clone());
+                    }
+                    connector = connector.danger_accept_invalid_certs(allow_insecure);
                     let stream = connector
                         .connect(&hostname, stream)
                         .await
-                        .map(|stream| asynchronous_codec::Framed::new(stream, Codec))?;
+                        .map(|stream| asynchronous_codec::Framed::new(stream, codec))?;
 
                     Connection::connect(
                         stream,
                         auth_data,
                         proxy_to_broker_url,
+                        frame_limit,
                         executor,
                         operation_timeout,
                     )
@@ -693,12 +1158,13 @@ impl<Exe: Executor> Connection<Exe> {
                 } else {
                     let stream = async_std::net::TcpStream::connect(&address)
                         .await
-                        .map(|stream| asynchronous_codec::Framed::new(stream, Codec))?;
+                        .map(|stream| asynchronous_codec::Framed::new(stream, codec))?;
 
                     Connection::connect(
                         stream,
                         auth_data,
                         proxy_to_broker_url,
+                        frame_limit,
                         executor,
                         operation_timeout,
                     )
@@ -714,8 +1180,9 @@ impl<Exe: Executor> Connection<Exe> {
 
     pub async fn connect<S>(
         mut stream: S,
-        auth_data: Option<Authentication>,
+        auth_data: Option<Credentials>,
         proxy_to_broker_url: Option<String>,
+        frame_limit: Option<Arc<AtomicUsize>>,
         executor: Arc<Exe>,
         operation_timeout: Duration,
     ) -> Result<ConnectionSender<Exe>, ConnectionError>
@@ -724,16 +1191,21 @@ impl<Exe: Executor> Connection<Exe> {
         S: Sink<Message, Error = ConnectionError>,
         S: Send + std::marker::Unpin + 'static,
     {
-        let _ = stream
+        let auth = match auth_data.as_ref() {
+            Some(credentials) => Some(credentials.current(&*executor).await?),
+            None => None,
+        };
+
+        stream
             .send({
-                let msg = messages::connect(auth_data, proxy_to_broker_url);
+                let msg = messages::connect(auth, proxy_to_broker_url);
                 trace!("connection message: {:?}", msg);
                 msg
             })
             .await?;
 
         let msg = stream.next().await;
-        match msg {
+        let connected = match msg {
             Some(Ok(Message {
                 command:
                     proto::BaseCommand {
@@ -757,12 +1229,22 @@ impl<Exe: Executor> Connection<Exe> {
             Some(Err(e)) => Err(e),
             None => Err(ConnectionError::Disconnected),
         }?;
+        let max_message_size = connected
+            .max_message_size
+            .filter(|size| *size > 0)
+            .map(|size| size as usize)
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        if let Some(frame_limit) = frame_limit {
+            frame_limit.store(max_message_size + FRAME_PADDING, Ordering::Relaxed);
+        }
+        let protocol_version = connected.protocol_version.unwrap_or(0);
 
         let (mut sink, stream) = stream.split();
         let (tx, mut rx) = mpsc::unbounded();
         let (registrations_tx, registrations_rx) = mpsc::unbounded();
         let error = SharedError::new();
         let (receiver_shutdown_tx, receiver_shutdown_rx) = oneshot::channel();
+        let (auth_challenges_tx, mut auth_challenges_rx) = mpsc::unbounded();
 
         if executor
             .spawn(Box::pin(
@@ -772,6 +1254,7 @@ impl<Exe: Executor> Connection<Exe> {
                     error.clone(),
                     registrations_rx,
                     receiver_shutdown_rx,
+                    auth_challenges_tx,
                 )
                 .map(|_| ()),
             ))
@@ -781,6 +1264,27 @@ impl<Exe: Executor> Connection<Exe> {
             return Err(ConnectionError::Shutdown);
         }
 
+        if let Some(credentials) = auth_data {
+            let tx = tx.clone();
+            let exe = executor.clone();
+            let res = executor.spawn(Box::pin(async move {
+                while auth_challenges_rx.next().await.is_some() {
+                    match credentials.current(&*exe).await {
+                        Ok(auth) => {
+                            if tx.unbounded_send(messages::auth_response(auth)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => error!("could not refresh the authentication data: {}", e),
+                    }
+                }
+            }));
+            if res.is_err() {
+                error!("the executor could not spawn the authentication future");
+                return Err(ConnectionError::Shutdown);
+            }
+        }
+
         let err = error.clone();
         let res = executor.spawn(Box::pin(async move {
             while let Some(msg) = rx.next().await {
@@ -803,6 +1307,8 @@ impl<Exe: Executor> Connection<Exe> {
             error,
             executor.clone(),
             operation_timeout,
+            max_message_size,
+            protocol_version,
         );
 
         Ok(sender)
@@ -816,6 +1322,12 @@ impl<Exe: Executor> Connection<Exe> {
         self.sender.error.remove()
     }
 
+    /// copy of the error that made the connection invalid, which is kept
+    /// unlike with [Connection::error]
+    pub fn last_error(&self) -> Option<ConnectionError> {
+        self.sender.error.get()
+    }
+
     pub fn is_valid(&self) -> bool {
         !self.sender.error.is_set()
     }
@@ -824,6 +1336,16 @@ impl<Exe: Executor> Connection<Exe> {
         &self.url
     }
 
+    /// maximum message size advertised by the broker when connecting
+    pub fn max_message_size(&self) -> usize {
+        self.sender.max_message_size
+    }
+
+    /// protocol version negotiated with the broker when connecting
+    pub fn protocol_version(&self) -> i32 {
+        self.sender.protocol_version
+    }
+
     /// Chain to send a message, e.g. conn.sender().send_ping()
     pub fn sender(&self) -> &ConnectionSender<Exe> {
         &self.sender
@@ -839,6 +1361,32 @@ impl<Exe: Executor> Drop for Connection<Exe> {
     }
 }
 
+/// responses of the transaction commands, carrying an optional error
+#[cfg(feature = "transactions")]
+trait TxnResponse {
+    fn take_error(&mut self) -> Option<(i32, Option<String>)>;
+}
+
+#[cfg(feature = "transactions")]
+macro_rules! txn_response {
+    ($($response:ty),*) => {
+        $(impl TxnResponse for $response {
+            fn take_error(&mut self) -> Option<(i32, Option<String>)> {
+                self.error.take().map(|error| (error, self.message.take()))
+            }
+        })*
+    };
+}
+
+#[cfg(feature = "transactions")]
+txn_response!(
+    proto::CommandNewTxnResponse,
+    proto::CommandAddPartitionToTxnResponse,
+    proto::CommandAddSubscriptionToTxnResponse,
+    proto::CommandEndTxnResponse,
+    proto::CommandAckResponse
+);
+
 fn extract_message<T: Debug, F>(message: Message, extract: F) -> Result<T, ConnectionError>
 where
     F: FnOnce(Message) -> Option<T>,
@@ -870,6 +1418,10 @@ pub(crate) mod messages {
         Message, Payload,
     };
     use crate::producer::{self, ProducerOptions};
+    #[cfg(feature = "transactions")]
+    use crate::transaction::TxnId;
+    #[cfg(feature = "transactions")]
+    use std::time::Duration;
 
     pub fn connect(auth: Option<Authentication>, proxy_to_broker_url: Option<String>) -> Message {
         let (auth_method_name, auth_data) = match auth {
@@ -884,8 +1436,8 @@ pub(crate) mod messages {
                     auth_method_name,
                     auth_data,
                     proxy_to_broker_url,
-                    client_version: String::from("2.0.1-incubating"),
-                    protocol_version: Some(12),
+                    client_version: String::from(super::CLIENT_VERSION),
+                    protocol_version: Some(super::PROTOCOL_VERSION),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -987,6 +1539,8 @@ pub(crate) mod messages {
                     producer_id,
                     sequence_id,
                     num_messages: message.num_messages_in_batch,
+                    txnid_least_bits: message.txnid_least_bits,
+                    txnid_most_bits: message.txnid_most_bits,
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -999,6 +1553,9 @@ pub(crate) mod messages {
                     publish_time: Utc::now().timestamp_millis() as u64,
                     replicated_from: None,
                     partition_key: message.partition_key,
+                    ordering_key: message.ordering_key,
+                    txnid_least_bits: message.txnid_least_bits,
+                    txnid_most_bits: message.txnid_most_bits,
                     replicate_to: message.replicate_to,
                     compression: message.compression,
                     uncompressed_size: message.uncompressed_size,
@@ -1008,6 +1565,11 @@ pub(crate) mod messages {
                     encryption_algo: message.encryption_algo,
                     encryption_param: message.encryption_param,
                     schema_version: message.schema_version,
+                    deliver_at_time: message.deliver_at_time.map(|t| t as i64),
+                    uuid: message.uuid,
+                    chunk_id: message.chunk_id,
+                    num_chunks_from_msg: message.num_chunks_from_msg,
+                    total_chunk_msg_size: message.total_chunk_msg_size,
                     ..Default::default()
                 },
                 data: message.payload,
@@ -1015,6 +1577,24 @@ pub(crate) mod messages {
         }
     }
 
+    pub fn auth_response(auth: Authentication) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::AuthResponse as i32,
+                auth_response: Some(proto::CommandAuthResponse {
+                    client_version: Some(String::from(super::CLIENT_VERSION)),
+                    response: Some(proto::AuthData {
+                        auth_method_name: Some(auth.name),
+                        auth_data: Some(auth.data),
+                    }),
+                    protocol_version: Some(super::PROTOCOL_VERSION),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
     pub fn lookup_topic(topic: String, authoritative: bool, request_id: u64) -> Message {
         Message {
             command: proto::BaseCommand {
@@ -1174,6 +1754,20 @@ pub(crate) mod messages {
         }
     }
 
+    pub fn unsubscribe(consumer_id: u64, request_id: u64) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::Unsubscribe as i32,
+                unsubscribe: Some(proto::CommandUnsubscribe {
+                    consumer_id,
+                    request_id,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
     pub fn seek(
         consumer_id: u64,
         request_id: u64,
@@ -1194,4 +1788,522 @@ pub(crate) mod messages {
             payload: None,
         }
     }
+
+    pub fn consumer_stats(consumer_id: u64, request_id: u64) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::ConsumerStats as i32,
+                consumer_stats: Some(proto::CommandConsumerStats {
+                    request_id,
+                    consumer_id,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn get_schema(request_id: u64, topic: String, schema_version: Option<Vec<u8>>) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::GetSchema as i32,
+                get_schema: Some(proto::CommandGetSchema {
+                    request_id,
+                    topic,
+                    schema_version,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub fn new_txn(request_id: u64, tc_id: u64, timeout: Duration) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::NewTxn as i32,
+                new_txn: Some(proto::CommandNewTxn {
+                    request_id,
+                    // despite its name, brokers read this field in milliseconds
+                    txn_ttl_seconds: Some(timeout.as_millis() as u64),
+                    tc_id: Some(tc_id),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub fn add_partition_to_txn(
+        request_id: u64,
+        txn_id: TxnId,
+        partitions: Vec<String>,
+    ) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::AddPartitionToTxn as i32,
+                add_partition_to_txn: Some(proto::CommandAddPartitionToTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    partitions,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub fn add_subscription_to_txn(
+        request_id: u64,
+        txn_id: TxnId,
+        topic: String,
+        subscription: String,
+    ) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::AddSubscriptionToTxn as i32,
+                add_subscription_to_txn: Some(proto::CommandAddSubscriptionToTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    subscription: vec![proto::Subscription {
+                        topic,
+                        subscription,
+                    }],
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub fn end_txn(request_id: u64, txn_id: TxnId, action: proto::TxnAction) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::EndTxn as i32,
+                end_txn: Some(proto::CommandEndTxn {
+                    request_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    txn_action: Some(action as i32),
+                    message_id: Vec::new(),
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    pub fn ack_in_txn(
+        consumer_id: u64,
+        request_id: u64,
+        message_id: Vec<proto::MessageIdData>,
+        txn_id: TxnId,
+    ) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::Ack as i32,
+                ack: Some(proto::CommandAck {
+                    consumer_id,
+                    ack_type: proto::command_ack::AckType::Individual as i32,
+                    message_id,
+                    txnid_least_bits: Some(txn_id.least_bits),
+                    txnid_most_bits: Some(txn_id.most_bits),
+                    request_id: Some(request_id),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+
+    pub fn get_last_message_id(consumer_id: u64, request_id: u64) -> Message {
+        Message {
+            command: proto::BaseCommand {
+                r#type: CommandType::GetLastMessageId as i32,
+                get_last_message_id: Some(proto::CommandGetLastMessageId {
+                    consumer_id,
+                    request_id,
+                }),
+                ..Default::default()
+            },
+            payload: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pongs_resolve_pings_in_order() {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded::<Result<Message, ConnectionError>>();
+        let (outbound_tx, _outbound_rx) = mpsc::unbounded();
+        let (registrations_tx, registrations_rx) = mpsc::unbounded();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (auth_challenges_tx, _auth_challenges_rx) = mpsc::unbounded();
+        tokio::spawn(Receiver::new(
+            inbound_rx,
+            outbound_tx,
+            SharedError::new(),
+            registrations_rx,
+            shutdown_rx,
+            auth_challenges_tx,
+        ));
+
+        let (first_tx, first_rx) = oneshot::channel();
+        let (second_tx, mut second_rx) = oneshot::channel();
+        registrations_tx
+            .unbounded_send(Register::Ping { resolver: first_tx })
+            .unwrap();
+        registrations_tx
+            .unbounded_send(Register::Ping {
+                resolver: second_tx,
+            })
+            .unwrap();
+
+        inbound_tx.unbounded_send(Ok(messages::pong())).unwrap();
+        first_rx.await.unwrap();
+        assert_eq!(second_rx.try_recv(), Ok(None));
+
+        inbound_tx.unbounded_send(Ok(messages::pong())).unwrap();
+        second_rx.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_broker_pings() {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded::<Result<Message, ConnectionError>>();
+        let (outbound_tx, mut outbound_rx) = mpsc::unbounded();
+        let (_registrations_tx, registrations_rx) = mpsc::unbounded();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (auth_challenges_tx, _auth_challenges_rx) = mpsc::unbounded();
+        tokio::spawn(Receiver::new(
+            inbound_rx,
+            outbound_tx,
+            SharedError::new(),
+            registrations_rx,
+            shutdown_rx,
+            auth_challenges_tx,
+        ));
+
+        inbound_tx.unbounded_send(Ok(messages::ping())).unwrap();
+        let answer = outbound_rx.next().await.unwrap();
+        assert_eq!(
+            answer.command.r#type,
+            proto::base_command::Type::Pong as i32
+        );
+        assert!(answer.command.pong.is_some());
+    }
+
+    #[tokio::test]
+    async fn unsupported_protocol_version() {
+        let (tx, _rx) = mpsc::unbounded();
+        let (registrations_tx, _registrations_rx) = mpsc::unbounded();
+        let (shutdown_tx, _shutdown_rx) = oneshot::channel();
+        let sender = ConnectionSender::new(
+            tx,
+            registrations_tx,
+            shutdown_tx,
+            SerialId::new(),
+            SharedError::new(),
+            Arc::new(crate::executor::TokioExecutor),
+            Duration::from_secs(1),
+            DEFAULT_MAX_MESSAGE_SIZE,
+            proto::ProtocolVersion::V7 as i32,
+        );
+
+        match sender.consumer_stats(1).await {
+            Err(ConnectionError::UnsupportedProtocolVersion {
+                command,
+                required,
+                actual,
+            }) => {
+                assert_eq!(command, "ConsumerStats");
+                assert_eq!(required, 8);
+                assert_eq!(actual, 7);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn missing_protocol_version() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::{Pulsar, TokioExecutor};
+
+        let broker = MockBroker::start_with_handler(|message| {
+            if message.command.r#type() != CommandType::Connect {
+                return None;
+            }
+            Some(vec![Message {
+                command: proto::BaseCommand {
+                    r#type: CommandType::Connected as i32,
+                    connected: Some(proto::CommandConnected {
+                        server_version: "mock".to_string(),
+                        protocol_version: None,
+                        max_message_size: None,
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        // a broker that does not send its version is assumed to support none
+        // of the commands that need a minimum version
+        let connection = pulsar.manager.get_base_connection().await.unwrap();
+        assert_eq!(connection.protocol_version(), 0);
+        let sender = connection.sender();
+        assert!(matches!(
+            sender.consumer_stats(1).await,
+            Err(ConnectionError::UnsupportedProtocolVersion { required: 8, .. })
+        ));
+        assert!(matches!(
+            sender.get_last_message_id(1).await,
+            Err(ConnectionError::UnsupportedProtocolVersion { required: 12, .. })
+        ));
+        #[cfg(feature = "transactions")]
+        assert!(matches!(
+            sender.new_txn(0, Duration::from_secs(60)).await,
+            Err(ConnectionError::UnsupportedProtocolVersion { required: 15, .. })
+        ));
+        assert!(broker
+            .commands_of_type(CommandType::ConsumerStats)
+            .is_empty());
+        assert!(broker
+            .commands_of_type(CommandType::GetLastMessageId)
+            .is_empty());
+        assert!(broker.commands_of_type(CommandType::NewTxn).is_empty());
+    }
+
+    #[test]
+    fn canceled_requests_are_bounded() {
+        let (_inbound_tx, inbound_rx) = mpsc::unbounded::<Result<Message, ConnectionError>>();
+        let (outbound_tx, _outbound_rx) = mpsc::unbounded();
+        let (_registrations_tx, registrations_rx) = mpsc::unbounded();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (auth_challenges_tx, _auth_challenges_rx) = mpsc::unbounded();
+        let mut receiver = Receiver::new(
+            inbound_rx,
+            outbound_tx,
+            SharedError::new(),
+            registrations_rx,
+            shutdown_rx,
+            auth_challenges_tx,
+        );
+
+        let count = MAX_CANCELED_REQUESTS as u64 + 10;
+        for request_id in 0..count {
+            let (resolver, _response) = oneshot::channel();
+            let key = RequestKey::RequestId(request_id);
+            receiver.pending_requests.insert(key.clone(), resolver);
+            receiver.cancel(key);
+        }
+        assert!(receiver.pending_requests.is_empty());
+        assert_eq!(receiver.canceled_requests.len(), MAX_CANCELED_REQUESTS);
+        assert!(!receiver
+            .canceled_requests
+            .contains(&RequestKey::RequestId(0)));
+        assert!(receiver
+            .canceled_requests
+            .contains(&RequestKey::RequestId(count - 1)));
+    }
+
+    #[tokio::test]
+    async fn broker_closes_producer() {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded::<Result<Message, ConnectionError>>();
+        let (outbound_tx, _outbound_rx) = mpsc::unbounded();
+        let (registrations_tx, registrations_rx) = mpsc::unbounded();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (auth_challenges_tx, _auth_challenges_rx) = mpsc::unbounded();
+        tokio::spawn(Receiver::new(
+            inbound_rx,
+            outbound_tx,
+            SharedError::new(),
+            registrations_rx,
+            shutdown_rx,
+            auth_challenges_tx,
+        ));
+
+        let (first_tx, mut first_rx) = oneshot::channel();
+        let (second_tx, second_rx) = oneshot::channel();
+        for (producer_id, resolver) in [(1, first_tx), (2, second_tx)] {
+            registrations_tx
+                .unbounded_send(Register::Producer {
+                    producer_id,
+                    resolver,
+                })
+                .unwrap();
+        }
+        let (first_send_tx, mut first_send_rx) = oneshot::channel();
+        let (second_send_tx, second_send_rx) = oneshot::channel();
+        for (producer_id, resolver) in [(1, first_send_tx), (2, second_send_tx)] {
+            registrations_tx
+                .unbounded_send(Register::Request {
+                    key: RequestKey::ProducerSend {
+                        producer_id,
+                        sequence_id: 3,
+                    },
+                    resolver,
+                })
+                .unwrap();
+        }
+
+        inbound_tx
+            .unbounded_send(Ok(messages::close_producer(2, 7)))
+            .unwrap();
+        second_rx.await.unwrap();
+        assert_eq!(first_rx.try_recv(), Ok(None));
+        // the pending sends of the closed producer are answered
+        let answer = second_send_rx.await.unwrap();
+        assert!(answer.command.close_producer.is_some());
+        assert!(first_send_rx.try_recv().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn frame_limit_follows_max_message_size() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::{Pulsar, TokioExecutor};
+
+        // a 20KB answer, above 1KB and the frame padding
+        let broker = MockBroker::start_with_handler(|message| {
+            let command = match message.command.r#type() {
+                CommandType::Connect => proto::BaseCommand {
+                    r#type: CommandType::Connected as i32,
+                    connected: Some(proto::CommandConnected {
+                        server_version: "mock".to_string(),
+                        protocol_version: Some(PROTOCOL_VERSION),
+                        max_message_size: Some(1024),
+                    }),
+                    ..Default::default()
+                },
+                CommandType::GetTopicsOfNamespace => proto::BaseCommand {
+                    r#type: CommandType::GetTopicsOfNamespaceResponse as i32,
+                    get_topics_of_namespace_response: Some(
+                        proto::CommandGetTopicsOfNamespaceResponse {
+                            request_id: message
+                                .command
+                                .get_topics_of_namespace
+                                .as_ref()?
+                                .request_id,
+                            topics: (0..1000)
+                                .map(|i| format!("persistent://public/default/{:010}", i))
+                                .collect(),
+                        },
+                    ),
+                    ..Default::default()
+                },
+                _ => return None,
+            };
+            Some(vec![Message {
+                command,
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_operation_retry_options(crate::OperationRetryOptions {
+                max_retries: Some(0),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let res = pulsar
+            .get_topics_of_namespace(
+                "public/default".to_string(),
+                proto::command_get_topics_of_namespace::Mode::All,
+            )
+            .await;
+        assert!(
+            matches!(
+                res,
+                Err(crate::Error::Connection(ConnectionError::FrameTooLarge { max, .. }))
+                    if max == 1024 + FRAME_PADDING
+            ),
+            "unexpected result: {:?}",
+            res
+        );
+
+        // a configured limit is kept
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_max_frame_size(1024 * 1024)
+            .build()
+            .await
+            .unwrap();
+        let topics = pulsar
+            .get_topics_of_namespace(
+                "public/default".to_string(),
+                proto::command_get_topics_of_namespace::Mode::All,
+            )
+            .await
+            .unwrap();
+        assert_eq!(topics.len(), 1000);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn connect_any_fails_over() {
+        use crate::TokioExecutor;
+
+        // a port that was just released refuses connections
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let connect = |address: SocketAddr| async move {
+            tokio::net::TcpStream::connect(address).await?;
+            Ok(address)
+        };
+
+        let connected = connect_any(
+            &TokioExecutor,
+            &[closed, open],
+            Duration::from_secs(1),
+            connect,
+        )
+        .await
+        .unwrap();
+        assert_eq!(connected, open);
+
+        let res = connect_any(&TokioExecutor, &[closed], Duration::from_secs(1), connect).await;
+        assert!(matches!(res, Err(ConnectionError::Io(_))));
+
+        // the timeout covers all the addresses
+        let start = std::time::Instant::now();
+        let res = connect_any(
+            &TokioExecutor,
+            &[closed, closed, closed],
+            Duration::from_millis(200),
+            |_| futures::future::pending::<Result<(), ConnectionError>>(),
+        )
+        .await;
+        assert!(
+            matches!(res, Err(ConnectionError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut)
+        );
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
 }
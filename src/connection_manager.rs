@@ -1,12 +1,15 @@
-use crate::connection::{Authentication, Connection};
+use crate::authentication::Credentials;
+use crate::connection::Connection;
 use crate::error::ConnectionError;
 use crate::executor::Executor;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::{channel::oneshot, lock::Mutex};
-use native_tls::Certificate;
+use native_tls::{Certificate, Identity};
+use rand::seq::SliceRandom;
 use rand::Rng;
 use url::Url;
 
@@ -31,10 +34,25 @@ pub struct ConnectionRetryOptions {
     pub max_backoff: Duration,
     /// maximum number of connection retries
     pub max_retries: u32,
-    /// time limit to establish a connection
+    /// time limit to establish a connection, shared by all the addresses of
+    /// the broker
     pub connection_timeout: Duration,
-    /// keep-alive interval for each broker connection
+    /// order in which the addresses of a broker are tried, when its host
+    /// name resolves to several addresses
+    pub address_order: AddressOrder,
+    /// keep-alive interval for each broker connection. A ping is sent at
+    /// this interval, and the connection is considered lost if the pong
+    /// does not come back before the next one
     pub keep_alive: Duration,
+    /// connections are shared by all the producers and consumers on the
+    /// same broker. A connection that is not used anymore is closed after
+    /// this delay (it is checked every minute)
+    pub idle_timeout: Duration,
+    /// maximum size of the frames received from the brokers. Larger frames
+    /// are rejected with [ConnectionError::FrameTooLarge](crate::error::ConnectionError::FrameTooLarge)
+    /// and close the connection. If it is not set, it is the maximum message
+    /// size advertised by each broker, plus 10KB for the command and metadata
+    pub max_frame_size: Option<usize>,
 }
 
 impl std::default::Default for ConnectionRetryOptions {
@@ -44,7 +62,31 @@ impl std::default::Default for ConnectionRetryOptions {
             max_backoff: Duration::from_secs(30),
             max_retries: 12u32,
             connection_timeout: Duration::from_secs(10),
-            keep_alive: Duration::from_secs(60),
+            address_order: AddressOrder::default(),
+            keep_alive: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            max_frame_size: None,
+        }
+    }
+}
+
+/// order in which the addresses a host name resolves to are tried
+///
+/// the next address is tried when a connection fails or reaches the
+/// connection timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressOrder {
+    /// in a random order, spreading the connections over the addresses
+    #[default]
+    Shuffled,
+    /// in the order returned by the resolver
+    Ordered,
+}
+
+impl AddressOrder {
+    pub(crate) fn arrange(self, addresses: &mut [SocketAddr]) {
+        if self == AddressOrder::Shuffled {
+            addresses.shuffle(&mut rand::thread_rng());
         }
     }
 }
@@ -58,6 +100,9 @@ pub struct OperationRetryOptions {
     pub retry_delay: Duration,
     /// maximum number of operation retries. None indicates infinite retries
     pub max_retries: Option<u32>,
+    /// maximum number of redirects followed by a topic lookup, before it
+    /// fails with [ConnectionError::TooManyRedirects]
+    pub max_lookup_redirects: u32,
 }
 
 impl std::default::Default for OperationRetryOptions {
@@ -66,19 +111,88 @@ impl std::default::Default for OperationRetryOptions {
             operation_timeout: Duration::from_secs(30),
             retry_delay: Duration::from_millis(500),
             max_retries: None,
+            max_lookup_redirects: 20,
         }
     }
 }
 
+/// retry policy used when a producer or consumer must reconnect to a broker,
+/// or when creating a producer fails with a transient error
+///
+/// the delay between attempts grows exponentially from `min_backoff` up to
+/// `max_backoff`, and is randomized with `jitter` so that the clients of a
+/// restarting broker do not all reconnect at the same time
+///
+/// by default, up to 12 retries are made over about two minutes at most,
+/// from 100ms up to 30s apart, to ride out the restart of a broker
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// maximum number of reconnection attempts. None indicates infinite retries
+    pub max_retries: Option<u32>,
+    /// delay before the first retry
+    pub min_backoff: Duration,
+    /// maximum delay between retries
+    pub max_backoff: Duration,
+    /// factor applied to the delay after each attempt
+    pub multiplier: f64,
+    /// fraction of the delay, between 0 and 1, that is randomly removed, so
+    /// that clients failing together do not retry together
+    ///
+    /// with 1, the "full jitter", each delay is taken uniformly between 0
+    /// and the exponential backoff. With 0, the delays are not randomized
+    ///
+    /// default: 1
+    pub jitter: f64,
+}
+
+impl std::default::Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: Some(12),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 1.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// returns true if another attempt can be made after `retries` failed ones
+    pub fn can_retry(&self, retries: u32) -> bool {
+        self.max_retries.map(|max| retries < max).unwrap_or(true)
+    }
+
+    /// delay to wait before the attempt number `retries` (starting at 0)
+    pub fn backoff(&self, retries: u32) -> Duration {
+        let backoff = (self.min_backoff.as_secs_f64()
+            * self.multiplier.powi(retries.min(i32::MAX as u32) as i32))
+        .min(self.max_backoff.as_secs_f64())
+        .max(0.0);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_secs_f64(backoff * (1.0 - jitter))
+    }
+}
+
 /// configuration for TLS connections
 #[derive(Debug, Clone, Default)]
 pub struct TlsOptions {
     /// contains a list of PEM encoded certificates
     pub certificate_chain: Option<Vec<u8>>,
+    /// PEM encoded client certificate, used with `client_key` for mutual TLS
+    pub client_certificate: Option<Vec<u8>>,
+    /// PEM encoded PKCS #8 private key of the client certificate
+    pub client_key: Option<Vec<u8>>,
+    /// accept invalid server certificates
+    ///
+    /// certificate validation is enabled by default. Disabling it makes
+    /// the connection vulnerable to man in the middle attacks
+    pub allow_insecure: bool,
 }
 
 enum ConnectionStatus<Exe: Executor> {
-    Connected(Arc<Connection<Exe>>),
+    /// the instant is set when the connection is found unused
+    Connected(Arc<Connection<Exe>>, Option<Instant>),
     Connecting(Vec<oneshot::Sender<Result<Arc<Connection<Exe>>, ConnectionError>>>),
 }
 
@@ -90,19 +204,20 @@ enum ConnectionStatus<Exe: Executor> {
 #[derive(Clone)]
 pub struct ConnectionManager<Exe: Executor> {
     pub url: Url,
-    auth: Option<Authentication>,
+    auth: Option<Credentials>,
     pub(crate) executor: Arc<Exe>,
     connections: Arc<Mutex<HashMap<BrokerAddress, ConnectionStatus<Exe>>>>,
     connection_retry_options: ConnectionRetryOptions,
     pub(crate) operation_retry_options: OperationRetryOptions,
-    tls_options: TlsOptions,
     certificate_chain: Vec<native_tls::Certificate>,
+    client_identity: Option<Identity>,
+    allow_insecure: bool,
 }
 
 impl<Exe: Executor> ConnectionManager<Exe> {
     pub async fn new(
         url: String,
-        auth: Option<Authentication>,
+        auth: Option<Credentials>,
         connection_retry: Option<ConnectionRetryOptions>,
         operation_retry_options: OperationRetryOptions,
         tls: Option<TlsOptions>,
@@ -127,16 +242,28 @@ impl<Exe: Executor> ConnectionManager<Exe> {
             None => vec![],
             Some(certificate_chain) => {
                 let mut v = vec![];
-                for cert in pem::parse_many(&certificate_chain).iter().rev() {
+                for cert in pem::parse_many(certificate_chain).iter().rev() {
                     v.push(
-                        Certificate::from_der(&cert.contents[..])
-                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?,
+                        Certificate::from_der(&cert.contents[..]).map_err(std::io::Error::other)?,
                     );
                 }
                 v
             }
         };
 
+        let client_identity = match (
+            tls_options.client_certificate.as_ref(),
+            tls_options.client_key.as_ref(),
+        ) {
+            (Some(certificate), Some(key)) => Some(Identity::from_pkcs8(certificate, key)?),
+            (None, None) => None,
+            _ => {
+                return Err(ConnectionError::Unexpected(
+                    "mutual TLS needs both a client certificate and a client key".to_string(),
+                ));
+            }
+        };
+
         let manager = ConnectionManager {
             url: url.clone(),
             auth,
@@ -144,8 +271,9 @@ impl<Exe: Executor> ConnectionManager<Exe> {
             connections: Arc::new(Mutex::new(HashMap::new())),
             connection_retry_options,
             operation_retry_options,
-            tls_options,
             certificate_chain,
+            client_identity,
+            allow_insecure: tls_options.allow_insecure,
         };
         let broker_address = BrokerAddress {
             url: url.clone(),
@@ -196,7 +324,7 @@ impl<Exe: Executor> ConnectionManager<Exe> {
             let mut conns = self.connections.lock().await;
             match conns.get_mut(broker) {
                 None => None,
-                Some(ConnectionStatus::Connected(conn)) => {
+                Some(ConnectionStatus::Connected(conn, _)) => {
                     if conn.is_valid() {
                         return Ok(conn.clone());
                     } else {
@@ -243,7 +371,7 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                         Some(rx)
                     }
                 }
-                ConnectionStatus::Connected(_) => None,
+                ConnectionStatus::Connected(_, _) => None,
             }
         };
         if let Some(rx) = rx {
@@ -269,7 +397,11 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                 self.auth.clone(),
                 proxy_url.clone(),
                 &self.certificate_chain,
+                self.client_identity.as_ref(),
+                self.allow_insecure,
                 self.connection_retry_options.connection_timeout,
+                self.connection_retry_options.address_order,
+                self.connection_retry_options.max_frame_size,
                 self.operation_retry_options.operation_timeout,
                 self.executor.clone(),
             )
@@ -314,27 +446,28 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         let connection_id = conn.id();
         if let Some(url) = proxy_url.as_ref() {
             info!(
-                "Connected n°{} to {} via proxy {} in {}ms",
+                "Connected n°{} to {} via proxy {} in {}ms (protocol version {})",
                 connection_id,
                 url,
                 broker.url,
-                (std::time::Instant::now() - start).as_millis()
+                (std::time::Instant::now() - start).as_millis(),
+                conn.protocol_version()
             );
         } else {
             info!(
-                "Connected n°{} to {} in {}ms",
+                "Connected n°{} to {} in {}ms (protocol version {})",
                 connection_id,
                 broker.url,
-                (std::time::Instant::now() - start).as_millis()
+                (std::time::Instant::now() - start).as_millis(),
+                conn.protocol_version()
             );
         }
         let c = Arc::new(conn);
 
         // set up client heartbeats for the connection
         let weak_conn = Arc::downgrade(&c);
-        let mut interval = self
-            .executor
-            .interval(self.connection_retry_options.keep_alive);
+        let keep_alive = self.connection_retry_options.keep_alive;
+        let mut interval = self.executor.interval(keep_alive);
         let broker_url = broker.url.clone();
         let proxy_to_broker_url = proxy_url.clone();
         let res = self.executor.spawn(Box::pin(async move {
@@ -351,7 +484,11 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                     trace!("will ping connection {} to {}", connection_id, broker_url);
                 }
                 if let Some(strong_conn) = weak_conn.upgrade() {
-                    if let Err(e) = strong_conn.sender().send_ping().await {
+                    if let Err(e) = strong_conn
+                        .sender()
+                        .send_ping_with_timeout(keep_alive)
+                        .await
+                    {
                         error!(
                             "could not ping connection {} to the server at {}: {}",
                             connection_id, broker_url, e
@@ -374,7 +511,7 @@ impl<Exe: Executor> ConnectionManager<Exe> {
             .connections
             .lock()
             .await
-            .insert(broker, ConnectionStatus::Connected(c.clone()));
+            .insert(broker, ConnectionStatus::Connected(c.clone(), None));
         match old {
             Some(ConnectionStatus::Connecting(mut v)) => {
                 //info!("was in connecting state({} waiting)", v.len());
@@ -382,7 +519,7 @@ impl<Exe: Executor> ConnectionManager<Exe> {
                     let _ = tx.send(Ok(c.clone()));
                 }
             }
-            Some(ConnectionStatus::Connected(_)) => {
+            Some(ConnectionStatus::Connected(_, _)) => {
                 //info!("removing old connection");
             }
             None => {
@@ -393,23 +530,94 @@ impl<Exe: Executor> ConnectionManager<Exe> {
         Ok(c)
     }
 
-    /// tests that all connections are valid and still used
+    /// tests that all connections are valid, and closes the connections
+    /// that were unused for longer than the idle timeout
     pub(crate) async fn check_connections(&self) {
         trace!("cleaning invalid or unused connections");
+        let now = Instant::now();
+        let idle_timeout = self.connection_retry_options.idle_timeout;
         self.connections
             .lock()
             .await
             .retain(|_, ref mut connection| match connection {
                 ConnectionStatus::Connecting(_) => true,
-                ConnectionStatus::Connected(conn) => {
+                ConnectionStatus::Connected(conn, idle_since) => {
+                    if !conn.is_valid() {
+                        return false;
+                    }
                     // if the manager holds the only reference to that
-                    // connection, we can remove it from the manager
+                    // connection, it is unused and can be removed from the
+                    // manager once the idle timeout is reached.
                     // no need for special synchronization here: we're already
                     // in a mutex, and a case appears where the Arc is cloned
                     // somewhere at the same time, that just means the manager
                     // will create a new connection the next time it is asked
-                    conn.is_valid() && Arc::strong_count(conn) > 1
+                    if Arc::strong_count(conn) > 1 {
+                        *idle_since = None;
+                        return true;
+                    }
+                    let idle_since = *idle_since.get_or_insert(now);
+                    now.duration_since(idle_since) < idle_timeout
                 }
             });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_order() {
+        let addresses: Vec<SocketAddr> = (1..=20)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+
+        let mut ordered = addresses.clone();
+        AddressOrder::Ordered.arrange(&mut ordered);
+        assert_eq!(ordered, addresses);
+
+        let mut shuffled = addresses.clone();
+        AddressOrder::Shuffled.arrange(&mut shuffled);
+        shuffled.sort();
+        assert_eq!(shuffled, addresses);
+    }
+
+    #[test]
+    fn retry_policy_backoff() {
+        let policy = RetryPolicy {
+            max_retries: Some(3),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 3.0,
+            jitter: 0.0,
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(300));
+        assert_eq!(policy.backoff(2), Duration::from_millis(900));
+        assert_eq!(policy.backoff(3), Duration::from_secs(1));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(1));
+        assert!(policy.can_retry(2));
+        assert!(!policy.can_retry(3));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let backoff = policy.backoff(1);
+            assert!(backoff > Duration::from_millis(150) && backoff <= Duration::from_millis(300));
+        }
+
+        // full jitter spreads the delays over the whole backoff
+        let policy = RetryPolicy {
+            jitter: 1.0,
+            ..policy
+        };
+        let delays: Vec<_> = (0..100).map(|_| policy.backoff(2)).collect();
+        assert!(delays.iter().all(|d| *d <= Duration::from_millis(900)));
+        assert!(delays.iter().any(|d| *d < Duration::from_millis(450)));
+        assert!(delays.iter().any(|d| *d > Duration::from_millis(450)));
+        assert_eq!(RetryPolicy::default().jitter, 1.0);
+    }
+}
@@ -3,8 +3,9 @@ use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, Utc};
 use futures::channel::mpsc::unbounded;
@@ -17,6 +18,9 @@ use futures::{
 use regex::Regex;
 
 use crate::connection::Connection;
+use crate::connection_manager::RetryPolicy;
+#[cfg(feature = "encryption")]
+use crate::encryption::Decryptor;
 use crate::error::{ConnectionError, ConsumerError, Error};
 use crate::executor::Executor;
 use crate::message::proto::CommandMessage;
@@ -25,9 +29,12 @@ use crate::message::{
     proto::{self, command_subscribe::SubType, MessageIdData, MessageMetadata, Schema},
     BatchedMessage, Message as RawMessage, Metadata, Payload,
 };
+use crate::producer;
 use crate::proto::{BaseCommand, CommandCloseConsumer};
+use crate::topic::Topic;
+#[cfg(feature = "transactions")]
+use crate::transaction::{Transaction, TxnId};
 use crate::{BrokerAddress, DeserializeMessage, Pulsar};
-use core::iter;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use std::convert::TryFrom;
@@ -36,6 +43,9 @@ use url::Url;
 /// Configuration options for consumers
 #[derive(Clone, Default, Debug)]
 pub struct ConsumerOptions {
+    /// priority of the consumer in Shared and Key_Shared subscriptions: the
+    /// broker sends messages to the consumers with the lowest value first,
+    /// while they have permits. Defaults to 0, and cannot be negative
     pub priority_level: Option<i32>,
     /// Signal wether the subscription should be backed by a
     /// durable cursor or not
@@ -46,11 +56,16 @@ pub struct ConsumerOptions {
     pub start_message_id: Option<MessageIdData>,
     /// Add optional metadata key=value to this consumer
     pub metadata: BTreeMap<String, String>,
+    /// read the compacted topic, containing only the latest message of each
+    /// key. Only available for Exclusive and Failover subscriptions
     pub read_compacted: Option<bool>,
     pub schema: Option<Schema>,
     /// Signal whether the subscription will initialize on latest
     /// or earliest message (default on latest)
     ///
+    /// it is only used when the subscription is created: the broker ignores
+    /// it for existing subscriptions, that resume from their cursor
+    ///
     /// an enum can be used to initialize it:
     ///
     /// ```rust,ignore
@@ -59,8 +74,73 @@ pub struct ConsumerOptions {
     /// }
     /// ```
     pub initial_position: InitialPosition,
+    /// number of remaining permits under which the consumer sends a
+    /// `CommandFlow` to refill its receiver queue. It cannot be above the
+    /// receiver queue size
+    ///
+    /// default: half of the receiver queue size
+    pub receiver_queue_refill_threshold: Option<u32>,
+    /// moves the subscription to the first message published after this
+    /// time (in milliseconds since the epoch) when the consumer is created,
+    /// before it asks for messages
+    pub start_timestamp: Option<u64>,
+    /// messages published longer ago than this are acknowledged and
+    /// skipped, instead of being delivered
+    pub message_ttl: Option<Duration>,
+    /// how to subscribe again when the connection to the broker is lost. By
+    /// default, the consumer retries with the default [RetryPolicy]
+    pub reconnection: Option<RetryPolicy>,
+    /// acknowledges the messages of a batch individually, by sending their
+    /// index in the batch. The broker must be configured with
+    /// `acknowledgmentAtBatchIndexLevelEnabled`
+    ///
+    /// by default, the acks of the messages of a batch are kept until all of
+    /// them are acked, then the whole batch is acked at once
+    pub batch_index_ack: Option<bool>,
+    /// maximum number of chunked messages being reassembled at the same
+    /// time. When a new one starts, the chunks of the oldest one are
+    /// dropped and redelivered
+    ///
+    /// default: 10
+    pub max_pending_chunked_messages: Option<usize>,
+    /// chunked messages whose last chunk did not come after this delay are
+    /// dropped, and their chunks are acknowledged
+    ///
+    /// default: 60 seconds
+    pub chunked_message_expiry: Option<Duration>,
+    /// PEM encoded RSA private key, decrypting the messages of producers
+    /// configured with encryption
+    #[cfg(feature = "encryption")]
+    pub decryption_key: Option<Vec<u8>>,
+    /// what to do with the messages that cannot be decrypted
+    ///
+    /// default: [CryptoFailureAction::Fail]
+    pub crypto_failure_action: Option<CryptoFailureAction>,
+}
+
+/// handling of the encrypted messages a consumer cannot decrypt, like the
+/// Java client's `ConsumerCryptoFailureAction`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CryptoFailureAction {
+    /// the consumer returns an error for the message, which is not acked
+    #[default]
+    Fail,
+    /// the message is acked and dropped
+    Discard,
+    /// the message is delivered with its encrypted payload. Batched
+    /// messages are delivered as a single message holding the whole batch
+    Consume,
 }
 
+/// property of a message sent to a dead letter topic, with the topic it was
+/// consumed from
+pub const DLQ_REAL_TOPIC: &str = "REAL_TOPIC";
+/// property of a message sent to a dead letter topic, with its original
+/// message id, formatted as `ledger_id:entry_id:partition[:batch_index]`
+pub const DLQ_ORIGIN_MESSAGE_ID: &str = "ORIGIN_MESSAGE_ID";
+
+/// messages redelivered more than `max_redeliver_count` times are sent to
+/// `dead_letter_topic`, then acked on the original topic
 #[derive(Debug, Clone)]
 pub struct DeadLetterPolicy {
     /// Maximum number of times that a message will be redelivered before being sent to the dead letter queue.
@@ -69,20 +149,25 @@ pub struct DeadLetterPolicy {
     pub dead_letter_topic: String,
 }
 
-/// position of the first message that will be consumed
+/// position to move a subscription to, used by [Consumer::seek_to]
 #[derive(Clone, Debug)]
+pub enum SeekTarget {
+    /// move to a specific message
+    MessageId(MessageIdData),
+    /// move to the first message published after this time (in milliseconds since the epoch)
+    Timestamp(u64),
+}
+
+/// position of the first message that will be consumed
+#[derive(Clone, Debug, Default)]
 pub enum InitialPosition {
     /// start at the oldest message
     Earliest,
     /// start at the most recent message
+    #[default]
     Latest,
 }
 
-impl Default for InitialPosition {
-    fn default() -> Self {
-        InitialPosition::Latest
-    }
-}
 impl From<InitialPosition> for i32 {
     fn from(i: InitialPosition) -> Self {
         match i {
@@ -92,6 +177,129 @@ impl From<InitialPosition> for i32 {
     }
 }
 
+/// counters describing a consumer's activity, returned by [Consumer::metrics]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsumerMetrics {
+    /// messages received from the broker
+    pub messages_received: u64,
+    /// messages acknowledged (a cumulative acknowledgement counts as one)
+    pub messages_acked: u64,
+    /// messages negatively acknowledged
+    pub messages_nacked: u64,
+}
+
+impl std::ops::Add for ConsumerMetrics {
+    type Output = ConsumerMetrics;
+
+    fn add(self, other: ConsumerMetrics) -> ConsumerMetrics {
+        ConsumerMetrics {
+            messages_received: self.messages_received + other.messages_received,
+            messages_acked: self.messages_acked + other.messages_acked,
+            messages_nacked: self.messages_nacked + other.messages_nacked,
+        }
+    }
+}
+
+impl std::iter::Sum for ConsumerMetrics {
+    fn sum<I: Iterator<Item = ConsumerMetrics>>(iter: I) -> ConsumerMetrics {
+        iter.fold(ConsumerMetrics::default(), |acc, m| acc + m)
+    }
+}
+
+/// atomic counters shared by a consumer and its engines, so they can be read
+/// while the consumer is polled
+#[derive(Debug, Default)]
+struct ConsumerCounters {
+    messages_received: AtomicU64,
+    messages_acked: AtomicU64,
+    messages_nacked: AtomicU64,
+}
+
+impl ConsumerCounters {
+    fn snapshot(&self) -> ConsumerMetrics {
+        ConsumerMetrics {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_acked: self.messages_acked.load(Ordering::Relaxed),
+            messages_nacked: self.messages_nacked.load(Ordering::Relaxed),
+        }
+    }
+
+    fn received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn acked(&self, messages: u64) {
+        self.messages_acked.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    fn nacked(&self) {
+        self.messages_nacked.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// reads the counters of a consumer from another task, returned by
+/// [Consumer::metrics_handle]
+#[derive(Debug, Clone)]
+pub struct ConsumerMetricsHandle(Arc<ConsumerCounters>);
+
+impl ConsumerMetricsHandle {
+    /// snapshot of the consumer's counters, aggregated over all topics
+    pub fn snapshot(&self) -> ConsumerMetrics {
+        self.0.snapshot()
+    }
+}
+
+/// statistics of a consumer reported by the broker, returned by [Consumer::stats]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerStats {
+    /// topic of the consumer
+    pub topic: String,
+    /// total rate of messages delivered to the consumer (msg/s)
+    pub msg_rate_out: Option<f64>,
+    /// total throughput delivered to the consumer (bytes/s)
+    pub msg_throughput_out: Option<f64>,
+    /// total rate of messages redelivered by this consumer (msg/s)
+    pub msg_rate_redeliver: Option<f64>,
+    /// name of the consumer
+    pub consumer_name: Option<String>,
+    /// number of available message permits for the consumer
+    pub available_permits: Option<u64>,
+    /// number of unacknowledged messages for the consumer
+    pub unacked_messages: Option<u64>,
+    /// whether the consumer is blocked because it has too many unacknowledged messages
+    pub blocked_consumer_on_unacked_msgs: Option<bool>,
+    /// address of the consumer
+    pub address: Option<String>,
+    /// time at which the consumer connected
+    pub connected_since: Option<String>,
+    /// subscription type (Exclusive, Shared, Failover or Key_Shared)
+    pub sub_type: Option<String>,
+    /// total rate of messages expired on this subscription (msg/s)
+    pub msg_rate_expired: Option<f64>,
+    /// number of messages in the subscription backlog
+    pub msg_backlog: Option<u64>,
+}
+
+impl ConsumerStats {
+    fn new(topic: String, response: proto::CommandConsumerStatsResponse) -> Self {
+        ConsumerStats {
+            topic,
+            msg_rate_out: response.msg_rate_out,
+            msg_throughput_out: response.msg_throughput_out,
+            msg_rate_redeliver: response.msg_rate_redeliver,
+            consumer_name: response.consumer_name,
+            available_permits: response.available_permits,
+            unacked_messages: response.unacked_messages,
+            blocked_consumer_on_unacked_msgs: response.blocked_consumer_on_unacked_msgs,
+            address: response.address,
+            connected_since: response.connected_since,
+            sub_type: response.r#type,
+            msg_rate_expired: response.msg_rate_expired,
+            msg_backlog: response.msg_backlog,
+        }
+    }
+}
+
 /// the consumer is used to subscribe to a topic
 ///
 /// ```rust,no_run
@@ -151,7 +359,39 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// acknowledges a message in a transaction
+    ///
+    /// the message is redelivered if the transaction is aborted
+    #[cfg(feature = "transactions")]
+    pub async fn ack_in_txn(
+        &mut self,
+        msg: &Message<T>,
+        txn: &Transaction<Exe>,
+    ) -> Result<(), Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.ack_in_txn(msg, txn).await,
+            InnerConsumer::Multi(c) => c.ack_in_txn(msg, txn).await,
+        }
+    }
+
+    /// acknowledges a list of messages
+    ///
+    /// the acknowledgements are grouped in one command per topic
+    pub async fn ack_all<'a, I>(&mut self, msgs: I) -> Result<(), ConsumerError>
+    where
+        T: 'a,
+        I: IntoIterator<Item = &'a Message<T>>,
+    {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.ack_all(msgs).await,
+            InnerConsumer::Multi(c) => c.ack_all(msgs).await,
+        }
+    }
+
     /// acknowledges a message and all the preceding messages
+    ///
+    /// acknowledging a message that was already covered by
+    /// a previous cumulative acknowledgement does nothing
     pub async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         match &mut self.inner {
             InnerConsumer::Single(c) => c.cumulative_ack(msg).await,
@@ -169,6 +409,66 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// moves the subscription's cursor to a message id or a publish time
+    ///
+    /// like [Consumer::seek], the consumers are recreated, so messages received
+    /// before the seek are not delivered. On a multi topic consumer, the seek is
+    /// applied to all topics
+    pub async fn seek_to(&mut self, target: SeekTarget) -> Result<(), Error> {
+        let (message_id, timestamp) = match target {
+            SeekTarget::MessageId(id) => (Some(id), None),
+            SeekTarget::Timestamp(timestamp) => (None, Some(timestamp)),
+        };
+        let client = match &self.inner {
+            InnerConsumer::Single(c) => c.client.clone(),
+            InnerConsumer::Multi(c) => c.pulsar.clone(),
+        };
+        let topics = self.topics();
+        self.seek(Some(topics), message_id, timestamp, client).await
+    }
+
+    /// asks the broker for the statistics of this consumer, with one entry
+    /// per topic for multi topic consumers
+    pub async fn stats(&mut self) -> Result<Vec<ConsumerStats>, Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => Ok(vec![c.stats().await?]),
+            InnerConsumer::Multi(c) => {
+                try_join_all(c.consumers.values_mut().map(|c| c.stats())).await
+            }
+        }
+    }
+
+    /// asks the broker for the id of the last message published on the topic
+    ///
+    /// a reader has caught up with the topic once it received this message.
+    /// On an empty topic, the broker answers with ledger and entry ids of -1,
+    /// which appear as `u64::MAX` since ids are unsigned here. Multi topic
+    /// consumers have no single last message, so they return an error
+    pub async fn get_last_message_id(&mut self) -> Result<MessageIdData, Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.get_last_message_id().await,
+            InnerConsumer::Multi(_) => Err(Error::Custom(
+                "get_last_message_id is not supported on multi topic consumers".to_string(),
+            )),
+        }
+    }
+
+    /// deletes the subscription, then closes the consumer
+    ///
+    /// the subscription's cursor and backlog are removed from the topics. The
+    /// broker refuses it with [ConsumerError::Unsubscribe] if other consumers
+    /// are connected to the subscription
+    pub async fn unsubscribe(mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.unsubscribe().await,
+            InnerConsumer::Multi(c) => {
+                try_join_all(c.consumers.values_mut().map(|c| c.unsubscribe()))
+                    .await
+                    .map(drop)
+            }
+        }
+    }
+
     /// seek currently destroys the existing consumer and creates a new one
     /// this is how java and cpp pulsar client implement this feature mainly because
     /// there are many minor problems with flushing existing messages and receiving new ones
@@ -185,7 +485,13 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                 let topic = c.topic().to_string();
                 let addr = client.lookup_topic(&topic).await?;
                 let config = c.config().clone();
-                InnerConsumer::Single(TopicConsumer::new(client, topic, addr, config).await?)
+                let counters = c.counters.clone();
+                let mut consumer =
+                    TopicConsumer::new(client, topic, addr, config, counters).await?;
+                for listener in c.active_listeners.drain(..) {
+                    consumer.watch_active(listener);
+                }
+                InnerConsumer::Single(consumer)
             }
             InnerConsumer::Multi(c) => {
                 c.seek(consumer_ids, message_id, timestamp).await?;
@@ -204,13 +510,25 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                     .collect();
 
                 let consumers = try_join_all(topic_addr_pair.into_iter().map(|(topic, addr)| {
-                    TopicConsumer::new(client.clone(), topic, addr, c.config().clone())
+                    TopicConsumer::new(
+                        client.clone(),
+                        topic,
+                        addr,
+                        c.config().clone(),
+                        c.counters.clone(),
+                    )
                 }))
                 .await?;
 
+                let active_listeners = std::mem::take(&mut c.active_listeners);
                 let consumers: BTreeMap<_, _> = consumers
                     .into_iter()
-                    .map(|c| (c.topic().to_owned(), Box::pin(c)))
+                    .map(|mut c| {
+                        for listener in active_listeners.iter() {
+                            c.watch_active(listener.clone());
+                        }
+                        (c.topic().to_owned(), Box::pin(c))
+                    })
                     .collect();
                 let topics = consumers.keys().cloned().collect();
                 let topic_refresh = Duration::from_secs(30);
@@ -218,9 +536,12 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                 let namespace = c.namespace.clone();
                 let config = c.config().clone();
                 let topic_regex = c.topic_regex.clone();
+                let partitioned_topics = c.partitioned_topics.clone();
+                let counters = c.counters.clone();
                 InnerConsumer::Multi(MultiTopicConsumer {
                     namespace,
                     topic_regex,
+                    partitioned_topics,
                     pulsar: client,
                     consumers,
                     topics,
@@ -228,7 +549,8 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
                     refresh,
                     config,
                     disc_last_message_received: None,
-                    disc_messages_received: 0,
+                    counters,
+                    active_listeners,
                 })
             }
         };
@@ -307,7 +629,15 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
-    /// returns the consumer's name
+    /// returns the consumer's name, the one set with
+    /// [ConsumerBuilder::with_consumer_name] or a random one
+    ///
+    /// the consumers of all the topics of a multi topic consumer share it
+    pub fn name(&self) -> &str {
+        self.consumer_name().unwrap_or_default()
+    }
+
+    /// returns the consumer's name, as sent to the broker
     pub fn consumer_name(&self) -> Option<&str> {
         match &self.inner {
             InnerConsumer::Single(c) => &c.config.consumer_name,
@@ -336,6 +666,14 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
         }
     }
 
+    /// returns the delay before nacked messages are sent again
+    pub fn nack_redelivery_delay(&self) -> Option<Duration> {
+        match &self.inner {
+            InnerConsumer::Single(c) => c.config.nack_redelivery_delay,
+            InnerConsumer::Multi(c) => c.config.nack_redelivery_delay,
+        }
+    }
+
     /// returns the date of the last message reception
     pub fn last_message_received(&self) -> Option<DateTime<Utc>> {
         match &self.inner {
@@ -346,10 +684,66 @@ impl<T: DeserializeMessage, Exe: Executor> Consumer<T, Exe> {
 
     /// returns the current number of messages received
     pub fn messages_received(&self) -> u64 {
+        self.metrics().messages_received
+    }
+
+    /// returns the consumer's counters, aggregated over all topics
+    pub fn metrics(&self) -> ConsumerMetrics {
+        self.metrics_handle().snapshot()
+    }
+
+    /// returns a handle on the consumer's counters, to read them from
+    /// another task while the consumer is polled
+    pub fn metrics_handle(&self) -> ConsumerMetricsHandle {
         match &self.inner {
-            InnerConsumer::Single(c) => c.messages_received(),
-            InnerConsumer::Multi(c) => c.messages_received(),
+            InnerConsumer::Single(c) => ConsumerMetricsHandle(c.counters.clone()),
+            InnerConsumer::Multi(c) => ConsumerMetricsHandle(c.counters.clone()),
+        }
+    }
+
+    /// returns a stream of the changes of the active consumer
+    ///
+    /// in Failover subscriptions, the broker only sends messages to one of
+    /// the consumers, and notifies each consumer when it becomes active or
+    /// inactive. The current state is sent first if it is already known.
+    /// For a consumer on multiple topics, the changes of all the topics are
+    /// sent, including the topics added later
+    pub fn active_consumer_changes(&mut self) -> ActiveConsumerChanges {
+        let (tx, rx) = unbounded();
+        match &mut self.inner {
+            InnerConsumer::Single(c) => c.watch_active(tx),
+            InnerConsumer::Multi(c) => {
+                for consumer in c.consumers.values_mut() {
+                    consumer.watch_active(tx.clone());
+                }
+                c.active_listeners.push(tx);
+            }
         }
+        ActiveConsumerChanges { rx }
+    }
+}
+
+/// change of the active consumer of a Failover subscription on a topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveConsumerChange {
+    /// topic on which the consumer became active or inactive
+    pub topic: String,
+    /// true if the consumer is now the one receiving messages
+    pub is_active: bool,
+}
+
+/// stream of [ActiveConsumerChange], created by [Consumer::active_consumer_changes]
+///
+/// it ends once the consumer is dropped
+pub struct ActiveConsumerChanges {
+    rx: mpsc::UnboundedReceiver<ActiveConsumerChange>,
+}
+
+impl Stream for ActiveConsumerChanges {
+    type Item = ActiveConsumerChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
     }
 }
 
@@ -370,9 +764,11 @@ enum InnerConsumer<T: DeserializeMessage, Exe: Executor> {
     Multi(MultiTopicConsumer<T, Exe>),
 }
 
-type MessageIdDataReceiver = mpsc::Receiver<Result<(proto::MessageIdData, Payload), Error>>;
+// messages with their redelivery count
+type MessageIdDataReceiver = mpsc::Receiver<Result<(proto::MessageIdData, Payload, u32), Error>>;
 
 pub(crate) struct TopicConsumer<T: DeserializeMessage, Exe: Executor> {
+    client: Pulsar<Exe>,
     consumer_id: u64,
     config: ConsumerConfig,
     topic: String,
@@ -382,7 +778,11 @@ pub(crate) struct TopicConsumer<T: DeserializeMessage, Exe: Executor> {
     data_type: PhantomData<fn(Payload) -> T::Output>,
     dead_letter_policy: Option<DeadLetterPolicy>,
     last_message_received: Option<DateTime<Utc>>,
-    messages_received: u64,
+    // shared with the engine, and with the other topics of a multi topic consumer
+    counters: Arc<ConsumerCounters>,
+    // receivers of the active consumer changes, kept to register them again
+    // on the new consumer after a seek
+    active_listeners: Vec<mpsc::UnboundedSender<ActiveConsumerChange>>,
 }
 
 impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
@@ -391,6 +791,7 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         topic: String,
         mut addr: BrokerAddress,
         config: ConsumerConfig,
+        counters: Arc<ConsumerCounters>,
     ) -> Result<TopicConsumer<T, Exe>, Error> {
         let ConsumerConfig {
             subscription,
@@ -399,12 +800,19 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             consumer_name,
             consumer_id,
             unacked_message_redelivery_delay,
+            nack_redelivery_delay,
             options,
             dead_letter_policy,
         } = config.clone();
         let consumer_id = consumer_id.unwrap_or_else(rand::random);
         let (resolver, messages) = mpsc::unbounded();
-        let batch_size = batch_size.unwrap_or(1000);
+        let batch_size = batch_size.unwrap_or(DEFAULT_RECEIVER_QUEUE_SIZE);
+        #[cfg(feature = "encryption")]
+        let decryptor = options
+            .decryption_key
+            .as_deref()
+            .map(Decryptor::new)
+            .transpose()?;
 
         let mut connection = client.manager.get_connection(&addr).await?;
         let mut current_retries = 0u32;
@@ -456,7 +864,7 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
 
                         // we need to look up again the topic's address
                         let prev = addr;
-                        addr = client.lookup_topic(&topic).await?;
+                        addr = client.lookup_topic_again(&topic).await?;
                         if prev != addr {
                             info!(
                                 "topic {} moved: previous = {:?}, new = {:?}",
@@ -480,6 +888,15 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             }
         }
 
+        // seek before the first flow command, so that no message published
+        // before the timestamp is received. Reconnections keep the cursor
+        if let Some(timestamp) = options.start_timestamp {
+            connection
+                .sender()
+                .seek(consumer_id, None, Some(timestamp))
+                .await?;
+        }
+
         connection
             .sender()
             .send_flow(consumer_id, batch_size)
@@ -509,7 +926,7 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             }
         }));
 
-        if unacked_message_redelivery_delay.is_some() {
+        if unacked_message_redelivery_delay.is_some() || nack_redelivery_delay.is_some() {
             let mut redelivery_tx = engine_tx.clone();
             let mut interval = client.executor.interval(Duration::from_millis(500));
             let res = client.executor.spawn(Box::pin(async move {
@@ -542,10 +959,16 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             engine_rx,
             batch_size,
             unacked_message_redelivery_delay,
+            nack_redelivery_delay,
             dead_letter_policy.clone(),
             options.clone(),
+            counters.clone(),
             _drop_signal,
         );
+        #[cfg(feature = "encryption")]
+        {
+            c.decryptor = decryptor;
+        }
         let f = async move {
             c.engine()
                 .map(|res| {
@@ -558,6 +981,7 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         }
 
         Ok(TopicConsumer {
+            client,
             consumer_id,
             config,
             topic,
@@ -566,7 +990,8 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             data_type: PhantomData,
             dead_letter_policy,
             last_message_received: None,
-            messages_received: 0,
+            counters,
+            active_listeners: Vec::new(),
         })
     }
 
@@ -587,6 +1012,17 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
         })
     }
 
+    fn watch_active(&mut self, listener: mpsc::UnboundedSender<ActiveConsumerChange>) {
+        if self
+            .engine_tx
+            .unbounded_send(EngineMessage::WatchActive(listener.clone()))
+            .is_err()
+        {
+            warn!("the consumer engine for {} is stopped", self.topic);
+        }
+        self.active_listeners.push(listener);
+    }
+
     async fn check_connection(&mut self) -> Result<(), Error> {
         let conn = self.connection().await?;
         info!("check connection for id {}", conn.id());
@@ -596,14 +1032,49 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
 
     async fn ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         self.engine_tx
-            .send(EngineMessage::Ack(msg.message_id.clone(), false))
+            .send(EngineMessage::Ack(vec![msg.message_id.clone()], false))
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "transactions")]
+    async fn ack_in_txn(&mut self, msg: &Message<T>, txn: &Transaction<Exe>) -> Result<(), Error> {
+        txn.add_subscription(&self.topic, &self.config.subscription)
+            .await?;
+        let (resolver, response) = oneshot::channel();
+        self.engine_tx
+            .send(EngineMessage::AckInTxn(
+                vec![msg.message_id.clone()],
+                txn.id(),
+                resolver,
+            ))
+            .await
+            .map_err(ConsumerError::from)?;
+        response.await.map_err(|oneshot::Canceled| {
+            error!("the consumer engine dropped the transaction ack");
+            ConsumerError::Connection(ConnectionError::Disconnected)
+        })??;
+        Ok(())
+    }
+
+    async fn ack_all<'a, I>(&mut self, msgs: I) -> Result<(), ConsumerError>
+    where
+        T: 'a,
+        I: IntoIterator<Item = &'a Message<T>>,
+    {
+        let message_ids: Vec<_> = msgs.into_iter().map(|m| m.message_id.clone()).collect();
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+        self.engine_tx
+            .send(EngineMessage::Ack(message_ids, false))
             .await?;
         Ok(())
     }
 
     async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         self.engine_tx
-            .send(EngineMessage::Ack(msg.message_id.clone(), true))
+            .send(EngineMessage::Ack(vec![msg.message_id.clone()], true))
             .await?;
         Ok(())
     }
@@ -626,22 +1097,70 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
             .sender()
             .seek(consumer_id, message_id, timestamp)
             .await?;
+        self.engine_tx
+            .send(EngineMessage::Seek)
+            .await
+            .map_err(ConsumerError::from)?;
         Ok(())
     }
 
-    fn last_message_received(&self) -> Option<DateTime<Utc>> {
-        self.last_message_received
+    async fn unsubscribe(&mut self) -> Result<(), Error> {
+        let consumer_id = self.consumer_id;
+        match self
+            .connection()
+            .await?
+            .sender()
+            .unsubscribe(consumer_id)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(ConnectionError::PulsarError(error, message)) => Err(ConsumerError::Unsubscribe {
+                topic: self.topic.clone(),
+                subscription: self.config.subscription.clone(),
+                error,
+                message,
+            }
+            .into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stats(&mut self) -> Result<ConsumerStats, Error> {
+        let consumer_id = self.consumer_id;
+        let response = self
+            .connection()
+            .await?
+            .sender()
+            .consumer_stats(consumer_id)
+            .await?;
+        Ok(ConsumerStats::new(self.topic.clone(), response))
+    }
+
+    async fn get_last_message_id(&mut self) -> Result<MessageIdData, Error> {
+        let consumer_id = self.consumer_id;
+        let response = self
+            .connection()
+            .await?
+            .sender()
+            .get_last_message_id(consumer_id)
+            .await?;
+        Ok(response.last_message_id)
     }
 
-    fn messages_received(&self) -> u64 {
-        self.messages_received
+    fn last_message_received(&self) -> Option<DateTime<Utc>> {
+        self.last_message_received
     }
 
     fn config(&self) -> &ConsumerConfig {
         &self.config
     }
 
-    fn create_message(&self, message_id: proto::MessageIdData, payload: Payload) -> Message<T> {
+    fn create_message(
+        &self,
+        message_id: proto::MessageIdData,
+        payload: Payload,
+        redelivery_count: u32,
+    ) -> Message<T> {
         Message {
             topic: self.topic.clone(),
             message_id: MessageData {
@@ -649,6 +1168,7 @@ impl<T: DeserializeMessage, Exe: Executor> TopicConsumer<T, Exe> {
                 batch_size: payload.metadata.num_messages_in_batch,
             },
             payload,
+            redelivery_count,
             _phantom: PhantomData,
         }
     }
@@ -661,10 +1181,10 @@ impl<T: DeserializeMessage, Exe: Executor> Stream for TopicConsumer<T, Exe> {
         match self.messages.as_mut().poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(Ok((id, payload)))) => {
+            Poll::Ready(Some(Ok((id, payload, redelivery_count)))) => {
                 self.last_message_received = Some(Utc::now());
-                self.messages_received += 1;
-                Poll::Ready(Some(Ok(self.create_message(id, payload))))
+                self.counters.received();
+                Poll::Ready(Some(Ok(self.create_message(id, payload, redelivery_count))))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
         }
@@ -679,23 +1199,174 @@ struct ConsumerEngine<Exe: Executor> {
     sub_type: SubType,
     id: u64,
     name: Option<String>,
-    tx: mpsc::Sender<Result<(proto::MessageIdData, Payload), Error>>,
+    tx: mpsc::Sender<Result<(proto::MessageIdData, Payload, u32), Error>>,
     messages_rx: Option<mpsc::UnboundedReceiver<RawMessage>>,
     engine_rx: Option<mpsc::UnboundedReceiver<EngineMessage<Exe>>>,
     batch_size: u32,
     remaining_messages: u32,
     unacked_message_redelivery_delay: Option<Duration>,
     unacked_messages: HashMap<MessageIdData, Instant>,
+    nack_redelivery_delay: Option<Duration>,
+    nacked_messages: HashMap<MessageIdData, Instant>,
+    last_cumulative_ack: Option<MessageIdData>,
     dead_letter_policy: Option<DeadLetterPolicy>,
     options: ConsumerOptions,
+    // chunks received for messages that are not complete yet, by uuid
+    chunked_messages: HashMap<String, ChunkedMessage>,
+    // ids of the previous chunks of a reassembled message, by the id of its
+    // last chunk, which is the id given to the message
+    chunk_ids: HashMap<MessageIdData, Vec<MessageIdData>>,
+    // messages recently sent to the consumer, to drop the ones the broker
+    // redelivers after a reconnection
+    delivered: DeliveredMessages,
+    // messages acked in the batches that are not completely acked yet
+    batch_acks: HashMap<(u64, u64, i32), BatchAcks>,
+    // last state sent by the broker in Failover subscriptions
+    is_active: Option<bool>,
+    active_listeners: Vec<mpsc::UnboundedSender<ActiveConsumerChange>>,
+    // acks and nacks are counted once they reach the engine
+    counters: Arc<ConsumerCounters>,
+    #[cfg(feature = "encryption")]
+    decryptor: Option<Decryptor>,
     _drop_signal: oneshot::Sender<()>,
 }
 
+/// number of delivered message ids kept to detect redeliveries
+const DELIVERED_WINDOW: usize = 1000;
+
+/// ids of the last messages sent to the consumer, and whether they were acked
+///
+/// the broker redelivers the unacked messages when the consumer subscribes
+/// again. The ones the consumer already received are skipped, and the ones
+/// it already acked are acked again, since the broker may not have received
+/// that ack before the connection was lost
+struct DeliveredMessages {
+    ids: HashMap<(u64, u64, i32), bool>,
+    order: VecDeque<MessageIdData>,
+}
+
+impl DeliveredMessages {
+    fn new() -> Self {
+        DeliveredMessages {
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// records a message id. If it was already delivered, returns whether
+    /// it was acked
+    fn insert(&mut self, id: &MessageIdData) -> Option<bool> {
+        if let Some(acked) = self.ids.get(&message_id_order(id)) {
+            return Some(*acked);
+        }
+        self.ids.insert(message_id_order(id), false);
+        self.order.push_back(id.clone());
+        if self.order.len() > DELIVERED_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&message_id_order(&oldest));
+            }
+        }
+        None
+    }
+
+    /// marks a message as acked, and the previous ones if `cumulative`
+    fn ack(&mut self, id: &MessageIdData, cumulative: bool) {
+        let order = message_id_order(id);
+        if cumulative {
+            for (other, acked) in self.ids.iter_mut() {
+                if *other <= order {
+                    *acked = true;
+                }
+            }
+        } else if let Some(acked) = self.ids.get_mut(&order) {
+            *acked = true;
+        }
+    }
+
+    /// forgets a message id, for messages whose redelivery was requested
+    fn remove(&mut self, id: &MessageIdData) {
+        if self.ids.remove(&message_id_order(id)).is_some() {
+            self.order
+                .retain(|other| message_id_order(other) != message_id_order(id));
+        }
+    }
+
+    fn last(&self) -> Option<&MessageIdData> {
+        self.order.back()
+    }
+}
+
+/// acked messages of a batch
+struct BatchAcks {
+    acked: Vec<bool>,
+}
+
+impl BatchAcks {
+    fn new(size: usize) -> Self {
+        BatchAcks {
+            acked: vec![false; size],
+        }
+    }
+
+    /// marks the message at `index` as acked, and the previous ones if
+    /// `cumulative`
+    fn ack(&mut self, index: usize, cumulative: bool) {
+        let start = if cumulative { 0 } else { index };
+        for acked in self.acked.iter_mut().take(index + 1).skip(start) {
+            *acked = true;
+        }
+    }
+
+    fn is_acked(&self, index: usize) -> bool {
+        self.acked.get(index).copied().unwrap_or(false)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.acked.iter().all(|acked| *acked)
+    }
+
+    /// bit set of the messages that are not acked yet, as sent to the broker
+    fn ack_set(&self) -> Vec<i64> {
+        let mut words = vec![0i64; self.acked.len().div_ceil(64)];
+        for (index, acked) in self.acked.iter().enumerate() {
+            if !acked {
+                words[index / 64] |= 1 << (index % 64);
+            }
+        }
+        words
+    }
+}
+
+/// default size of the receiver queue
+const DEFAULT_RECEIVER_QUEUE_SIZE: u32 = 1000;
+/// default maximum number of chunked messages being reassembled
+const DEFAULT_MAX_PENDING_CHUNKED_MESSAGES: usize = 10;
+/// default delay after which incomplete chunked messages are dropped
+const DEFAULT_CHUNKED_MESSAGE_EXPIRY: Duration = Duration::from_secs(60);
+
+/// chunks of a message, accumulated until the last one is received
+struct ChunkedMessage {
+    data: Vec<u8>,
+    ids: Vec<MessageIdData>,
+    // reception of the first chunk
+    started: Instant,
+}
+
 enum EngineMessage<Exe: Executor> {
-    Ack(MessageData, bool),
+    Ack(Vec<MessageData>, bool),
+    // individual acks in a transaction, the result is the broker's answer
+    #[cfg(feature = "transactions")]
+    AckInTxn(
+        Vec<MessageData>,
+        TxnId,
+        oneshot::Sender<Result<(), ConnectionError>>,
+    ),
     Nack(MessageData),
     UnackedRedelivery,
+    // the messages sent after a seek are not redeliveries
+    Seek,
     GetConnection(oneshot::Sender<Arc<Connection<Exe>>>),
+    WatchActive(mpsc::UnboundedSender<ActiveConsumerChange>),
 }
 
 impl<Exe: Executor> ConsumerEngine<Exe> {
@@ -707,13 +1378,15 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         sub_type: SubType,
         id: u64,
         name: Option<String>,
-        tx: mpsc::Sender<Result<(proto::MessageIdData, Payload), Error>>,
+        tx: mpsc::Sender<Result<(proto::MessageIdData, Payload, u32), Error>>,
         messages_rx: mpsc::UnboundedReceiver<RawMessage>,
         engine_rx: mpsc::UnboundedReceiver<EngineMessage<Exe>>,
         batch_size: u32,
         unacked_message_redelivery_delay: Option<Duration>,
+        nack_redelivery_delay: Option<Duration>,
         dead_letter_policy: Option<DeadLetterPolicy>,
         options: ConsumerOptions,
+        counters: Arc<ConsumerCounters>,
         _drop_signal: oneshot::Sender<()>,
     ) -> ConsumerEngine<Exe> {
         ConsumerEngine {
@@ -731,8 +1404,20 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             remaining_messages: batch_size,
             unacked_message_redelivery_delay,
             unacked_messages: HashMap::new(),
+            nack_redelivery_delay,
+            nacked_messages: HashMap::new(),
+            last_cumulative_ack: None,
             dead_letter_policy,
             options,
+            chunked_messages: HashMap::new(),
+            chunk_ids: HashMap::new(),
+            delivered: DeliveredMessages::new(),
+            batch_acks: HashMap::new(),
+            is_active: None,
+            active_listeners: Vec::new(),
+            counters,
+            #[cfg(feature = "encryption")]
+            decryptor: None,
             _drop_signal,
         }
     }
@@ -752,22 +1437,21 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 }
             }
 
-            if self.remaining_messages < self.batch_size / 2 {
+            let refill_threshold = self
+                .options
+                .receiver_queue_refill_threshold
+                .unwrap_or(self.batch_size / 2);
+            if self.remaining_messages < refill_threshold {
                 match self
                     .connection
                     .sender()
                     .send_flow(self.id, self.batch_size - self.remaining_messages)
                 {
-                    Ok(()) => {}
-                    Err(ConnectionError::Disconnected) => {
-                        self.reconnect().await?;
-                        self.connection
-                            .sender()
-                            .send_flow(self.id, self.batch_size - self.remaining_messages)?;
-                    }
+                    Ok(()) => self.remaining_messages = self.batch_size,
+                    // subscribing again sends the permits
+                    Err(ConnectionError::Disconnected) => self.reconnect().await?,
                     Err(e) => return Err(e.into()),
                 }
-                self.remaining_messages = self.batch_size;
             }
 
             let mut f = match messages_or_ack_f.take() {
@@ -812,12 +1496,13 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                             //return Err(Error::Consumer(ConsumerError::Connection(ConnectionError::Disconnected)).into());
                         }
                         Some(message) => {
-                            self.remaining_messages -= message
+                            let permits = message
                                 .payload
                                 .as_ref()
                                 .and_then(|payload| payload.metadata.num_messages_in_batch)
-                                .unwrap_or(1i32)
-                                as u32;
+                                .unwrap_or(1i32) as u32;
+                            self.remaining_messages =
+                                self.remaining_messages.saturating_sub(permits);
 
                             match self.process_message(message).await {
                                 // Continue
@@ -845,25 +1530,19 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                             trace!("ack channel was closed");
                             return Ok(());
                         }
-                        Some(EngineMessage::Ack(message_id, cumulative)) => {
-                            self.ack(message_id, cumulative);
+                        Some(EngineMessage::Ack(message_ids, cumulative)) => {
+                            self.ack(message_ids, cumulative);
+                        }
+                        #[cfg(feature = "transactions")]
+                        Some(EngineMessage::AckInTxn(message_ids, txn_id, resolver)) => {
+                            self.ack_in_txn(message_ids, txn_id, resolver);
                         }
                         Some(EngineMessage::Nack(message_id)) => {
-                            if let Err(e) = self
-                                .connection
-                                .sender()
-                                .send_redeliver_unacknowleged_messages(
-                                    self.id,
-                                    vec![message_id.id.clone()],
-                                )
-                            {
-                                error!(
-                                    "could not ask for redelivery for message {:?}: {:?}",
-                                    message_id, e
-                                );
-                            }
+                            self.nack(message_id);
                         }
                         Some(EngineMessage::UnackedRedelivery) => {
+                            self.redeliver_nacked_messages();
+
                             let mut h = HashSet::new();
                             let now = Instant::now();
                             //info!("unacked messages length: {}", self.unacked_messages.len());
@@ -876,69 +1555,288 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                             let ids: Vec<_> = h.iter().cloned().collect();
                             if !ids.is_empty() {
                                 //info!("will unack ids: {:?}", ids);
-                                if let Err(e) = self
-                                    .connection
-                                    .sender()
-                                    .send_redeliver_unacknowleged_messages(self.id, ids)
-                                {
+                                if let Err(e) = self.redeliver(ids) {
                                     error!("could not ask for redelivery: {:?}", e);
                                 } else {
                                     for i in h.iter() {
-                                        self.unacked_messages.remove(&i);
+                                        self.unacked_messages.remove(i);
                                     }
                                 }
                             }
                         }
+                        Some(EngineMessage::Seek) => {
+                            self.delivered = DeliveredMessages::new();
+                            self.last_cumulative_ack = None;
+                        }
                         Some(EngineMessage::GetConnection(sender)) => {
                             let _ = sender.send(self.connection.clone()).map_err(|_| {
                                 error!("consumer requested the engine's connection but dropped the channel before receiving");
                             });
                         }
+                        Some(EngineMessage::WatchActive(listener)) => {
+                            if let Some(is_active) = self.is_active {
+                                let _ = listener.unbounded_send(ActiveConsumerChange {
+                                    topic: self.topic.clone(),
+                                    is_active,
+                                });
+                            }
+                            self.active_listeners.push(listener);
+                        }
                     }
                 }
             };
         }
     }
 
-    fn ack(&mut self, message_id: MessageData, cumulative: bool) {
-        //FIXME: this does not handle cumulative acks
-        self.unacked_messages.remove(&message_id.id);
-        let res = self
-            .connection
-            .sender()
-            .send_ack(self.id, vec![message_id.id], cumulative);
+    fn ack(&mut self, message_ids: Vec<MessageData>, cumulative: bool) {
+        let (acked, ids) = self.acked_ids(message_ids, cumulative);
+        self.counters.acked(acked);
+        if ids.is_empty() {
+            return;
+        }
+
+        let res = self.connection.sender().send_ack(self.id, ids, cumulative);
         if res.is_err() {
             error!("ack error: {:?}", res);
         }
     }
 
-    /// Process the message. Returns `true` if there are more messages to process
-    async fn process_message(&mut self, message: RawMessage) -> Result<bool, Error> {
-        match message {
-            RawMessage {
-                command:
-                    BaseCommand {
-                        reached_end_of_topic: Some(_),
-                        ..
-                    },
-                ..
-            } => {
-                return Ok(false);
+    /// acks messages in a transaction, without waiting for the broker's
+    /// answer in the engine
+    #[cfg(feature = "transactions")]
+    fn ack_in_txn(
+        &mut self,
+        message_ids: Vec<MessageData>,
+        txn_id: TxnId,
+        resolver: oneshot::Sender<Result<(), ConnectionError>>,
+    ) {
+        let (acked, ids) = self.acked_ids(message_ids, false);
+        if ids.is_empty() {
+            self.counters.acked(acked);
+            let _ = resolver.send(Ok(()));
+            return;
+        }
+
+        let connection = self.connection.clone();
+        let counters = self.counters.clone();
+        let consumer_id = self.id;
+        let f = async move {
+            let res = connection
+                .sender()
+                .ack_in_txn(consumer_id, ids, txn_id)
+                .await;
+            if res.is_ok() {
+                counters.acked(acked);
             }
-            RawMessage {
-                command:
-                    BaseCommand {
-                        active_consumer_change: Some(active_consumer_change),
-                        ..
-                    },
-                ..
-            } => {
-                // TODO: Communicate this status to the Consumer and expose it
-                debug!(
+            let _ = resolver.send(res);
+        };
+        if self.client.executor.spawn(Box::pin(f)).is_err() {
+            error!("the executor could not spawn the transaction ack");
+        }
+    }
+
+    /// updates the state of the acked messages, and returns the number of
+    /// messages acked with the ids to send to the broker
+    ///
+    /// a cumulative ack counts as one message
+    fn acked_ids(
+        &mut self,
+        message_ids: Vec<MessageData>,
+        cumulative: bool,
+    ) -> (u64, Vec<MessageIdData>) {
+        // messages already covered by a cumulative ack do not need to be acked again
+        let mut messages: Vec<MessageData> = message_ids
+            .into_iter()
+            .filter(|m| {
+                self.last_cumulative_ack
+                    .as_ref()
+                    .map(|last| message_id_order(&m.id) > message_id_order(last))
+                    .unwrap_or(true)
+            })
+            .collect();
+        if messages.is_empty() {
+            trace!("ignoring ack for messages that were already acked");
+            return (0, Vec::new());
+        }
+        let acked = if cumulative { 1 } else { messages.len() as u64 };
+
+        let mut ids: Vec<MessageIdData>;
+        if cumulative {
+            // a cumulative ack only carries one message id
+            messages.sort_by_key(|m| message_id_order(&m.id));
+            let last = messages.pop().unwrap();
+            let order = message_id_order(&last.id);
+            self.chunk_ids.retain(|id, _| message_id_order(id) > order);
+            self.unacked_messages
+                .retain(|id, _| message_id_order(id) > order);
+            self.nacked_messages
+                .retain(|id, _| message_id_order(id) > order);
+            self.batch_acks
+                .retain(|key, _| (key.0, key.1) >= (order.0, order.1));
+            self.last_cumulative_ack = Some(last.id.clone());
+            self.delivered.ack(&last.id, true);
+            ids = self.ack_in_batch(last, true).into_iter().collect();
+        } else {
+            for message in messages.iter() {
+                self.unacked_messages.remove(&message.id);
+                self.nacked_messages.remove(&message.id);
+                self.delivered.ack(&message.id, false);
+            }
+            ids = messages
+                .into_iter()
+                .filter_map(|message| self.ack_in_batch(message, false))
+                .collect();
+            // a chunked message is acked with all its chunks
+            let chunks: Vec<MessageIdData> = ids
+                .iter()
+                .filter_map(|id| self.chunk_ids.remove(id))
+                .flatten()
+                .collect();
+            ids.extend(chunks);
+        }
+        (acked, ids)
+    }
+
+    /// records the ack of a message that is part of a batch, and returns the
+    /// message id to send to the broker, if any
+    ///
+    /// the whole batch is acked once all its messages are acked. Before that,
+    /// only the messages acked with their index are sent if batch index acks
+    /// are enabled, and a cumulative ack covers the previous entry
+    fn ack_in_batch(&mut self, message: MessageData, cumulative: bool) -> Option<MessageIdData> {
+        let (index, size) = match (message.id.batch_index, message.batch_size) {
+            (Some(index), Some(size)) if index >= 0 && size > 1 => (index as usize, size as usize),
+            _ => return Some(message.id),
+        };
+        let key = batch_key(&message.id);
+        let acks = self
+            .batch_acks
+            .entry(key)
+            .or_insert_with(|| BatchAcks::new(size));
+        acks.ack(index, cumulative);
+
+        if acks.is_complete() {
+            self.batch_acks.remove(&key);
+            return Some(MessageIdData {
+                batch_index: None,
+                ..message.id
+            });
+        }
+        if self.options.batch_index_ack.unwrap_or(false) {
+            return Some(MessageIdData {
+                ack_set: acks.ack_set(),
+                batch_size: Some(size as i32),
+                ..message.id
+            });
+        }
+        if cumulative && message.id.entry_id > 0 {
+            return Some(MessageIdData {
+                entry_id: message.id.entry_id - 1,
+                batch_index: None,
+                ..message.id
+            });
+        }
+        None
+    }
+
+    /// asks for the redelivery of messages, including all the chunks of
+    /// chunked messages
+    fn redeliver(&mut self, ids: Vec<MessageIdData>) -> Result<(), ConnectionError> {
+        for id in ids.iter() {
+            self.delivered.remove(id);
+        }
+        let ids = ids
+            .into_iter()
+            .flat_map(|id| {
+                let mut ids = self.chunk_ids.get(&id).cloned().unwrap_or_default();
+                ids.push(id);
+                ids
+            })
+            .collect();
+        self.connection
+            .sender()
+            .send_redeliver_unacknowleged_messages(self.id, ids)
+    }
+
+    fn nack(&mut self, message_id: MessageData) {
+        self.counters.nacked();
+        self.unacked_messages.remove(&message_id.id);
+        match self.nack_redelivery_delay {
+            Some(delay) => {
+                self.nacked_messages
+                    .insert(message_id.id, Instant::now() + delay);
+            }
+            None => {
+                if let Err(e) = self.redeliver(vec![message_id.id.clone()]) {
+                    error!(
+                        "could not ask for redelivery for message {:?}: {:?}",
+                        message_id, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// asks for redelivery of the nacked messages whose delay is over
+    fn redeliver_nacked_messages(&mut self) {
+        let now = Instant::now();
+        let ids: Vec<MessageIdData> = self
+            .nacked_messages
+            .iter()
+            .filter(|(_, t)| **t <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+
+        match self.redeliver(ids.clone()) {
+            Ok(()) => {
+                for id in ids.iter() {
+                    self.nacked_messages.remove(id);
+                }
+            }
+            Err(e) => error!("could not ask for redelivery of nacked messages: {:?}", e),
+        }
+    }
+
+    /// Process the message. Returns `true` if there are more messages to process
+    async fn process_message(&mut self, message: RawMessage) -> Result<bool, Error> {
+        match message {
+            RawMessage {
+                command:
+                    BaseCommand {
+                        reached_end_of_topic: Some(_),
+                        ..
+                    },
+                ..
+            } => {
+                return Ok(false);
+            }
+            RawMessage {
+                command:
+                    BaseCommand {
+                        active_consumer_change: Some(active_consumer_change),
+                        ..
+                    },
+                ..
+            } => {
+                debug!(
                     "Active consumer change for {} - Active: {:?}",
                     self.debug_format(),
                     active_consumer_change.is_active
                 );
+                let is_active = active_consumer_change.is_active.unwrap_or(false);
+                self.is_active = Some(is_active);
+                let topic = &self.topic;
+                self.active_listeners.retain(|listener| {
+                    listener
+                        .unbounded_send(ActiveConsumerChange {
+                            topic: topic.clone(),
+                            is_active,
+                        })
+                        .is_ok()
+                });
             }
             RawMessage {
                 command:
@@ -951,15 +1849,21 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 self.process_payload(message, payload).await?;
             }
             RawMessage {
-                command: BaseCommand {
-                    message: Some(_), ..
-                },
+                command:
+                    BaseCommand {
+                        message: Some(message),
+                        ..
+                    },
                 payload: None,
             } => {
+                // the codec drops the payloads failing their checksum
                 error!(
-                    "Consumer {} received message without payload",
+                    "Consumer {} received message without payload, asking for its redelivery",
                     self.debug_format()
                 );
+                if let Err(e) = self.redeliver(vec![message.message_id]) {
+                    error!("could not ask for redelivery of a message: {:?}", e);
+                }
             }
             RawMessage {
                 command:
@@ -990,11 +1894,189 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         Ok(true)
     }
 
+    /// accumulates the chunks of a chunked message. Returns the whole message
+    /// when its last chunk is received, or `None` until then
+    fn reassemble_chunks(
+        &mut self,
+        message: &CommandMessage,
+        mut payload: Payload,
+    ) -> Option<Payload> {
+        let num_chunks = match payload.metadata.num_chunks_from_msg {
+            Some(num_chunks) if num_chunks > 1 => num_chunks,
+            _ => return Some(payload),
+        };
+        let uuid = payload.metadata.uuid.clone().unwrap_or_default();
+        let chunk_id = payload.metadata.chunk_id.unwrap_or(0);
+        self.expire_chunked_messages();
+
+        if chunk_id == 0 {
+            let max = self
+                .options
+                .max_pending_chunked_messages
+                .unwrap_or(DEFAULT_MAX_PENDING_CHUNKED_MESSAGES);
+            if self.chunked_messages.len() >= max {
+                self.drop_oldest_chunked_message();
+            }
+            let size = payload.metadata.total_chunk_msg_size.unwrap_or(0).max(0) as usize;
+            self.chunked_messages.insert(
+                uuid.clone(),
+                ChunkedMessage {
+                    data: Vec::with_capacity(size),
+                    ids: Vec::with_capacity(num_chunks as usize),
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let chunked = match self.chunked_messages.get_mut(&uuid) {
+            Some(chunked) if chunked.ids.len() == chunk_id as usize => chunked,
+            _ => {
+                warn!(
+                    "dropping chunk {} of message {} on {}: the previous chunks are missing",
+                    chunk_id, uuid, self.topic
+                );
+                // the chunks are redelivered, to receive them in order again
+                let mut ids = self
+                    .chunked_messages
+                    .remove(&uuid)
+                    .map(|chunked| chunked.ids)
+                    .unwrap_or_default();
+                ids.push(message.message_id.clone());
+                if let Err(e) = self.redeliver(ids) {
+                    error!("could not ask for redelivery of chunks: {:?}", e);
+                }
+                return None;
+            }
+        };
+        chunked.data.append(&mut payload.data);
+        chunked.ids.push(message.message_id.clone());
+
+        if chunk_id + 1 < num_chunks {
+            return None;
+        }
+
+        let mut chunked = self.chunked_messages.remove(&uuid)?;
+        // the message gets the id of its last chunk
+        chunked.ids.pop();
+        self.chunk_ids
+            .insert(message.message_id.clone(), chunked.ids);
+        payload.data = chunked.data;
+        Some(payload)
+    }
+
+    /// drops the chunked messages that were not completed in time, and acks
+    /// their chunks
+    fn expire_chunked_messages(&mut self) {
+        let expiry = self
+            .options
+            .chunked_message_expiry
+            .unwrap_or(DEFAULT_CHUNKED_MESSAGE_EXPIRY);
+        let expired: Vec<String> = self
+            .chunked_messages
+            .iter()
+            .filter(|(_, chunked)| chunked.started.elapsed() > expiry)
+            .map(|(uuid, _)| uuid.clone())
+            .collect();
+        for uuid in expired {
+            if let Some(chunked) = self.chunked_messages.remove(&uuid) {
+                warn!(
+                    "dropping chunked message {} on {}: its last chunk did not come after {}ms",
+                    uuid,
+                    self.topic,
+                    expiry.as_millis()
+                );
+                let res = self
+                    .connection
+                    .sender()
+                    .send_ack(self.id, chunked.ids, false);
+                if res.is_err() {
+                    error!("ack error: {:?}", res);
+                }
+            }
+        }
+    }
+
+    /// makes room for a new chunked message, by asking for the redelivery of
+    /// the chunks of the oldest one
+    fn drop_oldest_chunked_message(&mut self) {
+        let oldest = match self
+            .chunked_messages
+            .iter()
+            .min_by_key(|(_, chunked)| chunked.started)
+        {
+            Some((uuid, _)) => uuid.clone(),
+            None => return,
+        };
+        warn!(
+            "dropping chunked message {} on {}: too many chunked messages are pending",
+            oldest, self.topic
+        );
+        if let Some(chunked) = self.chunked_messages.remove(&oldest) {
+            if let Err(e) = self.redeliver(chunked.ids) {
+                error!("could not ask for redelivery of chunks: {:?}", e);
+            }
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt(&mut self, payload: &Payload) -> Result<Vec<u8>, ConsumerError> {
+        match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor.decrypt(&payload.metadata, &payload.data),
+            None => Err(ConsumerError::Encryption(
+                "got an encrypted message but no decryption key is configured".to_string(),
+            )),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt(&mut self, _payload: &Payload) -> Result<Vec<u8>, ConsumerError> {
+        Err(ConsumerError::Encryption(
+            "got an encrypted message but the 'encryption' cargo feature is deactivated"
+                .to_string(),
+        ))
+    }
+
     async fn process_payload(
         &mut self,
         message: CommandMessage,
-        mut payload: Payload,
+        payload: Payload,
     ) -> Result<(), Error> {
+        let mut payload = match self.reassemble_chunks(&message, payload) {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        if !payload.metadata.encryption_keys.is_empty() {
+            match self.decrypt(&payload) {
+                Ok(data) => payload.data = data,
+                Err(e) => match self.options.crypto_failure_action.unwrap_or_default() {
+                    CryptoFailureAction::Fail => return Err(e.into()),
+                    CryptoFailureAction::Discard => {
+                        warn!(
+                            "discarding message {:?} on {}: {}",
+                            message.message_id, self.topic, e
+                        );
+                        self.ack(
+                            vec![MessageData {
+                                id: message.message_id,
+                                batch_size: None,
+                            }],
+                            false,
+                        );
+                        return Ok(());
+                    }
+                    CryptoFailureAction::Consume => {
+                        warn!(
+                            "delivering message {:?} on {} encrypted: {}",
+                            message.message_id, self.topic, e
+                        );
+                        let redelivery_count = message.redelivery_count.unwrap_or(0);
+                        return self
+                            .send_to_consumer(message.message_id, payload, redelivery_count)
+                            .await;
+                    }
+                },
+            }
+        }
         let compression = payload.metadata.compression;
 
         let payload = match compression {
@@ -1005,7 +2087,7 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 {
                     return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
                         std::io::ErrorKind::Other,
-                        "got a LZ4 compressed message but 'lz4' cargo feature is deactivated",
+                        "got a LZ4 compressed message but 'compression-lz4' cargo feature is deactivated",
                     )))
                     .into());
                 }
@@ -1056,7 +2138,7 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 {
                     return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
                         std::io::ErrorKind::Other,
-                        "got a zstd compressed message but 'zstd' cargo feature is deactivated",
+                        "got a zstd compressed message but 'compression-zstd' cargo feature is deactivated",
                     )))
                     .into());
                 }
@@ -1081,15 +2163,12 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                     .into());
                 }
 
+                // Pulsar uses the raw Snappy format, without framing
                 #[cfg(feature = "snap")]
                 {
-                    use std::io::Read;
-
-                    let mut decompressed_payload = Vec::new();
-                    let mut decoder = snap::read::FrameDecoder::new(&payload.data[..]);
-                    decoder
-                        .read_to_end(&mut decompressed_payload)
-                        .map_err(ConsumerError::Io)?;
+                    let decompressed_payload = snap::raw::Decoder::new()
+                        .decompress_vec(&payload.data[..])
+                        .map_err(|e| ConsumerError::Io(e.into()))?;
 
                     payload.data = decompressed_payload;
                     payload
@@ -1097,59 +2176,164 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             }
             Some(i) => {
                 error!("unknown compression type: {}", i);
-                return Err(Error::Consumer(ConsumerError::Io(std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                return Err(Error::Consumer(ConsumerError::Io(std::io::Error::other(
                     format!("unknown compression type: {}", i),
                 ))));
             }
         };
 
-        match payload.metadata.num_messages_in_batch {
-            Some(_) => {
-                let it = BatchedMessageIterator::new(message.message_id, payload)?;
-                for (id, payload) in it {
-                    // TODO: Dead letter policy for batched messages
-                    self.send_to_consumer(id, payload).await?;
-                }
+        if let Some(ttl) = self.options.message_ttl {
+            if is_expired(payload.metadata.publish_time, ttl) {
+                debug!(
+                    "skipping expired message {:?} on {}",
+                    message.message_id, self.topic
+                );
+                self.ack(
+                    vec![MessageData {
+                        id: message.message_id,
+                        batch_size: None,
+                    }],
+                    false,
+                );
+                return Ok(());
             }
-            None => match (message.redelivery_count, self.dead_letter_policy.as_ref()) {
-                (Some(redelivery_count), Some(dead_letter_policy)) => {
-                    // Send message to Dead Letter Topic and ack message in original topic
-                    if redelivery_count as usize >= dead_letter_policy.max_redeliver_count {
-                        self.client
-                            .send(&dead_letter_policy.dead_letter_topic, payload.data)
-                            .await?
-                            .await
-                            .map_err(|e| {
-                                error!("One shot cancelled {:?}", e);
-                                Error::Custom("DLQ send error".to_string())
-                            })?;
+        }
 
-                        self.ack(
-                            MessageData {
-                                id: message.message_id,
-                                batch_size: None,
-                            },
-                            false,
-                        );
-                    } else {
-                        self.send_to_consumer(message.message_id, payload).await?
+        let redelivery_count = message.redelivery_count.unwrap_or(0);
+        let dead_letter_topic = match (message.redelivery_count, self.dead_letter_policy.as_ref()) {
+            (Some(redelivery_count), Some(policy))
+                if redelivery_count as usize >= policy.max_redeliver_count =>
+            {
+                Some(policy.dead_letter_topic.clone())
+            }
+            _ => None,
+        };
+
+        match (payload.metadata.num_messages_in_batch, dead_letter_topic) {
+            // Send the messages to the Dead Letter Topic and ack them in the original topic
+            (batch, Some(dead_letter_topic)) => {
+                let messages: Vec<_> = match batch {
+                    Some(_) => {
+                        BatchedMessageIterator::new(message.message_id.clone(), payload)?.collect()
                     }
+                    None => vec![(message.message_id.clone(), payload)],
+                };
+                for (id, payload) in messages {
+                    self.send_to_dead_letter_topic(&dead_letter_topic, &id, payload)
+                        .await?;
                 }
-                _ => self.send_to_consumer(message.message_id, payload).await?,
-            },
+
+                self.ack(
+                    vec![MessageData {
+                        id: message.message_id,
+                        batch_size: None,
+                    }],
+                    false,
+                );
+            }
+            (Some(_), None) => {
+                let it = BatchedMessageIterator::new(message.message_id, payload)?;
+                for (id, payload) in it {
+                    self.send_to_consumer(id, payload, redelivery_count).await?;
+                }
+            }
+            (None, None) => {
+                self.send_to_consumer(message.message_id, payload, redelivery_count)
+                    .await?
+            }
         }
         Ok(())
     }
 
+    /// republishes a message that reached the maximum redelivery count, with
+    /// the properties [DLQ_REAL_TOPIC] and [DLQ_ORIGIN_MESSAGE_ID]
+    async fn send_to_dead_letter_topic(
+        &self,
+        dead_letter_topic: &str,
+        message_id: &MessageIdData,
+        payload: Payload,
+    ) -> Result<(), Error> {
+        let mut properties: HashMap<String, String> = payload
+            .metadata
+            .properties
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect();
+        properties.insert(DLQ_REAL_TOPIC.to_string(), self.topic.clone());
+        properties.insert(
+            DLQ_ORIGIN_MESSAGE_ID.to_string(),
+            format_message_id(message_id),
+        );
+
+        let message = producer::Message {
+            payload: payload.data,
+            properties,
+            partition_key: payload.metadata.partition_key,
+            ordering_key: payload.metadata.ordering_key,
+            event_time: payload.metadata.event_time,
+            ..Default::default()
+        };
+
+        self.client
+            .send(dead_letter_topic, message)
+            .await?
+            .await
+            .map_err(|e| {
+                error!("could not send a message to the dead letter topic: {:?}", e);
+                Error::Custom("DLQ send error".to_string())
+            })?;
+        Ok(())
+    }
+
     async fn send_to_consumer(
         &mut self,
         message_id: MessageIdData,
         payload: Payload,
+        redelivery_count: u32,
     ) -> Result<(), Error> {
+        let acked_in_batch = message_id
+            .batch_index
+            .zip(self.batch_acks.get(&batch_key(&message_id)));
+        if let Some((index, acks)) = acked_in_batch {
+            if acks.is_acked(index as usize) {
+                debug!(
+                    "skipping acked message {:?} of a batch redelivered to {}",
+                    message_id,
+                    self.debug_format()
+                );
+                return Ok(());
+            }
+        }
+        match self.delivered.insert(&message_id) {
+            Some(false) => {
+                debug!(
+                    "skipping message {:?} redelivered to {}",
+                    message_id,
+                    self.debug_format()
+                );
+                return Ok(());
+            }
+            Some(true) => {
+                debug!(
+                    "acking again message {:?} redelivered to {}",
+                    message_id,
+                    self.debug_format()
+                );
+                let res = self
+                    .connection
+                    .sender()
+                    .send_ack(self.id, vec![message_id], false);
+                if res.is_err() {
+                    error!("ack error: {:?}", res);
+                }
+                return Ok(());
+            }
+            None => {}
+        }
+
         let now = Instant::now();
         self.tx
-            .send(Ok((message_id.clone(), payload)))
+            .send(Ok((message_id.clone(), payload, redelivery_count)))
             .await
             .map_err(|e| {
                 error!("tx returned {:?}", e);
@@ -1161,9 +2345,40 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         Ok(())
     }
 
+    /// subscribes again, retrying with the reconnection policy
     async fn reconnect(&mut self) -> Result<(), Error> {
+        let retry_policy = self.options.reconnection.clone().unwrap_or_default();
+        let mut retries = 0u32;
+        loop {
+            match self.resubscribe().await {
+                Ok(()) => return Ok(()),
+                // the broker may not have noticed yet that the previous
+                // connection was lost
+                Err(e)
+                    if (e.is_retryable() || is_consumer_busy(&e))
+                        && retry_policy.can_retry(retries) =>
+                {
+                    let backoff = retry_policy.backoff(retries);
+                    retries += 1;
+                    error!(
+                        "could not reconnect consumer {} (attempt {}), retrying in {}ms: {}",
+                        self.debug_format(),
+                        retries,
+                        backoff.as_millis(),
+                        e
+                    );
+                    if backoff > Duration::from_millis(0) {
+                        self.client.executor.delay(backoff).await;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn resubscribe(&mut self) -> Result<(), Error> {
         debug!("reconnecting consumer for topic: {}", self.topic);
-        let broker_address = self.client.lookup_topic(&self.topic).await?;
+        let broker_address = self.client.lookup_topic_again(&self.topic).await?;
         let conn = self.client.manager.get_connection(&broker_address).await?;
 
         self.connection = conn;
@@ -1171,6 +2386,14 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
         let topic = self.topic.clone();
         let (resolver, messages) = mpsc::unbounded();
 
+        // the broker does not keep the position of non durable subscriptions
+        let mut options = self.options.clone();
+        if options.durable == Some(false) {
+            if let Some(last) = self.delivered.last() {
+                options.start_message_id = Some(last.clone());
+            }
+        }
+
         self.connection
             .sender()
             .subscribe(
@@ -1180,7 +2403,7 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
                 self.sub_type,
                 self.id,
                 self.name.clone(),
-                self.options.clone(),
+                options,
             )
             .await
             .map_err(Error::Connection)?;
@@ -1189,6 +2412,7 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
             .sender()
             .send_flow(self.id, self.batch_size)
             .map_err(|e| Error::Consumer(ConsumerError::Connection(e)))?;
+        self.remaining_messages = self.batch_size;
 
         self.messages_rx = Some(messages);
 
@@ -1233,12 +2457,87 @@ impl<Exe: Executor> ConsumerEngine<Exe> {
     }
 }
 
+fn is_consumer_busy(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Connection(ConnectionError::PulsarError(
+            Some(proto::ServerError::ConsumerBusy),
+            _
+        ))
+    )
+}
+
+/// entry of a batched message, identifying its batch
+fn batch_key(id: &MessageIdData) -> (u64, u64, i32) {
+    (id.ledger_id, id.entry_id, id.partition.unwrap_or(-1))
+}
+
+/// position of a message in the topic, used to compare message ids
+fn message_id_order(id: &MessageIdData) -> (u64, u64, i32) {
+    (id.ledger_id, id.entry_id, id.batch_index.unwrap_or(-1))
+}
+
+/// rejects the options the broker would refuse: reading compacted topics
+/// from Shared and Key_Shared subscriptions, and negative priority levels,
+/// and refill thresholds above the receiver queue size
+fn check_options(
+    sub_type: SubType,
+    batch_size: u32,
+    options: Option<&ConsumerOptions>,
+) -> Result<(), Error> {
+    if let Some(threshold) = options.and_then(|o| o.receiver_queue_refill_threshold) {
+        if threshold > batch_size {
+            return Err(Error::Custom(format!(
+                "the receiver queue refill threshold ({}) cannot be above the receiver queue size ({})",
+                threshold, batch_size
+            )));
+        }
+    }
+
+    if let Some(priority_level) = options.and_then(|o| o.priority_level) {
+        if priority_level < 0 {
+            return Err(Error::Custom(format!(
+                "the priority level cannot be negative: {}",
+                priority_level
+            )));
+        }
+    }
+
+    let read_compacted = options.and_then(|o| o.read_compacted).unwrap_or(false);
+    match sub_type {
+        SubType::Exclusive | SubType::Failover => Ok(()),
+        _ if !read_compacted => Ok(()),
+        _ => Err(Error::Custom(format!(
+            "read_compacted is only allowed on Exclusive and Failover subscriptions, not {:?}",
+            sub_type
+        ))),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MessageData {
     pub id: proto::MessageIdData,
     batch_size: Option<i32>,
 }
 
+/// whether a message published at `publish_time` (in milliseconds since the
+/// epoch) is older than `ttl`
+fn is_expired(publish_time: u64, ttl: Duration) -> bool {
+    UNIX_EPOCH + Duration::from_millis(publish_time) + ttl < SystemTime::now()
+}
+
+/// formats a message id like the Java client does
+fn format_message_id(id: &MessageIdData) -> String {
+    let partition = id.partition.unwrap_or(-1);
+    match id.batch_index {
+        Some(batch_index) if batch_index >= 0 => format!(
+            "{}:{}:{}:{}",
+            id.ledger_id, id.entry_id, partition, batch_index
+        ),
+        _ => format!("{}:{}:{}", id.ledger_id, id.entry_id, partition),
+    }
+}
+
 struct BatchedMessageIterator {
     messages: std::vec::IntoIter<BatchedMessage>,
     message_id: proto::MessageIdData,
@@ -1284,6 +2583,10 @@ impl Iterator for BatchedMessageIterator {
             let metadata = Metadata {
                 properties: batched_message.metadata.properties,
                 partition_key: batched_message.metadata.partition_key,
+                ordering_key: batched_message
+                    .metadata
+                    .ordering_key
+                    .or_else(|| self.metadata.ordering_key.clone()),
                 event_time: batched_message.metadata.event_time,
                 ..self.metadata.clone()
             };
@@ -1314,6 +2617,7 @@ pub struct ConsumerBuilder<Exe: Executor> {
     consumer_name: Option<String>,
     batch_size: Option<u32>,
     unacked_message_resend_delay: Option<Duration>,
+    nack_redelivery_delay: Option<Duration>,
     dead_letter_policy: Option<DeadLetterPolicy>,
     consumer_options: Option<ConsumerOptions>,
     namespace: Option<String>,
@@ -1334,6 +2638,7 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             batch_size: None,
             //TODO what should this default to? None seems incorrect..
             unacked_message_resend_delay: None,
+            nack_redelivery_delay: None,
             dead_letter_policy: None,
             consumer_options: None,
             namespace: None,
@@ -1393,7 +2698,8 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
         self
     }
 
-    /// Interval for refreshing the topics when using a topic regex. Unused otherwise.
+    /// Interval for refreshing the topics when using a topic regex, and the
+    /// partitions of partitioned topics (defaults to 30 seconds)
     pub fn with_topic_refresh(mut self, refresh_interval: Duration) -> Self {
         self.topic_refresh = Some(refresh_interval);
         self
@@ -1406,6 +2712,11 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
     }
 
     /// sets the consumer's name
+    ///
+    /// the name appears in the topic's stats, and on failover subscriptions
+    /// the broker uses it to choose the active consumer
+    ///
+    /// default: a random name
     pub fn with_consumer_name<S: Into<String>>(mut self, consumer_name: S) -> Self {
         self.consumer_name = Some(consumer_name.into());
         self
@@ -1422,23 +2733,170 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
         self
     }
 
-    /// sets consumer options
-    pub fn with_options(mut self, options: ConsumerOptions) -> Self {
-        self.consumer_options = Some(options);
-        self
+    /// sets the size of the receiver queue, the number of messages the
+    /// broker can push to the consumer before it asks for more
+    ///
+    /// this is the same setting as [ConsumerBuilder::with_batch_size]
+    ///
+    /// default value: 1000
+    pub fn with_receiver_queue_size(self, size: u32) -> Self {
+        self.with_batch_size(size)
     }
 
-    /// sets the dead letter policy
-    pub fn with_dead_letter_policy(mut self, dead_letter_policy: DeadLetterPolicy) -> Self {
-        self.dead_letter_policy = Some(dead_letter_policy);
+    /// asks the broker for more messages when fewer than `threshold` permits
+    /// remain in the receiver queue. Building the consumer fails if it is
+    /// above the receiver queue size
+    ///
+    /// default value: half of the receiver queue size
+    pub fn with_receiver_queue_refill_threshold(mut self, threshold: u32) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .receiver_queue_refill_threshold = Some(threshold);
         self
     }
 
-    /// The time after which a message is dropped without being acknowledged or nacked
-    /// that the message is resent. If `None`, messages will only be resent when a
-    /// consumer disconnects with pending unacknowledged messages.
-    pub fn with_unacked_message_resend_delay(mut self, delay: Option<Duration>) -> Self {
-        self.unacked_message_resend_delay = delay;
+    /// decrypts the messages of producers configured with
+    /// [ProducerBuilder::with_encryption](crate::producer::ProducerBuilder::with_encryption),
+    /// with a PEM encoded RSA private key
+    #[cfg(feature = "encryption")]
+    pub fn with_decryption(mut self, private_key: Vec<u8>) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .decryption_key = Some(private_key);
+        self
+    }
+
+    /// sets what to do with the messages that cannot be decrypted
+    ///
+    /// default: [CryptoFailureAction::Fail]
+    pub fn with_crypto_failure_action(mut self, action: CryptoFailureAction) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .crypto_failure_action = Some(action);
+        self
+    }
+
+    /// sets the priority level of the consumer in Shared and Key_Shared
+    /// subscriptions (default: 0). Consumers with a lower value get the
+    /// messages first, until their receiver queue is full
+    ///
+    /// building the consumer fails if the level is negative
+    pub fn with_priority_level(mut self, priority_level: i32) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .priority_level = Some(priority_level);
+        self
+    }
+
+    /// sets the position of the cursor of a new subscription (default: Latest)
+    ///
+    /// existing subscriptions resume from their cursor, whatever the initial
+    /// position
+    pub fn with_initial_position(mut self, initial_position: InitialPosition) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .initial_position = initial_position;
+        self
+    }
+
+    /// acknowledges and skips the messages published longer ago than `ttl`,
+    /// instead of delivering them
+    ///
+    /// the age is computed from the publish time set by the producer, so it
+    /// depends on the clocks of the producer and consumer hosts
+    pub fn with_message_ttl(mut self, ttl: Duration) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .message_ttl = Some(ttl);
+        self
+    }
+
+    /// reconnection policy used when the connection to the broker is lost
+    pub fn with_reconnection(mut self, retry_policy: RetryPolicy) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .reconnection = Some(retry_policy);
+        self
+    }
+
+    /// starts consuming at the first message published after this time, in
+    /// milliseconds since the epoch
+    ///
+    /// the subscription is moved there when the consumer is created, even if
+    /// it already existed, and before any message is received
+    pub fn start_at_timestamp(mut self, timestamp: u64) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .start_timestamp = Some(timestamp);
+        self
+    }
+
+    /// reads the compacted topic, where only the latest message of each key
+    /// is kept
+    ///
+    /// the broker only allows it for Exclusive and Failover subscriptions
+    pub fn with_read_compacted(mut self, read_compacted: bool) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .read_compacted = Some(read_compacted);
+        self
+    }
+
+    /// maximum number of chunked messages being reassembled at the same time,
+    /// see [ConsumerOptions::max_pending_chunked_messages]
+    pub fn with_max_pending_chunked_messages(mut self, max: usize) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .max_pending_chunked_messages = Some(max);
+        self
+    }
+
+    /// delay after which incomplete chunked messages are dropped, see
+    /// [ConsumerOptions::chunked_message_expiry]
+    pub fn with_chunked_message_expiry(mut self, expiry: Duration) -> Self {
+        self.consumer_options
+            .get_or_insert_with(Default::default)
+            .chunked_message_expiry = Some(expiry);
+        self
+    }
+
+    /// sets consumer options
+    pub fn with_options(mut self, options: ConsumerOptions) -> Self {
+        self.consumer_options = Some(options);
+        self
+    }
+
+    /// sets the dead letter policy
+    pub fn with_dead_letter_policy(mut self, dead_letter_policy: DeadLetterPolicy) -> Self {
+        self.dead_letter_policy = Some(dead_letter_policy);
+        self
+    }
+
+    /// The time after which a message is dropped without being acknowledged or nacked
+    /// that the message is resent. If `None`, messages will only be resent when a
+    /// consumer disconnects with pending unacknowledged messages.
+    pub fn with_unacked_message_resend_delay(mut self, delay: Option<Duration>) -> Self {
+        self.unacked_message_resend_delay = delay;
+        self
+    }
+
+    /// time after which a message that was received but not acknowledged is
+    /// sent again, like the Java client's `ackTimeout`
+    ///
+    /// the expired messages are checked every 500ms and requested for
+    /// redelivery. Acknowledging or nacking a message stops its tracking. Same
+    /// as [ConsumerBuilder::with_unacked_message_resend_delay]
+    pub fn with_ack_timeout(self, timeout: Duration) -> Self {
+        self.with_unacked_message_resend_delay(Some(timeout))
+    }
+
+    /// delay before negatively acknowledged messages are sent again
+    ///
+    /// nacks are accumulated during that time, and a message acknowledged
+    /// before the delay is over will not be sent again. If not set, the
+    /// redelivery is requested right away
+    pub fn with_nack_redelivery_delay(mut self, delay: Duration) -> Self {
+        self.nack_redelivery_delay = Some(delay);
         self
     }
 
@@ -1454,6 +2912,7 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             consumer_name,
             batch_size,
             unacked_message_resend_delay,
+            nack_redelivery_delay,
             namespace,
             topic_refresh,
             consumer_options,
@@ -1465,7 +2924,13 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
                 "Cannot create consumer with no topics and no topic regex".into(),
             ));
         }
+        check_options(
+            subscription_type.unwrap_or(SubType::Shared),
+            batch_size.unwrap_or(DEFAULT_RECEIVER_QUEUE_SIZE),
+            consumer_options.as_ref(),
+        )?;
 
+        let partitioned_topics = topics.clone().unwrap_or_default();
         let topics: Vec<(String, BrokerAddress)> = try_join_all(
             topics
                 .into_iter()
@@ -1507,25 +2972,47 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             warn!("Subscription Type not specified. Defaulting to `Shared`.");
             SubType::Shared
         });
+        // brokers do not name consumers themselves, and failover
+        // subscriptions choose their active consumer by name
+        let consumer_name = consumer_name.unwrap_or_else(|| {
+            (0..5)
+                .map(|_| rand::thread_rng().sample(Alphanumeric))
+                .map(|c| c as char)
+                .collect()
+        });
 
         let config = ConsumerConfig {
             subscription,
             sub_type,
             batch_size,
-            consumer_name,
+            consumer_name: Some(consumer_name),
             consumer_id,
             unacked_message_redelivery_delay: unacked_message_resend_delay,
+            nack_redelivery_delay,
             options: consumer_options.unwrap_or_default(),
             dead_letter_policy,
         };
 
-        let consumers =
-            try_join_all(topics.into_iter().map(|(topic, addr)| {
-                TopicConsumer::new(pulsar.clone(), topic, addr, config.clone())
-            }))
-            .await?;
-
-        let consumer = if consumers.len() == 1 {
+        let counters = Arc::new(ConsumerCounters::default());
+        let consumers = try_join_all(topics.into_iter().map(|(topic, addr)| {
+            TopicConsumer::new(
+                pulsar.clone(),
+                topic,
+                addr,
+                config.clone(),
+                counters.clone(),
+            )
+        }))
+        .await?;
+
+        // partitioned topics, even with a single partition, use a multi topic
+        // consumer, to pick up the partitions added later
+        let single = consumers.len() == 1
+            && topic_regex.is_none()
+            && Topic::parse(consumers[0].topic())
+                .map(|topic| topic.partition().is_none())
+                .unwrap_or(true);
+        let consumer = if single {
             let consumer = consumers.into_iter().next().unwrap();
             InnerConsumer::Single(consumer)
         } else {
@@ -1539,6 +3026,7 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
             let mut consumer = MultiTopicConsumer {
                 namespace: namespace.unwrap_or_else(|| "public/default".to_string()),
                 topic_regex,
+                partitioned_topics,
                 pulsar,
                 consumers,
                 topics,
@@ -1546,11 +3034,12 @@ impl<Exe: Executor> ConsumerBuilder<Exe> {
                 refresh,
                 config,
                 disc_last_message_received: None,
-                disc_messages_received: 0,
+                counters,
+                active_listeners: Vec::new(),
             };
             if consumer.topic_regex.is_some() {
                 consumer.update_topics();
-                let initial_consumers = consumer.new_consumers.take().unwrap().await?;
+                let (initial_consumers, _) = consumer.new_consumers.take().unwrap().await?;
                 consumer.add_consumers(initial_consumers);
             }
             InnerConsumer::Multi(consumer)
@@ -1578,6 +3067,8 @@ pub struct ConsumerConfig {
     consumer_id: Option<u64>,
     /// time after which unacked messages will be sent again
     unacked_message_redelivery_delay: Option<Duration>,
+    /// time after which nacked messages will be sent again
+    nack_redelivery_delay: Option<Duration>,
     /// consumer options
     options: ConsumerOptions,
     /// dead letter policy
@@ -1588,17 +3079,32 @@ pub struct ConsumerConfig {
 struct MultiTopicConsumer<T: DeserializeMessage, Exe: Executor> {
     namespace: String,
     topic_regex: Option<Regex>,
+    // topics given to the builder, whose partitions are looked up again on
+    // refresh, to consume from the partitions added later
+    partitioned_topics: Vec<String>,
     pulsar: Pulsar<Exe>,
     consumers: BTreeMap<String, Pin<Box<TopicConsumer<T, Exe>>>>,
     topics: VecDeque<String>,
+    // consumers for the topics matching the regex that were just created,
+    // and the list of all the matching topics
     #[allow(clippy::type_complexity)]
-    new_consumers:
-        Option<Pin<Box<dyn Future<Output = Result<Vec<TopicConsumer<T, Exe>>, Error>> + Send>>>,
+    new_consumers: Option<
+        Pin<
+            Box<
+                dyn Future<Output = Result<(Vec<TopicConsumer<T, Exe>>, BTreeSet<String>), Error>>
+                    + Send,
+            >,
+        >,
+    >,
     refresh: Pin<Box<dyn Stream<Item = ()> + Send>>,
     config: ConsumerConfig,
-    // Stats on disconnected consumers to keep metrics correct
-    disc_messages_received: u64,
+    // shared by all the topic consumers, so the counters of the removed
+    // topics are kept
+    counters: Arc<ConsumerCounters>,
     disc_last_message_received: Option<DateTime<Utc>>,
+    // receivers of the active consumer changes, registered on the consumers
+    // of the topics added later
+    active_listeners: Vec<mpsc::UnboundedSender<ActiveConsumerChange>>,
 }
 
 impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
@@ -1614,14 +3120,6 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
             .max()
     }
 
-    fn messages_received(&self) -> u64 {
-        self.consumers
-            .values()
-            .map(|c| c.messages_received)
-            .chain(iter::once(self.disc_messages_received))
-            .sum()
-    }
-
     async fn check_connections(&mut self) -> Result<(), Error> {
         self.pulsar
             .manager
@@ -1639,7 +3137,10 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
     }
 
     fn add_consumers<I: IntoIterator<Item = TopicConsumer<T, Exe>>>(&mut self, consumers: I) {
-        for consumer in consumers {
+        for mut consumer in consumers {
+            for listener in self.active_listeners.iter() {
+                consumer.watch_active(listener.clone());
+            }
             let topic = consumer.topic().to_owned();
             self.consumers.insert(topic.clone(), Box::pin(consumer));
             self.topics.push_back(topic);
@@ -1650,7 +3151,6 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         self.topics.retain(|t| !topics.contains(t));
         for topic in topics {
             if let Some(consumer) = self.consumers.remove(topic) {
-                self.disc_messages_received += consumer.messages_received;
                 self.disc_last_message_received = self
                     .disc_last_message_received
                     .into_iter()
@@ -1660,61 +3160,129 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
         }
     }
 
+    /// adds the consumers created for new topics, and closes the consumers
+    /// of the topics that do not exist anymore
+    fn update_consumers(
+        &mut self,
+        new_consumers: Vec<TopicConsumer<T, Exe>>,
+        matching_topics: &BTreeSet<String>,
+    ) {
+        let removed: Vec<String> = self
+            .consumers
+            .keys()
+            .filter(|t| !matching_topics.contains(*t))
+            .cloned()
+            .collect();
+        if !removed.is_empty() {
+            debug!("closing the consumers of deleted topics {:?}", removed);
+            self.remove_consumers(&removed);
+        }
+        self.add_consumers(new_consumers);
+    }
+
     fn update_topics(&mut self) {
-        if let Some(regex) = self.topic_regex.clone() {
-            let pulsar = self.pulsar.clone();
-            let namespace = self.namespace.clone();
-            let existing_topics: BTreeSet<String> = self.consumers.keys().cloned().collect();
-            let consumer_config = self.config.clone();
-
-            self.new_consumers = Some(Box::pin(async move {
-                let topics = pulsar
+        if self.topic_regex.is_none() && self.partitioned_topics.is_empty() {
+            return;
+        }
+
+        let regex = self.topic_regex.clone();
+        let partitioned_topics = self.partitioned_topics.clone();
+        let pulsar = self.pulsar.clone();
+        let namespace = self.namespace.clone();
+        let existing_topics: BTreeSet<String> = self.consumers.keys().cloned().collect();
+        let consumer_config = self.config.clone();
+        let counters = self.counters.clone();
+
+        self.new_consumers = Some(Box::pin(async move {
+            let mut topics = partitioned_topics;
+            if let Some(regex) = regex.as_ref() {
+                let namespace_topics = pulsar
                     .get_topics_of_namespace(
                         namespace.clone(),
                         proto::command_get_topics_of_namespace::Mode::All,
                     )
                     .await?;
-                trace!("fetched topics {:?}", topics);
+                trace!("fetched topics {:?}", namespace_topics);
+                topics.extend(namespace_topics.into_iter().filter(|t| regex.is_match(t)));
+            }
 
-                let topics: Vec<_> = try_join_all(
-                    topics
-                        .into_iter()
-                        .filter(|t| regex.is_match(t))
-                        .map(|topic| pulsar.lookup_partitioned_topic(topic)),
-                )
-                .await?
-                .into_iter()
-                .flatten()
-                .collect();
+            let topics: Vec<_> = try_join_all(
+                topics
+                    .into_iter()
+                    .map(|topic| pulsar.lookup_partitioned_topic(topic)),
+            )
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
 
-                trace!("matched topics {:?} (regex: {})", topics, &regex);
+            trace!("matched topics {:?} (regex: {:?})", topics, regex);
+            let matching_topics: BTreeSet<String> = topics.iter().map(|(t, _)| t.clone()).collect();
 
-                let consumers = try_join_all(
-                    topics
-                        .into_iter()
-                        .filter(|(t, _)| !existing_topics.contains(t))
-                        .map(|(topic, addr)| {
-                            TopicConsumer::new(pulsar.clone(), topic, addr, consumer_config.clone())
-                        }),
-                )
-                .await?;
-                trace!("created {} consumers", consumers.len());
-                Ok(consumers)
-            }));
-        }
+            let consumers = try_join_all(
+                topics
+                    .into_iter()
+                    .filter(|(t, _)| !existing_topics.contains(t))
+                    .map(|(topic, addr)| {
+                        TopicConsumer::new(
+                            pulsar.clone(),
+                            topic,
+                            addr,
+                            consumer_config.clone(),
+                            counters.clone(),
+                        )
+                    }),
+            )
+            .await?;
+            trace!("created {} consumers", consumers.len());
+            Ok((consumers, matching_topics))
+        }));
     }
 
     async fn ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         if let Some(c) = self.consumers.get_mut(&msg.topic) {
-            c.ack(&msg).await
+            c.ack(msg).await
+        } else {
+            Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
+        }
+    }
+
+    #[cfg(feature = "transactions")]
+    async fn ack_in_txn(&mut self, msg: &Message<T>, txn: &Transaction<Exe>) -> Result<(), Error> {
+        if let Some(c) = self.consumers.get_mut(&msg.topic) {
+            c.ack_in_txn(msg, txn).await
         } else {
             Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
         }
     }
 
+    async fn ack_all<'a, I>(&mut self, msgs: I) -> Result<(), ConsumerError>
+    where
+        T: 'a,
+        I: IntoIterator<Item = &'a Message<T>>,
+    {
+        let mut by_topic: BTreeMap<&str, Vec<&'a Message<T>>> = BTreeMap::new();
+        for msg in msgs {
+            by_topic.entry(&msg.topic).or_default().push(msg);
+        }
+
+        for (topic, msgs) in by_topic {
+            if let Some(c) = self.consumers.get_mut(topic) {
+                c.ack_all(msgs).await?;
+            } else {
+                return Err(ConnectionError::Unexpected(format!(
+                    "no consumer for topic {}",
+                    topic
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
     async fn cumulative_ack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         if let Some(c) = self.consumers.get_mut(&msg.topic) {
-            c.cumulative_ack(&msg).await
+            c.cumulative_ack(msg).await
         } else {
             Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
         }
@@ -1722,7 +3290,7 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
 
     async fn nack(&mut self, msg: &Message<T>) -> Result<(), ConsumerError> {
         if let Some(c) = self.consumers.get_mut(&msg.topic) {
-            c.nack(&msg).await?;
+            c.nack(msg).await?;
             Ok(())
         } else {
             Err(ConnectionError::Unexpected(format!("no consumer for topic {}", msg.topic)).into())
@@ -1750,9 +3318,7 @@ impl<T: DeserializeMessage, Exe: Executor> MultiTopicConsumer<T, Exe> {
                 let mut v = futures::future::join_all(actions).await;
 
                 for res in v.drain(..) {
-                    if res.is_err() {
-                        return res;
-                    }
+                    res?;
                 }
 
                 Ok(())
@@ -1780,6 +3346,7 @@ pub struct Message<T> {
     pub payload: Payload,
     /// contains the message's id and batch size data
     pub message_id: MessageData,
+    redelivery_count: u32,
     _phantom: PhantomData<T>,
 }
 
@@ -1793,6 +3360,58 @@ impl<T> Message<T> {
     pub fn message_id(&self) -> &proto::MessageIdData {
         &self.message_id.id
     }
+
+    /// number of times the broker delivered this message before, because it
+    /// was negatively acknowledged or not acknowledged in time
+    ///
+    /// brokers that do not track it always send 0
+    pub fn redelivery_count(&self) -> u32 {
+        self.redelivery_count
+    }
+
+    /// key used to choose the partition of the message, and the consumer
+    /// of Key_Shared subscriptions
+    pub fn partition_key(&self) -> Option<&str> {
+        self.payload.metadata.partition_key.as_deref()
+    }
+
+    /// key used instead of the partition key to choose the consumer of
+    /// Key_Shared subscriptions, if the producer set one
+    pub fn ordering_key(&self) -> Option<&[u8]> {
+        self.payload.metadata.ordering_key.as_deref()
+    }
+
+    /// version of the schema the message was produced with, if the
+    /// producer registered a schema
+    pub fn schema_version(&self) -> Option<&[u8]> {
+        self.payload.metadata.schema_version.as_deref()
+    }
+
+    /// time at which the producer published the message, in milliseconds
+    /// since the UNIX epoch
+    pub fn publish_time(&self) -> u64 {
+        self.payload.metadata.publish_time
+    }
+
+    /// time at which the producer published the message
+    pub fn publish_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.publish_time())
+    }
+
+    /// whether the message was published longer ago than `ttl`
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        is_expired(self.publish_time(), ttl)
+    }
+
+    /// time at which the event described by the message happened, if the
+    /// producer set it
+    pub fn event_time(&self) -> Option<SystemTime> {
+        self.payload
+            .metadata
+            .event_time
+            .filter(|millis| *millis != 0)
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+    }
 }
 impl<T: DeserializeMessage> Message<T> {
     /// directly deserialize a message
@@ -1817,8 +3436,8 @@ impl<T: 'static + DeserializeMessage, Exe: Executor> Stream for MultiTopicConsum
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Some(mut new_consumers) = self.new_consumers.take() {
             match new_consumers.as_mut().poll(cx) {
-                Poll::Ready(Ok(new_consumers)) => {
-                    self.add_consumers(new_consumers);
+                Poll::Ready(Ok((new_consumers, matching_topics))) => {
+                    self.update_consumers(new_consumers, &matching_topics);
                 }
                 Poll::Pending => {
                     self.new_consumers = Some(new_consumers);
@@ -1900,7 +3519,7 @@ mod tests {
         msg: u32,
     }
 
-    impl<'a> SerializeMessage for &'a TestData {
+    impl SerializeMessage for &TestData {
         fn serialize_message(input: Self) -> Result<producer::Message, Error> {
             let payload = serde_json::to_vec(&input).map_err(|e| Error::Custom(e.to_string()))?;
             Ok(producer::Message {
@@ -1925,7 +3544,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn multi_consumer() {
         let _ = log::set_logger(&MULTI_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
         let addr = "pulsar://127.0.0.1:6650";
 
         let topic_n: u16 = rand::random();
@@ -1972,7 +3591,7 @@ mod tests {
         let consumer_1: Consumer<TestData, _> = builder
             .clone()
             .with_subscription("consumer_1")
-            .with_topics(&[&topic1, &topic2])
+            .with_topics([&topic1, &topic2])
             .build()
             .await
             .unwrap();
@@ -2001,13 +3620,19 @@ mod tests {
                 .await
                 .unwrap()
             {
-                received.insert(message.unwrap().deserialize().unwrap());
+                let message = message.unwrap();
+                received.insert(message.deserialize().unwrap());
+                // acks are routed to the consumer of the message's topic
+                consumer.ack(&message).await.unwrap();
                 if received.len() == 4 {
                     break;
                 }
             }
             assert_eq!(expected, received);
             assert_eq!(consumer.messages_received(), 4);
+            // the acks are counted once the engines process them
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(consumer.metrics().messages_acked, 4);
             assert!(consumer.last_message_received().is_some());
         }
     }
@@ -2017,7 +3642,7 @@ mod tests {
     async fn consumer_dropped_with_lingering_acks() {
         use rand::{distributions::Alphanumeric, Rng};
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
         let addr = "pulsar://127.0.0.1:6650";
 
         let topic = format!(
@@ -2031,7 +3656,6 @@ mod tests {
             topic: std::iter::repeat(())
                 .map(|()| rand::thread_rng().sample(Alphanumeric) as char)
                 .take(8)
-                .map(|c| c as char)
                 .collect(),
             msg: 1,
         };
@@ -2110,7 +3734,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn dead_letter_queue() {
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
         let addr = "pulsar://127.0.0.1:6650";
 
         let test_id: u16 = rand::random();
@@ -2177,14 +3801,38 @@ mod tests {
             dlq_msg.deserialize().unwrap(),
             "we probably received a message from a previous run of the test"
         );
+        let properties: HashMap<_, _> = dlq_msg
+            .metadata()
+            .properties
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.as_str()))
+            .collect();
+        let origin_id = msg.message_id();
+        assert_eq!(
+            properties.get(DLQ_ORIGIN_MESSAGE_ID).copied(),
+            Some(
+                format!(
+                    "{}:{}:{}",
+                    origin_id.ledger_id,
+                    origin_id.entry_id,
+                    origin_id.partition.unwrap_or(-1)
+                )
+                .as_str()
+            )
+        );
+        assert!(properties
+            .get(DLQ_REAL_TOPIC)
+            .map(|t| t.ends_with(&topic))
+            .unwrap_or(false));
         dlq_consumer.ack(&dlq_msg).await.unwrap();
     }
 
     #[tokio::test]
     #[cfg(feature = "tokio-runtime")]
+    #[allow(clippy::let_underscore_future)]
     async fn failover() {
         let _ = log::set_logger(&MULTI_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
         let addr = "pulsar://127.0.0.1:6650";
         let topic = format!("failover_{}", rand::random::<u16>());
         let client: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
@@ -2241,7 +3889,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn seek_single_consumer() {
         let _ = log::set_logger(&MULTI_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
         log::info!("starting seek test");
         let addr = "pulsar://127.0.0.1:6650";
         let topic = format!("seek_{}", rand::random::<u16>());
@@ -2305,7 +3953,7 @@ mod tests {
 
         // // call seek(timestamp), roll back the consumer to start_time
         log::info!("calling seek method");
-        let _seek_result = consumer_1
+        consumer_1
             .seek(None, None, Some(start_time), client)
             .await
             .unwrap();
@@ -2350,4 +3998,1354 @@ mod tests {
         assert_eq!(50, consumed_1);
         assert_eq!(100, consumed_2);
     }
+
+    #[test]
+    fn read_compacted_sub_type() {
+        let options = ConsumerOptions {
+            read_compacted: Some(true),
+            ..Default::default()
+        };
+        assert!(check_options(SubType::Exclusive, 1000, Some(&options)).is_ok());
+        assert!(check_options(SubType::Failover, 1000, Some(&options)).is_ok());
+        assert!(check_options(SubType::Shared, 1000, Some(&options)).is_err());
+        assert!(check_options(SubType::KeyShared, 1000, Some(&options)).is_err());
+        assert!(check_options(SubType::Shared, 1000, None).is_ok());
+    }
+
+    #[test]
+    fn priority_level() {
+        let options = |priority_level| ConsumerOptions {
+            priority_level: Some(priority_level),
+            ..Default::default()
+        };
+        assert!(check_options(SubType::Shared, 1000, Some(&options(0))).is_ok());
+        assert!(check_options(SubType::Shared, 1000, Some(&options(2))).is_ok());
+        assert!(check_options(SubType::Shared, 1000, Some(&options(-1))).is_err());
+    }
+
+    #[test]
+    fn receiver_queue_refill_threshold() {
+        let options = ConsumerOptions {
+            receiver_queue_refill_threshold: Some(100),
+            ..Default::default()
+        };
+        assert!(check_options(SubType::Shared, 100, Some(&options)).is_ok());
+        assert!(check_options(SubType::Shared, 99, Some(&options)).is_err());
+    }
+
+    #[test]
+    fn expired() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let published = |ago: Duration| (now - ago).as_millis() as u64;
+        assert!(is_expired(
+            published(Duration::from_secs(120)),
+            Duration::from_secs(60)
+        ));
+        assert!(!is_expired(
+            published(Duration::from_secs(10)),
+            Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn delivered_messages() {
+        let id = |entry_id| MessageIdData {
+            ledger_id: 1,
+            entry_id,
+            ..Default::default()
+        };
+
+        let mut delivered = DeliveredMessages::new();
+        assert_eq!(delivered.insert(&id(0)), None);
+        assert_eq!(delivered.insert(&id(1)), None);
+        // redelivered after a reconnection
+        assert_eq!(delivered.insert(&id(0)), Some(false));
+        assert_eq!(delivered.last(), Some(&id(1)));
+
+        // redelivery requested by the consumer
+        delivered.remove(&id(0));
+        assert_eq!(delivered.insert(&id(0)), None);
+
+        delivered.ack(&id(1), false);
+        assert_eq!(delivered.insert(&id(0)), Some(false));
+        assert_eq!(delivered.insert(&id(1)), Some(true));
+        assert_eq!(delivered.insert(&id(2)), None);
+        delivered.ack(&id(2), true);
+        assert_eq!(delivered.insert(&id(0)), Some(true));
+
+        for entry_id in 3..(DELIVERED_WINDOW as u64 + 3) {
+            assert_eq!(delivered.insert(&id(entry_id)), None);
+        }
+        assert_eq!(delivered.insert(&id(1)), None);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn partitions_refresh() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        use crate::message::{proto::base_command::Type as CommandType, Message as RawMessage};
+        use crate::mock::MockBroker;
+
+        let partitions = Arc::new(AtomicU32::new(2));
+        let handler_partitions = partitions.clone();
+        let broker = MockBroker::start_with_handler(move |message| {
+            let request = message.command.partition_metadata.as_ref()?;
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::PartitionedMetadataResponse as i32,
+                    partition_metadata_response: Some(
+                        proto::CommandPartitionedTopicMetadataResponse {
+                            partitions: Some(handler_partitions.load(Ordering::SeqCst)),
+                            request_id: request.request_id,
+                            response: Some(
+                                proto::command_partitioned_topic_metadata_response::LookupType::Success
+                                    as i32,
+                            ),
+                            ..Default::default()
+                        },
+                    ),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/partitions_refresh")
+            .with_subscription("partitions_refresh")
+            .with_topic_refresh(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(consumer.topics().len(), 2);
+
+        partitions.store(3, Ordering::SeqCst);
+        // the refresh happens while polling the consumer
+        let _ = timeout(Duration::from_millis(500), consumer.next()).await;
+        let mut topics = consumer.topics();
+        topics.sort();
+        assert_eq!(
+            topics,
+            vec![
+                "persistent://public/default/partitions_refresh-partition-0".to_string(),
+                "persistent://public/default/partitions_refresh-partition-1".to_string(),
+                "persistent://public/default/partitions_refresh-partition-2".to_string(),
+            ]
+        );
+        assert_eq!(broker.commands_of_type(CommandType::Subscribe).len(), 3);
+    }
+
+    fn namespace_topics_handler(
+        topics: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    ) -> impl Fn(&RawMessage) -> Option<Vec<RawMessage>> {
+        use crate::message::proto::base_command::Type as CommandType;
+
+        move |message| {
+            let request = message.command.get_topics_of_namespace.as_ref()?;
+            let topics = topics.lock().unwrap();
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::GetTopicsOfNamespaceResponse as i32,
+                    get_topics_of_namespace_response: Some(
+                        proto::CommandGetTopicsOfNamespaceResponse {
+                            request_id: request.request_id,
+                            topics: topics.iter().map(|t| t.to_string()).collect(),
+                        },
+                    ),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn regex_matches_whole_topic() {
+        use crate::mock::MockBroker;
+
+        let topics = Arc::new(std::sync::Mutex::new(vec![
+            "persistent://public/default/events",
+            "persistent://public/default/events-old",
+            "persistent://public/default/old-events",
+        ]));
+        let broker = MockBroker::start_with_handler(namespace_topics_handler(topics))
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let consumer: Consumer<Vec<u8>, _> = pulsar
+            .subscribe_with_regex("persistent://public/default/events", "regex_whole_topic")
+            .await
+            .unwrap();
+        assert_eq!(
+            consumer.topics(),
+            vec!["persistent://public/default/events".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn regex_closes_deleted_topics() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+
+        let topics = Arc::new(std::sync::Mutex::new(vec![
+            "persistent://public/default/events-a",
+            "persistent://public/default/events-b",
+            "persistent://public/default/other",
+        ]));
+        let broker = MockBroker::start_with_handler(namespace_topics_handler(topics.clone()))
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic_regex(Regex::new("^persistent://public/default/events-.*$").unwrap())
+            .with_subscription("regex_deleted_topics")
+            .with_topic_refresh(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        let mut subscribed = consumer.topics();
+        subscribed.sort();
+        assert_eq!(
+            subscribed,
+            vec![
+                "persistent://public/default/events-a".to_string(),
+                "persistent://public/default/events-b".to_string(),
+            ]
+        );
+        assert!(broker
+            .commands_of_type(CommandType::CloseConsumer)
+            .is_empty());
+
+        topics
+            .lock()
+            .unwrap()
+            .retain(|t| *t != "persistent://public/default/events-b");
+        // the refresh happens while polling the consumer
+        let _ = timeout(Duration::from_millis(500), consumer.next()).await;
+        assert_eq!(
+            consumer.topics(),
+            vec!["persistent://public/default/events-a".to_string()]
+        );
+        assert_eq!(broker.commands_of_type(CommandType::CloseConsumer).len(), 1);
+    }
+
+    #[test]
+    fn batched_message_keys() {
+        let mut data = Vec::new();
+        for (key, ordering_key) in [("a", Some(b"x".to_vec())), ("b", None)] {
+            crate::message::BatchedMessage {
+                metadata: proto::SingleMessageMetadata {
+                    partition_key: Some(key.to_string()),
+                    ordering_key,
+                    payload_size: 1,
+                    ..Default::default()
+                },
+                payload: vec![0],
+            }
+            .serialize(&mut data);
+        }
+        let payload = Payload {
+            metadata: Metadata {
+                num_messages_in_batch: Some(2),
+                ordering_key: Some(b"batch".to_vec()),
+                ..Default::default()
+            },
+            data,
+        };
+
+        let messages: Vec<Message<Vec<u8>>> =
+            BatchedMessageIterator::new(MessageIdData::default(), payload)
+                .unwrap()
+                .map(|(id, payload)| Message {
+                    topic: "test".to_string(),
+                    payload,
+                    message_id: MessageData {
+                        id,
+                        batch_size: None,
+                    },
+                    redelivery_count: 0,
+                    _phantom: PhantomData,
+                })
+                .collect();
+        assert_eq!(messages[0].partition_key(), Some("a"));
+        assert_eq!(messages[0].ordering_key(), Some(&b"x"[..]));
+        assert_eq!(messages[1].partition_key(), Some("b"));
+        assert_eq!(messages[1].ordering_key(), Some(&b"batch"[..]));
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "tokio-runtime", feature = "snap"))]
+    async fn snappy_decompression() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // a message compressed by another client, in the raw Snappy format
+        let broker = MockBroker::start_with_handler(|message| {
+            let flow = message.command.flow.as_ref()?;
+            let data = b"hello hello hello hello".to_vec();
+            Some(vec![RawMessage {
+                command: BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(CommandMessage {
+                        consumer_id: flow.consumer_id,
+                        message_id: MessageIdData {
+                            ledger_id: 1,
+                            entry_id: 1,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(Payload {
+                    metadata: Metadata {
+                        producer_name: "legacy".to_string(),
+                        compression: Some(proto::CompressionType::Snappy as i32),
+                        uncompressed_size: Some(data.len() as u32),
+                        ..Default::default()
+                    },
+                    data: snap::raw::Encoder::new().compress_vec(&data).unwrap(),
+                }),
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/snappy")
+            .with_subscription("snappy")
+            .build()
+            .await
+            .unwrap();
+
+        let message = consumer.try_next().await.unwrap().unwrap();
+        assert_eq!(message.payload.data, b"hello hello hello hello".to_vec());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn initial_position() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let _latest: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/initial_position")
+            .with_subscription("latest")
+            .build()
+            .await
+            .unwrap();
+        let _earliest: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/initial_position")
+            .with_subscription("earliest")
+            .with_initial_position(InitialPosition::Earliest)
+            .build()
+            .await
+            .unwrap();
+
+        let positions: Vec<_> = broker
+            .commands_of_type(CommandType::Subscribe)
+            .iter()
+            .map(|message| message.command.subscribe.as_ref().unwrap().initial_position)
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                Some(proto::command_subscribe::InitialPosition::Latest as i32),
+                Some(proto::command_subscribe::InitialPosition::Earliest as i32),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn consumer_name() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let named: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/consumer_name")
+            .with_subscription("named")
+            .with_consumer_name("worker-1")
+            .build()
+            .await
+            .unwrap();
+        let unnamed: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/consumer_name")
+            .with_subscription("unnamed")
+            .build()
+            .await
+            .unwrap();
+        let multi: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topics([
+                "persistent://public/default/consumer_name_a",
+                "persistent://public/default/consumer_name_b",
+            ])
+            .with_subscription("multi")
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(named.name(), "worker-1");
+        assert_eq!(named.consumer_name(), Some("worker-1"));
+        let unnamed_name = unnamed.name();
+        assert_eq!(unnamed_name.len(), 5);
+        let multi_name = multi.name();
+        assert_eq!(multi_name.len(), 5);
+        assert_eq!(multi.consumer_name(), Some(multi_name));
+        let names: Vec<_> = broker
+            .commands_of_type(CommandType::Subscribe)
+            .iter()
+            .map(|message| {
+                message
+                    .command
+                    .subscribe
+                    .as_ref()
+                    .unwrap()
+                    .consumer_name
+                    .clone()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                Some("worker-1".to_string()),
+                Some(unnamed_name.to_string()),
+                Some(multi_name.to_string()),
+                Some(multi_name.to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn active_consumer_changes() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the broker makes the consumer active once it asks for messages
+        let broker = MockBroker::start_with_handler(|message: &RawMessage| {
+            let flow = message.command.flow.as_ref()?;
+            Some(vec![RawMessage {
+                command: BaseCommand {
+                    r#type: CommandType::ActiveConsumerChange as i32,
+                    active_consumer_change: Some(proto::CommandActiveConsumerChange {
+                        consumer_id: flow.consumer_id,
+                        is_active: Some(true),
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let topic = "persistent://public/default/active_consumer_changes";
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic(topic)
+            .with_subscription("failover")
+            .with_subscription_type(SubType::Failover)
+            .build()
+            .await
+            .unwrap();
+
+        let mut changes = consumer.active_consumer_changes();
+        let change = timeout(Duration::from_secs(5), changes.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            change,
+            ActiveConsumerChange {
+                topic: topic.to_string(),
+                is_active: true,
+            }
+        );
+
+        // the known state is sent to the streams created later
+        let mut changes = consumer.active_consumer_changes();
+        let change = timeout(Duration::from_secs(5), changes.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(change.is_active);
+    }
+
+    /// delivers a batch of three messages when the consumer asks for messages
+    /// delivers four messages, with entry ids 1 to 4, on each flow
+    fn four_messages_handler(message: &RawMessage) -> Option<Vec<RawMessage>> {
+        use crate::message::proto::base_command::Type as CommandType;
+
+        let flow = message.command.flow.as_ref()?;
+        let messages = (1..=4)
+            .map(|entry_id| RawMessage {
+                command: BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(CommandMessage {
+                        consumer_id: flow.consumer_id,
+                        message_id: MessageIdData {
+                            ledger_id: 1,
+                            entry_id,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(Payload {
+                    metadata: Metadata {
+                        producer_name: "mock".to_string(),
+                        ..Default::default()
+                    },
+                    data: b"data".to_vec(),
+                }),
+            })
+            .collect();
+        Some(messages)
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn ack_modes() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::message::proto::command_ack::AckType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(four_messages_handler)
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/ack_modes")
+            .with_subscription("ack_modes")
+            .build()
+            .await
+            .unwrap();
+        let metrics = consumer.metrics_handle();
+        let mut messages = Vec::new();
+        for _ in 0..4 {
+            messages.push(consumer.try_next().await.unwrap().unwrap());
+        }
+
+        consumer.cumulative_ack(&messages[1]).await.unwrap();
+        // already covered by the cumulative ack
+        consumer.ack(&messages[0]).await.unwrap();
+        consumer.cumulative_ack(&messages[0]).await.unwrap();
+        // both acks are sent in one command
+        consumer.ack_all(&messages[2..]).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let acks: Vec<(i32, Vec<u64>)> = broker
+            .commands_of_type(CommandType::Ack)
+            .into_iter()
+            .map(|message| {
+                let ack = message.command.ack.unwrap();
+                (
+                    ack.ack_type,
+                    ack.message_id.iter().map(|id| id.entry_id).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            acks,
+            vec![
+                (AckType::Cumulative as i32, vec![2]),
+                (AckType::Individual as i32, vec![3, 4]),
+            ]
+        );
+        // the ignored acks are not counted, and the counters can be read from
+        // another task
+        let metrics = tokio::spawn(async move { metrics.snapshot() })
+            .await
+            .unwrap();
+        assert_eq!(
+            metrics,
+            ConsumerMetrics {
+                messages_received: 4,
+                messages_acked: 3,
+                messages_nacked: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn nack_redelivery_delay() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(four_messages_handler)
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/nack_redelivery_delay")
+            .with_subscription("nack_redelivery_delay")
+            .with_subscription_type(SubType::Shared)
+            .with_nack_redelivery_delay(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+        let first = consumer.try_next().await.unwrap().unwrap();
+        let second = consumer.try_next().await.unwrap().unwrap();
+        consumer.nack(&first).await.unwrap();
+        consumer.nack(&second).await.unwrap();
+        // acked before the delay, it is not redelivered
+        consumer.ack(&second).await.unwrap();
+
+        let redelivered = || -> Vec<u64> {
+            broker
+                .commands_of_type(CommandType::RedeliverUnacknowledgedMessages)
+                .into_iter()
+                .flat_map(|message| {
+                    message
+                        .command
+                        .redeliver_unacknowledged_messages
+                        .unwrap()
+                        .message_ids
+                })
+                .map(|id| id.entry_id)
+                .collect()
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(redelivered().is_empty());
+        // the nacked messages are checked every 500ms
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(redelivered(), vec![1]);
+    }
+
+    fn batch_handler(message: &RawMessage) -> Option<Vec<RawMessage>> {
+        use crate::message::proto::base_command::Type as CommandType;
+
+        let flow = message.command.flow.as_ref()?;
+        let mut data = Vec::new();
+        for payload in [b"a", b"b", b"c"] {
+            crate::message::BatchedMessage {
+                metadata: proto::SingleMessageMetadata {
+                    payload_size: 1,
+                    ..Default::default()
+                },
+                payload: payload.to_vec(),
+            }
+            .serialize(&mut data);
+        }
+        Some(vec![RawMessage {
+            command: BaseCommand {
+                r#type: CommandType::Message as i32,
+                message: Some(CommandMessage {
+                    consumer_id: flow.consumer_id,
+                    message_id: MessageIdData {
+                        ledger_id: 1,
+                        entry_id: 7,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            payload: Some(Payload {
+                metadata: Metadata {
+                    producer_name: "mock".to_string(),
+                    num_messages_in_batch: Some(3),
+                    ..Default::default()
+                },
+                data,
+            }),
+        }])
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn batch_acks() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(batch_handler).await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let consumer_acks = |consumer: u64| {
+            broker
+                .commands_of_type(CommandType::Ack)
+                .into_iter()
+                .map(|message| message.command.ack.unwrap())
+                .filter(|ack| ack.consumer_id == consumer)
+                .flat_map(|ack| ack.message_id)
+                .collect::<Vec<_>>()
+        };
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/batch_acks")
+            .with_subscription("batch_acks")
+            .build()
+            .await
+            .unwrap();
+        let mut messages = Vec::new();
+        for _ in 0..3 {
+            messages.push(consumer.try_next().await.unwrap().unwrap());
+        }
+        assert_eq!(messages[1].message_id().batch_index, Some(1));
+
+        // the batch is only acked once all its messages are acked
+        consumer.ack(&messages[1]).await.unwrap();
+        consumer.ack(&messages[0]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = consumer.consumer_id()[0];
+        assert!(consumer_acks(id).is_empty());
+        consumer.ack(&messages[2]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let acks = consumer_acks(id);
+        assert_eq!(acks.len(), 1);
+        assert_eq!((acks[0].entry_id, acks[0].batch_index), (7, None));
+
+        // with batch index acks, each ack carries the remaining messages
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/batch_acks")
+            .with_subscription("batch_index_acks")
+            .with_options(ConsumerOptions {
+                batch_index_ack: Some(true),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        let first = consumer.try_next().await.unwrap().unwrap();
+        let second = consumer.try_next().await.unwrap().unwrap();
+        consumer.ack(&second).await.unwrap();
+        consumer.ack(&first).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let acks = consumer_acks(consumer.consumer_id()[0]);
+        assert_eq!(acks.len(), 2);
+        assert_eq!(acks[0].ack_set, vec![0b101]);
+        assert_eq!(acks[1].ack_set, vec![0b100]);
+        assert_eq!(acks[1].batch_size, Some(3));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn redelivered_messages() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the broker closes the consumer after the first ack, then redelivers
+        // both messages
+        let closed = std::sync::atomic::AtomicBool::new(false);
+        let broker = MockBroker::start_with_handler(move |message| {
+            if let Some(ack) = message.command.ack.as_ref() {
+                if closed.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    return None;
+                }
+                return Some(vec![RawMessage {
+                    command: BaseCommand {
+                        r#type: CommandType::CloseConsumer as i32,
+                        close_consumer: Some(CommandCloseConsumer {
+                            consumer_id: ack.consumer_id,
+                            request_id: 0,
+                        }),
+                        ..Default::default()
+                    },
+                    payload: None,
+                }]);
+            }
+            let flow = message.command.flow.as_ref()?;
+            let messages = (1..=2)
+                .map(|entry_id| RawMessage {
+                    command: BaseCommand {
+                        r#type: CommandType::Message as i32,
+                        message: Some(CommandMessage {
+                            consumer_id: flow.consumer_id,
+                            message_id: MessageIdData {
+                                ledger_id: 1,
+                                entry_id,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    payload: Some(Payload {
+                        metadata: Metadata {
+                            producer_name: "mock".to_string(),
+                            ..Default::default()
+                        },
+                        data: b"data".to_vec(),
+                    }),
+                })
+                .collect();
+            Some(messages)
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/redelivered_messages")
+            .with_subscription("redelivered_messages")
+            .build()
+            .await
+            .unwrap();
+        let first = consumer.try_next().await.unwrap().unwrap();
+        let _second = consumer.try_next().await.unwrap().unwrap();
+        consumer.ack(&first).await.unwrap();
+
+        // the unacked message is skipped, and the acked one is acked again
+        assert!(timeout(Duration::from_millis(500), consumer.try_next())
+            .await
+            .is_err());
+        assert_eq!(broker.commands_of_type(CommandType::Subscribe).len(), 2);
+        let acks: Vec<u64> = broker
+            .commands_of_type(CommandType::Ack)
+            .into_iter()
+            .flat_map(|message| message.command.ack.unwrap().message_id)
+            .map(|id| id.entry_id)
+            .collect();
+        assert_eq!(acks, vec![1, 1]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn pending_chunked_messages_are_capped() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the first chunk of message "a", then both chunks of message "b"
+        let broker = MockBroker::start_with_handler(|message| {
+            let flow = message.command.flow.as_ref()?;
+            let chunks = [("a", 0), ("b", 0), ("b", 1)];
+            let messages = chunks
+                .iter()
+                .zip(1..)
+                .map(|((uuid, chunk_id), entry_id)| RawMessage {
+                    command: BaseCommand {
+                        r#type: CommandType::Message as i32,
+                        message: Some(CommandMessage {
+                            consumer_id: flow.consumer_id,
+                            message_id: MessageIdData {
+                                ledger_id: 1,
+                                entry_id,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    payload: Some(Payload {
+                        metadata: Metadata {
+                            producer_name: "mock".to_string(),
+                            uuid: Some(uuid.to_string()),
+                            num_chunks_from_msg: Some(2),
+                            chunk_id: Some(*chunk_id),
+                            ..Default::default()
+                        },
+                        data: uuid.as_bytes().to_vec(),
+                    }),
+                })
+                .collect();
+            Some(messages)
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/pending_chunked_messages")
+            .with_subscription("pending_chunked_messages")
+            .with_max_pending_chunked_messages(1)
+            .build()
+            .await
+            .unwrap();
+        let message = consumer.try_next().await.unwrap().unwrap();
+        assert_eq!(message.payload.data, b"bb".to_vec());
+
+        // the chunk of "a" was dropped to make room for "b"
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let redelivered: Vec<u64> = broker
+            .commands_of_type(CommandType::RedeliverUnacknowledgedMessages)
+            .into_iter()
+            .flat_map(|message| {
+                message
+                    .command
+                    .redeliver_unacknowledged_messages
+                    .unwrap()
+                    .message_ids
+            })
+            .map(|id| id.entry_id)
+            .collect();
+        assert_eq!(redelivered, vec![1]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn out_of_order_chunks_are_redelivered() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // message "a" is missing its second chunk, message "b" is complete
+        let broker = MockBroker::start_with_handler(|message| {
+            let flow = message.command.flow.as_ref()?;
+            let chunks = [("a", 0), ("a", 2), ("b", 0), ("b", 1), ("b", 2)];
+            let messages = chunks
+                .iter()
+                .zip(1..)
+                .map(|((uuid, chunk_id), entry_id)| RawMessage {
+                    command: BaseCommand {
+                        r#type: CommandType::Message as i32,
+                        message: Some(CommandMessage {
+                            consumer_id: flow.consumer_id,
+                            message_id: MessageIdData {
+                                ledger_id: 1,
+                                entry_id,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    payload: Some(Payload {
+                        metadata: Metadata {
+                            producer_name: "mock".to_string(),
+                            uuid: Some(uuid.to_string()),
+                            num_chunks_from_msg: Some(3),
+                            chunk_id: Some(*chunk_id),
+                            ..Default::default()
+                        },
+                        data: uuid.as_bytes().to_vec(),
+                    }),
+                })
+                .collect();
+            Some(messages)
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/out_of_order_chunks")
+            .with_subscription("out_of_order_chunks")
+            .build()
+            .await
+            .unwrap();
+        let message = consumer.try_next().await.unwrap().unwrap();
+        assert_eq!(message.payload.data, b"bbb".to_vec());
+
+        // the chunks of "a" are redelivered, including the unexpected one
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let redelivered: Vec<u64> = broker
+            .commands_of_type(CommandType::RedeliverUnacknowledgedMessages)
+            .into_iter()
+            .flat_map(|message| {
+                message
+                    .command
+                    .redeliver_unacknowledged_messages
+                    .unwrap()
+                    .message_ids
+            })
+            .map(|id| id.entry_id)
+            .collect();
+        assert_eq!(redelivered, vec![1, 2]);
+        assert!(broker.commands_of_type(CommandType::Ack).is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn crypto_failure_action() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // an encrypted message, without the private key to decrypt it
+        let broker = MockBroker::start_with_handler(|message| {
+            let flow = message.command.flow.as_ref()?;
+            Some(vec![RawMessage {
+                command: BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(CommandMessage {
+                        consumer_id: flow.consumer_id,
+                        message_id: MessageIdData {
+                            ledger_id: 1,
+                            entry_id: 1,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(Payload {
+                    metadata: Metadata {
+                        producer_name: "mock".to_string(),
+                        encryption_keys: vec![proto::EncryptionKeys {
+                            key: "key".to_string(),
+                            value: vec![1, 2, 3],
+                            metadata: Vec::new(),
+                        }],
+                        ..Default::default()
+                    },
+                    data: b"encrypted".to_vec(),
+                }),
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let consumer = |action| {
+            pulsar
+                .consumer()
+                .with_topic("persistent://public/default/crypto_failure_action")
+                .with_subscription("crypto_failure_action")
+                .with_crypto_failure_action(action)
+                .build::<Vec<u8>>()
+        };
+
+        let mut failing = consumer(CryptoFailureAction::Fail).await.unwrap();
+        assert!(failing.try_next().await.is_err());
+        assert!(broker.commands_of_type(CommandType::Ack).is_empty());
+
+        let mut consuming = consumer(CryptoFailureAction::Consume).await.unwrap();
+        let message = consuming.try_next().await.unwrap().unwrap();
+        assert_eq!(message.payload.data, b"encrypted".to_vec());
+        assert!(!message.payload.metadata.encryption_keys.is_empty());
+
+        let mut discarding = consumer(CryptoFailureAction::Discard).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), discarding.try_next())
+            .await
+            .is_err());
+        assert_eq!(broker.commands_of_type(CommandType::Ack).len(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn corrupted_message_is_redelivered() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the first message lost its payload, as the codec does when its
+        // checksum does not match
+        let broker = MockBroker::start_with_handler(|message| {
+            let flow = message.command.flow.as_ref()?;
+            let messages = (1..=2)
+                .map(|entry_id| RawMessage {
+                    command: BaseCommand {
+                        r#type: CommandType::Message as i32,
+                        message: Some(CommandMessage {
+                            consumer_id: flow.consumer_id,
+                            message_id: MessageIdData {
+                                ledger_id: 1,
+                                entry_id,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    payload: (entry_id == 2).then(|| Payload {
+                        metadata: Metadata {
+                            producer_name: "mock".to_string(),
+                            ..Default::default()
+                        },
+                        data: b"data".to_vec(),
+                    }),
+                })
+                .collect();
+            Some(messages)
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/corrupted_message")
+            .with_subscription("corrupted_message")
+            .build()
+            .await
+            .unwrap();
+        let message = consumer.try_next().await.unwrap().unwrap();
+        assert_eq!(message.message_id.id.entry_id, 2);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let redelivered: Vec<u64> = broker
+            .commands_of_type(CommandType::RedeliverUnacknowledgedMessages)
+            .into_iter()
+            .flat_map(|message| {
+                message
+                    .command
+                    .redeliver_unacknowledged_messages
+                    .unwrap()
+                    .message_ids
+            })
+            .map(|id| id.entry_id)
+            .collect();
+        assert_eq!(redelivered, vec![1]);
+        // the connection was kept
+        assert_eq!(broker.commands_of_type(CommandType::Connect).len(), 1);
+    }
+
+    #[test]
+    fn batch_ack_set() {
+        let mut acks = BatchAcks::new(70);
+        acks.ack(1, false);
+        acks.ack(65, false);
+        let set = acks.ack_set();
+        assert_eq!(set.len(), 2);
+        assert_eq!(set[0], !0b10);
+        assert_eq!(set[1], 0b111101);
+
+        acks.ack(68, true);
+        assert!(!acks.is_complete());
+        assert_eq!(acks.ack_set(), vec![0, 0b100000]);
+        acks.ack(69, false);
+        assert!(acks.is_complete());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn unsubscribe() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/unsubscribe")
+            .with_subscription("batch_job")
+            .build()
+            .await
+            .unwrap();
+        let consumer_id = consumer.consumer_id()[0];
+        consumer.unsubscribe().await.unwrap();
+        let unsubscribes = broker.commands_of_type(CommandType::Unsubscribe);
+        assert_eq!(unsubscribes.len(), 1);
+        assert_eq!(
+            unsubscribes[0]
+                .command
+                .unsubscribe
+                .as_ref()
+                .unwrap()
+                .consumer_id,
+            consumer_id
+        );
+
+        // the broker refuses while other consumers are connected
+        let broker = MockBroker::start_with_handler(|message: &RawMessage| {
+            let request = message.command.unsubscribe.as_ref()?;
+            Some(vec![RawMessage {
+                command: BaseCommand {
+                    r#type: CommandType::Error as i32,
+                    error: Some(proto::CommandError {
+                        request_id: request.request_id,
+                        error: proto::ServerError::ConsumerBusy as i32,
+                        message: "subscription has other connected consumers".to_string(),
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/unsubscribe")
+            .with_subscription("shared")
+            .with_subscription_type(SubType::Shared)
+            .build()
+            .await
+            .unwrap();
+        match consumer.unsubscribe().await {
+            Err(Error::Consumer(ConsumerError::Unsubscribe {
+                topic,
+                subscription,
+                error,
+                message,
+            })) => {
+                assert_eq!(topic, "persistent://public/default/unsubscribe");
+                assert_eq!(subscription, "shared");
+                assert_eq!(error, Some(proto::ServerError::ConsumerBusy));
+                assert_eq!(
+                    message.as_deref(),
+                    Some("subscription has other connected consumers")
+                );
+            }
+            other => panic!("unexpected result: {:?}", other.map(drop)),
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn ack_timeout() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // delivers two messages on the first flow
+        let delivered = std::sync::atomic::AtomicBool::new(false);
+        let broker = MockBroker::start_with_handler(move |message: &RawMessage| {
+            let flow = message.command.flow.as_ref()?;
+            if delivered.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Some(vec![]);
+            }
+            Some(
+                (1..=2)
+                    .map(|entry_id| RawMessage {
+                        command: BaseCommand {
+                            r#type: CommandType::Message as i32,
+                            message: Some(proto::CommandMessage {
+                                consumer_id: flow.consumer_id,
+                                message_id: MessageIdData {
+                                    ledger_id: 1,
+                                    entry_id,
+                                    ..Default::default()
+                                },
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                        payload: Some(Payload {
+                            metadata: proto::MessageMetadata {
+                                producer_name: "mock".to_string(),
+                                ..Default::default()
+                            },
+                            data: b"data".to_vec(),
+                        }),
+                    })
+                    .collect(),
+            )
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/ack_timeout")
+            .with_subscription("ack_timeout")
+            .with_subscription_type(SubType::Shared)
+            .with_ack_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(
+            consumer.unacked_message_redelivery_delay(),
+            Some(Duration::from_millis(100))
+        );
+
+        let first = consumer.try_next().await.unwrap().unwrap();
+        let _second = consumer.try_next().await.unwrap().unwrap();
+        consumer.ack(&first).await.unwrap();
+
+        // only the message that was not acknowledged is sent again
+        let redeliveries = timeout(Duration::from_secs(5), async {
+            loop {
+                let redeliveries =
+                    broker.commands_of_type(CommandType::RedeliverUnacknowledgedMessages);
+                if !redeliveries.is_empty() {
+                    return redeliveries;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let ids = &redeliveries[0]
+            .command
+            .redeliver_unacknowledged_messages
+            .as_ref()
+            .unwrap()
+            .message_ids;
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[0].entry_id, 2);
+    }
 }
@@ -0,0 +1,279 @@
+//! End to end encryption of the message payloads
+//!
+//! like the Java client, payloads are encrypted with AES-256-GCM using a
+//! data key generated by the producer, and the data key is sent with each
+//! message, encrypted with the RSA public keys of the consumers. The broker
+//! only sees encrypted payloads
+//!
+//! chunked messages are encrypted as a whole before being split, the chunks
+//! are not encrypted separately
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use openssl::pkey::{Private, Public};
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Padding, Rsa};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::error::{ConsumerError, ProducerError};
+use crate::message::proto::{EncryptionKeys, MessageMetadata};
+use crate::producer::ProducerMessage;
+
+const DATA_KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const ENCRYPTION_ALGO: &str = "AES-256-GCM";
+/// producers generate a new data key after this delay
+const DATA_KEY_ROTATION: Duration = Duration::from_secs(4 * 60 * 60);
+/// consumers forget the data keys they decrypted above this count
+const MAX_CACHED_DATA_KEYS: usize = 100;
+
+/// RSA public keys used by a producer to encrypt its data keys
+///
+/// ```rust,no_run
+/// use pulsar::encryption::EncryptionConfig;
+///
+/// # fn run() -> std::io::Result<()> {
+/// let config = EncryptionConfig::new("app-key", std::fs::read("public.pem")?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EncryptionConfig {
+    /// PEM encoded RSA public keys, by name. The data key is encrypted with
+    /// each of them, so any consumer with one of the private keys can read
+    /// the messages
+    pub public_keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl EncryptionConfig {
+    /// encrypts the data keys with a single public key
+    pub fn new<S: Into<String>>(key_name: S, public_key: Vec<u8>) -> Self {
+        EncryptionConfig::default().with_key(key_name, public_key)
+    }
+
+    /// adds a public key
+    pub fn with_key<S: Into<String>>(mut self, key_name: S, public_key: Vec<u8>) -> Self {
+        self.public_keys.insert(key_name.into(), public_key);
+        self
+    }
+}
+
+struct DataKey {
+    key: Vec<u8>,
+    encrypted: Vec<EncryptionKeys>,
+    created_at: Instant,
+}
+
+/// encrypts the payloads of a producer
+pub(crate) struct Encryptor {
+    public_keys: Vec<(String, Rsa<Public>)>,
+    data_key: Mutex<Option<DataKey>>,
+}
+
+impl Encryptor {
+    pub fn new(config: &EncryptionConfig) -> Result<Self, ProducerError> {
+        if config.public_keys.is_empty() {
+            return Err(ProducerError::Encryption(
+                "no public key configured".to_string(),
+            ));
+        }
+
+        let public_keys = config
+            .public_keys
+            .iter()
+            .map(|(name, pem)| {
+                Rsa::public_key_from_pem(pem)
+                    .map(|key| (name.clone(), key))
+                    .map_err(|e| {
+                        ProducerError::Encryption(format!("invalid public key {}: {}", name, e))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Encryptor {
+            public_keys,
+            data_key: Mutex::new(None),
+        })
+    }
+
+    fn generate_data_key(&self) -> Result<DataKey, ProducerError> {
+        let mut key = vec![0; DATA_KEY_LENGTH];
+        rand_bytes(&mut key).map_err(|e| {
+            ProducerError::Encryption(format!("could not generate a data key: {}", e))
+        })?;
+        let encrypted = self
+            .public_keys
+            .iter()
+            .map(|(name, public_key)| {
+                let mut value = vec![0; public_key.size() as usize];
+                let size = public_key
+                    .public_encrypt(&key, &mut value, Padding::PKCS1_OAEP)
+                    .map_err(|e| {
+                        ProducerError::Encryption(format!(
+                            "could not encrypt the data key with {}: {}",
+                            name, e
+                        ))
+                    })?;
+                value.truncate(size);
+                Ok(EncryptionKeys {
+                    key: name.clone(),
+                    value,
+                    metadata: Vec::new(),
+                })
+            })
+            .collect::<Result<_, ProducerError>>()?;
+
+        Ok(DataKey {
+            key,
+            encrypted,
+            created_at: Instant::now(),
+        })
+    }
+
+    /// encrypts the payload and fills the encryption metadata of the message
+    pub fn encrypt(&self, mut message: ProducerMessage) -> Result<ProducerMessage, ProducerError> {
+        let mut data_key = self.data_key.lock().unwrap();
+        let expired = data_key
+            .as_ref()
+            .map(|k| k.created_at.elapsed() > DATA_KEY_ROTATION)
+            .unwrap_or(true);
+        if expired {
+            *data_key = Some(self.generate_data_key()?);
+        }
+        let data_key = data_key.as_ref().unwrap();
+
+        let mut iv = vec![0; IV_LENGTH];
+        rand_bytes(&mut iv)
+            .map_err(|e| ProducerError::Encryption(format!("could not generate an IV: {}", e)))?;
+        let mut tag = [0; TAG_LENGTH];
+        let mut payload = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &data_key.key,
+            Some(&iv),
+            &[],
+            &message.payload,
+            &mut tag,
+        )
+        .map_err(|e| ProducerError::Encryption(format!("could not encrypt the payload: {}", e)))?;
+        payload.extend_from_slice(&tag);
+
+        message.payload = payload;
+        message.encryption_keys = data_key.encrypted.clone();
+        message.encryption_algo = Some(ENCRYPTION_ALGO.to_string());
+        message.encryption_param = Some(iv);
+        Ok(message)
+    }
+}
+
+/// decrypts the payloads received by a consumer
+pub(crate) struct Decryptor {
+    private_key: Rsa<Private>,
+    // decrypted data keys, by encrypted value
+    data_keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Decryptor {
+    pub fn new(private_key: &[u8]) -> Result<Self, ConsumerError> {
+        let private_key = Rsa::private_key_from_pem(private_key)
+            .map_err(|e| ConsumerError::Encryption(format!("invalid private key: {}", e)))?;
+
+        Ok(Decryptor {
+            private_key,
+            data_keys: HashMap::new(),
+        })
+    }
+
+    fn data_key(&mut self, keys: &[EncryptionKeys]) -> Result<Vec<u8>, ConsumerError> {
+        for key in keys {
+            if let Some(data_key) = self.data_keys.get(&key.value) {
+                return Ok(data_key.clone());
+            }
+
+            let mut data_key = vec![0; self.private_key.size() as usize];
+            if let Ok(size) =
+                self.private_key
+                    .private_decrypt(&key.value, &mut data_key, Padding::PKCS1_OAEP)
+            {
+                data_key.truncate(size);
+                if self.data_keys.len() >= MAX_CACHED_DATA_KEYS {
+                    self.data_keys.clear();
+                }
+                self.data_keys.insert(key.value.clone(), data_key.clone());
+                return Ok(data_key);
+            }
+        }
+
+        Err(ConsumerError::Encryption(format!(
+            "the private key cannot decrypt any of the data keys ({})",
+            keys.iter()
+                .map(|k| k.key.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+
+    /// decrypts a payload encrypted by an [Encryptor]
+    pub fn decrypt(
+        &mut self,
+        metadata: &MessageMetadata,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ConsumerError> {
+        let data_key = self.data_key(&metadata.encryption_keys)?;
+        let iv = metadata.encryption_param.as_deref().ok_or_else(|| {
+            ConsumerError::Encryption("missing encryption_param in the metadata".to_string())
+        })?;
+        if data.len() < TAG_LENGTH {
+            return Err(ConsumerError::Encryption(
+                "the encrypted payload is too short".to_string(),
+            ));
+        }
+
+        let (data, tag) = data.split_at(data.len() - TAG_LENGTH);
+        decrypt_aead(Cipher::aes_256_gcm(), &data_key, Some(iv), &[], data, tag)
+            .map_err(|e| ConsumerError::Encryption(format!("could not decrypt the payload: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let public_key = rsa.public_key_to_pem().unwrap();
+        let private_key = rsa.private_key_to_pem().unwrap();
+
+        let encryptor = Encryptor::new(&EncryptionConfig::new("key", public_key)).unwrap();
+        let message = encryptor
+            .encrypt(ProducerMessage {
+                payload: b"hello".to_vec(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_ne!(message.payload, b"hello".to_vec());
+        assert_eq!(message.encryption_keys.len(), 1);
+        assert_eq!(message.encryption_keys[0].key, "key");
+
+        let metadata = MessageMetadata {
+            encryption_keys: message.encryption_keys,
+            encryption_param: message.encryption_param,
+            ..Default::default()
+        };
+        let mut decryptor = Decryptor::new(&private_key).unwrap();
+        assert_eq!(
+            decryptor.decrypt(&metadata, &message.payload).unwrap(),
+            b"hello".to_vec()
+        );
+
+        let other = Rsa::generate(2048).unwrap().private_key_to_pem().unwrap();
+        assert!(matches!(
+            Decryptor::new(&other)
+                .unwrap()
+                .decrypt(&metadata, &message.payload),
+            Err(ConsumerError::Encryption(_))
+        ));
+    }
+}
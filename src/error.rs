@@ -35,10 +35,42 @@ impl From<ProducerError> for Error {
 
 impl From<ServiceDiscoveryError> for Error {
     fn from(err: ServiceDiscoveryError) -> Self {
-        Error::ServiceDiscovery(err)
+        match err {
+            // lookups stuck between brokers fail like the other connection errors
+            ServiceDiscoveryError::Connection(e @ ConnectionError::TooManyRedirects { .. }) => {
+                Error::Connection(e)
+            }
+            err => Error::ServiceDiscovery(err),
+        }
+    }
+}
+
+impl Error {
+    /// transient errors, after which the operation can be attempted again:
+    /// connection failures, and brokers not ready or overloaded. Errors like
+    /// authorization failures are not retryable
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            Error::Connection(e) => e.is_retryable(),
+            Error::Producer(ProducerError::Connection(e)) => e.is_retryable(),
+            Error::Consumer(ConsumerError::Connection(e)) => e.is_retryable(),
+            Error::ServiceDiscovery(ServiceDiscoveryError::Connection(e)) => e.is_retryable(),
+            Error::ServiceDiscovery(ServiceDiscoveryError::Query(e, _)) => {
+                is_retryable_server_error(*e)
+            }
+            _ => false,
+        }
     }
 }
 
+fn is_retryable_server_error(error: Option<crate::message::proto::ServerError>) -> bool {
+    use crate::message::proto::ServerError;
+    matches!(
+        error,
+        Some(ServerError::ServiceNotReady) | Some(ServerError::TooManyRequests)
+    )
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -72,13 +104,101 @@ pub enum ConnectionError {
     PulsarError(Option<crate::message::proto::ServerError>, Option<String>),
     Unexpected(String),
     Decoding(String),
+    /// the CRC32C checksum of a received payload does not match its content.
+    /// It is logged when the payload is dropped, the connection is kept
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
     Encoding(String),
+    /// the broker's protocol version does not support a command
+    UnsupportedProtocolVersion {
+        command: &'static str,
+        required: i32,
+        actual: i32,
+    },
     SocketAddr(String),
     UnexpectedResponse(String),
     Tls(native_tls::Error),
+    Authentication(String),
+    TokenFile(String),
     NotFound,
     Canceled,
     Shutdown,
+    /// the broker did not answer a request within the operation timeout
+    OperationTimeout,
+    /// a lookup was redirected more than `max_lookup_redirects` times, with
+    /// the brokers it went through. They may be redirecting to each other.
+    /// Lookups return it as [Error::Connection]
+    TooManyRedirects {
+        brokers: Vec<String>,
+    },
+    /// a received frame announces a size above the connection's maximum frame
+    /// size. It was rejected without being buffered
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl ConnectionError {
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            ConnectionError::Io(_)
+            | ConnectionError::Disconnected
+            | ConnectionError::OperationTimeout => true,
+            ConnectionError::PulsarError(e, _) => is_retryable_server_error(*e),
+            _ => false,
+        }
+    }
+
+    /// copies the error. I/O and TLS errors cannot be cloned, so only their
+    /// kind and message are kept
+    pub(crate) fn duplicate(&self) -> ConnectionError {
+        match self {
+            ConnectionError::Io(e) => ConnectionError::Io(io::Error::new(e.kind(), e.to_string())),
+            ConnectionError::Disconnected => ConnectionError::Disconnected,
+            ConnectionError::PulsarError(e, s) => ConnectionError::PulsarError(*e, s.clone()),
+            ConnectionError::Unexpected(e) => ConnectionError::Unexpected(e.clone()),
+            ConnectionError::Decoding(e) => ConnectionError::Decoding(e.clone()),
+            ConnectionError::ChecksumMismatch { expected, actual } => {
+                ConnectionError::ChecksumMismatch {
+                    expected: *expected,
+                    actual: *actual,
+                }
+            }
+            ConnectionError::Encoding(e) => ConnectionError::Encoding(e.clone()),
+            ConnectionError::UnsupportedProtocolVersion {
+                command,
+                required,
+                actual,
+            } => ConnectionError::UnsupportedProtocolVersion {
+                command,
+                required: *required,
+                actual: *actual,
+            },
+            ConnectionError::SocketAddr(e) => ConnectionError::SocketAddr(e.clone()),
+            ConnectionError::UnexpectedResponse(e) => {
+                ConnectionError::UnexpectedResponse(e.clone())
+            }
+            ConnectionError::Tls(e) => {
+                ConnectionError::Unexpected(format!("Error connecting TLS stream: {}", e))
+            }
+            ConnectionError::Authentication(e) => ConnectionError::Authentication(e.clone()),
+            ConnectionError::TokenFile(e) => ConnectionError::TokenFile(e.clone()),
+            ConnectionError::NotFound => ConnectionError::NotFound,
+            ConnectionError::Canceled => ConnectionError::Canceled,
+            ConnectionError::Shutdown => ConnectionError::Shutdown,
+            ConnectionError::OperationTimeout => ConnectionError::OperationTimeout,
+            ConnectionError::TooManyRedirects { brokers } => ConnectionError::TooManyRedirects {
+                brokers: brokers.clone(),
+            },
+            ConnectionError::FrameTooLarge { size, max } => ConnectionError::FrameTooLarge {
+                size: *size,
+                max: *max,
+            },
+        }
+    }
 }
 
 impl From<io::Error> for ConnectionError {
@@ -103,15 +223,44 @@ impl fmt::Display for ConnectionError {
             }
             ConnectionError::Unexpected(e) => write!(f, "{}", e),
             ConnectionError::Decoding(e) => write!(f, "Error decoding message: {}", e),
+            ConnectionError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:#010x}, computed {:#010x}",
+                expected, actual
+            ),
             ConnectionError::Encoding(e) => write!(f, "Error encoding message: {}", e),
+            ConnectionError::UnsupportedProtocolVersion {
+                command,
+                required,
+                actual,
+            } => write!(
+                f,
+                "{} requires protocol version {}, the broker supports version {}",
+                command, required, actual
+            ),
             ConnectionError::SocketAddr(e) => write!(f, "Error obtaining socket address: {}", e),
             ConnectionError::Tls(e) => write!(f, "Error connecting TLS stream: {}", e),
+            ConnectionError::Authentication(e) => write!(f, "Authentication error: {}", e),
+            ConnectionError::TokenFile(e) => write!(f, "Error reading the token file: {}", e),
             ConnectionError::UnexpectedResponse(e) => {
                 write!(f, "Unexpected response from pulsar: {}", e)
             }
             ConnectionError::NotFound => write!(f, "error looking up URL"),
             ConnectionError::Canceled => write!(f, "canceled request"),
             ConnectionError::Shutdown => write!(f, "The connection was shut down"),
+            ConnectionError::OperationTimeout => {
+                write!(f, "timeout waiting for the answer of the Pulsar server")
+            }
+            ConnectionError::TooManyRedirects { brokers } => write!(
+                f,
+                "lookup redirected too many times, through {}",
+                brokers.join(" -> ")
+            ),
+            ConnectionError::FrameTooLarge { size, max } => write!(
+                f,
+                "received a frame of {} bytes, above the maximum frame size of {} bytes",
+                size, max
+            ),
         }
     }
 }
@@ -132,6 +281,16 @@ pub enum ConsumerError {
     Io(io::Error),
     ChannelFull,
     Closed,
+    /// the message could not be decrypted
+    Encryption(String),
+    /// the broker refused to delete the subscription, for example because
+    /// other consumers are still connected to it
+    Unsubscribe {
+        topic: String,
+        subscription: String,
+        error: Option<crate::message::proto::ServerError>,
+        message: Option<String>,
+    },
 }
 
 impl From<ConnectionError> for ConsumerError {
@@ -170,6 +329,20 @@ impl fmt::Display for ConsumerError {
                 f,
                 "cannot send message to the consumer engine: the channel is closed"
             ),
+            ConsumerError::Encryption(s) => write!(f, "Decryption error: {}", s),
+            ConsumerError::Unsubscribe {
+                topic,
+                subscription,
+                error,
+                message,
+            } => write!(
+                f,
+                "could not delete subscription {} on topic {}: {:?} ({})",
+                subscription,
+                topic,
+                error,
+                message.as_deref().unwrap_or_default()
+            ),
         }
     }
 }
@@ -190,6 +363,46 @@ pub enum ProducerError {
     PartialSend(Vec<Result<SendFuture, Error>>),
     /// Indiciates the error was part of sending a batch, and thus shared across the batch
     Batch(Arc<Error>),
+    /// the message's receipt was not received before the send timeout
+    Timeout,
+    /// the message could not be encrypted
+    Encryption(String),
+    /// the message could not be serialized
+    #[cfg(feature = "json-schema")]
+    Serialization(serde_json::Error),
+    /// the task sending the producer's messages stopped before sending the message
+    EngineShutdown,
+    /// the message is larger than the maximum size accepted by the broker
+    MessageTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// another producer with the same name is already connected to the topic
+    ProducerBusy(String),
+    /// the producer's schema is not compatible with the schema of the topic,
+    /// with the broker's explanation
+    IncompatibleSchema {
+        topic: String,
+        message: Option<String>,
+    },
+    /// the receipt of a message sent with an explicit sequence id carries
+    /// another sequence id
+    SequenceIdMismatch {
+        expected: u64,
+        received: u64,
+    },
+    /// the broker dropped the message sent with an explicit sequence id, as
+    /// a duplicate of a message it already received
+    Duplicate {
+        sequence_id: u64,
+    },
+}
+
+#[cfg(feature = "json-schema")]
+impl From<serde_json::Error> for ProducerError {
+    fn from(err: serde_json::Error) -> Self {
+        ProducerError::Serialization(err)
+    }
 }
 
 impl From<ConnectionError> for ProducerError {
@@ -211,6 +424,34 @@ impl fmt::Display for ProducerError {
             ProducerError::Io(e) => write!(f, "Compression error: {}", e),
             ProducerError::Custom(s) => write!(f, "Custom error: {}", s),
             ProducerError::Batch(e) => write!(f, "Batch error: {}", e),
+            ProducerError::Timeout => write!(f, "send timeout"),
+            ProducerError::Encryption(s) => write!(f, "Encryption error: {}", s),
+            #[cfg(feature = "json-schema")]
+            ProducerError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            ProducerError::EngineShutdown => write!(f, "producer unexpectedly disconnected"),
+            ProducerError::ProducerBusy(s) => write!(f, "Producer busy: {}", s),
+            ProducerError::IncompatibleSchema { topic, message } => {
+                write!(f, "incompatible schema for topic {}", topic)?;
+                if let Some(message) = message {
+                    write!(f, ": {}", message)?;
+                }
+                Ok(())
+            }
+            ProducerError::MessageTooLarge { size, max } => write!(
+                f,
+                "message of {} bytes is larger than the maximum size of {} bytes",
+                size, max
+            ),
+            ProducerError::SequenceIdMismatch { expected, received } => write!(
+                f,
+                "unexpected sequence id in receipt: expected {}, got {}",
+                expected, received
+            ),
+            ProducerError::Duplicate { sequence_id } => write!(
+                f,
+                "message with sequence id {} dropped as a duplicate",
+                sequence_id
+            ),
             ProducerError::PartialSend(e) => {
                 let (successes, failures) = e.iter().fold((0, 0), |(s, f), r| match r {
                     Ok(_) => (s + 1, f),
@@ -245,6 +486,28 @@ impl fmt::Debug for ProducerError {
             ProducerError::Custom(msg) => write!(f, "Custom({:?})", msg),
             ProducerError::Io(e) => write!(f, "Connection({:?})", e),
             ProducerError::Batch(e) => write!(f, "Connection({:?})", e),
+            ProducerError::Timeout => write!(f, "Timeout"),
+            ProducerError::Encryption(msg) => write!(f, "Encryption({:?})", msg),
+            #[cfg(feature = "json-schema")]
+            ProducerError::Serialization(e) => write!(f, "Serialization({:?})", e),
+            ProducerError::EngineShutdown => write!(f, "EngineShutdown"),
+            ProducerError::MessageTooLarge { size, max } => {
+                write!(f, "MessageTooLarge {{ size: {}, max: {} }}", size, max)
+            }
+            ProducerError::ProducerBusy(msg) => write!(f, "ProducerBusy({:?})", msg),
+            ProducerError::IncompatibleSchema { topic, message } => write!(
+                f,
+                "IncompatibleSchema {{ topic: {:?}, message: {:?} }}",
+                topic, message
+            ),
+            ProducerError::SequenceIdMismatch { expected, received } => write!(
+                f,
+                "SequenceIdMismatch {{ expected: {}, received: {} }}",
+                expected, received
+            ),
+            ProducerError::Duplicate { sequence_id } => {
+                write!(f, "Duplicate {{ sequence_id: {} }}", sequence_id)
+            }
             ProducerError::PartialSend(parts) => {
                 write!(f, "PartialSend(")?;
                 for (i, part) in parts.iter().enumerate() {
@@ -273,6 +536,16 @@ impl std::error::Error for ProducerError {
                 .find(|r| r.is_err())
                 .map(|r| r.as_ref().map(drop).unwrap_err() as _),
             ProducerError::Custom(_) => None,
+            ProducerError::Timeout => None,
+            ProducerError::Encryption(_) => None,
+            #[cfg(feature = "json-schema")]
+            ProducerError::Serialization(e) => Some(e),
+            ProducerError::EngineShutdown => None,
+            ProducerError::MessageTooLarge { .. } => None,
+            ProducerError::ProducerBusy(_) => None,
+            ProducerError::IncompatibleSchema { .. } => None,
+            ProducerError::SequenceIdMismatch { .. } => None,
+            ProducerError::Duplicate { .. } => None,
         }
     }
 }
@@ -337,6 +610,15 @@ impl SharedError {
         self.error_set.load(Ordering::Relaxed)
     }
 
+    /// returns a copy of the error, without clearing it
+    pub fn get(&self) -> Option<ConnectionError> {
+        self.error
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(ConnectionError::duplicate)
+    }
+
     pub fn remove(&self) -> Option<ConnectionError> {
         let mut lock = self.error.lock().unwrap();
         let error = lock.take();
@@ -355,31 +637,62 @@ use crate::message::proto::ServerError;
 use crate::producer::SendFuture;
 
 pub(crate) fn server_error(i: i32) -> Option<ServerError> {
-    match i {
-        0 => Some(ServerError::UnknownError),
-        1 => Some(ServerError::MetadataError),
-        2 => Some(ServerError::PersistenceError),
-        3 => Some(ServerError::AuthenticationError),
-        4 => Some(ServerError::AuthorizationError),
-        5 => Some(ServerError::ConsumerBusy),
-        6 => Some(ServerError::ServiceNotReady),
-        7 => Some(ServerError::ProducerBlockedQuotaExceededError),
-        8 => Some(ServerError::ProducerBlockedQuotaExceededException),
-        9 => Some(ServerError::ChecksumError),
-        10 => Some(ServerError::UnsupportedVersionError),
-        11 => Some(ServerError::TopicNotFound),
-        12 => Some(ServerError::SubscriptionNotFound),
-        13 => Some(ServerError::ConsumerNotFound),
-        14 => Some(ServerError::TooManyRequests),
-        15 => Some(ServerError::TopicTerminatedError),
-        16 => Some(ServerError::ProducerBusy),
-        17 => Some(ServerError::InvalidTopicName),
-        /* FIXME: why aren't they found by the compiler? Max enum size?
-        18 => Some(ServerError::IncompatibleSchema),
-        19 => Some(ServerError::ConsumerAssignError),
-        20 => Some(ServerError::TransactionCoordinatorNotFound),
-        21 => Some(ServerError::InvalidTxnStatus),
-        */
-        _ => None,
+    ServerError::from_i32(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable() {
+        use crate::message::proto::ServerError;
+
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(Error::Connection(ConnectionError::Io(refused)).is_retryable());
+        assert!(Error::Connection(ConnectionError::OperationTimeout).is_retryable());
+        assert!(Error::ServiceDiscovery(ServiceDiscoveryError::Query(
+            Some(ServerError::ServiceNotReady),
+            None
+        ))
+        .is_retryable());
+        assert!(
+            Error::Producer(ProducerError::Connection(ConnectionError::PulsarError(
+                Some(ServerError::TooManyRequests),
+                None
+            )))
+            .is_retryable()
+        );
+
+        assert!(!Error::Connection(ConnectionError::PulsarError(
+            Some(ServerError::AuthorizationError),
+            None
+        ))
+        .is_retryable());
+        assert!(!Error::Custom("topic not set".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn shared_error() {
+        let error = SharedError::new();
+        assert!(error.get().is_none());
+
+        error.set(ConnectionError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no pong",
+        )));
+        // reading the error keeps it
+        match error.get() {
+            Some(ConnectionError::Io(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(e.to_string(), "no pong");
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert!(error.is_set());
+
+        assert!(error.remove().is_some());
+        assert!(!error.is_set());
+        assert!(error.get().is_none());
     }
 }
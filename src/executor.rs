@@ -16,7 +16,7 @@ pub enum ExecutorKind {
 /// Wrapper trait abstracting the Tokio and async-std executors
 pub trait Executor: Clone + Send + Sync + 'static {
     /// spawns a new task
-    #[allow(clippy::clippy::result_unit_err)]
+    #[allow(clippy::result_unit_err)]
     fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()>;
     /// spawns a new blocking task
     fn spawn_blocking<F, Res>(&self, f: F) -> JoinHandle<Res>
@@ -69,6 +69,64 @@ impl Executor for TokioExecutor {
     }
 }
 
+/// Tokio executor spawning the tasks on a specific runtime, instead of the
+/// runtime of the current thread
+///
+/// this runs the client on a dedicated runtime, and works from threads
+/// that are not running inside a Tokio runtime
+///
+/// ```rust,no_run
+/// use pulsar::{Pulsar, TokioRuntimeExecutor};
+///
+/// # fn run() -> Result<(), pulsar::Error> {
+/// let runtime = tokio::runtime::Builder::new_multi_thread()
+///     .worker_threads(2)
+///     .enable_all()
+///     .build()
+///     .unwrap();
+/// let executor = TokioRuntimeExecutor(runtime.handle().clone());
+/// let pulsar: Pulsar<_> = futures::executor::block_on(
+///     runtime.spawn(Pulsar::builder("pulsar://127.0.0.1:6650", executor).build()),
+/// )
+/// .unwrap()?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "tokio-runtime")]
+#[derive(Clone, Debug)]
+pub struct TokioRuntimeExecutor(pub tokio::runtime::Handle);
+
+#[cfg(feature = "tokio-runtime")]
+impl Executor for TokioRuntimeExecutor {
+    fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send>>) -> Result<(), ()> {
+        self.0.spawn(f);
+        Ok(())
+    }
+
+    fn spawn_blocking<F, Res>(&self, f: F) -> JoinHandle<Res>
+    where
+        F: FnOnce() -> Res + Send + 'static,
+        Res: Send + 'static,
+    {
+        JoinHandle::Tokio(self.0.spawn_blocking(f))
+    }
+
+    fn interval(&self, duration: std::time::Duration) -> Interval {
+        // timers are registered on the runtime of the current context
+        let _guard = self.0.enter();
+        Interval::Tokio(tokio::time::interval(duration))
+    }
+
+    fn delay(&self, duration: std::time::Duration) -> Delay {
+        let _guard = self.0.enter();
+        Delay::Tokio(tokio::time::sleep(duration))
+    }
+
+    fn kind(&self) -> ExecutorKind {
+        ExecutorKind::Tokio
+    }
+}
+
 /// Wrapper for the async-std executor
 #[cfg(feature = "async-std-runtime")]
 #[derive(Clone, Debug)]
@@ -236,3 +294,41 @@ impl Future for Delay {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "tokio-runtime")]
+    fn tokio_runtime_executor() {
+        use futures::channel::oneshot;
+        use futures::StreamExt;
+        use std::time::Duration;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let executor = TokioRuntimeExecutor(runtime.handle().clone());
+
+        // called outside of the runtime
+        let delay = executor.delay(Duration::from_millis(10));
+        let mut interval = executor.interval(Duration::from_millis(10));
+        let (tx, rx) = oneshot::channel();
+        executor
+            .spawn(Box::pin(async move {
+                delay.await;
+                interval.next().await;
+                let _ = tx.send(());
+            }))
+            .unwrap();
+
+        futures::executor::block_on(rx).unwrap();
+        assert_eq!(
+            futures::executor::block_on(executor.spawn_blocking(|| 1)),
+            Some(1)
+        );
+    }
+}
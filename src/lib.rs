@@ -136,6 +136,7 @@
 //! ```
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::large_enum_variant)]
+#![allow(clippy::result_large_err)]
 extern crate futures;
 #[macro_use]
 extern crate log;
@@ -150,7 +151,8 @@ extern crate serde;
 pub use client::{DeserializeMessage, Pulsar, PulsarBuilder, SerializeMessage};
 pub use connection::Authentication;
 pub use connection_manager::{
-    BrokerAddress, ConnectionRetryOptions, OperationRetryOptions, TlsOptions,
+    AddressOrder, BrokerAddress, ConnectionRetryOptions, OperationRetryOptions, RetryPolicy,
+    TlsOptions,
 };
 pub use consumer::{Consumer, ConsumerBuilder, ConsumerOptions};
 pub use error::Error;
@@ -158,23 +160,37 @@ pub use error::Error;
 pub use executor::AsyncStdExecutor;
 pub use executor::Executor;
 #[cfg(feature = "tokio-runtime")]
-pub use executor::TokioExecutor;
+pub use executor::{TokioExecutor, TokioRuntimeExecutor};
 pub use message::proto::command_subscribe::SubType;
 pub use message::{
     proto::{self, CommandSendReceipt},
     Payload,
 };
 pub use producer::{MultiTopicProducer, Producer, ProducerOptions};
+pub use reader::Reader;
 
+pub mod authentication;
 mod client;
 mod connection;
 mod connection_manager;
 pub mod consumer;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod error;
 pub mod executor;
 pub mod message;
+#[cfg(all(feature = "tokio-runtime", any(test, feature = "test-util")))]
+pub mod mock;
 pub mod producer;
+pub mod reader;
+pub mod router;
+pub mod schema;
 mod service_discovery;
+#[cfg(feature = "sync")]
+pub mod sync;
+pub mod topic;
+#[cfg(feature = "transactions")]
+pub mod transaction;
 
 #[cfg(test)]
 mod tests {
@@ -203,7 +219,7 @@ mod tests {
         pub data: String,
     }
 
-    impl<'a> SerializeMessage for &'a TestData {
+    impl SerializeMessage for &TestData {
         fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
             let payload =
                 serde_json::to_vec(input).map_err(|e| PulsarError::Custom(e.to_string()))?;
@@ -223,6 +239,7 @@ mod tests {
     }
 
     #[derive(Debug)]
+    #[allow(dead_code)]
     enum Error {
         Pulsar(PulsarError),
         Timeout(std::io::Error),
@@ -295,7 +312,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn round_trip() {
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
 
         let addr = "pulsar://127.0.0.1:6650";
         let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
@@ -360,7 +377,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn unsized_data() {
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
 
         let addr = "pulsar://127.0.0.1:6650";
         let test_id: u16 = rand::random();
@@ -446,7 +463,7 @@ mod tests {
     #[cfg(feature = "tokio-runtime")]
     async fn redelivery() {
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
 
         let addr = "pulsar://127.0.0.1:6650";
         let topic = format!("test_redelivery_{}", rand::random::<u16>());
@@ -471,11 +488,13 @@ mod tests {
             .await
             .unwrap();
 
-        let _first_receipt = timeout(Duration::from_secs(2), consumer.next())
+        let first_receipt = timeout(Duration::from_secs(2), consumer.next())
             .await
             .unwrap()
             .unwrap()
             .unwrap();
+        assert_eq!(first_receipt.redelivery_count(), 0);
+        assert!(first_receipt.publish_system_time() <= std::time::SystemTime::now());
         let first_received = Instant::now();
         let second_receipt = timeout(Duration::from_secs(2), consumer.next())
             .await
@@ -488,11 +507,585 @@ mod tests {
         assert!(redelivery < Duration::from_secs(1));
     }
 
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn start_timestamp() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_start_timestamp_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        pulsar
+            .send(&topic, String::from("before"))
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pulsar
+            .send(&topic, String::from("after"))
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(topic)
+            .with_initial_position(InitialPosition::Earliest)
+            .start_at_timestamp(start_time)
+            .build()
+            .await
+            .unwrap();
+
+        let message = timeout(Duration::from_secs(2), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.deserialize().unwrap(), "after");
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn delayed_delivery() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_delayed_delivery_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+
+        // delayed delivery is only supported by shared subscriptions
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_subscription_type(SubType::Shared)
+            .build()
+            .await
+            .unwrap();
+
+        let delay = Duration::from_secs(3);
+        let message = producer::Message {
+            payload: b"delayed".to_vec(),
+            ..Default::default()
+        }
+        .with_delay(delay);
+        let sent = Instant::now();
+        pulsar.send(&topic, message).await.unwrap().await.unwrap();
+
+        assert!(timeout(Duration::from_secs(1), consumer.next())
+            .await
+            .is_err());
+
+        let message = timeout(Duration::from_secs(10), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(sent.elapsed() >= delay - Duration::from_millis(100));
+        assert_eq!(message.deserialize().unwrap(), "delayed");
+        consumer.ack(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn avro_schema_version() {
+        use crate::schema::{Avro, SchemaDefinition};
+
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_avro_schema_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+
+        let definition = SchemaDefinition::Avro(
+            r#"{"type":"record","name":"Counter","fields":[{"name":"count","type":"int"}]}"#
+                .to_string(),
+        );
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_options(ProducerOptions {
+                schema: Some(definition.into_schema("Counter")),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        let schema_version = producer.schema_version().map(|v| v.to_vec());
+        assert!(schema_version.is_some());
+
+        let mut consumer: Consumer<Avro, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_options(ConsumerOptions {
+                initial_position: InitialPosition::Earliest,
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        // the Avro encoding of {"count": 21}
+        producer.send(Avro(vec![42])).await.unwrap().await.unwrap();
+
+        let message = timeout(Duration::from_secs(10), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.schema_version().map(|v| v.to_vec()), schema_version);
+        assert_eq!(message.deserialize(), Avro(vec![42]));
+        consumer.ack(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn chunked_message() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_chunked_message_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+
+        let mut consumer: Consumer<Vec<u8>, _> =
+            pulsar.consumer().with_topic(&topic).build().await.unwrap();
+
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .enable_chunking(true)
+            .build()
+            .await
+            .unwrap();
+
+        // larger than the default maximum message size of the broker
+        let payload: Vec<u8> = (0..6 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        producer.send(payload.clone()).await.unwrap().await.unwrap();
+        producer.send("small").await.unwrap().await.unwrap();
+
+        let message = timeout(Duration::from_secs(10), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.deserialize(), payload);
+        consumer.ack(&message).await.unwrap();
+
+        let message = timeout(Duration::from_secs(10), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.deserialize(), b"small".to_vec());
+        consumer.ack(&message).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn receiver_queue_refill() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_receiver_queue_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_receiver_queue_size(4)
+            .with_receiver_queue_refill_threshold(1)
+            .build()
+            .await
+            .unwrap();
+
+        let count = 20;
+        for i in 0..count {
+            pulsar
+                .send(&topic, i.to_string())
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+
+        // many more messages than the receiver queue size, so the consumer
+        // must keep asking for permits
+        for i in 0..count {
+            let message = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.deserialize().unwrap(), i.to_string());
+            consumer.ack(&message).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn producer_with_partitions() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_with_partitions_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+
+        let mut producer = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_partitions(3)
+            .build()
+            .await
+            .unwrap();
+
+        let partitions = producer.partitions().unwrap();
+        assert_eq!(partitions.len(), 3);
+        assert!(partitions[2].ends_with(&format!("{}-partition-2", topic)));
+        producer.send("hello").await.unwrap().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn last_message_id() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_last_message_id_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(&topic).build().await.unwrap();
+
+        let empty = consumer.get_last_message_id().await.unwrap();
+        assert_eq!(empty.ledger_id as i64, -1);
+        assert_eq!(empty.entry_id as i64, -1);
+
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+        let receipt = producer.send("hello").await.unwrap().await.unwrap();
+        let sent = receipt.message_id();
+
+        let last = consumer.get_last_message_id().await.unwrap();
+        assert_eq!(last.ledger_id, sent.ledger_id);
+        assert_eq!(last.entry_id, sent.entry_id);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn consumer_stats() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_consumer_stats_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_consumer_name("stats")
+            .build()
+            .await
+            .unwrap();
+
+        let stats = consumer.stats().await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].topic.ends_with(&topic));
+        assert_eq!(stats[0].consumer_name.as_deref(), Some("stats"));
+        assert_eq!(stats[0].msg_backlog, Some(0));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn producer_busy() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_producer_busy_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let unnamed = pulsar.producer().with_topic(&topic).build().await.unwrap();
+        assert!(!unnamed.name().is_empty());
+
+        let named = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_name("busy")
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(named.name(), "busy");
+
+        let res = pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_name("busy")
+            .build()
+            .await;
+        assert!(matches!(
+            res,
+            Err(PulsarError::Producer(error::ProducerError::ProducerBusy(_)))
+        ));
+
+        // the name can be used again once the producer is closed
+        named.close().await.unwrap();
+        pulsar
+            .producer()
+            .with_topic(&topic)
+            .with_name("busy")
+            .build()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_with_properties() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_send_with_properties_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(&topic).build().await.unwrap();
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+
+        let mut message = producer::Message {
+            payload: b"hello".to_vec(),
+            ..Default::default()
+        };
+        message
+            .properties
+            .insert("trace-id".to_string(), "serialized".to_string());
+        message
+            .properties
+            .insert("origin".to_string(), "test".to_string());
+        producer
+            .send_with_properties(message, vec![("trace-id", "explicit")])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let message = timeout(Duration::from_secs(10), consumer.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let properties: BTreeSet<(String, String)> = message
+            .metadata()
+            .properties
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect();
+        let expected: BTreeSet<(String, String)> = vec![
+            ("origin".to_string(), "test".to_string()),
+            ("trace-id".to_string(), "explicit".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(properties, expected);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_as_batch() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_send_as_batch_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(&topic).build().await.unwrap();
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+
+        let receipts = producer.send_as_batch(vec!["a", "b", "c"]).await.unwrap();
+        assert_eq!(receipts.len(), 3);
+        for (index, receipt) in receipts.iter().enumerate() {
+            assert_eq!(receipt.batch_index(), Some(index as i32));
+            assert_eq!(receipt.entry_id(), receipts[0].entry_id());
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let message = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(message.deserialize().unwrap());
+        }
+        assert_eq!(received, vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_all() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        struct Item(Option<&'static str>);
+        impl SerializeMessage for Item {
+            fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
+                match input.0 {
+                    Some(data) => SerializeMessage::serialize_message(data),
+                    None => Err(PulsarError::Custom("cannot serialize".to_string())),
+                }
+            }
+        }
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_send_all_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut consumer: Consumer<String, _> =
+            pulsar.consumer().with_topic(&topic).build().await.unwrap();
+        let mut producer = pulsar.producer().with_topic(&topic).build().await.unwrap();
+
+        // nothing is sent if one of the messages cannot be serialized
+        assert!(producer
+            .send_all(vec![Item(Some("skipped")), Item(None)])
+            .await
+            .is_err());
+
+        let receipts = producer
+            .send_all(vec![Item(Some("a")), Item(Some("b"))])
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 2);
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let message = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(message.deserialize().unwrap());
+        }
+        assert_eq!(received, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn multi_topic_producer_ordering() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_ordering_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar.producer().build_multi_topic();
+
+        let mut sends = Vec::new();
+        for i in 0..50u32 {
+            let message = producer::Message {
+                payload: i.to_string().into_bytes(),
+                partition_key: Some("key".to_string()),
+                ..Default::default()
+            };
+            sends.push(producer.send(&topic, message).await.unwrap());
+        }
+        let receipts = try_join_all(sends).await.unwrap();
+        let entries: Vec<_> = receipts.iter().map(|r| r.entry_id()).collect();
+        let mut sorted = entries.clone();
+        sorted.sort_unstable();
+        assert_eq!(entries, sorted);
+
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_options(ConsumerOptions {
+                initial_position: InitialPosition::Earliest,
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        for i in 0..50u32 {
+            let message = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.deserialize().unwrap(), i.to_string());
+            consumer.ack(&message).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn multi_topic_producer_close() {
+        let _ = log::set_logger(&TEST_LOGGER);
+        log::set_max_level(LevelFilter::Debug);
+
+        let addr = "pulsar://127.0.0.1:6650";
+        let topic = format!("test_close_{}", rand::random::<u16>());
+
+        let pulsar: Pulsar<_> = Pulsar::builder(addr, TokioExecutor).build().await.unwrap();
+        let mut producer = pulsar.producer().with_batch_size(10).build_multi_topic();
+
+        let mut sends = Vec::new();
+        for i in 0..3 {
+            sends.push(producer.send(&topic, i.to_string()).await.unwrap());
+        }
+        producer.close().await.unwrap();
+        timeout(Duration::from_secs(1), try_join_all(sends))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut consumer: Consumer<String, _> = pulsar
+            .consumer()
+            .with_topic(&topic)
+            .with_options(ConsumerOptions {
+                initial_position: InitialPosition::Earliest,
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        for i in 0..3 {
+            let message = timeout(Duration::from_secs(10), consumer.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.deserialize().unwrap(), i.to_string());
+        }
+    }
+
     #[tokio::test]
     #[cfg(feature = "tokio-runtime")]
     async fn batching() {
         let _ = log::set_logger(&TEST_LOGGER);
-        let _ = log::set_max_level(LevelFilter::Debug);
+        log::set_max_level(LevelFilter::Debug);
 
         let addr = "pulsar://127.0.0.1:6650";
         let topic = format!("test_batching_{}", rand::random::<u16>());
@@ -6,6 +6,8 @@ use nom::number::streaming::{be_u16, be_u32};
 use prost::{self, Message as ImplProtobuf};
 use std::convert::TryFrom;
 use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 const CRC_CASTAGNOLI: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
 
@@ -67,10 +69,6 @@ impl Message {
                 seek: Some(CommandSeek { request_id, .. }),
                 ..
             }
-            | BaseCommand {
-                close_producer: Some(CommandCloseProducer { request_id, .. }),
-                ..
-            }
             | BaseCommand {
                 success: Some(CommandSuccess { request_id, .. }),
                 ..
@@ -112,6 +110,47 @@ impl Message {
             | BaseCommand {
                 get_schema_response: Some(CommandGetSchemaResponse { request_id, .. }),
                 ..
+            }
+            | BaseCommand {
+                get_or_create_schema_response:
+                    Some(CommandGetOrCreateSchemaResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                new_txn_response: Some(CommandNewTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_partition_to_txn_response:
+                    Some(CommandAddPartitionToTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                add_subscription_to_txn_response:
+                    Some(CommandAddSubscriptionToTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                end_txn_response: Some(CommandEndTxnResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                end_txn_on_partition_response:
+                    Some(CommandEndTxnOnPartitionResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                end_txn_on_subscription_response:
+                    Some(CommandEndTxnOnSubscriptionResponse { request_id, .. }),
+                ..
+            }
+            | BaseCommand {
+                ack_response:
+                    Some(CommandAckResponse {
+                        request_id: Some(request_id),
+                        ..
+                    }),
+                ..
             } => Some(RequestKey::RequestId(*request_id)),
             BaseCommand {
                 send:
@@ -181,6 +220,17 @@ impl Message {
                 consumer_id: *consumer_id,
                 request_id: *request_id,
             }),
+            BaseCommand {
+                close_producer:
+                    Some(CommandCloseProducer {
+                        producer_id,
+                        request_id,
+                    }),
+                ..
+            } => Some(RequestKey::CloseProducer {
+                producer_id: *producer_id,
+                request_id: *request_id,
+            }),
             BaseCommand {
                 connect: Some(_), ..
             }
@@ -211,7 +261,33 @@ impl Message {
 }
 
 /// tokio and async-std codec for Pulsar messages
-pub struct Codec;
+///
+/// frames announcing a size above the maximum frame size are rejected with
+/// [ConnectionError::FrameTooLarge], instead of being buffered
+pub struct Codec {
+    max_frame_size: Arc<AtomicUsize>,
+}
+
+impl Codec {
+    /// creates a codec rejecting the frames larger than `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        Codec {
+            max_frame_size: Arc::new(AtomicUsize::new(max_frame_size)),
+        }
+    }
+
+    /// returns a handle on the maximum frame size, shared with the codec, to
+    /// update it once the codec is moved in a stream
+    pub(crate) fn frame_limit_handle(&self) -> Arc<AtomicUsize> {
+        self.max_frame_size.clone()
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new(crate::connection::DEFAULT_MAX_FRAME_SIZE)
+    }
+}
 
 #[cfg(feature = "tokio-runtime")]
 impl tokio_util::codec::Encoder<Message> for Codec {
@@ -272,6 +348,13 @@ impl tokio_util::codec::Decoder for Codec {
             let mut buf = Cursor::new(src);
             // `messageSize` refers only to _remaining_ message size, so we add 4 to get total frame size
             let message_size = buf.get_u32() as usize + 4;
+            let max_frame_size = self.max_frame_size.load(Ordering::Relaxed);
+            if message_size > max_frame_size {
+                return Err(ConnectionError::FrameTooLarge {
+                    size: message_size,
+                    max: max_frame_size,
+                });
+            }
             let src = buf.into_inner();
             if src.len() >= message_size {
                 let msg = {
@@ -292,13 +375,18 @@ impl tokio_util::codec::Decoder for Codec {
                             ))
                         })?;
 
-                        // TODO: Check crc32 of payload data
-
-                        let metadata = Metadata::decode(payload_frame.metadata)?;
-                        Some(Payload {
-                            metadata,
-                            data: buf.to_vec(),
-                        })
+                        // a corrupted payload is dropped and the connection is
+                        // kept, the consumer asks for its redelivery
+                        match verify_checksum(&payload_frame, buf) {
+                            Ok(()) => Some(Payload {
+                                metadata: Metadata::decode(payload_frame.metadata)?,
+                                data: buf.to_vec(),
+                            }),
+                            Err(e) => {
+                                error!("discarding the payload of a message: {}", e);
+                                None
+                            }
+                        }
                     } else {
                         None
                     };
@@ -376,6 +464,13 @@ impl asynchronous_codec::Decoder for Codec {
             let mut buf = Cursor::new(src);
             // `messageSize` refers only to _remaining_ message size, so we add 4 to get total frame size
             let message_size = buf.get_u32() as usize + 4;
+            let max_frame_size = self.max_frame_size.load(Ordering::Relaxed);
+            if message_size > max_frame_size {
+                return Err(ConnectionError::FrameTooLarge {
+                    size: message_size,
+                    max: max_frame_size,
+                });
+            }
             let src = buf.into_inner();
             if src.len() >= message_size {
                 let msg = {
@@ -396,13 +491,18 @@ impl asynchronous_codec::Decoder for Codec {
                             ))
                         })?;
 
-                        // TODO: Check crc32 of payload data
-
-                        let metadata = Metadata::decode(payload_frame.metadata)?;
-                        Some(Payload {
-                            metadata,
-                            data: buf.to_vec(),
-                        })
+                        // a corrupted payload is dropped and the connection is
+                        // kept, the consumer asks for its redelivery
+                        match verify_checksum(&payload_frame, buf) {
+                            Ok(()) => Some(Payload {
+                                metadata: Metadata::decode(payload_frame.metadata)?,
+                                data: buf.to_vec(),
+                            }),
+                            Err(e) => {
+                                error!("discarding the payload of a message: {}", e);
+                                None
+                            }
+                        }
                     } else {
                         None
                     };
@@ -429,6 +529,17 @@ pub struct Payload {
     pub data: Vec<u8>,
 }
 
+impl Payload {
+    /// value of a property set by the producer
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .properties
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str())
+    }
+}
+
 struct CommandFrame<'a> {
     #[allow(dead_code)]
     total_size: u32,
@@ -453,11 +564,8 @@ named!(command_frame<CommandFrame>,
 );
 
 struct PayloadFrame<'a> {
-    #[allow(dead_code)]
     magic_number: u16,
-    #[allow(dead_code)]
     checksum: u32,
-    #[allow(dead_code)]
     metadata_size: u32,
     metadata: &'a [u8],
 }
@@ -479,6 +587,30 @@ named!(payload_frame<PayloadFrame>,
     )
 );
 
+/// magic number of payload frames with a CRC32C checksum
+const MAGIC_CRC32C: u16 = 0x0e01;
+
+/// checks the CRC32C checksum of a payload frame, that covers the metadata
+/// size, the metadata and the payload
+fn verify_checksum(frame: &PayloadFrame, payload: &[u8]) -> Result<(), ConnectionError> {
+    if frame.magic_number != MAGIC_CRC32C {
+        return Ok(());
+    }
+
+    let mut digest = CRC_CASTAGNOLI.digest();
+    digest.update(&frame.metadata_size.to_be_bytes());
+    digest.update(frame.metadata);
+    digest.update(payload);
+    let actual = digest.finalize();
+    if actual != frame.checksum {
+        return Err(ConnectionError::ChecksumMismatch {
+            expected: frame.checksum,
+            actual,
+        });
+    }
+    Ok(())
+}
+
 pub(crate) struct BatchedMessage {
     pub metadata: proto::SingleMessageMetadata,
     pub payload: Vec<u8>,
@@ -513,6 +645,11 @@ pub(crate) fn parse_batched_message(
 }
 
 impl BatchedMessage {
+    /// number of bytes written by [BatchedMessage::serialize]
+    pub(crate) fn serialized_size(&self) -> usize {
+        4 + self.metadata.encoded_len() + self.payload.len()
+    }
+
     pub(crate) fn serialize(&self, w: &mut Vec<u8>) {
         w.put_u32(self.metadata.encoded_len() as u32);
         let _ = self.metadata.encode(w);
@@ -521,6 +658,7 @@ impl BatchedMessage {
 }
 
 #[rustfmt::skip]
+#[allow(clippy::all)]
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/pulsar.proto.rs"));
 
@@ -597,6 +735,7 @@ impl From<prost::DecodeError> for ConnectionError {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::ConnectionError;
     use crate::message::Codec;
     use bytes::BytesMut;
     use std::convert::TryFrom;
@@ -610,17 +749,17 @@ mod tests {
             0x6E, 0x67, 0x20, 0x0C, 0x2A, 0x04, 0x6E, 0x6F, 0x6E, 0x65,
         ];
 
-        let message = Codec.decode(&mut input.into()).unwrap().unwrap();
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
 
         {
             let connect = message.command.connect.as_ref().unwrap();
-            assert_eq!(connect.client_version, "2.0.1-incubating");
+            assert_eq!(connect.client_version, crate::connection::CLIENT_VERSION);
             assert_eq!(connect.auth_method_name.as_ref().unwrap(), "none");
             assert_eq!(connect.protocol_version.as_ref().unwrap(), &12);
         }
 
         let mut output = BytesMut::with_capacity(38);
-        Codec.encode(message, &mut output).unwrap();
+        Codec::default().encode(message, &mut output).unwrap();
         assert_eq!(&output, input);
     }
 
@@ -634,7 +773,7 @@ mod tests {
             0x2D, 0x70, 0x75, 0x6C, 0x73, 0x61, 0x72, 0x2D, 0x38,
         ];
 
-        let message = Codec.decode(&mut input.into()).unwrap().unwrap();
+        let message = Codec::default().decode(&mut input.into()).unwrap().unwrap();
         {
             let send = message.command.send.as_ref().unwrap();
             assert_eq!(send.producer_id, 0);
@@ -649,10 +788,56 @@ mod tests {
         }
 
         let mut output = BytesMut::with_capacity(65);
-        Codec.encode(message, &mut output).unwrap();
+        Codec::default().encode(message, &mut output).unwrap();
         assert_eq!(&output, input);
     }
 
+    #[test]
+    fn checksum_mismatch() {
+        let mut input: Vec<u8> = vec![
+            0x00, 0x00, 0x00, 0x3D, 0x00, 0x00, 0x00, 0x08, 0x08, 0x06, 0x32, 0x04, 0x08, 0x00,
+            0x10, 0x08, 0x0E, 0x01, 0x42, 0x83, 0x54, 0xB5, 0x00, 0x00, 0x00, 0x19, 0x0A, 0x0E,
+            0x73, 0x74, 0x61, 0x6E, 0x64, 0x61, 0x6C, 0x6F, 0x6E, 0x65, 0x2D, 0x30, 0x2D, 0x33,
+            0x10, 0x08, 0x18, 0xBE, 0xC0, 0xFC, 0x84, 0xD2, 0x2C, 0x68, 0x65, 0x6C, 0x6C, 0x6F,
+            0x2D, 0x70, 0x75, 0x6C, 0x73, 0x61, 0x72, 0x2D, 0x38,
+        ];
+        // corrupt the payload
+        let last = input.len() - 1;
+        input[last] = 0x39;
+
+        // the command is kept, without its payload
+        let mut src: BytesMut = input[..].into();
+        let message = Codec::default().decode(&mut src).unwrap().unwrap();
+        assert!(message.command.send.is_some());
+        assert!(message.payload.is_none());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn frame_too_large() {
+        // a garbled size prefix announcing a 2GB frame
+        let input: &[u8] = &[0x7F, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x1E, 0x08, 0x02];
+        match Codec::default().decode(&mut input.into()) {
+            Err(ConnectionError::FrameTooLarge { size, max }) => {
+                assert_eq!(size, 0x7FFFFFFF + 4);
+                assert_eq!(max, crate::connection::DEFAULT_MAX_FRAME_SIZE);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        // the limit is configurable
+        let input: &[u8] = &[
+            0x00, 0x00, 0x00, 0x22, 0x00, 0x00, 0x00, 0x1E, 0x08, 0x02, 0x12, 0x1A, 0x0A, 0x10,
+            0x32, 0x2E, 0x30, 0x2E, 0x31, 0x2D, 0x69, 0x6E, 0x63, 0x75, 0x62, 0x61, 0x74, 0x69,
+            0x6E, 0x67, 0x20, 0x0C, 0x2A, 0x04, 0x6E, 0x6F, 0x6E, 0x65,
+        ];
+        assert!(matches!(
+            Codec::new(16).decode(&mut input.into()),
+            Err(ConnectionError::FrameTooLarge { size: 38, max: 16 })
+        ));
+        assert!(Codec::new(38).decode(&mut input.into()).unwrap().is_some());
+    }
+
     #[test]
     fn base_command_type_parsing() {
         use super::proto::base_command::Type;
@@ -0,0 +1,756 @@
+//! Mock Pulsar broker, to test producers and consumers without a cluster
+//!
+//! the mock broker listens on a local port and speaks the Pulsar binary
+//! protocol, so a regular [Pulsar](crate::Pulsar) client can connect to it.
+//! It records the commands it receives, and answers them with successful
+//! responses, unless a handler returns other responses
+//!
+//! ```rust,no_run
+//! use pulsar::mock::MockBroker;
+//! use pulsar::message::proto::base_command::Type as CommandType;
+//! use pulsar::{Pulsar, TokioExecutor};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let broker = MockBroker::start().await?;
+//! let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor).build().await?;
+//! let mut producer = pulsar.producer().with_topic("test").build().await?;
+//! producer.send("hello").await?.await?;
+//!
+//! assert_eq!(broker.commands_of_type(CommandType::Send).len(), 1);
+//! # Ok(())
+//! # }
+//! ```
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+use crate::message::proto::{self, base_command::Type as CommandType};
+use crate::message::{Codec, Message};
+
+type Handler = dyn Fn(&Message) -> Option<Vec<Message>> + Send + Sync;
+
+/// Pulsar broker answering on a local port, for tests
+pub struct MockBroker {
+    addr: SocketAddr,
+    commands: Arc<Mutex<Vec<Message>>>,
+    // the broker stops accepting connections when it is dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl MockBroker {
+    /// starts a broker answering all commands with successful responses
+    pub async fn start() -> std::io::Result<MockBroker> {
+        MockBroker::start_with_handler(|_| None).await
+    }
+
+    /// starts a broker calling `handler` on each command it receives
+    ///
+    /// the handler returns the responses to send back, or `None` for the
+    /// default response. The default response to a `CommandLookupTopic`
+    /// points to the mock broker, and topics are not partitioned
+    pub async fn start_with_handler<F>(handler: F) -> std::io::Result<MockBroker>
+    where
+        F: Fn(&Message) -> Option<Vec<Message>> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let entry_id = Arc::new(AtomicU64::new(0));
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let recorded = commands.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match select(Box::pin(listener.accept()), &mut shutdown_rx).await {
+                    Either::Left((Ok((stream, _)), _)) => stream,
+                    Either::Left((Err(e), _)) => {
+                        error!("mock broker could not accept a connection: {}", e);
+                        break;
+                    }
+                    Either::Right(_) => break,
+                };
+                tokio::spawn(serve(
+                    stream,
+                    format!("pulsar://{}", addr),
+                    recorded.clone(),
+                    handler.clone(),
+                    entry_id.clone(),
+                ));
+            }
+        });
+
+        Ok(MockBroker {
+            addr,
+            commands,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    /// service URL of the broker, to create a client
+    pub fn url(&self) -> String {
+        format!("pulsar://{}", self.addr)
+    }
+
+    /// all the commands received so far, in order
+    pub fn commands(&self) -> Vec<Message> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// the commands of one type received so far, in order
+    pub fn commands_of_type(&self, command_type: CommandType) -> Vec<Message> {
+        self.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.command.r#type == command_type as i32)
+            .cloned()
+            .collect()
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    url: String,
+    commands: Arc<Mutex<Vec<Message>>>,
+    handler: Arc<Handler>,
+    entry_id: Arc<AtomicU64>,
+) {
+    let mut framed = Framed::new(stream, Codec::default());
+    while let Some(Ok(message)) = framed.next().await {
+        commands.lock().unwrap().push(message.clone());
+        let responses = handler(&message).unwrap_or_else(|| {
+            default_response(&message, &url, &entry_id)
+                .into_iter()
+                .collect()
+        });
+        for response in responses {
+            if framed.send(response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// successful response to a command, if it expects one
+fn default_response(message: &Message, url: &str, entry_id: &AtomicU64) -> Option<Message> {
+    let command = &message.command;
+    let response = match CommandType::from_i32(command.r#type)? {
+        CommandType::Connect => proto::BaseCommand {
+            r#type: CommandType::Connected as i32,
+            connected: Some(proto::CommandConnected {
+                server_version: "mock".to_string(),
+                protocol_version: Some(crate::connection::PROTOCOL_VERSION),
+                max_message_size: None,
+            }),
+            ..Default::default()
+        },
+        CommandType::Ping => proto::BaseCommand {
+            r#type: CommandType::Pong as i32,
+            pong: Some(proto::CommandPong {}),
+            ..Default::default()
+        },
+        CommandType::PartitionedMetadata => proto::BaseCommand {
+            r#type: CommandType::PartitionedMetadataResponse as i32,
+            partition_metadata_response: Some(proto::CommandPartitionedTopicMetadataResponse {
+                partitions: Some(0),
+                request_id: command.partition_metadata.as_ref()?.request_id,
+                response: Some(
+                    proto::command_partitioned_topic_metadata_response::LookupType::Success as i32,
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        CommandType::Lookup => proto::BaseCommand {
+            r#type: CommandType::LookupResponse as i32,
+            lookup_topic_response: Some(proto::CommandLookupTopicResponse {
+                broker_service_url: Some(url.to_string()),
+                response: Some(proto::command_lookup_topic_response::LookupType::Connect as i32),
+                request_id: command.lookup_topic.as_ref()?.request_id,
+                authoritative: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        CommandType::Producer => {
+            let producer = command.producer.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::ProducerSuccess as i32,
+                producer_success: Some(proto::CommandProducerSuccess {
+                    request_id: producer.request_id,
+                    producer_name: producer
+                        .producer_name
+                        .clone()
+                        .unwrap_or_else(|| format!("mock-{}", producer.producer_id)),
+                    last_sequence_id: Some(-1),
+                    schema_version: None,
+                }),
+                ..Default::default()
+            }
+        }
+        CommandType::Send => {
+            let send = command.send.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::SendReceipt as i32,
+                send_receipt: Some(proto::CommandSendReceipt {
+                    producer_id: send.producer_id,
+                    sequence_id: send.sequence_id,
+                    message_id: Some(proto::MessageIdData {
+                        ledger_id: 0,
+                        entry_id: entry_id.fetch_add(1, Ordering::Relaxed),
+                        ..Default::default()
+                    }),
+                    highest_sequence_id: None,
+                }),
+                ..Default::default()
+            }
+        }
+        CommandType::Subscribe => success(command.subscribe.as_ref()?.request_id),
+        CommandType::CloseProducer => success(command.close_producer.as_ref()?.request_id),
+        CommandType::CloseConsumer => success(command.close_consumer.as_ref()?.request_id),
+        CommandType::Unsubscribe => success(command.unsubscribe.as_ref()?.request_id),
+        CommandType::Seek => success(command.seek.as_ref()?.request_id),
+        // topics have no schema
+        CommandType::GetSchema => proto::BaseCommand {
+            r#type: CommandType::GetSchemaResponse as i32,
+            get_schema_response: Some(proto::CommandGetSchemaResponse {
+                request_id: command.get_schema.as_ref()?.request_id,
+                error_code: Some(proto::ServerError::TopicNotFound as i32),
+                error_message: Some("Topic not found or no-schema".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        CommandType::NewTxn => proto::BaseCommand {
+            r#type: CommandType::NewTxnResponse as i32,
+            new_txn_response: Some(proto::CommandNewTxnResponse {
+                request_id: command.new_txn.as_ref()?.request_id,
+                txnid_least_bits: Some(entry_id.fetch_add(1, Ordering::Relaxed)),
+                txnid_most_bits: command.new_txn.as_ref()?.tc_id,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        CommandType::AddPartitionToTxn => {
+            let add = command.add_partition_to_txn.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::AddPartitionToTxnResponse as i32,
+                add_partition_to_txn_response: Some(proto::CommandAddPartitionToTxnResponse {
+                    request_id: add.request_id,
+                    txnid_least_bits: add.txnid_least_bits,
+                    txnid_most_bits: add.txnid_most_bits,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        }
+        CommandType::AddSubscriptionToTxn => {
+            let add = command.add_subscription_to_txn.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::AddSubscriptionToTxnResponse as i32,
+                add_subscription_to_txn_response: Some(
+                    proto::CommandAddSubscriptionToTxnResponse {
+                        request_id: add.request_id,
+                        txnid_least_bits: add.txnid_least_bits,
+                        txnid_most_bits: add.txnid_most_bits,
+                        ..Default::default()
+                    },
+                ),
+                ..Default::default()
+            }
+        }
+        CommandType::EndTxn => {
+            let end = command.end_txn.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::EndTxnResponse as i32,
+                end_txn_response: Some(proto::CommandEndTxnResponse {
+                    request_id: end.request_id,
+                    txnid_least_bits: end.txnid_least_bits,
+                    txnid_most_bits: end.txnid_most_bits,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        }
+        // only transactional acks expect an answer
+        CommandType::Ack => {
+            let ack = command.ack.as_ref()?;
+            proto::BaseCommand {
+                r#type: CommandType::AckResponse as i32,
+                ack_response: Some(proto::CommandAckResponse {
+                    consumer_id: ack.consumer_id,
+                    request_id: Some(ack.request_id?),
+                    txnid_least_bits: ack.txnid_least_bits,
+                    txnid_most_bits: ack.txnid_most_bits,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        }
+        _ => return None,
+    };
+
+    Some(Message {
+        command: response,
+        payload: None,
+    })
+}
+
+fn success(request_id: u64) -> proto::BaseCommand {
+    proto::BaseCommand {
+        r#type: CommandType::Success as i32,
+        success: Some(proto::CommandSuccess {
+            request_id,
+            schema: None,
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::error::{ConnectionError, Error, ProducerError};
+    use crate::{Pulsar, TokioExecutor};
+
+    async fn client(broker: &MockBroker) -> Pulsar<TokioExecutor> {
+        Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn send() {
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar = client(&broker).await;
+        let mut producer = pulsar
+            .producer()
+            .with_topic("mock-send")
+            .with_name("mock-producer")
+            .build()
+            .await
+            .unwrap();
+
+        let receipt = producer.send("hello").await.unwrap().await.unwrap();
+        assert_eq!(receipt.sequence_id(), 0);
+        assert_eq!(receipt.message_id().entry_id, 0);
+        let receipt = producer.send("world").await.unwrap().await.unwrap();
+        assert_eq!(receipt.sequence_id(), 1);
+
+        let producers = broker.commands_of_type(CommandType::Producer);
+        assert_eq!(producers.len(), 1);
+        let command = producers[0].command.producer.as_ref().unwrap();
+        assert_eq!(command.producer_name.as_deref(), Some("mock-producer"));
+
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[1].payload.as_ref().unwrap().data, b"world".to_vec());
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar = client(&broker).await;
+        let mut producer = pulsar
+            .producer()
+            .with_topic("mock-batch")
+            .with_batch_size(2)
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        assert!(broker.commands_of_type(CommandType::Send).is_empty());
+        let second = producer.send("b").await.unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().num_messages,
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn send_timeout() {
+        // the broker never answers the messages
+        let broker = MockBroker::start_with_handler(|message| {
+            if message.command.r#type == CommandType::Send as i32 {
+                Some(Vec::new())
+            } else {
+                None
+            }
+        })
+        .await
+        .unwrap();
+        let pulsar = client(&broker).await;
+        let mut producer = pulsar
+            .producer()
+            .with_topic("mock-timeout")
+            .with_send_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+
+        let res = producer.send("hello").await.unwrap().await;
+        assert!(matches!(res, Err(Error::Producer(ProducerError::Timeout))));
+    }
+
+    #[tokio::test]
+    async fn get_schema() {
+        let broker = MockBroker::start_with_handler(|message| {
+            let get_schema = message.command.get_schema.as_ref()?;
+            if get_schema.topic != "with-schema" {
+                return None;
+            }
+            Some(vec![Message {
+                command: proto::BaseCommand {
+                    r#type: CommandType::GetSchemaResponse as i32,
+                    get_schema_response: Some(proto::CommandGetSchemaResponse {
+                        request_id: get_schema.request_id,
+                        schema: Some(
+                            crate::schema::SchemaDefinition::Json("{}".to_string())
+                                .into_schema("mock"),
+                        ),
+                        schema_version: Some(vec![0, 1]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar = client(&broker).await;
+
+        let registered = pulsar
+            .get_schema("with-schema", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(registered.schema.r#type, proto::schema::Type::Json as i32);
+        assert_eq!(registered.schema.schema_data, b"{}".to_vec());
+        assert_eq!(registered.version, Some(vec![0, 1]));
+
+        assert_eq!(
+            pulsar.get_schema("without-schema", None).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_command() {
+        // a command without support in the client
+        let broker = MockBroker::start_with_handler(|message| {
+            let request = message.command.get_or_create_schema.as_ref()?;
+            let response = if request.topic == "unknown" {
+                proto::BaseCommand {
+                    r#type: CommandType::Error as i32,
+                    error: Some(proto::CommandError {
+                        request_id: request.request_id,
+                        error: proto::ServerError::TopicNotFound as i32,
+                        message: "unknown topic".to_string(),
+                    }),
+                    ..Default::default()
+                }
+            } else {
+                proto::BaseCommand {
+                    r#type: CommandType::GetOrCreateSchemaResponse as i32,
+                    get_or_create_schema_response: Some(proto::CommandGetOrCreateSchemaResponse {
+                        request_id: request.request_id,
+                        schema_version: Some(vec![1]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            };
+            Some(vec![Message {
+                command: response,
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar = client(&broker).await;
+        let address = pulsar.lookup_topic("raw").await.unwrap();
+
+        let command = |topic: &'static str| {
+            move |request_id| proto::BaseCommand {
+                r#type: CommandType::GetOrCreateSchema as i32,
+                get_or_create_schema: Some(proto::CommandGetOrCreateSchema {
+                    request_id,
+                    topic: topic.to_string(),
+                    schema: Default::default(),
+                }),
+                ..Default::default()
+            }
+        };
+        let response = pulsar
+            .send_raw_command(&address, command("raw"))
+            .await
+            .unwrap();
+        let response = response.get_or_create_schema_response.unwrap();
+        assert_eq!(response.schema_version, Some(vec![1]));
+        let sent = broker.commands_of_type(CommandType::GetOrCreateSchema);
+        assert_eq!(
+            sent[0]
+                .command
+                .get_or_create_schema
+                .as_ref()
+                .unwrap()
+                .request_id,
+            response.request_id
+        );
+
+        let res = pulsar.send_raw_command(&address, command("unknown")).await;
+        assert!(matches!(
+            res,
+            Err(Error::Connection(ConnectionError::PulsarError(
+                Some(proto::ServerError::TopicNotFound),
+                _
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn operation_timeout() {
+        // the broker never answers GetSchema
+        let broker = MockBroker::start_with_handler(|message| {
+            message.command.get_schema.as_ref().map(|_| Vec::new())
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_operation_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+
+        let res = pulsar.get_schema("no-answer", None).await;
+        assert!(matches!(
+            res,
+            Err(Error::Connection(ConnectionError::OperationTimeout))
+        ));
+
+        // the connection can still be used
+        assert_eq!(
+            pulsar
+                .lookup_partitioned_topic_number("no-answer")
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn partitioned_topic_metadata() {
+        let broker = MockBroker::start_with_handler(|message: &Message| {
+            let request = message.command.partition_metadata.as_ref()?;
+            if request.topic != "persistent://public/default/partitioned" {
+                return None;
+            }
+            Some(vec![Message {
+                command: proto::BaseCommand {
+                    r#type: CommandType::PartitionedMetadataResponse as i32,
+                    partition_metadata_response: Some(
+                        proto::CommandPartitionedTopicMetadataResponse {
+                            partitions: Some(3),
+                            request_id: request.request_id,
+                            response: Some(
+                                proto::command_partitioned_topic_metadata_response::LookupType::Success
+                                    as i32,
+                            ),
+                            ..Default::default()
+                        },
+                    ),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar = client(&broker).await;
+
+        assert_eq!(
+            pulsar
+                .get_partitioned_topic_metadata("persistent://public/default/partitioned")
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            pulsar
+                .get_partitioned_topic_metadata("persistent://public/default/plain")
+                .await
+                .unwrap(),
+            0
+        );
+        // both requests go through the same connection
+        assert_eq!(broker.commands_of_type(CommandType::Connect).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_producer_options() {
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_producer_options(crate::ProducerOptions {
+                batch_size: Some(2),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        // the producers created by the client batch the messages
+        let first = pulsar.send("batched", "a").await.unwrap();
+        let second = pulsar.send("batched", "b").await.unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().num_messages,
+            Some(2)
+        );
+
+        // like the producers of a multi topic producer
+        let mut producer = pulsar.producer().with_batch_size(2).build_multi_topic();
+        for topic in ["one", "two"] {
+            let first = producer.send(topic, "a").await.unwrap();
+            let second = producer.send(topic, "b").await.unwrap();
+            first.await.unwrap();
+            second.await.unwrap();
+        }
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 3);
+        assert!(sends
+            .iter()
+            .all(|send| send.command.send.as_ref().unwrap().num_messages == Some(2)));
+    }
+
+    #[tokio::test]
+    async fn too_many_redirects() {
+        // two brokers redirecting lookups to each other, through the proxy
+        let broker = MockBroker::start_with_handler(|message| {
+            let request = message.command.lookup_topic.as_ref()?;
+            let target = if request.request_id % 2 == 0 {
+                "a"
+            } else {
+                "b"
+            };
+            Some(vec![Message {
+                command: proto::BaseCommand {
+                    r#type: CommandType::LookupResponse as i32,
+                    lookup_topic_response: Some(proto::CommandLookupTopicResponse {
+                        broker_service_url: Some(format!("pulsar://broker-{}:6650", target)),
+                        response: Some(
+                            proto::command_lookup_topic_response::LookupType::Redirect as i32,
+                        ),
+                        request_id: request.request_id,
+                        proxy_through_service_url: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_max_lookup_redirects(3)
+            .build()
+            .await
+            .unwrap();
+
+        match pulsar.lookup_topic("redirected").await {
+            Err(Error::Connection(ConnectionError::TooManyRedirects { brokers })) => {
+                assert_eq!(brokers.len(), 5);
+                assert!(brokers[1..].iter().all(|b| b.starts_with("broker-")));
+            }
+            res => panic!("expected too many redirects, got {:?}", res),
+        }
+        assert_eq!(broker.commands_of_type(CommandType::Lookup).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn auth_challenge() {
+        // the broker asks for new authentication data after connecting
+        let broker = MockBroker::start_with_handler(|message| {
+            message.command.connect.as_ref()?;
+            let connected = default_response(message, "", &AtomicU64::new(0))?;
+            let challenge = Message {
+                command: proto::BaseCommand {
+                    r#type: CommandType::AuthChallenge as i32,
+                    auth_challenge: Some(proto::CommandAuthChallenge {
+                        server_version: Some("mock".to_string()),
+                        challenge: Some(proto::AuthData {
+                            auth_method_name: Some("token".to_string()),
+                            auth_data: Some(b"refresh".to_vec()),
+                        }),
+                        protocol_version: Some(crate::connection::PROTOCOL_VERSION),
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            };
+            Some(vec![connected, challenge])
+        })
+        .await
+        .unwrap();
+        let _pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_auth(crate::Authentication::token("secret"))
+            .build()
+            .await
+            .unwrap();
+
+        let mut responses = Vec::new();
+        for _ in 0..50 {
+            responses = broker.commands_of_type(CommandType::AuthResponse);
+            if !responses.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let response = responses[0].command.auth_response.as_ref().unwrap();
+        let data = response.response.as_ref().unwrap();
+        assert_eq!(data.auth_method_name.as_deref(), Some("token"));
+        assert_eq!(data.auth_data.as_deref(), Some(&b"secret"[..]));
+        assert_eq!(
+            response.client_version.as_deref(),
+            Some(crate::connection::CLIENT_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn dynamic_auth() {
+        let path = std::env::temp_dir().join(format!("pulsar_token_{}", rand::random::<u32>()));
+        std::fs::write(&path, "from-file\n").unwrap();
+        let broker = MockBroker::start().await.unwrap();
+        let _pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .with_dynamic_auth(crate::authentication::DynamicAuthentication::token_from_file(&path))
+            .build()
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let connects = broker.commands_of_type(CommandType::Connect);
+        let connect = connects[0].command.connect.as_ref().unwrap();
+        assert_eq!(connect.auth_method_name.as_deref(), Some("token"));
+        assert_eq!(connect.auth_data.as_deref(), Some(&b"from-file"[..]));
+    }
+}
@@ -1,16 +1,35 @@
 //! Message publication
-use futures::{channel::oneshot, future::try_join_all, lock::Mutex};
+use futures::{
+    channel::oneshot,
+    future::{join_all, select, try_join_all, Either, FutureExt, Shared},
+    lock::Mutex,
+    ready,
+    stream::FuturesUnordered,
+    Sink, StreamExt,
+};
 use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(any(feature = "lz4", feature = "flate2"))]
 use std::io::Write;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::client::SerializeMessage;
 use crate::connection::{Connection, SerialId};
+use crate::connection_manager::RetryPolicy;
+#[cfg(feature = "encryption")]
+use crate::encryption::{EncryptionConfig, Encryptor};
 use crate::error::{ConnectionError, ProducerError};
 use crate::executor::Executor;
-use crate::message::proto::{self, CommandSendReceipt, CompressionType, EncryptionKeys, Schema};
+use crate::message::proto::{
+    self, CommandSendReceipt, CompressionType, EncryptionKeys, MessageIdData, Schema,
+};
 use crate::message::BatchedMessage;
+use crate::router::{RoundRobinRouter, Router};
+use crate::topic::Topic;
+#[cfg(feature = "transactions")]
+use crate::transaction::Transaction;
 use crate::{Error, Pulsar};
 use futures::task::{Context, Poll};
 use futures::Future;
@@ -18,6 +37,10 @@ use futures::Future;
 type ProducerId = u64;
 type ProducerName = String;
 
+/// zstd compression level used if [ProducerOptions::compression_level] is not set
+#[cfg(feature = "zstd")]
+const DEFAULT_ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
 /// returned by [Producer::send]
 ///
 /// it contains a channel on which we can await to get the message receipt.
@@ -25,19 +48,83 @@ type ProducerName = String;
 /// the server's load, the send receipt could come much later after sending it
 pub struct SendFuture(pub(crate) oneshot::Receiver<Result<CommandSendReceipt, Error>>);
 
-impl Future for SendFuture {
-    type Output = Result<CommandSendReceipt, Error>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+impl SendFuture {
+    fn poll_receipt(&mut self, cx: &mut Context<'_>) -> Poll<Result<CommandSendReceipt, Error>> {
         match Pin::new(&mut self.0).poll(cx) {
             Poll::Ready(Ok(r)) => Poll::Ready(r),
-            Poll::Ready(Err(_)) => Poll::Ready(Err(ProducerError::Custom(
-                "producer unexpectedly disconnected".into(),
-            )
-            .into())),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(ProducerError::EngineShutdown.into())),
             Poll::Pending => Poll::Pending,
         }
     }
+
+    /// waits for the raw receipt, to forward it to another channel
+    pub(crate) async fn receipt(mut self) -> Result<CommandSendReceipt, Error> {
+        futures::future::poll_fn(|cx| self.poll_receipt(cx)).await
+    }
+}
+
+impl Future for SendFuture {
+    type Output = Result<SendReceipt, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_receipt(cx).map(|r| r.map(SendReceipt::from))
+    }
+}
+
+/// receipt of a message acknowledged by the broker, returned by [SendFuture]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendReceipt {
+    producer_id: u64,
+    sequence_id: u64,
+    message_id: MessageIdData,
+}
+
+impl SendReceipt {
+    /// id of the producer that sent the message
+    pub fn producer_id(&self) -> u64 {
+        self.producer_id
+    }
+
+    /// sequence id of the message
+    pub fn sequence_id(&self) -> u64 {
+        self.sequence_id
+    }
+
+    /// id of the published message, that consumers will see
+    pub fn message_id(&self) -> &MessageIdData {
+        &self.message_id
+    }
+
+    /// ledger where the message was stored
+    pub fn ledger_id(&self) -> u64 {
+        self.message_id.ledger_id
+    }
+
+    /// position of the message in its ledger
+    pub fn entry_id(&self) -> u64 {
+        self.message_id.entry_id
+    }
+
+    /// partition of the topic on which the message was published,
+    /// or `None` for non partitioned topics
+    pub fn partition(&self) -> Option<i32> {
+        self.message_id.partition.filter(|p| *p >= 0)
+    }
+
+    /// position of the message in its batch, or `None` if it was not batched
+    pub fn batch_index(&self) -> Option<i32> {
+        self.message_id.batch_index.filter(|i| *i >= 0)
+    }
+}
+
+impl From<CommandSendReceipt> for SendReceipt {
+    fn from(receipt: CommandSendReceipt) -> Self {
+        SendReceipt {
+            producer_id: receipt.producer_id,
+            sequence_id: receipt.sequence_id,
+            message_id: receipt.message_id.unwrap_or_default(),
+        }
+    }
 }
 
 /// message data that will be sent on a topic
@@ -54,6 +141,9 @@ pub struct Message {
     pub properties: HashMap<String, String>,
     /// key to decide partition for the message
     pub partition_key: ::std::option::Option<String>,
+    /// key used by key shared subscriptions to keep messages in order,
+    /// instead of the partition key
+    pub ordering_key: ::std::option::Option<Vec<u8>>,
     /// Override namespace's replication
     pub replicate_to: ::std::vec::Vec<String>,
     /// the timestamp that this event occurs. it is typically set by applications.
@@ -61,6 +151,34 @@ pub struct Message {
     pub event_time: ::std::option::Option<u64>,
     /// current version of the schema
     pub schema_version: ::std::option::Option<Vec<u8>>,
+    /// UTC Unix timestamp in milliseconds, time at which the message should be
+    /// delivered to consumers
+    pub deliver_at_time: ::std::option::Option<u64>,
+}
+
+impl Message {
+    /// delays the delivery of the message to consumers by `delay` from now
+    ///
+    /// delayed delivery only works with shared subscriptions
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.deliver_at_time = to_millis(SystemTime::now() + delay);
+        self
+    }
+
+    /// sets the time at which the event described by the message happened
+    ///
+    /// consumers read it with [consumer::Message::event_time](crate::consumer::Message::event_time)
+    pub fn with_event_time(mut self, event_time: SystemTime) -> Self {
+        self.event_time = to_millis(event_time);
+        self
+    }
+}
+
+/// UTC Unix timestamp in milliseconds
+fn to_millis(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_millis() as u64)
 }
 
 /// internal message type carrying options that must be defined
@@ -71,8 +189,12 @@ pub(crate) struct ProducerMessage {
     pub properties: HashMap<String, String>,
     ///key to decide partition for the msg
     pub partition_key: ::std::option::Option<String>,
+    pub ordering_key: ::std::option::Option<Vec<u8>>,
     /// Override namespace's replication
     pub replicate_to: ::std::vec::Vec<String>,
+    /// transaction in which the message is sent
+    pub txnid_least_bits: ::std::option::Option<u64>,
+    pub txnid_most_bits: ::std::option::Option<u64>,
     pub compression: ::std::option::Option<i32>,
     pub uncompressed_size: ::std::option::Option<u32>,
     /// Removed below checksum field from Metadata as
@@ -88,6 +210,16 @@ pub(crate) struct ProducerMessage {
     /// Additional parameters required by encryption
     pub encryption_param: ::std::option::Option<Vec<u8>>,
     pub schema_version: ::std::option::Option<Vec<u8>>,
+    pub deliver_at_time: ::std::option::Option<u64>,
+    /// sequence id set by the user, instead of the producer's internal counter
+    pub sequence_id: ::std::option::Option<u64>,
+    /// identifier shared by all the chunks of a message
+    pub uuid: ::std::option::Option<String>,
+    /// index of this chunk
+    pub chunk_id: ::std::option::Option<i32>,
+    pub num_chunks_from_msg: ::std::option::Option<i32>,
+    /// size of the whole payload, over all the chunks
+    pub total_chunk_msg_size: ::std::option::Option<i32>,
 }
 
 impl From<Message> for ProducerMessage {
@@ -96,27 +228,186 @@ impl From<Message> for ProducerMessage {
             payload: m.payload,
             properties: m.properties,
             partition_key: m.partition_key,
+            ordering_key: m.ordering_key,
             replicate_to: m.replicate_to,
             event_time: m.event_time,
             schema_version: m.schema_version,
+            deliver_at_time: m.deliver_at_time,
             ..Default::default()
         }
     }
 }
 
+/// counters describing a producer's activity, returned by [Producer::metrics]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProducerMetrics {
+    /// messages acknowledged by the broker
+    pub messages_sent: u64,
+    /// uncompressed payload bytes acknowledged by the broker
+    pub bytes_sent: u64,
+    /// messages that could not be sent. A message reaching the send timeout
+    /// is counted here, even if its receipt comes later
+    pub send_errors: u64,
+    /// messages sent or batched that are still waiting for their receipt
+    pub in_flight: u64,
+}
+
+impl std::ops::Add for ProducerMetrics {
+    type Output = ProducerMetrics;
+
+    fn add(self, other: ProducerMetrics) -> ProducerMetrics {
+        ProducerMetrics {
+            messages_sent: self.messages_sent + other.messages_sent,
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            send_errors: self.send_errors + other.send_errors,
+            in_flight: self.in_flight + other.in_flight,
+        }
+    }
+}
+
+impl std::iter::Sum for ProducerMetrics {
+    fn sum<I: Iterator<Item = ProducerMetrics>>(iter: I) -> ProducerMetrics {
+        iter.fold(ProducerMetrics::default(), |acc, m| acc + m)
+    }
+}
+
+/// atomic counters updated by a topic producer, so reading them never
+/// blocks the send path
+#[derive(Debug, Default)]
+struct ProducerCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    in_flight: AtomicU64,
+    /// notified when pending messages get their receipt
+    released: std::sync::Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl ProducerCounters {
+    fn snapshot(&self) -> ProducerMetrics {
+        ProducerMetrics {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    fn sent(&self, messages: u64, bytes: u64) {
+        self.messages_sent.fetch_add(messages, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn failed(&self, messages: u64) {
+        self.send_errors.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    fn start(&self, messages: u64) {
+        self.in_flight.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    fn finish(&self, messages: u64) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(messages))
+            });
+        for tx in self.released.lock().unwrap().drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    /// resolves the next time pending messages get their receipt
+    fn released(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut released = self.released.lock().unwrap();
+        released.retain(|tx| !tx.is_canceled());
+        released.push(tx);
+        rx
+    }
+}
+
+/// marks a non batched message as in flight until it is dropped, so the
+/// counter stays correct if the send is cancelled by the send timeout
+struct InFlight(Arc<ProducerCounters>);
+
+impl InFlight {
+    fn new(counters: &Arc<ProducerCounters>) -> Self {
+        counters.start(1);
+        InFlight(counters.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.finish(1);
+    }
+}
+
 /// Configuration options for producers
+///
+/// # Batching and flow control
+///
+/// with `batch_size`, messages are accumulated in a batch, which is sent
+/// once it holds `batch_size` messages, once adding a message would make it
+/// larger than `batch_max_bytes`, or once `batch_timeout` is reached.
+///
+/// a batched message counts as pending from the moment it is added to the
+/// batch until its receipt arrives, like a message sent on its own. When
+/// `max_pending_messages` is reached, `send` waits instead of adding more
+/// messages to the batch: it sends the current batch if it is not empty,
+/// then waits for the receipts of the batches in flight. A batch never holds
+/// more than `max_pending_messages` messages, so `max_pending_messages`
+/// should be at least `batch_size` to send full batches
 #[derive(Clone, Default)]
 pub struct ProducerOptions {
-    /// end to end message encryption (not implemented yet)
+    /// tells the broker that the messages are encrypted. It is set by
+    /// `ProducerBuilder::with_encryption`
     pub encrypted: Option<bool>,
+    /// public keys used to encrypt the messages
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<EncryptionConfig>,
     /// user defined properties added to all messages
     pub metadata: BTreeMap<String, String>,
     /// schema used to encode this producer's messages
     pub schema: Option<Schema>,
     /// batch message size
     pub batch_size: Option<u32>,
+    /// maximum size in bytes of a batch, including the metadata of each
+    /// message, before compression. Only used if the batch size is set
+    pub batch_max_bytes: Option<usize>,
+    /// maximum number of messages waiting for their receipt, including the
+    /// messages of the current batch. Sending more messages waits until
+    /// receipts arrive
+    pub max_pending_messages: Option<u64>,
+    /// maximum time a message can wait in the current batch before it is sent,
+    /// even if the batch is not full
+    pub batch_timeout: Option<Duration>,
     /// algorithm used to compress the messages
     pub compression: Option<proto::CompressionType>,
+    /// zstd compression level (defaults to 3). Creating a producer with a
+    /// compression level and another compression algorithm fails
+    pub compression_level: Option<i32>,
+    /// maximum time to wait for a message's receipt. If it is reached, the
+    /// receipt future resolves to [ProducerError::Timeout] and a receipt
+    /// coming later is ignored
+    pub send_timeout: Option<Duration>,
+    /// how to reconnect when the connection to the broker is lost. By default,
+    /// the producer retries with the default [RetryPolicy], then tries to
+    /// send the message again
+    pub reconnection: Option<RetryPolicy>,
+    /// maximum number of batched messages kept while the producer is
+    /// disconnected. They wait in the batch until the producer is
+    /// recreated by the next send, and once there are that many, that send
+    /// reconnects the producer and sends them, instead of waiting for the
+    /// batch to be full
+    pub max_reconnection_buffer: Option<u32>,
+    /// selects the partition of each message on partitioned topics
+    /// (defaults to [RoundRobinRouter])
+    pub router: Option<Arc<dyn Router>>,
+    /// splits messages larger than the broker's maximum message size in
+    /// chunks, reassembled by the consumers. It cannot be used with batching
+    pub chunking: bool,
 }
 
 /// Wrapper structure that manges multiple producers at once, creating them as needed
@@ -143,6 +434,8 @@ pub struct MultiTopicProducer<Exe: Executor> {
     producers: BTreeMap<String, Producer<Exe>>,
     options: ProducerOptions,
     name: Option<String>,
+    // metrics of the closed producers, to keep the totals correct
+    closed_metrics: ProducerMetrics,
 }
 
 impl<Exe: Executor> MultiTopicProducer<Exe> {
@@ -156,24 +449,82 @@ impl<Exe: Executor> MultiTopicProducer<Exe> {
         self.producers.keys().cloned().collect()
     }
 
+    /// last connection error of each topic's producer
+    ///
+    /// only the topics whose connection is currently failed are present
+    pub fn errors(&self) -> HashMap<String, ConnectionError> {
+        self.producers
+            .iter()
+            .filter_map(|(topic, p)| p.error().map(|e| (topic.clone(), e)))
+            .collect()
+    }
+
+    /// metrics aggregated over all the topics this producer sent to
+    pub fn metrics(&self) -> ProducerMetrics {
+        self.producers
+            .values()
+            .map(|p| p.metrics())
+            .chain(std::iter::once(self.closed_metrics))
+            .sum()
+    }
+
     /// stops the producer
     pub async fn close_producer<S: Into<String>>(&mut self, topic: S) -> Result<(), Error> {
         let partitions = self.client.lookup_partitioned_topic(topic).await?;
         for (topic, _) in partitions {
-            self.producers.remove(&topic);
+            if let Some(producer) = self.producers.remove(&topic) {
+                let mut metrics = producer.metrics();
+                metrics.in_flight = 0;
+                self.closed_metrics = self.closed_metrics + metrics;
+            }
         }
         Ok(())
     }
 
+    /// sends the pending batched messages of all producers, then closes them
+    ///
+    /// all producers are closed even if one of them fails, and the first error
+    /// is returned. Dropping the producer also closes them, but without
+    /// sending the batched messages
+    pub async fn close(mut self) -> Result<(), Error> {
+        let producers = std::mem::take(&mut self.producers).into_values();
+        let results = join_all(producers.map(|p| p.close())).await;
+        results.into_iter().collect()
+    }
+
+    /// creates the producer of a topic, if it does not exist yet
+    ///
+    /// producers are otherwise created by the first message sent on their
+    /// topic, which then waits for the topic lookup and the producer creation
+    ///
+    /// ```rust,no_run
+    /// # async fn run(mut producer: pulsar::MultiTopicProducer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// producer.prepare("persistent://public/default/events").await?;
+    /// producer.send("persistent://public/default/events", "hello").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn prepare<S: Into<String>>(&mut self, topic: S) -> Result<(), Error> {
+        self.producer(topic.into()).await.map(drop)
+    }
+
     /// sends one message on a topic
+    ///
+    /// messages sent on the same topic are published in the order of the calls
+    /// to this method, so their receipts also come back in that order
     pub async fn send<T: SerializeMessage + Sized, S: Into<String>>(
         &mut self,
         topic: S,
         message: T,
     ) -> Result<SendFuture, Error> {
         let message = T::serialize_message(message)?;
-        let topic = topic.into();
+        self.producer(topic.into()).await?.send(message).await
+    }
+
+    /// returns the producer of a topic, created if needed
+    async fn producer(&mut self, topic: String) -> Result<&mut Producer<Exe>, Error> {
         if !self.producers.contains_key(&topic) {
+            debug!("creating a producer for topic {}", topic);
             let mut builder = self
                 .client
                 .producer()
@@ -186,8 +537,7 @@ impl<Exe: Executor> MultiTopicProducer<Exe> {
             self.producers.insert(topic.clone(), producer);
         }
 
-        let producer = self.producers.get_mut(&topic).unwrap();
-        producer.send(message).await
+        Ok(self.producers.get_mut(&topic).unwrap())
     }
 
     /// sends a list of messages on a topic
@@ -225,7 +575,7 @@ pub struct Producer<Exe: Executor> {
 impl<Exe: Executor> Producer<Exe> {
     /// creates a producer builder from a client instance
     pub fn builder(pulsar: &Pulsar<Exe>) -> ProducerBuilder<Exe> {
-        ProducerBuilder::new(&pulsar)
+        ProducerBuilder::new(pulsar)
     }
 
     /// this producer's topic
@@ -236,6 +586,16 @@ impl<Exe: Executor> Producer<Exe> {
         }
     }
 
+    /// name of this producer, chosen by the broker if it was not set
+    ///
+    /// on partitioned topics, this is the name of the first partition's producer
+    pub fn name(&self) -> &str {
+        match &self.inner {
+            ProducerInner::Single(p) => p.name(),
+            ProducerInner::Partitioned(p) => p.producers[0].name(),
+        }
+    }
+
     /// list of partitions for this producer's topic
     pub fn partitions(&self) -> Option<Vec<String>> {
         match &self.inner {
@@ -254,10 +614,45 @@ impl<Exe: Executor> Producer<Exe> {
         }
     }
 
+    /// version of the schema registered by the broker for this producer
+    ///
+    /// it is set on every message that does not have a schema version
+    pub fn schema_version(&self) -> Option<&[u8]> {
+        match &self.inner {
+            ProducerInner::Single(p) => p.schema_version.as_deref(),
+            ProducerInner::Partitioned(p) => p
+                .producers
+                .first()
+                .and_then(|p| p.schema_version.as_deref()),
+        }
+    }
+
+    /// error of the broker connection, if it failed
+    ///
+    /// on partitioned topics, this is the error of the first failed partition
+    pub fn error(&self) -> Option<ConnectionError> {
+        match &self.inner {
+            ProducerInner::Single(p) => p.connection.last_error(),
+            ProducerInner::Partitioned(p) => {
+                p.producers.iter().find_map(|p| p.connection.last_error())
+            }
+        }
+    }
+
+    /// snapshot of the producer's counters, aggregated over all partitions
+    pub fn metrics(&self) -> ProducerMetrics {
+        match &self.inner {
+            ProducerInner::Single(p) => p.counters.snapshot(),
+            ProducerInner::Partitioned(p) => {
+                p.producers.iter().map(|p| p.counters.snapshot()).sum()
+            }
+        }
+    }
+
     /// creates a message builder
     ///
     /// the created message will ber sent by this producer in [MessageBuilder::send]
-    pub fn create_message(&mut self) -> MessageBuilder<(), Exe> {
+    pub fn create_message(&mut self) -> MessageBuilder<'_, (), Exe> {
         MessageBuilder::new(self)
     }
 
@@ -279,7 +674,7 @@ impl<Exe: Executor> Producer<Exe> {
     /// this function was called, for various reasons:
     /// - the message was sent successfully but Pulsar did not send the receipt yet
     /// - the producer is batching messages, so this function must return immediately,
-    /// and the receipt will come when the batched messages are actually sent
+    ///   and the receipt will come when the batched messages are actually sent
     ///
     /// Usage:
     ///
@@ -298,29 +693,200 @@ impl<Exe: Executor> Producer<Exe> {
     ) -> Result<SendFuture, Error> {
         match &mut self.inner {
             ProducerInner::Single(p) => p.send(message).await,
-            ProducerInner::Partitioned(p) => p.next().send(message).await,
+            ProducerInner::Partitioned(p) => {
+                let message = T::serialize_message(message)?;
+                p.route(&message).send_raw(message.into()).await
+            }
+        }
+    }
+
+    /// sends a message in a transaction
+    ///
+    /// the message is only visible to consumers once the transaction is
+    /// committed. Transactional messages are never batched
+    ///
+    /// ```rust,no_run
+    /// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>, mut producer: pulsar::Producer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let txn = pulsar.new_transaction(std::time::Duration::from_secs(60)).await?;
+    /// producer.send_in_txn("hello", &txn).await?.await?;
+    /// txn.commit().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "transactions")]
+    pub async fn send_in_txn<T: SerializeMessage + Sized>(
+        &mut self,
+        message: T,
+        txn: &Transaction<Exe>,
+    ) -> Result<SendFuture, Error> {
+        let message = T::serialize_message(message)?;
+        let producer = match &mut self.inner {
+            ProducerInner::Single(p) => p,
+            ProducerInner::Partitioned(p) => p.route(&message),
+        };
+        txn.add_partition(producer.topic()).await?;
+
+        let mut message = ProducerMessage::from(message);
+        message.txnid_least_bits = Some(txn.id().least_bits);
+        message.txnid_most_bits = Some(txn.id().most_bits);
+        producer.send_raw(message).await
+    }
+
+    /// sends a message to one partition of a partitioned topic, instead of
+    /// the one chosen by the router
+    ///
+    /// fails if the topic is not partitioned, or if it does not have this
+    /// partition
+    ///
+    /// ```rust,no_run
+    /// # async fn run(mut producer: pulsar::Producer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let receipt = producer.send_to_partition(2, "hello").await?.await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_to_partition<T: SerializeMessage + Sized>(
+        &mut self,
+        partition: u32,
+        message: T,
+    ) -> Result<SendFuture, Error> {
+        match &mut self.inner {
+            ProducerInner::Single(p) => Err(ProducerError::Custom(format!(
+                "cannot send to partition {}: topic {} is not partitioned",
+                partition, p.topic
+            ))
+            .into()),
+            ProducerInner::Partitioned(p) => {
+                let partitions = p.producers.len();
+                let topic = &p.topic;
+                let producer = p.producers.get_mut(partition as usize).ok_or_else(|| {
+                    ProducerError::Custom(format!(
+                        "cannot send to partition {}: topic {} has {} partitions",
+                        partition, topic, partitions
+                    ))
+                })?;
+                let message = T::serialize_message(message)?;
+                producer.send_raw(message.into()).await
+            }
         }
     }
 
-    /// sends a list of messages
-    pub async fn send_all<T, I>(&mut self, messages: I) -> Result<Vec<SendFuture>, Error>
+    /// sends a message with additional properties
+    ///
+    /// the properties are merged with the ones set by
+    /// [SerializeMessage::serialize_message], and replace them when both
+    /// define the same key
+    pub async fn send_with_properties<T, I, K, V>(
+        &mut self,
+        message: T,
+        properties: I,
+    ) -> Result<SendFuture, Error>
+    where
+        T: SerializeMessage + Sized,
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut message = T::serialize_message(message)?;
+        message.properties.extend(
+            properties
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self.send_raw(message.into()).await
+    }
+
+    /// sends the messages in a single batched message, and returns the
+    /// receipt of each one, with its index in the batch
+    ///
+    /// this does not depend on the producer's batching options, but the
+    /// messages waiting in the producer's batch are sent first, to keep the
+    /// order. On a partitioned topic, all the messages go to the partition
+    /// chosen for the first one
+    pub async fn send_as_batch<T, I>(&mut self, messages: I) -> Result<Vec<SendReceipt>, Error>
     where
         T: SerializeMessage,
         I: IntoIterator<Item = T>,
     {
+        let messages = messages
+            .into_iter()
+            .map(|message| T::serialize_message(message).map(ProducerMessage::from))
+            .collect::<Result<Vec<_>, _>>()?;
         let producer = match &mut self.inner {
             ProducerInner::Single(p) => p,
-            ProducerInner::Partitioned(p) => p.next(),
+            ProducerInner::Partitioned(p) => {
+                let (partition_key, ordering_key) = messages
+                    .first()
+                    .map(|message| (message.partition_key.clone(), message.ordering_key.clone()))
+                    .unwrap_or_default();
+                p.route_key(partition_key.as_deref(), ordering_key.as_deref())
+            }
         };
+
+        let sends = producer.send_as_batch(messages).await?;
+        try_join_all(sends).await
+    }
+
+    /// sends a message with an explicit sequence id
+    ///
+    /// when deduplication is activated on the namespace, the broker will drop messages
+    /// with a sequence id lower or equal to the last one it received from this producer,
+    /// so a message can be sent again after a timeout without creating a duplicate.
+    /// Sequence ids must be increasing, so this should not be mixed with [Producer::send].
+    ///
+    /// the send fails with [ProducerError::Duplicate] if the broker dropped the
+    /// message as a duplicate, and with [ProducerError::SequenceIdMismatch] if
+    /// the receipt is for another sequence id
+    ///
+    /// this is not supported by batching producers
+    pub async fn send_with_sequence_id<T: SerializeMessage + Sized>(
+        &mut self,
+        message: T,
+        sequence_id: u64,
+    ) -> Result<SendFuture, Error> {
+        let mut message: ProducerMessage = T::serialize_message(message)?.into();
+        message.sequence_id = Some(sequence_id);
+        self.send_raw(message).await
+    }
+
+    /// sends a list of messages, and waits for all their receipts
+    ///
+    /// all the messages are serialized before the first one is sent, so a
+    /// serialization error does not send anything. With batching, they are
+    /// grouped in batches like messages sent one by one, and the last batch
+    /// is sent without waiting for more messages. If a message cannot be
+    /// sent, the error is [ProducerError::PartialSend], with the messages
+    /// sent before it
+    ///
+    /// ```rust,no_run
+    /// # async fn run(mut producer: pulsar::Producer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// let receipts = producer.send_all(vec!["a", "b", "c"]).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_all<T, I>(&mut self, messages: I) -> Result<Vec<CommandSendReceipt>, Error>
+    where
+        T: SerializeMessage,
+        I: IntoIterator<Item = T>,
+    {
+        let messages = messages
+            .into_iter()
+            .map(T::serialize_message)
+            .collect::<Result<Vec<_>, _>>()?;
+
         let mut sends = Vec::new();
         for message in messages {
-            sends.push(producer.send(message).await);
+            let send = match &mut self.inner {
+                ProducerInner::Single(p) => p.send_raw(message.into()).await,
+                ProducerInner::Partitioned(p) => p.route(&message).send_raw(message.into()).await,
+            };
+            sends.push(send);
         }
-        if sends.iter().all(|s| s.is_ok()) {
-            Ok(sends.into_iter().map(|s| s.unwrap()).collect())
-        } else {
-            Err(ProducerError::PartialSend(sends).into())
+        if !sends.iter().all(|s| s.is_ok()) {
+            return Err(ProducerError::PartialSend(sends).into());
         }
+
+        self.flush().await?;
+        try_join_all(sends.into_iter().map(|s| s.unwrap().receipt())).await
     }
 
     /// sends the current batch of messages
@@ -335,47 +901,254 @@ impl<Exe: Executor> Producer<Exe> {
         }
     }
 
+    /// sends any pending batched messages immediately
+    ///
+    /// unlike [Producer::send_batch], this does nothing for a producer
+    /// that does not batch messages
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            ProducerInner::Single(p) => p.flush().await,
+            ProducerInner::Partitioned(p) => {
+                try_join_all(p.producers.iter_mut().map(|p| p.flush()))
+                    .await
+                    .map(drop)
+            }
+        }
+    }
+
+    /// flushes the producer and closes it on all partitions
+    ///
+    /// this resolves once the broker acknowledged the closing, unlike
+    /// dropping the producer, which closes it in the background
+    ///
+    /// ```rust,no_run
+    /// # async fn run(mut producer: pulsar::Producer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// producer.send("last message").await?.await?;
+    /// producer.close().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn close(mut self) -> Result<(), Error> {
+        match &mut self.inner {
+            ProducerInner::Single(p) => p.close().await,
+            ProducerInner::Partitioned(p) => {
+                let results = join_all(p.producers.iter_mut().map(|p| p.close())).await;
+                results.into_iter().collect()
+            }
+        }
+    }
+
+    /// turns the producer into a [Sink], to forward a stream to the topic
+    ///
+    /// ```rust,no_run
+    /// # async fn run(producer: pulsar::Producer<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+    /// use futures::StreamExt;
+    ///
+    /// let items = futures::stream::iter(vec!["a", "b", "c"]).map(Ok);
+    /// items.forward(producer.into_sink()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_sink(self) -> ProducerSink<Exe> {
+        ProducerSink {
+            producer: Some(self),
+            operation: None,
+            flushing: false,
+            receipts: FuturesUnordered::new(),
+        }
+    }
+
     pub(crate) async fn send_raw(&mut self, message: ProducerMessage) -> Result<SendFuture, Error> {
         match &mut self.inner {
             ProducerInner::Single(p) => p.send_raw(message).await,
-            ProducerInner::Partitioned(p) => p.next().send_raw(message).await,
+            ProducerInner::Partitioned(p) => {
+                let partition_key = message.partition_key.clone();
+                let ordering_key = message.ordering_key.clone();
+                p.route_key(partition_key.as_deref(), ordering_key.as_deref())
+                    .send_raw(message)
+                    .await
+            }
         }
     }
 }
 
-enum ProducerInner<Exe: Executor> {
-    Single(TopicProducer<Exe>),
-    Partitioned(PartitionedProducer<Exe>),
-}
+// send or flush running on the producer of a sink, giving the producer back
+type SinkOperation<Exe> =
+    Pin<Box<dyn Future<Output = (Producer<Exe>, Result<Option<SendFuture>, Error>)> + Send>>;
 
-struct PartitionedProducer<Exe: Executor> {
-    // Guaranteed to be non-empty
-    producers: VecDeque<TopicProducer<Exe>>,
-    topic: String,
-    options: ProducerOptions,
+/// [Sink] sending its items with a [Producer], created by [Producer::into_sink]
+///
+/// the sink is ready once the previous item was handed to the producer, so
+/// it waits like [Producer::send] when `max_pending_messages` is reached,
+/// and batches the items if the producer batches messages. The receipts
+/// are kept until the sink is flushed: flushing sends the current batch,
+/// waits for all the receipts and fails if a message could not be sent.
+/// Closing the sink flushes it, the producer is closed when it is dropped
+pub struct ProducerSink<Exe: Executor> {
+    producer: Option<Producer<Exe>>,
+    operation: Option<SinkOperation<Exe>>,
+    flushing: bool,
+    receipts: FuturesUnordered<SendFuture>,
 }
 
-impl<Exe: Executor> PartitionedProducer<Exe> {
-    pub fn next(&mut self) -> &mut TopicProducer<Exe> {
-        self.producers.rotate_left(1);
-        self.producers.front_mut().unwrap()
+impl<Exe: Executor> ProducerSink<Exe> {
+    /// waits for the item being sent, and returns the producer
+    ///
+    /// the receipts that were not checked by flushing the sink are dropped
+    pub async fn into_inner(mut self) -> Producer<Exe> {
+        let _ = futures::future::poll_fn(|cx| self.poll_operation(cx)).await;
+        self.producer
+            .take()
+            .expect("the producer is only taken by the current operation")
     }
-}
 
-/// a producer is used to publish messages on a topic
-struct TopicProducer<Exe: Executor> {
-    client: Pulsar<Exe>,
-    connection: Arc<Connection<Exe>>,
-    id: ProducerId,
-    name: ProducerName,
-    topic: String,
+    fn start(
+        &mut self,
+        operation: impl FnOnce(Producer<Exe>) -> SinkOperation<Exe>,
+    ) -> Result<(), Error> {
+        let producer = self.producer.take().ok_or_else(|| {
+            Error::Producer(ProducerError::Custom(
+                "the sink is not ready, poll_ready must be called first".to_string(),
+            ))
+        })?;
+        self.operation = Some(operation(producer));
+        Ok(())
+    }
+
+    fn poll_operation(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(operation) = self.operation.as_mut() {
+            let (producer, res) = ready!(operation.as_mut().poll(cx));
+            self.operation = None;
+            self.producer = Some(producer);
+            if let Some(receipt) = res? {
+                self.receipts.push(receipt);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// checks the receipts that arrived, or waits for all of them if `all`
+    fn poll_receipts(&mut self, cx: &mut Context<'_>, all: bool) -> Poll<Result<(), Error>> {
+        loop {
+            match self.receipts.poll_next_unpin(cx) {
+                Poll::Ready(Some(receipt)) => {
+                    receipt?;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending if all => return Poll::Pending,
+                Poll::Pending => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    fn poll_flush_inner(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.poll_operation(cx))?;
+        if !self.flushing {
+            self.start(|mut producer| {
+                Box::pin(async move {
+                    let res = producer.flush().await.map(|()| None);
+                    (producer, res)
+                })
+            })?;
+            self.flushing = true;
+            ready!(self.poll_operation(cx))?;
+        }
+        ready!(self.poll_receipts(cx, true))?;
+        self.flushing = false;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: SerializeMessage, Exe: Executor> Sink<T> for ProducerSink<Exe> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_operation(cx))?;
+        this.poll_receipts(cx, false)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Error> {
+        let message = T::serialize_message(item)?;
+        self.get_mut().start(|mut producer| {
+            Box::pin(async move {
+                let res = producer.send(message).await.map(Some);
+                (producer, res)
+            })
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let res = ready!(this.poll_flush_inner(cx));
+        if res.is_err() {
+            this.flushing = false;
+        }
+        Poll::Ready(res)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        <Self as Sink<T>>::poll_flush(self, cx)
+    }
+}
+
+enum ProducerInner<Exe: Executor> {
+    Single(TopicProducer<Exe>),
+    Partitioned(PartitionedProducer<Exe>),
+}
+
+struct PartitionedProducer<Exe: Executor> {
+    // Guaranteed to be non-empty, ordered by partition index
+    producers: Vec<TopicProducer<Exe>>,
+    router: Arc<dyn Router>,
+    topic: String,
+    options: ProducerOptions,
+}
+
+impl<Exe: Executor> PartitionedProducer<Exe> {
+    /// selects the partition for a message with the configured router
+    fn route(&mut self, message: &Message) -> &mut TopicProducer<Exe> {
+        self.route_key(
+            message.partition_key.as_deref(),
+            message.ordering_key.as_deref(),
+        )
+    }
+
+    fn route_key(
+        &mut self,
+        partition_key: Option<&str>,
+        ordering_key: Option<&[u8]>,
+    ) -> &mut TopicProducer<Exe> {
+        let partitions = self.producers.len();
+        let partition = self
+            .router
+            .choose_partition_with_ordering_key(partition_key, ordering_key, partitions)
+            .min(partitions - 1);
+        &mut self.producers[partition]
+    }
+}
+
+/// a producer is used to publish messages on a topic
+struct TopicProducer<Exe: Executor> {
+    client: Pulsar<Exe>,
+    connection: Arc<Connection<Exe>>,
+    id: ProducerId,
+    name: ProducerName,
+    topic: String,
     message_id: SerialId,
     //putting it in a mutex because we must send multiple messages at once
     // while we might be pushing more messages from elsewhere
-    batch: Option<Mutex<Batch>>,
+    batch: Option<Arc<Mutex<Batch>>>,
     compression: Option<proto::CompressionType>,
-    _drop_signal: oneshot::Sender<()>,
+    drop_signal: Option<oneshot::Sender<()>>,
     options: ProducerOptions,
+    counters: Arc<ProducerCounters>,
+    // version of the schema registered for this producer, set on its messages
+    schema_version: Option<Vec<u8>>,
+    #[cfg(feature = "encryption")]
+    encryptor: Option<Arc<Encryptor>>,
+    // resolved when the broker closes the producer
+    closed_by_broker: Shared<oneshot::Receiver<()>>,
 }
 
 impl<Exe: Executor> TopicProducer<Exe> {
@@ -394,11 +1167,31 @@ impl<Exe: Executor> TopicProducer<Exe> {
         let batch_size = options.batch_size;
         let compression = options.compression;
 
+        if options.chunking && batch_size.is_some() {
+            return Err(ProducerError::Custom(
+                "chunking cannot be used for a batching producer".to_string(),
+            )
+            .into());
+        }
+
+        #[cfg(feature = "encryption")]
+        let encryptor = match options.encryption.as_ref() {
+            None => None,
+            Some(config) => Some(Arc::new(Encryptor::new(config)?)),
+        };
+
+        if options.compression_level.is_some() && compression != Some(CompressionType::Zstd) {
+            return Err(ProducerError::Custom(
+                "a compression level can only be set with zstd compression".to_string(),
+            )
+            .into());
+        }
+
         match compression {
             None | Some(CompressionType::None) => {}
             Some(CompressionType::Lz4) => {
                 #[cfg(not(feature = "lz4"))]
-                return Err(Error::Custom("cannot create a producer with LZ4 compression because the 'lz4' cargo feature is not active".to_string()));
+                return Err(Error::Custom("cannot create a producer with LZ4 compression because the 'compression-lz4' cargo feature is not active".to_string()));
             }
             Some(CompressionType::Zlib) => {
                 #[cfg(not(feature = "flate2"))]
@@ -406,7 +1199,22 @@ impl<Exe: Executor> TopicProducer<Exe> {
             }
             Some(CompressionType::Zstd) => {
                 #[cfg(not(feature = "zstd"))]
-                return Err(Error::Custom("cannot create a producer with zstd compression because the 'zstd' cargo feature is not active".to_string()));
+                return Err(Error::Custom("cannot create a producer with zstd compression because the 'compression-zstd' cargo feature is not active".to_string()));
+
+                #[cfg(feature = "zstd")]
+                {
+                    let level = options
+                        .compression_level
+                        .unwrap_or(DEFAULT_ZSTD_COMPRESSION_LEVEL);
+                    if !zstd::compression_level_range().contains(&level) {
+                        return Err(ProducerError::Custom(format!(
+                            "invalid zstd compression level {}, it must be in the range {:?}",
+                            level,
+                            zstd::compression_level_range()
+                        ))
+                        .into());
+                    }
+                }
             }
             Some(CompressionType::Snappy) => {
                 #[cfg(not(feature = "snap"))]
@@ -415,6 +1223,7 @@ impl<Exe: Executor> TopicProducer<Exe> {
         };
 
         let producer_name: ProducerName;
+        let schema_version;
         let mut current_retries = 0u32;
         let start = std::time::Instant::now();
         let operation_retry_options = client.operation_retry_options.clone();
@@ -430,11 +1239,12 @@ impl<Exe: Executor> TopicProducer<Exe> {
                 }) {
                 Ok(success) => {
                     producer_name = success.producer_name;
+                    schema_version = success.schema_version;
 
                     if current_retries > 0 {
                         let dur = (std::time::Instant::now() - start).as_secs();
                         log::info!(
-                            "subscribe({}) success after {} retries over {} seconds",
+                            "create_producer({}) success after {} retries over {} seconds",
                             topic,
                             current_retries + 1,
                             dur
@@ -459,7 +1269,7 @@ impl<Exe: Executor> TopicProducer<Exe> {
                             .delay(operation_retry_options.retry_delay)
                             .await;
 
-                        let addr = client.lookup_topic(&topic).await?;
+                        let addr = client.lookup_topic_again(&topic).await?;
                         connection = client.manager.get_connection(&addr).await?;
 
                         continue;
@@ -473,37 +1283,170 @@ impl<Exe: Executor> TopicProducer<Exe> {
                         .into());
                     }
                 }
+                Err(ConnectionError::PulsarError(Some(proto::ServerError::ProducerBusy), text)) => {
+                    return Err(ProducerError::ProducerBusy(text.unwrap_or_default()).into());
+                }
+                Err(ConnectionError::PulsarError(
+                    Some(proto::ServerError::IncompatibleSchema),
+                    text,
+                )) => {
+                    return Err(ProducerError::IncompatibleSchema {
+                        topic: topic.clone(),
+                        message: text,
+                    }
+                    .into());
+                }
                 Err(e) => return Err(Error::Connection(e)),
             }
         }
+        debug!(
+            "created producer {} (id {}) for topic {} on connection {}",
+            producer_name,
+            producer_id,
+            topic,
+            connection.id()
+        );
+        let closed_by_broker = connection
+            .sender()
+            .watch_producer_close(producer_id)?
+            .shared();
 
         // drop_signal will be dropped when the TopicProducer is dropped, then
-        // drop_receiver will return, and we can close the producer
-        let (_drop_signal, drop_receiver) = oneshot::channel::<()>();
+        // drop_receiver will return, and we can close the producer. If the
+        // producer was closed explicitly, the signal is sent instead
+        let (drop_signal, drop_receiver) = oneshot::channel::<()>();
         let conn = connection.clone();
         let _ = client.executor.spawn(Box::pin(async move {
-            let _res = drop_receiver.await;
-            let _ = conn.sender().close_producer(producer_id).await;
+            if drop_receiver.await.is_err() {
+                let _ = conn.sender().close_producer(producer_id).await;
+            }
         }));
 
-        Ok(TopicProducer {
+        let producer = TopicProducer {
             client,
             connection,
             id: producer_id,
             name: producer_name,
             topic,
             message_id: sequence_ids,
-            batch: batch_size.map(Batch::new).map(Mutex::new),
+            batch: batch_size
+                .map(|length| Batch::new(length, options.batch_max_bytes))
+                .map(Mutex::new)
+                .map(Arc::new),
             compression,
-            _drop_signal,
+            drop_signal: Some(drop_signal),
             options,
-        })
+            counters: Arc::new(ProducerCounters::default()),
+            schema_version,
+            #[cfg(feature = "encryption")]
+            encryptor,
+            closed_by_broker,
+        };
+        producer.start_batch_timer();
+
+        Ok(producer)
+    }
+
+    /// periodically sends the current batch if the producer was configured
+    /// with a batch timeout
+    ///
+    /// the timer task only keeps a weak reference to the batch, so it stops
+    /// when the producer is dropped or when the batch is replaced after a
+    /// reconnection
+    fn start_batch_timer(&self) {
+        let (batch, timeout) = match (self.batch.as_ref(), self.options.batch_timeout) {
+            (Some(batch), Some(timeout)) => (Arc::downgrade(batch), timeout),
+            _ => return,
+        };
+
+        let mut interval = self.client.executor.interval(timeout);
+        let connection = self.connection.clone();
+        let producer_id = self.id;
+        let producer_name = self.name.clone();
+        let sequence_ids = self.message_id.clone();
+        let compression = self.compression;
+        let compression_level = self.options.compression_level;
+        let topic = self.topic.clone();
+        let counters = self.counters.clone();
+        let schema_version = self.schema_version.clone();
+        let closed_by_broker = self.closed_by_broker.clone();
+        let send_timeout = self.options.send_timeout.is_some();
+        #[cfg(feature = "encryption")]
+        let encryptor = self.encryptor.clone();
+
+        let res = self.client.executor.spawn(Box::pin(async move {
+            use futures::StreamExt;
+            while let Some(()) = interval.next().await {
+                let batch: Arc<Mutex<Batch>> = match Weak::upgrade(&batch) {
+                    Some(batch) => batch,
+                    None => {
+                        trace!("batch was dropped, stopping batch timer for {}", topic);
+                        break;
+                    }
+                };
+                // the messages stay in the batch, and are sent once the
+                // producer is recreated by its next send
+                if is_closed(&closed_by_broker) {
+                    trace!(
+                        "producer {} was closed, not sending its batch on {}",
+                        producer_id,
+                        topic
+                    );
+                    continue;
+                }
+
+                let (mut message, receipts) = match batch.lock().await.flush().await {
+                    Some(res) => res,
+                    None => continue,
+                };
+                message.schema_version = schema_version.clone();
+
+                trace!(
+                    "batch timeout reached for {}, sending a batched message of size {}",
+                    topic,
+                    receipts.len()
+                );
+                let size = message.payload.len() as u64;
+                let message = compress_message(compression, compression_level, message);
+                #[cfg(feature = "encryption")]
+                let message = message.and_then(|m| encrypt_message(encryptor.as_deref(), m));
+                let message =
+                    message.and_then(|m| check_message_size(m, connection.max_message_size()));
+                let send_receipt = match message {
+                    Ok(message) => connection
+                        .sender()
+                        .send(
+                            producer_id,
+                            producer_name.clone(),
+                            sequence_ids.get(),
+                            message,
+                        )
+                        .await
+                        .map_err(|e| ProducerError::Connection(e).into()),
+                    Err(e) => Err(e),
+                };
+                resolve_batch(
+                    receipts,
+                    send_receipt.map_err(Arc::new),
+                    size,
+                    &counters,
+                    send_timeout,
+                );
+            }
+        }));
+        if res.is_err() {
+            error!("the executor could not spawn the batch timer future");
+        }
     }
 
     fn topic(&self) -> &str {
         &self.topic
     }
 
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     fn options(&self) -> &ProducerOptions {
         &self.options
     }
@@ -524,88 +1467,185 @@ impl<Exe: Executor> TopicProducer<Exe> {
         match self.batch.as_ref() {
             None => Err(ProducerError::Custom("not a batching producer".to_string()).into()),
             Some(batch) => {
-                let mut payload: Vec<u8> = Vec::new();
-                let mut receipts = Vec::new();
-                let message_count;
-
-                {
-                    let batch = batch.lock().await;
-                    let messages = batch.get_messages().await;
-                    message_count = messages.len();
-                    for (tx, message) in messages {
-                        receipts.push(tx);
-                        message.serialize(&mut payload);
-                    }
-                }
-
-                if message_count == 0 {
-                    return Ok(());
-                }
-
-                let message = ProducerMessage {
-                    payload,
-                    num_messages_in_batch: Some(message_count as i32),
-                    ..Default::default()
+                let (message, receipts) = match batch.lock().await.flush().await {
+                    Some(res) => res,
+                    None => return Ok(()),
                 };
 
-                trace!("sending a batched message of size {}", message_count);
+                trace!("sending a batched message of size {}", receipts.len());
+                let size = message.payload.len() as u64;
                 let send_receipt = self.send_compress(message).await.map_err(Arc::new);
-                for resolver in receipts {
-                    let _ = resolver.send(
-                        send_receipt
-                            .clone()
-                            .map_err(|e| ProducerError::Batch(e).into()),
-                    );
-                }
+                let send_timeout = self.options.send_timeout.is_some();
+                resolve_batch(receipts, send_receipt, size, &self.counters, send_timeout);
 
                 Ok(())
             }
         }
     }
 
+    /// sends the messages in one batch, separately from the producer's own batch
+    async fn send_as_batch(
+        &mut self,
+        messages: Vec<ProducerMessage>,
+    ) -> Result<Vec<SendFuture>, Error> {
+        self.flush().await?;
+        let batch = Batch::new(messages.len() as u32, None);
+        let mut sends = Vec::with_capacity(messages.len());
+        for message in messages {
+            let (tx, rx) = oneshot::channel();
+            batch.push_back((tx, message.into())).await;
+            sends.push(SendFuture(rx));
+        }
+
+        let (message, receipts) = match batch.flush().await {
+            Some(res) => res,
+            None => return Ok(sends),
+        };
+        self.counters.start(receipts.len() as u64);
+
+        trace!("sending an explicit batch of size {}", receipts.len());
+        let size = message.payload.len() as u64;
+        let send_receipt = self.send_compress(message).await.map_err(Arc::new);
+        // the messages of an explicit batch are not waited on with the send timeout
+        resolve_batch(receipts, send_receipt, size, &self.counters, false);
+
+        Ok(sends)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.batch.is_none() {
+            return Ok(());
+        }
+        self.send_batch().await
+    }
+
+    /// sends the pending batched messages, then closes the producer on the broker
+    async fn close(&mut self) -> Result<(), Error> {
+        debug!("closing producer {} for topic {}", self.name, self.topic);
+        let flushed = self.flush().await;
+
+        if let Some(signal) = self.drop_signal.take() {
+            let _ = signal.send(());
+        }
+        self.connection.sender().close_producer(self.id).await?;
+        flushed
+    }
+
     pub(crate) async fn send_raw(&mut self, message: ProducerMessage) -> Result<SendFuture, Error> {
+        let timeout = match self.options.send_timeout {
+            None => return self.enqueue(message).await,
+            Some(timeout) => timeout,
+        };
+
+        let executor = self.client.executor.clone();
+        let topic = self.topic.clone();
+        let counters = self.counters.clone();
+        let delay = Box::pin(executor.delay(timeout));
+        // the enqueue is not interrupted, since it can send a batch holding
+        // other messages. Each request sent on the connection stops waiting
+        // for its receipt after the send timeout
+        let receipt = match self.enqueue(message).await {
+            Ok(receipt) => receipt,
+            Err(Error::Producer(ProducerError::Timeout)) => {
+                let (tx, rx) = oneshot::channel();
+                let _ = tx.send(Err(ProducerError::Timeout.into()));
+                return Ok(SendFuture(rx));
+            }
+            Err(e) => return Err(e),
+        };
+
+        // the receipt of a non batched message is already there, but batched
+        // messages must still wait for their batch to be sent
         let (tx, rx) = oneshot::channel();
-        match self.batch.as_ref() {
+        let mut receipt = receipt;
+        executor
+            .spawn(Box::pin(async move {
+                let received = match select(
+                    Box::pin(futures::future::poll_fn(|cx| receipt.poll_receipt(cx))),
+                    delay,
+                )
+                .await
+                {
+                    Either::Left((res, _)) => Some(res),
+                    Either::Right(_) => None,
+                };
+                // once closed, a receipt sent by the batch is not counted as sent
+                let res = match received {
+                    Some(res) => res,
+                    None => {
+                        receipt.0.close();
+                        match receipt.0.try_recv() {
+                            Ok(Some(res)) => res,
+                            _ => {
+                                warn!("send timeout reached for a message on {}", topic);
+                                counters.failed(1);
+                                Err(ProducerError::Timeout.into())
+                            }
+                        }
+                    }
+                };
+                let _ = tx.send(res);
+            }))
+            .map_err(|_| Error::Executor)?;
+
+        Ok(SendFuture(rx))
+    }
+
+    async fn enqueue(&mut self, message: ProducerMessage) -> Result<SendFuture, Error> {
+        if message.sequence_id.is_some() && self.batch.is_some() {
+            return Err(ProducerError::Custom(
+                "cannot set the sequence id of a message sent by a batching producer".to_string(),
+            )
+            .into());
+        }
+        self.wait_for_pending_messages().await;
+
+        let (tx, rx) = oneshot::channel();
+        // delayed and transactional messages are sent individually, outside of the batch
+        let batch = self
+            .batch
+            .as_ref()
+            .filter(|_| message.deliver_at_time.is_none() && message.txnid_most_bits.is_none());
+        match batch {
             None => {
-                let receipt = self.send_compress(message).await?;
-                let _ = tx.send(Ok(receipt));
-                Ok(SendFuture(rx))
+                let size = message.payload.len() as u64;
+                let _in_flight = InFlight::new(&self.counters);
+                match self.send_compress(message).await {
+                    Ok(receipt) => {
+                        self.counters.sent(1, size);
+                        let _ = tx.send(Ok(receipt));
+                        Ok(SendFuture(rx))
+                    }
+                    Err(e) => {
+                        self.counters.failed(1);
+                        Err(e)
+                    }
+                }
             }
             Some(batch) => {
-                let mut payload: Vec<u8> = Vec::new();
-                let mut receipts = Vec::new();
-                let mut counter = 0i32;
-
+                let message = BatchedMessage::from(message);
+                let mut flushed = Vec::new();
                 {
                     let batch = batch.lock().await;
+                    // the current batch is sent first if the message would
+                    // make it too large
+                    if !batch.has_room(&message) {
+                        flushed.extend(batch.flush().await);
+                    }
                     batch.push_back((tx, message)).await;
+                    self.counters.start(1);
 
-                    if batch.is_full().await {
-                        for (tx, message) in batch.get_messages().await {
-                            receipts.push(tx);
-                            message.serialize(&mut payload);
-                            counter += 1;
-                        }
+                    if batch.is_full().await || self.reconnection_buffer_full(&batch).await {
+                        flushed.extend(batch.flush().await);
                     }
                 }
 
-                if counter > 0 {
-                    let message = ProducerMessage {
-                        payload,
-                        num_messages_in_batch: Some(counter),
-                        ..Default::default()
-                    };
-
+                for (message, receipts) in flushed {
+                    trace!("sending a batched message of size {}", receipts.len());
+                    let size = message.payload.len() as u64;
                     let send_receipt = self.send_compress(message).await.map_err(Arc::new);
-
-                    trace!("sending a batched message of size {}", counter);
-                    for tx in receipts.drain(..) {
-                        let _ = tx.send(
-                            send_receipt
-                                .clone()
-                                .map_err(|e| ProducerError::Batch(e).into()),
-                        );
-                    }
+                    let send_timeout = self.options.send_timeout.is_some();
+                    resolve_batch(receipts, send_receipt, size, &self.counters, send_timeout);
                 }
 
                 Ok(SendFuture(rx))
@@ -613,151 +1653,240 @@ impl<Exe: Executor> TopicProducer<Exe> {
         }
     }
 
-    async fn send_compress(
-        &mut self,
-        mut message: ProducerMessage,
-    ) -> Result<proto::CommandSendReceipt, Error> {
-        let compressed_message = match self.compression {
-            None | Some(CompressionType::None) => message,
-            Some(CompressionType::Lz4) => {
-                #[cfg(not(feature = "lz4"))]
-                return unimplemented!();
-
-                #[cfg(feature = "lz4")]
-                {
-                    let v: Vec<u8> = Vec::new();
-                    let mut encoder = lz4::EncoderBuilder::new()
-                        .build(v)
-                        .map_err(ProducerError::Io)?;
-                    encoder
-                        .write(&message.payload[..])
-                        .map_err(ProducerError::Io)?;
-                    let (compressed_payload, result) = encoder.finish();
-
-                    result.map_err(ProducerError::Io)?;
-                    message.payload = compressed_payload;
-                    message.compression = Some(1);
-                    message
-                }
-            }
-            Some(CompressionType::Zlib) => {
-                #[cfg(not(feature = "flate2"))]
-                return unimplemented!();
+    /// waits until the number of messages waiting for their receipt is
+    /// under `max_pending_messages`
+    async fn wait_for_pending_messages(&mut self) {
+        let max = match self.options.max_pending_messages {
+            Some(max) => max,
+            None => return,
+        };
 
-                #[cfg(feature = "flate2")]
-                {
-                    let mut e =
-                        flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
-                    e.write_all(&message.payload[..])
-                        .map_err(ProducerError::Io)?;
-                    let compressed_payload = e.finish().map_err(ProducerError::Io)?;
-
-                    message.payload = compressed_payload;
-                    message.compression = Some(2);
-                    message
+        while self.counters.in_flight.load(Ordering::Relaxed) >= max {
+            // batched messages only get their receipt once the batch is sent
+            let batched = match self.batch.as_ref() {
+                Some(batch) => !batch.lock().await.is_empty().await,
+                None => false,
+            };
+            if batched {
+                trace!(
+                    "producer {} reached {} pending messages, sending the batch",
+                    self.name,
+                    max
+                );
+                if let Err(e) = self.send_batch().await {
+                    warn!("producer {} could not send its batch: {}", self.name, e);
                 }
-            }
-            Some(CompressionType::Zstd) => {
-                #[cfg(not(feature = "zstd"))]
-                return unimplemented!();
-
-                #[cfg(feature = "zstd")]
-                {
-                    let compressed_payload =
-                        zstd::encode_all(&message.payload[..], 0).map_err(ProducerError::Io)?;
-                    message.compression = Some(3);
-                    message.payload = compressed_payload;
-                    message
+            } else {
+                // registered before checking again, so a receipt coming in
+                // between is not missed
+                let released = self.counters.released();
+                if self.counters.in_flight.load(Ordering::Relaxed) >= max {
+                    let _ = released.await;
                 }
             }
-            Some(CompressionType::Snappy) => {
-                #[cfg(not(feature = "snap"))]
-                return unimplemented!();
+        }
+    }
 
-                #[cfg(feature = "snap")]
-                {
-                    let compressed_payload: Vec<u8> = Vec::new();
-                    let mut encoder = snap::write::FrameEncoder::new(compressed_payload);
-                    encoder
-                        .write(&message.payload[..])
-                        .map_err(ProducerError::Io)?;
-                    let compressed_payload = encoder
-                        .into_inner()
-                        //FIXME
-                        .map_err(|e| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("Snappy compression error: {:?}", e),
-                            )
-                        })
-                        .map_err(ProducerError::Io)?;
+    async fn send_compress(
+        &mut self,
+        mut message: ProducerMessage,
+    ) -> Result<proto::CommandSendReceipt, Error> {
+        if message.schema_version.is_none() {
+            message.schema_version = self.schema_version.clone();
+        }
+        let compressed_message =
+            compress_message(self.compression, self.options.compression_level, message)?;
+        #[cfg(feature = "encryption")]
+        let compressed_message = encrypt_message(self.encryptor.as_deref(), compressed_message)?;
+        if self.options.chunking
+            && compressed_message.payload.len() > self.connection.max_message_size()
+        {
+            return self.send_chunks(compressed_message).await;
+        }
+        let message = check_message_size(compressed_message, self.connection.max_message_size())?;
+        self.send_inner(message).await
+    }
 
-                    message.payload = compressed_payload;
-                    message.compression = Some(4);
-                    message
-                }
-            }
-        };
+    /// sends the chunks of a message one after the other, and returns the
+    /// receipt of the last one
+    async fn send_chunks(
+        &mut self,
+        message: ProducerMessage,
+    ) -> Result<proto::CommandSendReceipt, Error> {
+        let sequence_id = message.sequence_id.unwrap_or_else(|| self.message_id.get());
+        // the uuid set on the message is kept, the consumers reassemble the
+        // chunks that share it
+        let uuid = message
+            .uuid
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", self.name, sequence_id));
+        let chunk_size = self.connection.max_message_size();
+        let chunks = split_chunks(message, sequence_id, uuid, chunk_size);
+        trace!(
+            "sending a message in {} chunks on {}",
+            chunks.len(),
+            self.topic
+        );
 
-        self.send_inner(compressed_message).await
+        let mut receipt = None;
+        for chunk in chunks {
+            receipt = Some(self.send_inner(chunk).await?);
+        }
+        receipt.ok_or_else(|| ProducerError::Custom("no chunk to send".to_string()).into())
     }
 
     async fn send_inner(
         &mut self,
         message: ProducerMessage,
     ) -> Result<proto::CommandSendReceipt, Error> {
-        let msg = message.clone();
-        match self
-            .connection
-            .sender()
-            .send(self.id, self.name.clone(), self.message_id.get(), message)
-            .await
-        {
-            Ok(receipt) => return Ok(receipt),
-            Err(ConnectionError::Disconnected) => {}
-            Err(ConnectionError::Io(e)) => {
-                if e.kind() != std::io::ErrorKind::TimedOut {
-                    error!("send_inner got io error: {:?}", e);
-                    return Err(ProducerError::Connection(ConnectionError::Io(e)).into());
-                }
-            }
-            Err(e) => {
-                error!("send_inner got error: {:?}", e);
-                return Err(ProducerError::Connection(e).into());
+        let retry_policy = self.options.reconnection.clone().unwrap_or_default();
+        let mut current_retries = 0u32;
+
+        loop {
+            if self.is_closed_by_broker() {
+                warn!(
+                    "send_inner: producer {} was closed by the broker for topic {}",
+                    self.id, self.topic
+                );
+            } else {
+                let sequence_id = message.sequence_id.unwrap_or_else(|| self.message_id.get());
+                trace!(
+                    "producer {} sending message {} on {}",
+                    self.name,
+                    sequence_id,
+                    self.topic
+                );
+                let sender = self.connection.sender();
+                let res = match self.options.send_timeout {
+                    Some(timeout) => {
+                        sender
+                            .send_with_timeout(
+                                self.id,
+                                self.name.clone(),
+                                sequence_id,
+                                message.clone(),
+                                timeout,
+                            )
+                            .await
+                    }
+                    None => {
+                        sender
+                            .send(self.id, self.name.clone(), sequence_id, message.clone())
+                            .await
+                    }
+                };
+                match res {
+                    Ok(receipt) => {
+                        trace!(
+                            "producer {} received the receipt of message {} on {}: {:?}",
+                            self.name,
+                            sequence_id,
+                            self.topic,
+                            receipt.message_id
+                        );
+                        if message.sequence_id.is_some() {
+                            check_receipt(sequence_id, &receipt)?;
+                        }
+                        return Ok(receipt);
+                    }
+                    // the message is not sent again once its send timeout is reached
+                    Err(ConnectionError::OperationTimeout)
+                        if self.options.send_timeout.is_some() =>
+                    {
+                        warn!("send timeout reached for a message on {}", self.topic);
+                        return Err(ProducerError::Timeout.into());
+                    }
+                    Err(ConnectionError::Disconnected) | Err(ConnectionError::OperationTimeout) => {
+                    }
+                    // the broker answers the sends of a producer it closes
+                    // with the close command, they are sent again once the
+                    // producer is recreated
+                    Err(ConnectionError::UnexpectedResponse(_)) if self.is_closed_by_broker() => {
+                        warn!(
+                            "send_inner: producer {} was closed by the broker while sending on {}",
+                            self.id, self.topic
+                        );
+                    }
+                    Err(ConnectionError::Io(e)) => {
+                        if e.kind() != std::io::ErrorKind::TimedOut {
+                            error!("send_inner got io error: {:?}", e);
+                            return Err(ProducerError::Connection(ConnectionError::Io(e)).into());
+                        }
+                    }
+                    Err(e) => {
+                        error!("send_inner got error: {:?}", e);
+                        return Err(ProducerError::Connection(e).into());
+                    }
+                };
+
+                error!(
+                    "send_inner: connection {} disconnected",
+                    self.connection.id()
+                );
             }
-        };
 
-        error!(
-            "send_inner: connection {} disconnected",
-            self.connection.id()
-        );
-        self.reconnect().await?;
+            loop {
+                if !retry_policy.can_retry(current_retries) {
+                    error!(
+                        "send_inner: reached max reconnection retries ({:?}) for topic {}",
+                        retry_policy.max_retries, self.topic
+                    );
+                    return Err(ProducerError::Connection(ConnectionError::Disconnected).into());
+                }
 
-        match self
-            .connection
-            .sender()
-            .send(self.id, self.name.clone(), self.message_id.get(), msg)
-            .await
-        {
-            Ok(receipt) => Ok(receipt),
-            Err(e) => {
-                error!("send_inner got error: {:?}", e);
-                Err(ProducerError::Connection(e).into())
+                let backoff = retry_policy.backoff(current_retries);
+                current_retries += 1;
+                if backoff > Duration::from_millis(0) {
+                    self.client.executor.delay(backoff).await;
+                }
+
+                match self.reconnect().await {
+                    Ok(()) => {
+                        debug!(
+                            "send_inner: reconnected producer {} for topic {}",
+                            self.name, self.topic
+                        );
+                        break;
+                    }
+                    Err(e) => {
+                        error!(
+                            "send_inner: could not reconnect producer for topic {} (attempt {}): {}",
+                            self.topic, current_retries, e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// whether the messages kept in the batch while the producer is closed
+    /// reached `max_reconnection_buffer`
+    async fn reconnection_buffer_full(&self, batch: &Batch) -> bool {
+        match self.options.max_reconnection_buffer {
+            Some(max) => {
+                self.is_closed_by_broker() && batch.storage.lock().await.len() >= max as usize
             }
+            None => false,
         }
     }
 
+    /// whether the broker closed the producer, which must then be recreated
+    fn is_closed_by_broker(&self) -> bool {
+        is_closed(&self.closed_by_broker)
+    }
+
     async fn reconnect(&mut self) -> Result<(), Error> {
         debug!("reconnecting producer for topic: {}", self.topic);
-        let broker_address = self.client.lookup_topic(&self.topic).await?;
+        let broker_address = self.client.lookup_topic_again(&self.topic).await?;
         let conn = self.client.manager.get_connection(&broker_address).await?;
 
         self.connection = conn;
 
         let topic = self.topic.clone();
         let batch_size = self.options.batch_size;
+        let batch_max_bytes = self.options.batch_max_bytes;
 
-        let _ = self
+        let success = self
             .connection
             .sender()
             .create_producer(
@@ -767,25 +1896,274 @@ impl<Exe: Executor> TopicProducer<Exe> {
                 self.options.clone(),
             )
             .await?;
+        self.schema_version = success.schema_version;
+        self.closed_by_broker = self
+            .connection
+            .sender()
+            .watch_producer_close(self.id)?
+            .shared();
 
         // drop_signal will be dropped when the TopicProducer is dropped, then
-        // drop_receiver will return, and we can close the producer
-        let (_drop_signal, drop_receiver) = oneshot::channel::<()>();
-        let batch = batch_size.map(Batch::new).map(Mutex::new);
+        // drop_receiver will return, and we can close the producer. If the
+        // producer was closed explicitly, the signal is sent instead
+        let (drop_signal, drop_receiver) = oneshot::channel::<()>();
+        let batch = batch_size
+            .map(|length| Batch::new(length, batch_max_bytes))
+            .map(Mutex::new)
+            .map(Arc::new);
         let conn = self.connection.clone();
         let producer_id = self.id;
         let _ = self.client.executor.spawn(Box::pin(async move {
-            let _res = drop_receiver.await;
-            let _ = conn.sender().close_producer(producer_id).await;
+            if drop_receiver.await.is_err() {
+                let _ = conn.sender().close_producer(producer_id).await;
+            }
         }));
 
+        // messages waiting in the current batch are moved to the new one, and
+        // will be sent with the next batch
+        if let (Some(old), Some(new)) = (self.batch.as_ref(), batch.as_ref()) {
+            let messages = old.lock().await.get_messages().await;
+            new.lock().await.extend(messages).await;
+        }
+
         self.batch = batch;
-        self._drop_signal = _drop_signal;
+        self.drop_signal = Some(drop_signal);
+        self.start_batch_timer();
 
         Ok(())
     }
 }
 
+/// whether a producer was closed by the broker. The receiver is also
+/// canceled if the connection stopped
+fn is_closed(closed_by_broker: &Shared<oneshot::Receiver<()>>) -> bool {
+    closed_by_broker.clone().now_or_never().is_some()
+}
+
+/// checks the receipt of a message sent with an explicit sequence id
+///
+/// the broker answers a message it dropped as a duplicate with a -1:-1
+/// message id
+fn check_receipt(sequence_id: u64, receipt: &proto::CommandSendReceipt) -> Result<(), Error> {
+    if receipt.sequence_id != sequence_id {
+        return Err(ProducerError::SequenceIdMismatch {
+            expected: sequence_id,
+            received: receipt.sequence_id,
+        }
+        .into());
+    }
+    if let Some(id) = receipt.message_id.as_ref() {
+        if id.ledger_id == u64::MAX && id.entry_id == u64::MAX {
+            return Err(ProducerError::Duplicate { sequence_id }.into());
+        }
+    }
+    Ok(())
+}
+
+/// warns if the number of partitions set with [ProducerBuilder::with_partitions]
+/// is not the one of the topic
+fn check_partitions(topic: &str, configured: u32, actual: u32) -> bool {
+    if configured != actual {
+        warn!(
+            "producer for {} was created with {} partitions, but the topic has {}",
+            topic, configured, actual
+        );
+        return false;
+    }
+    true
+}
+
+/// splits the payload of a message in chunks of at most `chunk_size` bytes.
+/// All the chunks have the same sequence id and uuid
+fn split_chunks(
+    mut message: ProducerMessage,
+    sequence_id: u64,
+    uuid: String,
+    chunk_size: usize,
+) -> Vec<ProducerMessage> {
+    let payload = std::mem::take(&mut message.payload);
+    let total_size = payload.len();
+    let num_chunks = total_size.div_ceil(chunk_size);
+
+    payload
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(chunk_id, data)| ProducerMessage {
+            payload: data.to_vec(),
+            sequence_id: Some(sequence_id),
+            uuid: Some(uuid.clone()),
+            chunk_id: Some(chunk_id as i32),
+            num_chunks_from_msg: Some(num_chunks as i32),
+            total_chunk_msg_size: Some(total_size as i32),
+            ..message.clone()
+        })
+        .collect()
+}
+
+/// rejects messages the broker would refuse
+fn check_message_size(message: ProducerMessage, max: usize) -> Result<ProducerMessage, Error> {
+    let size = message.payload.len();
+    if size > max {
+        return Err(ProducerError::MessageTooLarge { size, max }.into());
+    }
+    Ok(message)
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt_message(
+    encryptor: Option<&Encryptor>,
+    message: ProducerMessage,
+) -> Result<ProducerMessage, Error> {
+    match encryptor {
+        None => Ok(message),
+        Some(encryptor) => Ok(encryptor.encrypt(message)?),
+    }
+}
+
+// without any compression feature, every compressing arm returns an error
+#[cfg_attr(
+    not(any(
+        feature = "lz4",
+        feature = "flate2",
+        feature = "zstd",
+        feature = "snap"
+    )),
+    allow(unused_mut, unused_variables, unreachable_code)
+)]
+fn compress_message(
+    compression: Option<CompressionType>,
+    compression_level: Option<i32>,
+    mut message: ProducerMessage,
+) -> Result<ProducerMessage, Error> {
+    let uncompressed_size = message.payload.len() as u32;
+    let mut compressed_message: ProducerMessage = match compression {
+        None | Some(CompressionType::None) => return Ok(message),
+        Some(CompressionType::Lz4) => {
+            #[cfg(not(feature = "lz4"))]
+            return Err(ProducerError::Custom(
+                "cannot compress the message: the 'compression-lz4' cargo feature is not active"
+                    .to_string(),
+            )
+            .into());
+
+            #[cfg(feature = "lz4")]
+            {
+                let v: Vec<u8> = Vec::new();
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .build(v)
+                    .map_err(ProducerError::Io)?;
+                encoder
+                    .write_all(&message.payload[..])
+                    .map_err(ProducerError::Io)?;
+                let (compressed_payload, result) = encoder.finish();
+
+                result.map_err(ProducerError::Io)?;
+                message.payload = compressed_payload;
+                message.compression = Some(1);
+                message
+            }
+        }
+        Some(CompressionType::Zlib) => {
+            #[cfg(not(feature = "flate2"))]
+            return Err(ProducerError::Custom(
+                "cannot compress the message: the 'flate2' cargo feature is not active".to_string(),
+            )
+            .into());
+
+            #[cfg(feature = "flate2")]
+            {
+                let mut e =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                e.write_all(&message.payload[..])
+                    .map_err(ProducerError::Io)?;
+                let compressed_payload = e.finish().map_err(ProducerError::Io)?;
+
+                message.payload = compressed_payload;
+                message.compression = Some(2);
+                message
+            }
+        }
+        Some(CompressionType::Zstd) => {
+            #[cfg(not(feature = "zstd"))]
+            return Err(ProducerError::Custom(
+                "cannot compress the message: the 'compression-zstd' cargo feature is not active"
+                    .to_string(),
+            )
+            .into());
+
+            #[cfg(feature = "zstd")]
+            {
+                let level = compression_level.unwrap_or(DEFAULT_ZSTD_COMPRESSION_LEVEL);
+                let compressed_payload =
+                    zstd::encode_all(&message.payload[..], level).map_err(ProducerError::Io)?;
+                message.compression = Some(3);
+                message.payload = compressed_payload;
+                message
+            }
+        }
+        Some(CompressionType::Snappy) => {
+            #[cfg(not(feature = "snap"))]
+            return Err(ProducerError::Custom(
+                "cannot compress the message: the 'compression-snappy' cargo feature is not active"
+                    .to_string(),
+            )
+            .into());
+
+            // Pulsar uses the raw Snappy format, without framing
+            #[cfg(feature = "snap")]
+            {
+                let compressed_payload = snap::raw::Encoder::new()
+                    .compress_vec(&message.payload[..])
+                    .map_err(|e| ProducerError::Io(e.into()))?;
+
+                message.payload = compressed_payload;
+                message.compression = Some(4);
+                message
+            }
+        }
+    };
+
+    compressed_message.uncompressed_size = Some(uncompressed_size);
+    Ok(compressed_message)
+}
+
+/// resolves the receipts of all the messages in a batch from the batch's receipt
+///
+/// each message gets a copy of the batch receipt, with its own position in
+/// the batch set as `batch_index`. With `send_timeout`, the messages that
+/// reached the timeout were already counted as failed, and their receipt
+/// cannot be delivered anymore: they are not counted again
+fn resolve_batch(
+    receipts: Vec<oneshot::Sender<Result<proto::CommandSendReceipt, Error>>>,
+    send_receipt: Result<proto::CommandSendReceipt, Arc<Error>>,
+    size: u64,
+    counters: &ProducerCounters,
+    send_timeout: bool,
+) {
+    counters.finish(receipts.len() as u64);
+
+    let mut messages = 0;
+    for (index, tx) in receipts.into_iter().enumerate() {
+        let receipt = match &send_receipt {
+            Ok(receipt) => {
+                let mut receipt = receipt.clone();
+                if let Some(message_id) = receipt.message_id.as_mut() {
+                    message_id.batch_index = Some(index as i32);
+                }
+                Ok(receipt)
+            }
+            Err(e) => Err(ProducerError::Batch(e.clone()).into()),
+        };
+        if tx.send(receipt).is_ok() || !send_timeout {
+            messages += 1;
+        }
+    }
+    match &send_receipt {
+        Ok(_) => counters.sent(messages, size),
+        Err(_) => counters.failed(messages),
+    }
+}
+
 /// Helper structure to prepare a producer
 ///
 /// generated from [Pulsar::producer]
@@ -795,6 +2173,7 @@ pub struct ProducerBuilder<Exe: Executor> {
     topic: Option<String>,
     name: Option<String>,
     producer_options: Option<ProducerOptions>,
+    partitions: Option<u32>,
 }
 
 impl<Exe: Executor> ProducerBuilder<Exe> {
@@ -805,6 +2184,7 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
             topic: None,
             name: None,
             producer_options: None,
+            partitions: None,
         }
     }
 
@@ -826,6 +2206,147 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
         self
     }
 
+    /// sets the number of partitions of the topic, instead of asking the broker
+    ///
+    /// the producer then creates one producer per partition without waiting
+    /// for the partitioned topic metadata. The metadata is requested in the
+    /// background once the producer is created, and a warning is logged if
+    /// it does not match. 0 means the topic is not partitioned
+    pub fn with_partitions(mut self, partitions: u32) -> Self {
+        self.partitions = Some(partitions);
+        self
+    }
+
+    /// accumulates up to `batch_size` messages before sending them
+    /// as one batched message
+    pub fn with_batch_size(mut self, batch_size: u32) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .batch_size = Some(batch_size);
+        self
+    }
+
+    /// compresses the messages with this algorithm before sending them
+    ///
+    /// the matching cargo feature must be active (`compression-lz4` for LZ4)
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .compression = Some(compression);
+        self
+    }
+
+    /// encrypts the messages end to end
+    ///
+    /// payloads are encrypted with a data key, sent with the messages after
+    /// being encrypted with the configured RSA public keys. Consumers need
+    /// one of the private keys, set with [ConsumerBuilder::with_decryption](crate::ConsumerBuilder::with_decryption)
+    ///
+    /// with batching, the assembled batch is compressed then encrypted as a
+    /// single payload, and consumers decrypt it before splitting it. With
+    /// chunking, the whole message is compressed and encrypted before being
+    /// split, like the Java client does, and consumers decrypt it once
+    /// reassembled
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, config: EncryptionConfig) -> Self {
+        let options = self.producer_options.get_or_insert_with(Default::default);
+        options.encrypted = Some(true);
+        options.encryption = Some(config);
+        self
+    }
+
+    /// sets the zstd compression level. Building the producer fails if the
+    /// compression is not zstd
+    pub fn with_compression_level(mut self, level: i32) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .compression_level = Some(level);
+        self
+    }
+
+    /// sets the router selecting the partition of each message
+    ///
+    /// only used on partitioned topics
+    pub fn with_router<R: Router + 'static>(mut self, router: R) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .router = Some(Arc::new(router));
+        self
+    }
+
+    /// reconnection policy used when the connection to the broker is lost
+    pub fn with_reconnection(mut self, retry_policy: RetryPolicy) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .reconnection = Some(retry_policy);
+        self
+    }
+
+    /// maximum number of batched messages kept while the producer is
+    /// disconnected, see [ProducerOptions::max_reconnection_buffer]
+    pub fn with_max_reconnection_buffer(mut self, max_reconnection_buffer: u32) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .max_reconnection_buffer = Some(max_reconnection_buffer);
+        self
+    }
+
+    /// fails sends that did not get a receipt after this delay
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .send_timeout = Some(send_timeout);
+        self
+    }
+
+    /// splits messages larger than the broker's maximum message size in chunks
+    ///
+    /// it cannot be used with batching
+    pub fn enable_chunking(mut self, chunking: bool) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .chunking = chunking;
+        self
+    }
+
+    /// maximum number of messages waiting for their receipt
+    ///
+    /// when it is reached, sending a message waits until receipts arrive,
+    /// and sends the current batch if needed. It never fails because of
+    /// this limit, and building the producer fails if it is 0. Batched
+    /// messages are pending as soon as they are added to the batch, so a
+    /// value lower than the batch size makes smaller batches, see
+    /// [ProducerOptions] for details
+    pub fn with_max_pending_messages(mut self, max_pending_messages: u64) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .max_pending_messages = Some(max_pending_messages);
+        self
+    }
+
+    /// sends the current batch when it reaches `max_bytes`, even if it does
+    /// not have `batch_size` messages yet
+    ///
+    /// the size includes the metadata of each message, and is computed
+    /// before compression. A message larger than `max_bytes` is sent in its
+    /// own batch. Only used if the batch size is set
+    pub fn with_batch_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .batch_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// sends the current batch after this delay, even if it is not full
+    ///
+    /// only used if the batch size is set
+    pub fn with_batch_timeout(mut self, batch_timeout: Duration) -> Self {
+        self.producer_options
+            .get_or_insert_with(Default::default)
+            .batch_timeout = Some(batch_timeout);
+        self
+    }
+
     /// creates a new producer
     pub async fn build(self) -> Result<Producer<Exe>, Error> {
         let ProducerBuilder {
@@ -833,29 +2354,107 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
             topic,
             name,
             producer_options,
+            partitions,
         } = self;
         let topic = topic.ok_or_else(|| Error::Custom("topic not set".to_string()))?;
+        // accepts short names, and non-persistent topics
+        let topic = Topic::parse(&topic)?.to_string();
         let options = producer_options.unwrap_or_default();
+        if options.max_pending_messages == Some(0) {
+            return Err(ProducerError::Custom(
+                "max_pending_messages must be at least 1".to_string(),
+            )
+            .into());
+        }
 
-        let producers: Vec<TopicProducer<Exe>> = try_join_all(
-            pulsar
-                .lookup_partitioned_topic(&topic)
-                .await?
-                .into_iter()
-                .map(|(topic, addr)| {
-                    let name = name.clone();
-                    let options = options.clone();
+        let retry_policy = match pulsar.retry_policy.clone() {
+            None => return Self::create(&pulsar, topic, name, options, partitions).await,
+            Some(retry_policy) => retry_policy,
+        };
+        let mut retries = 0u32;
+        loop {
+            match Self::create(
+                &pulsar,
+                topic.clone(),
+                name.clone(),
+                options.clone(),
+                partitions,
+            )
+            .await
+            {
+                Err(e) if e.is_retryable() && retry_policy.can_retry(retries) => {
+                    let backoff = retry_policy.backoff(retries);
+                    warn!(
+                        "could not create a producer for {} (attempt {}), retrying in {}ms: {}",
+                        topic,
+                        retries + 1,
+                        backoff.as_millis(),
+                        e
+                    );
+                    pulsar.executor.delay(backoff).await;
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// looks up the topic and creates the producers of its partitions
+    async fn create(
+        pulsar: &Pulsar<Exe>,
+        topic: String,
+        name: Option<String>,
+        options: ProducerOptions,
+        partitions: Option<u32>,
+    ) -> Result<Producer<Exe>, Error> {
+        let topics = match partitions {
+            None => pulsar.lookup_partitioned_topic(&topic).await?,
+            Some(0) => vec![(topic.clone(), pulsar.lookup_topic(&topic).await?)],
+            Some(partitions) => {
+                try_join_all((0..partitions).map(|i| {
+                    let partition = format!("{}-partition-{}", topic, i);
                     let pulsar = pulsar.clone();
                     async move {
-                        let conn = pulsar.manager.get_connection(&addr).await?;
-                        let producer =
-                            TopicProducer::from_connection(pulsar, conn, topic, name, options)
-                                .await?;
-                        Ok::<_, Error>(producer)
+                        let addr = pulsar.lookup_topic(&partition).await?;
+                        Ok::<_, Error>((partition, addr))
                     }
-                }),
-        )
-        .await?;
+                }))
+                .await?
+            }
+        };
+
+        let producers: Vec<TopicProducer<Exe>> =
+            try_join_all(topics.into_iter().map(|(topic, addr)| {
+                let name = name.clone();
+                let options = options.clone();
+                let pulsar = pulsar.clone();
+                async move {
+                    let conn = pulsar.manager.get_connection(&addr).await?;
+                    let producer =
+                        TopicProducer::from_connection(pulsar, conn, topic, name, options).await?;
+                    Ok::<_, Error>(producer)
+                }
+            }))
+            .await?;
+
+        if let Some(partitions) = partitions {
+            let client = pulsar.clone();
+            let checked_topic = topic.clone();
+            let res = pulsar.executor.spawn(Box::pin(async move {
+                match client.lookup_partitioned_topic_number(&checked_topic).await {
+                    Ok(actual) => {
+                        check_partitions(&checked_topic, partitions, actual);
+                    }
+                    Err(e) => debug!("could not check the partitions of {}: {}", checked_topic, e),
+                }
+            }));
+            if res.is_err() {
+                debug!(
+                    "the executor could not spawn the partitions check of {}",
+                    topic
+                );
+            }
+        }
 
         let producer = match producers.len() {
             0 => {
@@ -866,11 +2465,13 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
             }
             1 => ProducerInner::Single(producers.into_iter().next().unwrap()),
             _ => {
-                let mut producers = VecDeque::from(producers);
-                // write to topic-1 first
-                producers.rotate_right(1);
+                let router = options
+                    .router
+                    .clone()
+                    .unwrap_or_else(|| Arc::new(RoundRobinRouter::new()));
                 ProducerInner::Partitioned(PartitionedProducer {
                     producers,
+                    router,
                     topic,
                     options,
                 })
@@ -887,12 +2488,16 @@ impl<Exe: Executor> ProducerBuilder<Exe> {
             producers: Default::default(),
             options: self.producer_options.unwrap_or_default(),
             name: self.name,
+            closed_metrics: ProducerMetrics::default(),
         }
     }
 }
 
 struct Batch {
     pub length: u32,
+    pub max_bytes: Option<usize>,
+    // serialized size of the messages in storage
+    size: AtomicUsize,
     // put it in a mutex because the design of Producer requires an immutable TopicProducer,
     // so we cannot have a mutable Batch in a send_raw(&mut self, ...)
     #[allow(clippy::type_complexity)]
@@ -905,51 +2510,119 @@ struct Batch {
 }
 
 impl Batch {
-    pub fn new(length: u32) -> Batch {
+    pub fn new(length: u32, max_bytes: Option<usize>) -> Batch {
         Batch {
             length,
+            max_bytes,
+            size: AtomicUsize::new(0),
             storage: Mutex::new(VecDeque::with_capacity(length as usize)),
         }
     }
 
     pub async fn is_full(&self) -> bool {
+        let size = self.size.load(Ordering::Relaxed);
         self.storage.lock().await.len() >= self.length as usize
+            || self.max_bytes.map(|max| size >= max).unwrap_or(false)
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.storage.lock().await.is_empty()
+    }
+
+    /// whether the message can be added without going over `max_bytes`. An
+    /// empty batch accepts any message
+    pub fn has_room(&self, message: &BatchedMessage) -> bool {
+        let size = self.size.load(Ordering::Relaxed);
+        match self.max_bytes {
+            Some(max) => size == 0 || size + message.serialized_size() <= max,
+            None => true,
+        }
     }
 
     pub async fn push_back(
         &self,
         msg: (
             oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
-            ProducerMessage,
+            BatchedMessage,
         ),
     ) {
-        let (tx, message) = msg;
+        self.size
+            .fetch_add(msg.1.serialized_size(), Ordering::Relaxed);
+        self.storage.lock().await.push_back(msg)
+    }
 
+    pub async fn extend(
+        &self,
+        messages: Vec<(
+            oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
+            BatchedMessage,
+        )>,
+    ) {
+        for msg in messages {
+            self.push_back(msg).await;
+        }
+    }
+
+    pub async fn get_messages(
+        &self,
+    ) -> Vec<(
+        oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
+        BatchedMessage,
+    )> {
+        let mut storage = self.storage.lock().await;
+        self.size.store(0, Ordering::Relaxed);
+        storage.drain(..).collect()
+    }
+
+    /// takes all the messages from the batch and assembles them in one message
+    ///
+    /// returns `None` if the batch was empty
+    #[allow(clippy::type_complexity)]
+    pub async fn flush(
+        &self,
+    ) -> Option<(
+        ProducerMessage,
+        Vec<oneshot::Sender<Result<proto::CommandSendReceipt, Error>>>,
+    )> {
+        let messages = self.get_messages().await;
+        if messages.is_empty() {
+            return None;
+        }
+
+        let mut payload: Vec<u8> = Vec::new();
+        let mut receipts = Vec::with_capacity(messages.len());
+        for (tx, message) in messages {
+            receipts.push(tx);
+            message.serialize(&mut payload);
+        }
+
+        let message = ProducerMessage {
+            payload,
+            num_messages_in_batch: Some(receipts.len() as i32),
+            ..Default::default()
+        };
+        Some((message, receipts))
+    }
+}
+
+impl From<ProducerMessage> for BatchedMessage {
+    fn from(message: ProducerMessage) -> Self {
         let properties = message
             .properties
             .into_iter()
             .map(|(key, value)| proto::KeyValue { key, value })
             .collect();
 
-        let batched = BatchedMessage {
+        BatchedMessage {
             metadata: proto::SingleMessageMetadata {
                 properties,
                 partition_key: message.partition_key,
+                ordering_key: message.ordering_key,
                 payload_size: message.payload.len() as i32,
                 ..Default::default()
             },
             payload: message.payload,
-        };
-        self.storage.lock().await.push_back((tx, batched))
-    }
-
-    pub async fn get_messages(
-        &self,
-    ) -> Vec<(
-        oneshot::Sender<Result<proto::CommandSendReceipt, Error>>,
-        BatchedMessage,
-    )> {
-        self.storage.lock().await.drain(..).collect()
+        }
     }
 }
 
@@ -960,6 +2633,8 @@ pub struct MessageBuilder<'a, T, Exe: Executor> {
     producer: &'a mut Producer<Exe>,
     properties: HashMap<String, String>,
     partition_key: Option<String>,
+    ordering_key: Option<Vec<u8>>,
+    event_time: Option<u64>,
     content: T,
 }
 
@@ -970,6 +2645,8 @@ impl<'a, Exe: Executor> MessageBuilder<'a, (), Exe> {
             producer,
             properties: HashMap::new(),
             partition_key: None,
+            ordering_key: None,
+            event_time: None,
             content: (),
         }
     }
@@ -982,6 +2659,8 @@ impl<'a, T, Exe: Executor> MessageBuilder<'a, T, Exe> {
             producer: self.producer,
             properties: self.properties,
             partition_key: self.partition_key,
+            ordering_key: self.ordering_key,
+            event_time: self.event_time,
             content,
         }
     }
@@ -992,11 +2671,24 @@ impl<'a, T, Exe: Executor> MessageBuilder<'a, T, Exe> {
         self
     }
 
+    /// sets the message's ordering key, used instead of the partition key
+    /// to keep messages in order on key shared subscriptions
+    pub fn with_ordering_key<K: Into<Vec<u8>>>(mut self, ordering_key: K) -> Self {
+        self.ordering_key = Some(ordering_key.into());
+        self
+    }
+
     /// sets a user defined property
     pub fn with_property<S1: Into<String>, S2: Into<String>>(mut self, key: S1, value: S2) -> Self {
         self.properties.insert(key.into(), value.into());
         self
     }
+
+    /// sets the time at which the event described by the message happened
+    pub fn with_event_time(mut self, event_time: SystemTime) -> Self {
+        self.event_time = to_millis(event_time);
+        self
+    }
 }
 
 impl<'a, T: SerializeMessage + Sized, Exe: Executor> MessageBuilder<'a, T, Exe> {
@@ -1006,12 +2698,982 @@ impl<'a, T: SerializeMessage + Sized, Exe: Executor> MessageBuilder<'a, T, Exe>
             producer,
             properties,
             partition_key,
+            ordering_key,
+            event_time,
             content,
         } = self;
 
         let mut message = T::serialize_message(content)?;
         message.properties = properties;
         message.partition_key = partition_key;
+        if ordering_key.is_some() {
+            message.ordering_key = ordering_key;
+        }
+        if event_time.is_some() {
+            message.event_time = event_time;
+        }
         producer.send_raw(message.into()).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_receipt() {
+        let receipt = SendReceipt::from(CommandSendReceipt {
+            producer_id: 1,
+            sequence_id: 2,
+            message_id: Some(MessageIdData {
+                ledger_id: 3,
+                entry_id: 4,
+                partition: Some(-1),
+                batch_index: Some(5),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        assert_eq!(receipt.producer_id(), 1);
+        assert_eq!(receipt.sequence_id(), 2);
+        assert_eq!(receipt.ledger_id(), 3);
+        assert_eq!(receipt.entry_id(), 4);
+        assert_eq!(receipt.partition(), None);
+        assert_eq!(receipt.batch_index(), Some(5));
+    }
+
+    #[test]
+    fn send_future_engine_shutdown() {
+        let (tx, rx) = oneshot::channel();
+        drop(tx);
+        let res = futures::executor::block_on(SendFuture(rx));
+        assert!(matches!(
+            res,
+            Err(Error::Producer(ProducerError::EngineShutdown))
+        ));
+    }
+
+    #[test]
+    fn message_size() {
+        let message = ProducerMessage {
+            payload: vec![0; 10],
+            ..Default::default()
+        };
+        assert!(check_message_size(message.clone(), 10).is_ok());
+        assert!(matches!(
+            check_message_size(message, 9),
+            Err(Error::Producer(ProducerError::MessageTooLarge {
+                size: 10,
+                max: 9
+            }))
+        ));
+    }
+
+    #[test]
+    fn event_time() {
+        let event_time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+        let message = Message::default().with_event_time(event_time);
+        assert_eq!(message.event_time, Some(1_600_000_000_123));
+
+        let message: ProducerMessage = message.into();
+        assert_eq!(message.event_time, Some(1_600_000_000_123));
+    }
+
+    #[test]
+    fn chunks() {
+        let message = ProducerMessage {
+            payload: (0..10).collect(),
+            partition_key: Some("key".to_string()),
+            ..Default::default()
+        };
+
+        let chunks = split_chunks(message, 7, "producer-7".to_string(), 4);
+        assert_eq!(chunks.len(), 3);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.sequence_id, Some(7));
+            assert_eq!(chunk.uuid.as_deref(), Some("producer-7"));
+            assert_eq!(chunk.chunk_id, Some(i as i32));
+            assert_eq!(chunk.num_chunks_from_msg, Some(3));
+            assert_eq!(chunk.total_chunk_msg_size, Some(10));
+            assert_eq!(chunk.partition_key.as_deref(), Some("key"));
+        }
+        assert_eq!(chunks[0].payload, vec![0, 1, 2, 3]);
+        assert_eq!(chunks[2].payload, vec![8, 9]);
+    }
+
+    #[test]
+    fn counters() {
+        let counters = Arc::new(ProducerCounters::default());
+        {
+            let _in_flight = InFlight::new(&counters);
+            assert_eq!(counters.snapshot().in_flight, 1);
+            counters.sent(1, 10);
+        }
+
+        counters.start(3);
+        let (receipts, _rxs): (Vec<_>, Vec<_>) = (0..3).map(|_| oneshot::channel()).unzip();
+        resolve_batch(
+            receipts,
+            Err(Arc::new(Error::Custom("failed".to_string()))),
+            30,
+            &counters,
+            false,
+        );
+        // with a send timeout, the messages whose receipt cannot be delivered
+        // anymore already reached the timeout, and were counted as failed
+        counters.start(2);
+        let (receipts, mut rxs): (Vec<_>, Vec<_>) = (0..2).map(|_| oneshot::channel()).unzip();
+        rxs.pop();
+        resolve_batch(
+            receipts,
+            Err(Arc::new(Error::Custom("failed".to_string()))),
+            20,
+            &counters,
+            true,
+        );
+
+        let metrics = counters.snapshot();
+        assert_eq!(
+            metrics,
+            ProducerMetrics {
+                messages_sent: 1,
+                bytes_sent: 10,
+                send_errors: 4,
+                in_flight: 0,
+            }
+        );
+        let total: ProducerMetrics = vec![metrics, metrics].into_iter().sum();
+        assert_eq!(total.messages_sent, 2);
+        assert_eq!(total.send_errors, 8);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_to_partition() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/send_to_partition")
+            .with_partitions(3)
+            .build()
+            .await
+            .unwrap();
+
+        producer
+            .send_to_partition(2, "hello")
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let producers = broker.commands_of_type(CommandType::Producer);
+        let partition_2 = producers
+            .iter()
+            .filter_map(|message| message.command.producer.as_ref())
+            .find(|producer| producer.topic.ends_with("-partition-2"))
+            .unwrap()
+            .producer_id;
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().producer_id,
+            partition_2
+        );
+
+        assert!(producer.send_to_partition(3, "hello").await.is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn partitions_mismatch() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the mock broker answers that topics are not partitioned
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let _producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/partitions_mismatch")
+            .with_partitions(2)
+            .build()
+            .await
+            .unwrap();
+
+        // the metadata is checked after the producer is created
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let metadata = broker.commands_of_type(CommandType::PartitionedMetadata);
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(
+            metadata[0]
+                .command
+                .partition_metadata
+                .as_ref()
+                .unwrap()
+                .topic,
+            "persistent://public/default/partitions_mismatch"
+        );
+
+        assert!(!check_partitions("topic", 2, 0));
+        assert!(check_partitions("topic", 2, 2));
+    }
+
+    #[test]
+    #[cfg(feature = "snap")]
+    fn snappy_raw_format() {
+        let payload = b"hello hello hello hello".to_vec();
+        let message = ProducerMessage {
+            payload: payload.clone(),
+            ..Default::default()
+        };
+        let compressed = compress_message(Some(CompressionType::Snappy), None, message).unwrap();
+        assert_eq!(compressed.compression, Some(CompressionType::Snappy as i32));
+        assert_eq!(compressed.uncompressed_size, Some(payload.len() as u32));
+        // other clients expect the raw format, not the framed one
+        assert_eq!(
+            snap::raw::Decoder::new()
+                .decompress_vec(&compressed.payload)
+                .unwrap(),
+            payload
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn ordering_key() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::router::{partition_for_key, OrderingKeyRouter, RoundRobinRouter};
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/ordering_key")
+            .with_partitions(3)
+            .with_router(OrderingKeyRouter::new(RoundRobinRouter::new()))
+            .build()
+            .await
+            .unwrap();
+
+        producer
+            .create_message()
+            .with_content("hello")
+            .with_partition_key("key")
+            .with_ordering_key("order")
+            .send()
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        let partition = format!("-partition-{}", partition_for_key(b"order", 3));
+        let producer_id = broker
+            .commands_of_type(CommandType::Producer)
+            .iter()
+            .filter_map(|message| message.command.producer.as_ref())
+            .find(|producer| producer.topic.ends_with(&partition))
+            .unwrap()
+            .producer_id;
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().producer_id,
+            producer_id
+        );
+        let metadata = &sends[0].payload.as_ref().unwrap().metadata;
+        assert_eq!(metadata.partition_key.as_deref(), Some("key"));
+        assert_eq!(metadata.ordering_key.as_deref(), Some(&b"order"[..]));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn batch_max_bytes() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/batch_max_bytes")
+            .with_batch_size(10)
+            .with_batch_max_bytes(250)
+            .build()
+            .await
+            .unwrap();
+
+        let mut receipts = Vec::new();
+        for _ in 0..3 {
+            receipts.push(producer.send(vec![0u8; 100]).await.unwrap());
+        }
+        // the third message did not fit in the first batch
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().num_messages,
+            Some(2)
+        );
+
+        // a message larger than the limit is sent alone
+        receipts.push(producer.send(vec![0u8; 1000]).await.unwrap());
+        futures::future::try_join_all(receipts).await.unwrap();
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 3);
+        assert_eq!(
+            sends[1].command.send.as_ref().unwrap().num_messages,
+            Some(1)
+        );
+        assert_eq!(
+            sends[2].command.send.as_ref().unwrap().num_messages,
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn multi_topic_prepare() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar.producer().build_multi_topic();
+
+        let topic = "persistent://public/default/multi_topic_prepare";
+        producer.prepare(topic).await.unwrap();
+        assert_eq!(producer.topics(), vec![topic.to_string()]);
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 1);
+
+        producer.prepare(topic).await.unwrap();
+        producer.send(topic, "hello").await.unwrap().await.unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn max_pending_messages_unbatched() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let res = pulsar
+            .producer()
+            .with_topic("persistent://public/default/max_pending_messages_unbatched")
+            .with_max_pending_messages(0)
+            .build()
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::Producer(ProducerError::Custom(_)))
+        ));
+
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/max_pending_messages_unbatched")
+            .with_max_pending_messages(1)
+            .build()
+            .await
+            .unwrap();
+        // each send waits for the receipt of the previous message
+        let receipts = futures::future::try_join_all(vec![
+            producer.send("a").await.unwrap(),
+            producer.send("b").await.unwrap(),
+            producer.send("c").await.unwrap(),
+        ]);
+        tokio::time::timeout(Duration::from_secs(1), receipts)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 3);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn max_pending_messages() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/max_pending_messages")
+            .with_batch_size(10)
+            .with_max_pending_messages(2)
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        let second = producer.send("b").await.unwrap();
+        assert!(broker.commands_of_type(CommandType::Send).is_empty());
+
+        // the batch is sent to make room for the third message
+        let _third = producer.send("c").await.unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().num_messages,
+            Some(2)
+        );
+        assert_eq!(producer.metrics().in_flight, 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_timeout() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the broker never answers the first message
+        let broker = MockBroker::start_with_handler(|message| match &message.command.send {
+            Some(send) if send.sequence_id == 0 => Some(vec![]),
+            _ => None,
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/send_timeout")
+            .with_send_timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+
+        let res = producer.send("a").await.unwrap().await;
+        assert!(matches!(res, Err(Error::Producer(ProducerError::Timeout))));
+        // the message is not sent again, and the producer keeps working
+        producer.send("b").await.unwrap().await.unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 2);
+    }
+
+    #[test]
+    fn receipt_sequence_id() {
+        let receipt = |sequence_id, ledger_id, entry_id| CommandSendReceipt {
+            producer_id: 1,
+            sequence_id,
+            message_id: Some(MessageIdData {
+                ledger_id,
+                entry_id,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert!(check_receipt(5, &receipt(5, 3, 4)).is_ok());
+        assert!(matches!(
+            check_receipt(5, &receipt(6, 3, 4)),
+            Err(Error::Producer(ProducerError::SequenceIdMismatch {
+                expected: 5,
+                received: 6
+            }))
+        ));
+        assert!(matches!(
+            check_receipt(5, &receipt(5, u64::MAX, u64::MAX)),
+            Err(Error::Producer(ProducerError::Duplicate { sequence_id: 5 }))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn duplicate_sequence_id() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        use crate::message::{proto::base_command::Type as CommandType, Message as RawMessage};
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        // the broker deduplicates the messages, like with deduplication
+        // enabled on the namespace
+        let next_sequence_id = AtomicU64::new(0);
+        let broker = MockBroker::start_with_handler(move |message| {
+            let send = message.command.send.as_ref()?;
+            let duplicate = send.sequence_id < next_sequence_id.load(Ordering::SeqCst);
+            if !duplicate {
+                next_sequence_id.store(send.sequence_id + 1, Ordering::SeqCst);
+            }
+            let (ledger_id, entry_id) = if duplicate {
+                (u64::MAX, u64::MAX)
+            } else {
+                (0, send.sequence_id)
+            };
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::SendReceipt as i32,
+                    send_receipt: Some(CommandSendReceipt {
+                        producer_id: send.producer_id,
+                        sequence_id: send.sequence_id,
+                        message_id: Some(MessageIdData {
+                            ledger_id,
+                            entry_id,
+                            ..Default::default()
+                        }),
+                        highest_sequence_id: None,
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/duplicate_sequence_id")
+            .build()
+            .await
+            .unwrap();
+
+        let receipt = producer
+            .send_with_sequence_id("a", 5)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(receipt.sequence_id, 5);
+        // the error comes from the send or its future, depending on when
+        // the receipt arrives
+        let res = match producer.send_with_sequence_id("a", 5).await {
+            Ok(send) => send.await,
+            Err(e) => Err(e),
+        };
+        assert!(matches!(
+            res,
+            Err(Error::Producer(ProducerError::Duplicate { sequence_id: 5 }))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn compression_level_without_zstd() {
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let res = pulsar
+            .producer()
+            .with_topic("persistent://public/default/compression_level")
+            .with_options(ProducerOptions {
+                compression: Some(proto::CompressionType::Lz4),
+                ..Default::default()
+            })
+            .with_compression_level(5)
+            .build()
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::Producer(ProducerError::Custom(_)))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_as_batch() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/send_as_batch")
+            .with_batch_size(10)
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        let receipts = producer.send_as_batch(vec!["b", "c"]).await.unwrap();
+        first.await.unwrap();
+        assert_eq!(receipts.len(), 2);
+
+        // the pending batch is sent before the explicit one
+        let sends = broker.commands_of_type(CommandType::Send);
+        let num_messages: Vec<_> = sends
+            .iter()
+            .map(|send| send.command.send.as_ref().unwrap().num_messages)
+            .collect();
+        assert_eq!(num_messages, vec![Some(1), Some(2)]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn send_all_batched() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/send_all_batched")
+            .with_batch_size(2)
+            .build()
+            .await
+            .unwrap();
+
+        // the last batch is not full, but is sent to get its receipt
+        let receipts = producer.send_all(vec!["a", "b", "c"]).await.unwrap();
+        let batch_indexes: Vec<_> = receipts
+            .iter()
+            .map(|receipt| receipt.message_id.as_ref().unwrap().batch_index)
+            .collect();
+        assert_eq!(batch_indexes, vec![Some(0), Some(1), Some(0)]);
+
+        let sends = broker.commands_of_type(CommandType::Send);
+        let num_messages: Vec<_> = sends
+            .iter()
+            .map(|send| send.command.send.as_ref().unwrap().num_messages)
+            .collect();
+        assert_eq!(num_messages, vec![Some(2), Some(1)]);
+    }
+
+    /// closes the producer instead of answering its first message
+    fn close_on_first_send(
+        message: &crate::message::Message,
+    ) -> Option<Vec<crate::message::Message>> {
+        let send = message.command.send.as_ref()?;
+        if send.sequence_id != 0 {
+            return None;
+        }
+        Some(vec![crate::connection::messages::close_producer(
+            send.producer_id,
+            u64::MAX,
+        )])
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn closed_while_sending() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(close_on_first_send)
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/closed_while_sending")
+            .build()
+            .await
+            .unwrap();
+
+        // the message is sent again by the recreated producer, without
+        // waiting for the operation timeout
+        let send = async { producer.send("a").await.unwrap().await.unwrap() };
+        tokio::time::timeout(Duration::from_secs(5), send)
+            .await
+            .unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 2);
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn closed_batch_timer() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(close_on_first_send)
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/closed_batch_timer")
+            .with_batch_size(10)
+            .with_batch_timeout(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        assert!(first.await.is_err());
+
+        // the batch timer does not send on the closed producer
+        let second = producer.send("b").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 1);
+        producer.send_batch().await.unwrap();
+        second.await.unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn max_reconnection_buffer() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(close_on_first_send)
+            .await
+            .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/max_reconnection_buffer")
+            .with_batch_size(10)
+            .with_batch_timeout(Duration::from_millis(50))
+            .with_max_reconnection_buffer(2)
+            .build()
+            .await
+            .unwrap();
+
+        let first = producer.send("a").await.unwrap();
+        assert!(first.await.is_err());
+
+        let second = producer.send("b").await.unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 1);
+        // the buffer is full, the producer is recreated to send the messages
+        let third = producer.send("c").await.unwrap();
+        second.await.unwrap();
+        third.await.unwrap();
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 2);
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 2);
+        assert_eq!(
+            sends[1].command.send.as_ref().unwrap().num_messages,
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn sink() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+        use futures::{SinkExt, StreamExt};
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/sink")
+            .with_batch_size(10)
+            .build()
+            .await
+            .unwrap();
+
+        let mut sink = producer.into_sink();
+        futures::stream::iter(vec!["a", "b", "c"])
+            .map(Ok)
+            .forward(&mut sink)
+            .await
+            .unwrap();
+        // the items are batched, and the batch is sent when the sink is closed
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        assert_eq!(
+            sends[0].command.send.as_ref().unwrap().num_messages,
+            Some(3)
+        );
+
+        sink.send("d").await.unwrap();
+        let producer = sink.into_inner().await;
+        assert_eq!(producer.metrics().messages_sent, 4);
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 2);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn incompatible_schema() {
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::message::Message as RawMessage;
+        use crate::mock::MockBroker;
+        use crate::TokioExecutor;
+
+        let broker = MockBroker::start_with_handler(|message: &RawMessage| {
+            let request = message.command.producer.as_ref()?;
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::Error as i32,
+                    error: Some(proto::CommandError {
+                        request_id: request.request_id,
+                        error: proto::ServerError::IncompatibleSchema as i32,
+                        message: "field removed".to_string(),
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let res = pulsar
+            .producer()
+            .with_topic("persistent://public/default/incompatible_schema")
+            .build()
+            .await;
+        match res {
+            Err(Error::Producer(ProducerError::IncompatibleSchema { topic, message })) => {
+                assert_eq!(topic, "persistent://public/default/incompatible_schema");
+                assert_eq!(message.as_deref(), Some("field removed"));
+            }
+            res => panic!(
+                "expected an incompatible schema error, got {:?}",
+                res.map(drop)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "tokio-runtime", feature = "encryption"))]
+    async fn encrypted_batch() {
+        use futures::TryStreamExt;
+        use openssl::rsa::Rsa;
+
+        use crate::message::proto::base_command::Type as CommandType;
+        use crate::message::Message as RawMessage;
+        use crate::mock::MockBroker;
+        use crate::{Consumer, TokioExecutor};
+
+        let rsa = Rsa::generate(2048).unwrap();
+        let public_key = rsa.public_key_to_pem().unwrap();
+        let private_key = rsa.private_key_to_pem().unwrap();
+
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/encrypted_batch")
+            .with_batch_size(2)
+            .with_compression(CompressionType::Zlib)
+            .with_encryption(EncryptionConfig::new("key", public_key))
+            .build()
+            .await
+            .unwrap();
+        let first = producer.send("first").await.unwrap();
+        let second = producer.send("second").await.unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+
+        // the whole batch is compressed then encrypted as one payload
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 1);
+        let payload = sends[0].payload.clone().unwrap();
+        assert_eq!(payload.metadata.num_messages_in_batch, Some(2));
+        assert_eq!(payload.metadata.encryption_keys.len(), 1);
+        assert!(!payload.data.windows(5).any(|w| w == b"first"));
+
+        // a consumer decrypts the batch before splitting it
+        let broker = MockBroker::start_with_handler(move |message: &RawMessage| {
+            let flow = message.command.flow.as_ref()?;
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(proto::CommandMessage {
+                        consumer_id: flow.consumer_id,
+                        message_id: MessageIdData {
+                            ledger_id: 1,
+                            entry_id: 1,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(payload.clone()),
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/encrypted_batch")
+            .with_subscription("encrypted_batch")
+            .with_decryption(private_key)
+            .build()
+            .await
+            .unwrap();
+        let first = consumer.try_next().await.unwrap().unwrap();
+        let second = consumer.try_next().await.unwrap().unwrap();
+        assert_eq!(first.payload.data, b"first".to_vec());
+        assert_eq!(second.payload.data, b"second".to_vec());
+    }
+}
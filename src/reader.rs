@@ -0,0 +1,243 @@
+//! Topic reads without a durable subscription
+use std::pin::Pin;
+
+use futures::task::{Context, Poll};
+use futures::{Stream, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::consumer::{Consumer, ConsumerOptions, InitialPosition, Message};
+use crate::error::Error;
+use crate::executor::Executor;
+use crate::message::proto::{command_subscribe::SubType, MessageIdData};
+use crate::{DeserializeMessage, Pulsar};
+
+/// position from which a [Reader] starts reading
+#[derive(Clone, Debug)]
+pub enum StartMessageId {
+    /// start at the oldest message available on the topic
+    Earliest,
+    /// start at the next message published on the topic
+    Latest,
+    /// start at a specific message
+    MessageId(MessageIdData),
+}
+
+/// reads messages from a topic without a durable subscription
+///
+/// the reader uses an exclusive, non durable subscription, so it does not
+/// leave a cursor on the topic, and messages do not need to be acknowledged.
+/// The subscription is closed when the reader is dropped
+///
+/// ```rust,no_run
+/// use futures::TryStreamExt;
+/// use pulsar::reader::{Reader, StartMessageId};
+///
+/// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+/// let mut reader: Reader<String, _> = pulsar
+///     .create_reader("persistent://public/default/test", StartMessageId::Earliest)
+///     .await?;
+///
+/// while let Some(msg) = reader.try_next().await? {
+///     log::info!("got message: {:?}", msg.deserialize());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Reader<T: DeserializeMessage, Exe: Executor> {
+    consumer: Consumer<T, Exe>,
+    // message returned by peek_next, that the stream yields next
+    peeked: Option<Message<T>>,
+}
+
+impl<T: DeserializeMessage, Exe: Executor> Reader<T, Exe> {
+    pub(crate) async fn new<S: Into<String>>(
+        pulsar: &Pulsar<Exe>,
+        topic: S,
+        start: StartMessageId,
+    ) -> Result<Self, Error> {
+        let (initial_position, start_message_id) = match start {
+            StartMessageId::Earliest => (InitialPosition::Earliest, None),
+            StartMessageId::Latest => (InitialPosition::Latest, None),
+            StartMessageId::MessageId(id) => (InitialPosition::Latest, Some(id)),
+        };
+
+        let s: String = (0..8)
+            .map(|_| rand::thread_rng().sample(Alphanumeric))
+            .map(|c| c as char)
+            .collect();
+
+        let consumer = pulsar
+            .consumer()
+            .with_topic(topic)
+            .with_subscription(format!("reader_{}", s))
+            .with_subscription_type(SubType::Exclusive)
+            .with_options(ConsumerOptions {
+                durable: Some(false),
+                start_message_id,
+                initial_position,
+                ..Default::default()
+            })
+            .build()
+            .await?;
+
+        Ok(Reader {
+            consumer,
+            peeked: None,
+        })
+    }
+
+    /// topics read by this reader (one per partition for partitioned topics)
+    pub fn topics(&self) -> Vec<String> {
+        self.consumer.topics()
+    }
+
+    /// name of the reader's internal subscription
+    pub fn subscription(&self) -> &str {
+        self.consumer.subscription()
+    }
+
+    /// waits for the next message without advancing the reader
+    ///
+    /// repeated calls return the same message, until the reader advances by
+    /// polling the stream, which yields this message first. Returns `None`
+    /// if the reader's stream ended
+    pub async fn peek_next(&mut self) -> Result<Option<&Message<T>>, Error>
+    where
+        T: 'static,
+    {
+        if self.peeked.is_none() {
+            self.peeked = self.consumer.next().await.transpose()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+}
+
+// the fields are never pinned, the peeked message is moved out when polling
+impl<T: DeserializeMessage, Exe: Executor> Unpin for Reader<T, Exe> {}
+
+impl<T: DeserializeMessage + 'static, Exe: Executor> Stream for Reader<T, Exe> {
+    type Item = Result<Message<T>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(message) = self.peeked.take() {
+            return Poll::Ready(Some(Ok(message)));
+        }
+        Pin::new(&mut self.consumer).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "tokio-runtime")]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::message::proto::{self, base_command::Type as CommandType};
+    use crate::message::{Message as RawMessage, Payload};
+    use crate::mock::MockBroker;
+    use crate::TokioExecutor;
+
+    /// delivers two messages when the reader asks for messages
+    fn handler(message: &RawMessage) -> Option<Vec<RawMessage>> {
+        let flow = message.command.flow.as_ref()?;
+        let messages = (1..3)
+            .map(|entry_id| RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(proto::CommandMessage {
+                        consumer_id: flow.consumer_id,
+                        message_id: MessageIdData {
+                            ledger_id: 1,
+                            entry_id,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(Payload {
+                    metadata: proto::MessageMetadata {
+                        producer_name: "mock".to_string(),
+                        ..Default::default()
+                    },
+                    data: format!("message {}", entry_id).into_bytes(),
+                }),
+            })
+            .collect();
+        Some(messages)
+    }
+
+    #[tokio::test]
+    async fn peek_next() {
+        let broker = MockBroker::start_with_handler(handler).await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut reader: Reader<String, _> = pulsar
+            .create_reader("persistent://public/default/peek", StartMessageId::Earliest)
+            .await
+            .unwrap();
+
+        let peeked = reader.peek_next().await.unwrap().unwrap().deserialize();
+        assert_eq!(peeked.unwrap(), "message 1");
+        let peeked = reader.peek_next().await.unwrap().unwrap().deserialize();
+        assert_eq!(peeked.unwrap(), "message 1");
+
+        let message = reader.try_next().await.unwrap().unwrap();
+        assert_eq!(message.deserialize().unwrap(), "message 1");
+        let peeked = reader.peek_next().await.unwrap().unwrap().deserialize();
+        assert_eq!(peeked.unwrap(), "message 2");
+        let message = reader.try_next().await.unwrap().unwrap();
+        assert_eq!(message.deserialize().unwrap(), "message 2");
+        assert!(broker.commands_of_type(CommandType::Ack).is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscription() {
+        let broker = MockBroker::start_with_handler(handler).await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let start = MessageIdData {
+            ledger_id: 1,
+            entry_id: 2,
+            ..Default::default()
+        };
+        let mut reader: Reader<String, _> = pulsar
+            .create_reader(
+                "persistent://public/default/reader_subscription",
+                StartMessageId::MessageId(start.clone()),
+            )
+            .await
+            .unwrap();
+        reader.try_next().await.unwrap().unwrap();
+
+        let subscribes = broker.commands_of_type(CommandType::Subscribe);
+        assert_eq!(subscribes.len(), 1);
+        let subscribe = subscribes[0].command.subscribe.as_ref().unwrap();
+        assert_eq!(subscribe.sub_type, SubType::Exclusive as i32);
+        assert_eq!(subscribe.durable, Some(false));
+        assert_eq!(subscribe.start_message_id, Some(start));
+        assert_eq!(subscribe.subscription, reader.subscription());
+
+        // dropping the reader closes its consumer
+        let consumer_id = subscribe.consumer_id;
+        drop(reader);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let closes = broker.commands_of_type(CommandType::CloseConsumer);
+        assert_eq!(closes.len(), 1);
+        assert_eq!(
+            closes[0]
+                .command
+                .close_consumer
+                .as_ref()
+                .unwrap()
+                .consumer_id,
+            consumer_id
+        );
+        assert!(broker.commands_of_type(CommandType::Ack).is_empty());
+    }
+}
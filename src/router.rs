@@ -0,0 +1,221 @@
+//! Partition selection for producers on partitioned topics
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// selects the partition on which a message will be sent
+///
+/// the default implementation is [RoundRobinRouter]. Use
+/// [ProducerBuilder::with_router](crate::producer::ProducerBuilder::with_router)
+/// to set another one
+pub trait Router: Send + Sync {
+    /// returns the index of the partition for a message, between 0 and `partitions - 1`
+    ///
+    /// `partitions` is guaranteed to be greater than 0
+    fn choose_partition(&self, partition_key: Option<&str>, partitions: usize) -> usize;
+
+    /// returns the index of the partition for a message that can also have
+    /// an ordering key
+    ///
+    /// the default implementation ignores the ordering key and calls
+    /// [Router::choose_partition]
+    fn choose_partition_with_ordering_key(
+        &self,
+        partition_key: Option<&str>,
+        _ordering_key: Option<&[u8]>,
+        partitions: usize,
+    ) -> usize {
+        self.choose_partition(partition_key, partitions)
+    }
+}
+
+/// sends messages with a partition key to the partition that key hashes to,
+/// and distributes the other messages in round robin over all partitions
+#[derive(Debug, Default)]
+pub struct RoundRobinRouter {
+    next: AtomicUsize,
+}
+
+impl RoundRobinRouter {
+    /// creates a router starting at the first partition
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Router for RoundRobinRouter {
+    fn choose_partition(&self, partition_key: Option<&str>, partitions: usize) -> usize {
+        match partition_key {
+            Some(key) => partition_for_key(key.as_bytes(), partitions),
+            None => self.next.fetch_add(1, Ordering::Relaxed) % partitions,
+        }
+    }
+}
+
+/// sends messages with a partition key to the partition that key hashes to,
+/// and all the other messages to one partition chosen randomly
+#[derive(Debug)]
+pub struct SinglePartitionRouter {
+    partition: usize,
+}
+
+impl SinglePartitionRouter {
+    /// creates a router with a random partition for messages without key
+    pub fn new() -> Self {
+        SinglePartitionRouter {
+            partition: rand::random::<u32>() as usize,
+        }
+    }
+}
+
+impl Default for SinglePartitionRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router for SinglePartitionRouter {
+    fn choose_partition(&self, partition_key: Option<&str>, partitions: usize) -> usize {
+        match partition_key {
+            Some(key) => partition_for_key(key.as_bytes(), partitions),
+            None => self.partition % partitions,
+        }
+    }
+}
+
+/// sends messages with an ordering key to the partition that key hashes to,
+/// and delegates the choice for the other messages to another router
+#[derive(Debug, Default)]
+pub struct OrderingKeyRouter<R> {
+    inner: R,
+}
+
+impl<R: Router> OrderingKeyRouter<R> {
+    /// creates a router using `inner` for the messages without ordering key
+    pub fn new(inner: R) -> Self {
+        OrderingKeyRouter { inner }
+    }
+}
+
+impl<R: Router> Router for OrderingKeyRouter<R> {
+    fn choose_partition(&self, partition_key: Option<&str>, partitions: usize) -> usize {
+        self.inner.choose_partition(partition_key, partitions)
+    }
+
+    fn choose_partition_with_ordering_key(
+        &self,
+        partition_key: Option<&str>,
+        ordering_key: Option<&[u8]>,
+        partitions: usize,
+    ) -> usize {
+        match ordering_key {
+            Some(key) => partition_for_key(key, partitions),
+            None => self
+                .inner
+                .choose_partition_with_ordering_key(partition_key, None, partitions),
+        }
+    }
+}
+
+/// selects the partition for a message key, using the same hash as the
+/// Java client (Murmur3 32 bits, with a seed of 0)
+pub(crate) fn partition_for_key(key: &[u8], partitions: usize) -> usize {
+    ((murmur3_32(key, 0) & i32::MAX as u32) as usize) % partitions
+}
+
+/// Murmur3 32 bits hash function
+pub(crate) fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut hash = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1);
+        k = k.rotate_left(15);
+        k = k.wrapping_mul(C2);
+
+        hash ^= k;
+        hash = hash.rotate_left(13);
+        hash = hash.wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let remainder = chunks.remainder();
+    if !remainder.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in remainder.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        k = k.wrapping_mul(C1);
+        k = k.rotate_left(15);
+        k = k.wrapping_mul(C2);
+        hash ^= k;
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn murmur3() {
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(
+            murmur3_32(b"The quick brown fox jumps over the lazy dog", 0),
+            0x2e4f_f723
+        );
+    }
+
+    #[test]
+    fn stable_key_partition() {
+        let partition = partition_for_key(b"key", 7);
+        assert!(partition < 7);
+        for _ in 0..10 {
+            assert_eq!(partition_for_key(b"key", 7), partition);
+        }
+    }
+
+    #[test]
+    fn routers() {
+        let router = RoundRobinRouter::new();
+        let partitions: Vec<_> = (0..6).map(|_| router.choose_partition(None, 3)).collect();
+        assert_eq!(partitions, vec![0, 1, 2, 0, 1, 2]);
+        assert_eq!(
+            router.choose_partition(Some("key"), 3),
+            partition_for_key(b"key", 3)
+        );
+
+        let router = SinglePartitionRouter::new();
+        let partition = router.choose_partition(None, 5);
+        for _ in 0..10 {
+            assert_eq!(router.choose_partition(None, 5), partition);
+        }
+    }
+
+    #[test]
+    fn ordering_key_router() {
+        let router = OrderingKeyRouter::new(RoundRobinRouter::new());
+        assert_eq!(
+            router.choose_partition_with_ordering_key(Some("key"), Some(b"order"), 5),
+            partition_for_key(b"order", 5)
+        );
+        assert_eq!(
+            router.choose_partition_with_ordering_key(Some("key"), None, 5),
+            partition_for_key(b"key", 5)
+        );
+
+        let router = RoundRobinRouter::new();
+        assert_eq!(
+            router.choose_partition_with_ordering_key(Some("key"), Some(b"order"), 5),
+            partition_for_key(b"key", 5)
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Schemas registered on the broker for producers
+//!
+//! the JSON schema helpers need the `json-schema` Cargo feature
+#[cfg(feature = "json-schema")]
+use std::collections::HashMap;
+
+#[cfg(feature = "json-schema")]
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::client::{DeserializeMessage, SerializeMessage};
+use crate::error::Error;
+#[cfg(feature = "json-schema")]
+use crate::error::ProducerError;
+use crate::message::{proto, Payload};
+use crate::producer;
+
+/// type with a JSON schema definition
+///
+/// Pulsar describes JSON schemas with the Avro record syntax, as done
+/// by the Java client. The schema is registered under the name of the
+/// type, unless `schema_name` is implemented:
+///
+/// ```rust
+/// use pulsar::schema::JsonSchema;
+///
+/// struct Temperature {
+///     sensor: String,
+///     value: f64,
+/// }
+///
+/// impl JsonSchema for Temperature {
+///     fn schema_definition() -> String {
+///         r#"{"type":"record","name":"Temperature","fields":[
+///             {"name":"sensor","type":"string"},
+///             {"name":"value","type":"double"}
+///         ]}"#
+///         .to_string()
+///     }
+/// }
+/// ```
+#[cfg(feature = "json-schema")]
+pub trait JsonSchema {
+    /// JSON encoded definition of the schema
+    fn schema_definition() -> String;
+
+    /// name under which the schema is registered
+    fn schema_name() -> String {
+        std::any::type_name::<Self>()
+            .rsplit("::")
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+/// schema definition registered by a producer, set in
+/// [ProducerOptions::schema](crate::ProducerOptions::schema)
+///
+/// ```rust,no_run
+/// use pulsar::schema::{Avro, SchemaDefinition};
+/// use pulsar::ProducerOptions;
+///
+/// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>, encoded: Vec<u8>) -> Result<(), pulsar::Error> {
+/// let definition = SchemaDefinition::Avro(
+///     r#"{"type":"record","name":"User","fields":[{"name":"name","type":"string"}]}"#
+///         .to_string(),
+/// );
+/// let mut producer = pulsar
+///     .producer()
+///     .with_topic("persistent://public/default/users")
+///     .with_options(ProducerOptions {
+///         schema: Some(definition.into_schema("User")),
+///         ..Default::default()
+///     })
+///     .build()
+///     .await?;
+/// // the payload was encoded with an Avro library, using the same schema
+/// producer.send(Avro(encoded)).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaDefinition {
+    /// JSON encoded messages, described with the Avro record syntax
+    Json(String),
+    /// Avro encoded messages, with the JSON definition of the Avro schema
+    Avro(String),
+}
+
+impl SchemaDefinition {
+    /// JSON schema of `T`
+    #[cfg(feature = "json-schema")]
+    pub fn json<T: JsonSchema>() -> Self {
+        SchemaDefinition::Json(T::schema_definition())
+    }
+
+    /// builds the schema sent in `CommandProducer`, registered under `name`
+    pub fn into_schema<S: Into<String>>(self, name: S) -> proto::Schema {
+        let (r#type, definition) = match self {
+            SchemaDefinition::Json(definition) => (proto::schema::Type::Json, definition),
+            SchemaDefinition::Avro(definition) => (proto::schema::Type::Avro, definition),
+        };
+
+        proto::Schema {
+            name: name.into(),
+            schema_data: definition.into_bytes(),
+            r#type: r#type as i32,
+            properties: Vec::new(),
+        }
+    }
+}
+
+/// schema registered on a topic, returned by
+/// [Pulsar::get_schema](crate::Pulsar::get_schema)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredSchema {
+    /// type and definition of the schema
+    pub schema: proto::Schema,
+    /// version of the schema, as set in the messages by the broker
+    pub version: Option<Vec<u8>>,
+}
+
+/// wrapper encoding a value in JSON, for producers created with
+/// [Pulsar::create_producer_with_schema](crate::Pulsar::create_producer_with_schema)
+/// and the consumers of their topic
+///
+/// ```rust,no_run
+/// # use pulsar::schema::{Json, JsonSchema};
+/// # #[derive(serde::Serialize)]
+/// # struct Temperature { value: f64 }
+/// # impl JsonSchema for Temperature {
+/// #     fn schema_definition() -> String { String::new() }
+/// # }
+/// # async fn run(pulsar: pulsar::Pulsar<pulsar::TokioExecutor>) -> Result<(), pulsar::Error> {
+/// let mut producer = pulsar
+///     .create_producer_with_schema::<Temperature>("persistent://public/default/temperatures")
+///     .await?;
+/// producer.send(Json(Temperature { value: 21.5 })).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "json-schema")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+/// property set on the messages serialized by `Json`
+pub const CONTENT_TYPE: &str = "content-type";
+
+#[cfg(feature = "json-schema")]
+impl<T: Serialize> SerializeMessage for Json<T> {
+    fn serialize_message(input: Self) -> Result<producer::Message, Error> {
+        let payload = serde_json::to_vec(&input.0).map_err(ProducerError::Serialization)?;
+        let mut properties = HashMap::new();
+        properties.insert(CONTENT_TYPE.to_string(), "application/json".to_string());
+        Ok(producer::Message {
+            payload,
+            properties,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl<T: DeserializeOwned> DeserializeMessage for Json<T> {
+    type Output = Result<T, serde_json::Error>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        serde_json::from_slice(&payload.data)
+    }
+}
+
+/// payload already encoded in Avro
+///
+/// this crate does not encode Avro itself: the payload is encoded by the
+/// application, and sent by a producer created with [SchemaDefinition::Avro]. When
+/// consuming, the [schema version](crate::consumer::Message::schema_version)
+/// of each message indicates which writer schema to decode it with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Avro(pub Vec<u8>);
+
+impl SerializeMessage for Avro {
+    fn serialize_message(input: Self) -> Result<producer::Message, Error> {
+        Ok(producer::Message {
+            payload: input.0,
+            ..Default::default()
+        })
+    }
+}
+
+impl DeserializeMessage for Avro {
+    type Output = Avro;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        Avro(payload.data.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "json-schema")]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[cfg(feature = "json-schema")]
+    impl JsonSchema for Point {
+        fn schema_definition() -> String {
+            r#"{"type":"record","name":"Point","fields":[{"name":"x","type":"int"},{"name":"y","type":"int"}]}"#.to_string()
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json-schema")]
+    fn json() {
+        let schema = SchemaDefinition::json::<Point>().into_schema(Point::schema_name());
+        assert_eq!(schema.name, "Point");
+        assert_eq!(schema.r#type, proto::schema::Type::Json as i32);
+        assert_eq!(schema.schema_data, Point::schema_definition().into_bytes());
+
+        let message = Json::serialize_message(Json(Point { x: 1, y: 2 })).unwrap();
+        assert_eq!(message.payload, br#"{"x":1,"y":2}"#.to_vec());
+        assert_eq!(
+            message.properties.get(CONTENT_TYPE).map(String::as_str),
+            Some("application/json")
+        );
+
+        let payload = Payload {
+            metadata: proto::MessageMetadata {
+                properties: message
+                    .properties
+                    .into_iter()
+                    .map(|(key, value)| proto::KeyValue { key, value })
+                    .collect(),
+                ..Default::default()
+            },
+            data: message.payload,
+        };
+        assert_eq!(payload.property(CONTENT_TYPE), Some("application/json"));
+        assert_eq!(
+            Json::<Point>::deserialize_message(&payload).unwrap(),
+            Point { x: 1, y: 2 }
+        );
+    }
+
+    #[test]
+    fn avro() {
+        let definition = r#"{"type":"record","name":"Point","fields":[{"name":"x","type":"int"}]}"#;
+        let schema = SchemaDefinition::Avro(definition.to_string()).into_schema("Point");
+        assert_eq!(schema.name, "Point");
+        assert_eq!(schema.r#type, proto::schema::Type::Avro as i32);
+        assert_eq!(schema.schema_data, definition.as_bytes().to_vec());
+
+        let message = Avro::serialize_message(Avro(vec![2])).unwrap();
+        assert_eq!(message.payload, vec![2]);
+    }
+}
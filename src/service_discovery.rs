@@ -6,9 +6,14 @@ use crate::message::proto::{
     CommandLookupTopicResponse,
 };
 use futures::{future::try_join_all, FutureExt};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use url::Url;
 
+/// how long the broker address found for a topic is reused
+const LOOKUP_CACHE_TTL: Duration = Duration::from_secs(60);
+
 /// Look up broker addresses for topics and partitioned topics
 ///
 /// The ServiceDiscovery object provides a single interface to start
@@ -17,26 +22,88 @@ use url::Url;
 #[derive(Clone)]
 pub struct ServiceDiscovery<Exe: Executor> {
     manager: Arc<ConnectionManager<Exe>>,
+    lookup_cache: Arc<Mutex<HashMap<String, (BrokerAddress, Instant)>>>,
+    // the service URL is a Pulsar proxy: every broker is reached through it
+    proxy: bool,
 }
 
 impl<Exe: Executor> ServiceDiscovery<Exe> {
     pub fn with_manager(manager: Arc<ConnectionManager<Exe>>) -> Self {
-        ServiceDiscovery { manager }
+        ServiceDiscovery {
+            manager,
+            lookup_cache: Arc::new(Mutex::new(HashMap::new())),
+            proxy: false,
+        }
+    }
+
+    /// connects to the brokers through the service URL, even if the lookup
+    /// responses do not ask for it
+    pub fn with_proxy(mut self, proxy: bool) -> Self {
+        self.proxy = proxy;
+        self
     }
 
     /// get the broker address for a topic
+    ///
+    /// the result is cached for a while, and reused as long as the
+    /// connection to that broker works
     pub async fn lookup_topic<S: Into<String>>(
         &self,
         topic: S,
     ) -> Result<BrokerAddress, ServiceDiscoveryError> {
         let topic = topic.into();
-        let mut proxied_query = false;
+
+        let cached = self
+            .lookup_cache
+            .lock()
+            .unwrap()
+            .get(&topic)
+            .filter(|(_, looked_up)| looked_up.elapsed() < LOOKUP_CACHE_TTL)
+            .map(|(address, _)| address.clone());
+        if let Some(address) = cached {
+            match self.manager.get_connection(&address).await {
+                Ok(_) => return Ok(address),
+                Err(e) => {
+                    warn!(
+                        "could not connect to the cached broker for {}, looking it up again: {}",
+                        topic, e
+                    );
+                    self.invalidate(&topic);
+                }
+            }
+        }
+
+        let address = self.lookup_topic_uncached(topic.clone()).await?;
+        self.lookup_cache
+            .lock()
+            .unwrap()
+            .insert(topic, (address.clone(), Instant::now()));
+        Ok(address)
+    }
+
+    /// removes the cached broker address of a topic
+    pub fn invalidate(&self, topic: &str) {
+        self.lookup_cache.lock().unwrap().remove(topic);
+    }
+
+    /// removes all the cached broker addresses
+    pub fn clear_cache(&self) {
+        self.lookup_cache.lock().unwrap().clear();
+    }
+
+    async fn lookup_topic_uncached(
+        &self,
+        topic: String,
+    ) -> Result<BrokerAddress, ServiceDiscoveryError> {
+        let mut proxied_query = self.proxy;
         let mut conn = self.manager.get_base_connection().await?;
         let base_url = self.manager.url.clone();
         let mut is_authoritative = false;
         let mut broker_address = self.manager.get_base_address();
 
         let mut current_retries = 0u32;
+        // brokers queried, to report redirection loops
+        let mut brokers = vec![broker_address.broker_url.clone()];
         let start = std::time::Instant::now();
         let operation_retry_options = self.manager.operation_retry_options.clone();
 
@@ -129,6 +196,19 @@ impl<Exe: Executor> ServiceDiscovery<Exe> {
             // if the response indicated a redirect, do another query
             // to the target broker
             if redirect {
+                brokers.push(broker_address.broker_url.clone());
+                if brokers.len() as u32 > operation_retry_options.max_lookup_redirects + 1 {
+                    error!(
+                        "lookup({}) redirected {} times, giving up",
+                        topic,
+                        brokers.len() - 1
+                    );
+                    return Err(ConnectionError::TooManyRedirects { brokers }.into());
+                }
+                debug!(
+                    "lookup({}) redirected to {} (authoritative: {})",
+                    topic, broker_address.broker_url, is_authoritative
+                );
                 conn = self.manager.get_connection(&broker_address).await?;
                 proxied_query = broker_address.proxy;
                 continue;
@@ -274,7 +354,7 @@ fn convert_lookup_response(
     })?;
 
     let broker_url_tls = match response.broker_service_url_tls.as_ref() {
-        Some(u) => Some(Url::parse(&u).map_err(|e| {
+        Some(u) => Some(Url::parse(u).map_err(|e| {
             error!("error parsing URL: {:?}", e);
             ServiceDiscoveryError::NotFound
         })?),
@@ -289,3 +369,75 @@ fn convert_lookup_response(
         authoritative,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Pulsar, TokioExecutor};
+
+    #[tokio::test]
+    #[cfg(feature = "tokio-runtime")]
+    async fn with_proxy() {
+        use crate::message::{
+            proto::{self, base_command::Type as CommandType},
+            Message as RawMessage,
+        };
+        use crate::mock::MockBroker;
+
+        // the lookups answer a broker address that is not reachable from the
+        // client, without asking to go through the service URL
+        let broker = MockBroker::start_with_handler(|message| {
+            let request = message.command.lookup_topic.as_ref()?;
+            Some(vec![RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::LookupResponse as i32,
+                    lookup_topic_response: Some(proto::CommandLookupTopicResponse {
+                        broker_service_url: Some("pulsar://internal-broker:6650".to_string()),
+                        response: Some(
+                            proto::command_lookup_topic_response::LookupType::Connect as i32,
+                        ),
+                        request_id: request.request_id,
+                        authoritative: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: None,
+            }])
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::with_proxy(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+
+        let address = pulsar
+            .lookup_topic("persistent://public/default/with_proxy")
+            .await
+            .unwrap();
+        assert_eq!(address.url, broker.url().parse().unwrap());
+        assert_eq!(address.broker_url, "internal-broker:6650");
+        assert!(address.proxy);
+
+        pulsar
+            .producer()
+            .with_topic("persistent://public/default/with_proxy")
+            .build()
+            .await
+            .unwrap();
+        let proxied: Vec<_> = broker
+            .commands_of_type(CommandType::Connect)
+            .iter()
+            .filter_map(|message| {
+                message
+                    .command
+                    .connect
+                    .as_ref()?
+                    .proxy_to_broker_url
+                    .clone()
+            })
+            .collect();
+        assert_eq!(proxied, vec!["internal-broker:6650".to_string()]);
+        assert_eq!(broker.commands_of_type(CommandType::Producer).len(), 1);
+    }
+}
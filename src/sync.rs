@@ -0,0 +1,186 @@
+//! Blocking API, for scripts and tests that do not run an async runtime
+//!
+//! the types of this module run the async client on an internal single
+//! threaded Tokio runtime. The client's background work, like answering
+//! pings or reconnecting, only progresses while one of their methods runs
+//!
+//! ```rust,no_run
+//! use pulsar::sync::SyncProducer;
+//!
+//! # fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut producer = SyncProducer::new("pulsar://127.0.0.1:6650", "persistent://public/default/test")?;
+//! let receipt = producer.send("hello")?;
+//! println!("sent message {:?}", receipt.message_id());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! authentication, TLS and the other client options are set on a
+//! [PulsarBuilder], passed to [SyncProducer::from_builder]
+use tokio::runtime::{Builder, Runtime};
+
+use crate::client::PulsarBuilder;
+use crate::error::{Error, ProducerError};
+use crate::executor::{Executor, TokioExecutor, TokioRuntimeExecutor};
+use crate::producer::{Producer, ProducerOptions, SendReceipt};
+use crate::{Pulsar, SerializeMessage};
+
+/// producer whose methods block until the broker answers
+pub struct SyncProducer {
+    producer: Producer<TokioRuntimeExecutor>,
+    // the client is kept to close its connections once the producer is dropped
+    _client: Pulsar<TokioRuntimeExecutor>,
+    runtime: Runtime,
+}
+
+impl SyncProducer {
+    /// connects to the cluster at `url` and creates a producer on `topic`
+    pub fn new<S1: Into<String>, S2: Into<String>>(url: S1, topic: S2) -> Result<Self, Error> {
+        SyncProducer::with_options(url, topic, ProducerOptions::default())
+    }
+
+    /// connects to the cluster at `url` and creates a producer on `topic`
+    /// with the given options
+    pub fn with_options<S1: Into<String>, S2: Into<String>>(
+        url: S1,
+        topic: S2,
+        options: ProducerOptions,
+    ) -> Result<Self, Error> {
+        SyncProducer::from_builder(Pulsar::builder(url, TokioExecutor), topic, options)
+    }
+
+    /// creates a client from `builder`, then a producer on `topic` with the
+    /// given options
+    ///
+    /// the executor of the builder is replaced by the internal runtime
+    ///
+    /// ```rust,no_run
+    /// use pulsar::{sync::SyncProducer, Authentication, Pulsar, TokioExecutor};
+    ///
+    /// # fn run() -> Result<(), pulsar::Error> {
+    /// let builder = Pulsar::builder("pulsar+ssl://127.0.0.1:6651", TokioExecutor)
+    ///     .with_auth(Authentication::token("my-token"));
+    /// let mut producer = SyncProducer::from_builder(
+    ///     builder,
+    ///     "persistent://public/default/test",
+    ///     Default::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_builder<Exe: Executor, S: Into<String>>(
+        builder: PulsarBuilder<Exe>,
+        topic: S,
+        options: ProducerOptions,
+    ) -> Result<Self, Error> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Custom(format!("could not start the runtime: {}", e)))?;
+        let builder = builder.with_executor(TokioRuntimeExecutor(runtime.handle().clone()));
+        let topic = topic.into();
+
+        let (client, producer) = runtime.block_on(async move {
+            let client: Pulsar<_> = builder.build().await?;
+            let producer = client
+                .producer()
+                .with_topic(topic)
+                .with_options(options)
+                .build()
+                .await?;
+            Ok::<_, Error>((client, producer))
+        })?;
+
+        Ok(SyncProducer {
+            producer,
+            _client: client,
+            runtime,
+        })
+    }
+
+    /// topic of the producer
+    pub fn topic(&self) -> &str {
+        self.producer.topic()
+    }
+
+    /// sends a message and waits for its receipt
+    ///
+    /// with batching options, the batch is sent right away, so each
+    /// message is sent in its own batch
+    pub fn send<T: SerializeMessage + Sized>(
+        &mut self,
+        message: T,
+    ) -> Result<SendReceipt, ProducerError> {
+        let producer = &mut self.producer;
+        self.runtime
+            .block_on(async move {
+                let receipt = producer.send(message).await?;
+                // nothing else would complete the batch before the receipt
+                producer.flush().await?;
+                receipt.await
+            })
+            .map_err(producer_error)
+    }
+
+    /// closes the producer
+    pub fn close(self) -> Result<(), Error> {
+        let SyncProducer {
+            producer, runtime, ..
+        } = self;
+        runtime.block_on(producer.close())
+    }
+}
+
+fn producer_error(error: Error) -> ProducerError {
+    match error {
+        Error::Producer(e) => e,
+        Error::Connection(e) => ProducerError::Connection(e),
+        e => ProducerError::Custom(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::proto::base_command::Type as CommandType;
+    use crate::mock::MockBroker;
+
+    #[test]
+    fn send() {
+        // the broker runs on its own runtime, like a remote cluster
+        let broker_runtime = tokio::runtime::Runtime::new().unwrap();
+        let broker = broker_runtime.block_on(MockBroker::start()).unwrap();
+
+        let mut producer =
+            SyncProducer::new(broker.url(), "persistent://public/default/sync").unwrap();
+        assert_eq!(producer.topic(), "persistent://public/default/sync");
+        let receipt = producer.send("hello").unwrap();
+        assert_eq!(receipt.sequence_id(), 0);
+        let receipt = producer.send("world").unwrap();
+        assert_eq!(receipt.sequence_id(), 1);
+        producer.close().unwrap();
+
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0].payload.as_ref().unwrap().data, b"hello".to_vec());
+        assert_eq!(broker.commands_of_type(CommandType::CloseProducer).len(), 1);
+    }
+
+    #[test]
+    fn send_batched() {
+        let broker_runtime = tokio::runtime::Runtime::new().unwrap();
+        let broker = broker_runtime.block_on(MockBroker::start()).unwrap();
+
+        let builder = Pulsar::builder(broker.url(), TokioExecutor);
+        let options = ProducerOptions {
+            batch_size: Some(10),
+            ..Default::default()
+        };
+        let mut producer =
+            SyncProducer::from_builder(builder, "persistent://public/default/sync", options)
+                .unwrap();
+        let receipt = producer.send("hello").unwrap();
+        assert_eq!(receipt.sequence_id(), 0);
+        assert_eq!(broker.commands_of_type(CommandType::Send).len(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+//! Topic name parsing
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+const DEFAULT_TENANT: &str = "public";
+const DEFAULT_NAMESPACE: &str = "default";
+const PARTITION_SUFFIX: &str = "-partition-";
+
+/// storage of the messages published on a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TopicDomain {
+    /// messages are stored on disk until they are acknowledged (`persistent://`)
+    Persistent,
+    /// messages are only kept in memory, and dropped if no consumer is
+    /// connected (`non-persistent://`)
+    NonPersistent,
+}
+
+impl TopicDomain {
+    /// prefix of the full topic name, without `://`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TopicDomain::Persistent => "persistent",
+            TopicDomain::NonPersistent => "non-persistent",
+        }
+    }
+}
+
+/// a fully qualified topic name
+///
+/// short names are completed with the defaults of the Pulsar CLI tools:
+/// `my-topic` is `persistent://public/default/my-topic`, and
+/// `my-tenant/my-namespace/my-topic` is `persistent://my-tenant/my-namespace/my-topic`
+///
+/// ```rust
+/// use pulsar::topic::{Topic, TopicDomain};
+///
+/// let topic: Topic = "non-persistent://public/telemetry/cpu".parse().unwrap();
+/// assert_eq!(topic.domain(), TopicDomain::NonPersistent);
+/// assert_eq!(topic.namespace(), "telemetry");
+/// assert_eq!(topic.local_name(), "cpu");
+///
+/// let topic: Topic = "events".parse().unwrap();
+/// assert_eq!(topic.to_string(), "persistent://public/default/events");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Topic {
+    domain: TopicDomain,
+    tenant: String,
+    namespace: String,
+    local_name: String,
+}
+
+impl Topic {
+    /// parses a topic name, in its short or complete form
+    pub fn parse(name: &str) -> Result<Topic, Error> {
+        let invalid = || Error::Custom(format!("invalid topic name: {}", name));
+
+        let (domain, rest) = match name.find("://") {
+            None => (TopicDomain::Persistent, name),
+            Some(index) => {
+                let domain = match &name[..index] {
+                    "persistent" => TopicDomain::Persistent,
+                    "non-persistent" => TopicDomain::NonPersistent,
+                    _ => return Err(invalid()),
+                };
+                (domain, &name[index + 3..])
+            }
+        };
+
+        let parts: Vec<&str> = rest.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+
+        let (tenant, namespace, local_name) = match (parts.as_slice(), name.contains("://")) {
+            ([local_name], false) => (DEFAULT_TENANT, DEFAULT_NAMESPACE.to_string(), *local_name),
+            ([tenant, namespace, local_name], _) => (*tenant, namespace.to_string(), *local_name),
+            // legacy names including the cluster
+            ([tenant, cluster, namespace, local_name], true) => {
+                (*tenant, format!("{}/{}", cluster, namespace), *local_name)
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(Topic {
+            domain,
+            tenant: tenant.to_string(),
+            namespace,
+            local_name: local_name.to_string(),
+        })
+    }
+
+    /// `persistent` or `non-persistent`
+    pub fn domain(&self) -> TopicDomain {
+        self.domain
+    }
+
+    /// whether messages are stored until they are acknowledged
+    pub fn is_persistent(&self) -> bool {
+        self.domain == TopicDomain::Persistent
+    }
+
+    /// tenant owning the topic
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// namespace of the topic, without the tenant
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// name of the topic inside its namespace
+    pub fn local_name(&self) -> &str {
+        &self.local_name
+    }
+
+    /// partition index, if this is the name of one partition of a partitioned topic
+    pub fn partition(&self) -> Option<u32> {
+        let index = self.local_name.rfind(PARTITION_SUFFIX)?;
+        self.local_name[index + PARTITION_SUFFIX.len()..]
+            .parse()
+            .ok()
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}/{}/{}",
+            self.domain.as_str(),
+            self.tenant,
+            self.namespace,
+            self.local_name
+        )
+    }
+}
+
+impl FromStr for Topic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Topic::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let topic = Topic::parse("test").unwrap();
+        assert_eq!(topic.to_string(), "persistent://public/default/test");
+        assert!(topic.is_persistent());
+
+        let topic = Topic::parse("tenant/ns/test").unwrap();
+        assert_eq!(topic.to_string(), "persistent://tenant/ns/test");
+
+        let topic = Topic::parse("non-persistent://tenant/ns/test-partition-3").unwrap();
+        assert_eq!(topic.domain(), TopicDomain::NonPersistent);
+        assert_eq!(topic.tenant(), "tenant");
+        assert_eq!(topic.namespace(), "ns");
+        assert_eq!(topic.local_name(), "test-partition-3");
+        assert_eq!(topic.partition(), Some(3));
+
+        let topic = Topic::parse("persistent://tenant/cluster/ns/test").unwrap();
+        assert_eq!(topic.namespace(), "cluster/ns");
+        assert_eq!(topic.partition(), None);
+
+        assert!(Topic::parse("").is_err());
+        assert!(Topic::parse("tenant/test").is_err());
+        assert!(Topic::parse("other://tenant/ns/test").is_err());
+        assert!(Topic::parse("persistent://tenant//test").is_err());
+        assert!(Topic::parse("persistent://test").is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! Transactions, to produce and acknowledge messages atomically over several topics
+//!
+//! a transaction is created with [Pulsar::new_transaction]. Messages sent with
+//! [Producer::send_in_txn](crate::Producer::send_in_txn) are only visible to
+//! consumers once the transaction is committed, and messages acknowledged with
+//! [Consumer::ack_in_txn](crate::Consumer::ack_in_txn) are redelivered if it is
+//! aborted
+//!
+//! ```rust,no_run
+//! # async fn run(
+//! #     pulsar: pulsar::Pulsar<pulsar::TokioExecutor>,
+//! #     mut producer: pulsar::Producer<pulsar::TokioExecutor>,
+//! #     mut consumer: pulsar::Consumer<String, pulsar::TokioExecutor>,
+//! # ) -> Result<(), Box<dyn std::error::Error>> {
+//! use futures::TryStreamExt;
+//! use std::time::Duration;
+//!
+//! let txn = pulsar.new_transaction(Duration::from_secs(60)).await?;
+//! if let Some(message) = consumer.try_next().await? {
+//!     producer.send_in_txn("processed", &txn).await?.await?;
+//!     consumer.ack_in_txn(&message, &txn).await?;
+//! }
+//! txn.commit().await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::lock::Mutex;
+
+use crate::connection::Connection;
+use crate::error::{ConnectionError, Error};
+use crate::executor::Executor;
+use crate::message::proto::TxnAction;
+use crate::Pulsar;
+
+/// topic whose partitions are assigned to the transaction coordinators
+const TC_ASSIGN_TOPIC: &str = "persistent://pulsar/system/transaction_coordinator_assign";
+
+/// identifier of a transaction, allocated by a transaction coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TxnId {
+    pub most_bits: u64,
+    pub least_bits: u64,
+}
+
+/// an open transaction
+///
+/// it must be ended with [Transaction::commit] or [Transaction::abort]. The
+/// receipts of the messages sent in the transaction should be awaited before
+/// committing it. A transaction that is dropped is aborted by the broker
+/// once its timeout is reached
+pub struct Transaction<Exe: Executor> {
+    id: TxnId,
+    // connection to the transaction coordinator
+    connection: Arc<Connection<Exe>>,
+    partitions: Mutex<BTreeSet<String>>,
+    subscriptions: Mutex<BTreeSet<(String, String)>>,
+}
+
+impl<Exe: Executor> Transaction<Exe> {
+    pub(crate) async fn new(client: &Pulsar<Exe>, timeout: Duration) -> Result<Self, Error> {
+        let coordinators = client
+            .lookup_partitioned_topic_number(TC_ASSIGN_TOPIC)
+            .await?;
+        if coordinators == 0 {
+            return Err(Error::Custom(
+                "no transaction coordinator, transactions are not enabled on the cluster"
+                    .to_string(),
+            ));
+        }
+
+        let tc_id = rand::random::<u32>() % coordinators;
+        let address = client
+            .lookup_topic(format!("{}-partition-{}", TC_ASSIGN_TOPIC, tc_id))
+            .await?;
+        let connection = client.manager.get_connection(&address).await?;
+        let response = connection.sender().new_txn(tc_id as u64, timeout).await?;
+        let id = match (response.txnid_most_bits, response.txnid_least_bits) {
+            (Some(most_bits), Some(least_bits)) => TxnId {
+                most_bits,
+                least_bits,
+            },
+            _ => {
+                return Err(ConnectionError::UnexpectedResponse(format!(
+                    "transaction coordinator {} did not return a transaction id",
+                    tc_id
+                ))
+                .into())
+            }
+        };
+        debug!("created transaction {:?} on coordinator {}", id, tc_id);
+
+        Ok(Transaction {
+            id,
+            connection,
+            partitions: Mutex::new(BTreeSet::new()),
+            subscriptions: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// identifier of the transaction
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    /// registers a topic partition on which messages are sent in the transaction
+    pub(crate) async fn add_partition(&self, topic: &str) -> Result<(), ConnectionError> {
+        let mut partitions = self.partitions.lock().await;
+        if partitions.contains(topic) {
+            return Ok(());
+        }
+
+        self.connection
+            .sender()
+            .add_partition_to_txn(self.id, vec![topic.to_string()])
+            .await?;
+        partitions.insert(topic.to_string());
+        Ok(())
+    }
+
+    /// registers a subscription on which messages are acknowledged in the transaction
+    pub(crate) async fn add_subscription(
+        &self,
+        topic: &str,
+        subscription: &str,
+    ) -> Result<(), ConnectionError> {
+        let key = (topic.to_string(), subscription.to_string());
+        let mut subscriptions = self.subscriptions.lock().await;
+        if subscriptions.contains(&key) {
+            return Ok(());
+        }
+
+        self.connection
+            .sender()
+            .add_subscription_to_txn(self.id, key.0.clone(), key.1.clone())
+            .await?;
+        subscriptions.insert(key);
+        Ok(())
+    }
+
+    /// commits the transaction, making its messages visible and its
+    /// acknowledgements definitive
+    pub async fn commit(self) -> Result<(), Error> {
+        self.end(TxnAction::Commit).await
+    }
+
+    /// aborts the transaction, discarding its messages and acknowledgements
+    pub async fn abort(self) -> Result<(), Error> {
+        self.end(TxnAction::Abort).await
+    }
+
+    async fn end(self, action: TxnAction) -> Result<(), Error> {
+        self.connection.sender().end_txn(self.id, action).await?;
+        debug!("ended transaction {:?} with {:?}", self.id, action);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "tokio-runtime")]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::message::proto::{self, base_command::Type as CommandType};
+    use crate::message::{Message as RawMessage, Payload};
+    use crate::mock::MockBroker;
+    use crate::{Consumer, TokioExecutor};
+
+    /// answers with one transaction coordinator, and delivers one message
+    /// to the consumers
+    fn handler(message: &RawMessage) -> Option<Vec<RawMessage>> {
+        let command = &message.command;
+        let response = match CommandType::from_i32(command.r#type)? {
+            CommandType::PartitionedMetadata => {
+                let request = command.partition_metadata.as_ref()?;
+                if request.topic != TC_ASSIGN_TOPIC {
+                    return None;
+                }
+                RawMessage {
+                    command: proto::BaseCommand {
+                        r#type: CommandType::PartitionedMetadataResponse as i32,
+                        partition_metadata_response: Some(
+                            proto::CommandPartitionedTopicMetadataResponse {
+                                partitions: Some(1),
+                                request_id: request.request_id,
+                                response: Some(proto::command_partitioned_topic_metadata_response::LookupType::Success as i32),
+                                ..Default::default()
+                            },
+                        ),
+                        ..Default::default()
+                    },
+                    payload: None,
+                }
+            }
+            CommandType::Flow => RawMessage {
+                command: proto::BaseCommand {
+                    r#type: CommandType::Message as i32,
+                    message: Some(proto::CommandMessage {
+                        consumer_id: command.flow.as_ref()?.consumer_id,
+                        message_id: proto::MessageIdData {
+                            ledger_id: 1,
+                            entry_id: 1,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                payload: Some(Payload {
+                    metadata: proto::MessageMetadata {
+                        producer_name: "mock".to_string(),
+                        ..Default::default()
+                    },
+                    data: b"hello".to_vec(),
+                }),
+            },
+            _ => return None,
+        };
+        Some(vec![response])
+    }
+
+    #[tokio::test]
+    async fn produce_and_ack() {
+        let broker = MockBroker::start_with_handler(handler).await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut producer = pulsar
+            .producer()
+            .with_topic("persistent://public/default/txn_out")
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/txn_in")
+            .with_subscription("txn")
+            .build()
+            .await
+            .unwrap();
+
+        let txn = pulsar
+            .new_transaction(Duration::from_secs(60))
+            .await
+            .unwrap();
+        let id = txn.id();
+        let message = consumer.try_next().await.unwrap().unwrap();
+        producer
+            .send_in_txn("a", &txn)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        producer
+            .send_in_txn("b", &txn)
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        consumer.ack_in_txn(&message, &txn).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let sends = broker.commands_of_type(CommandType::Send);
+        assert_eq!(sends.len(), 2);
+        for send in sends.iter() {
+            let metadata = &send.payload.as_ref().unwrap().metadata;
+            assert_eq!(metadata.txnid_least_bits, Some(id.least_bits));
+            assert_eq!(metadata.txnid_most_bits, Some(id.most_bits));
+        }
+        // the partition is only registered once
+        assert_eq!(
+            broker
+                .commands_of_type(CommandType::AddPartitionToTxn)
+                .len(),
+            1
+        );
+        let subscriptions = broker.commands_of_type(CommandType::AddSubscriptionToTxn);
+        assert_eq!(subscriptions.len(), 1);
+        let subscription = &subscriptions[0]
+            .command
+            .add_subscription_to_txn
+            .as_ref()
+            .unwrap()
+            .subscription[0];
+        assert_eq!(subscription.subscription, "txn");
+
+        let acks = broker.commands_of_type(CommandType::Ack);
+        let ack = acks.last().unwrap().command.ack.as_ref().unwrap();
+        assert_eq!(ack.txnid_least_bits, Some(id.least_bits));
+        let ends = broker.commands_of_type(CommandType::EndTxn);
+        assert_eq!(ends.len(), 1);
+        assert_eq!(
+            ends[0].command.end_txn.as_ref().unwrap().txn_action,
+            Some(TxnAction::Commit as i32)
+        );
+    }
+
+    #[tokio::test]
+    async fn acked_message_is_not_redelivered() {
+        let broker = MockBroker::start_with_handler(handler).await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let mut consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topic("persistent://public/default/txn_ack_timeout")
+            .with_subscription("txn")
+            .with_ack_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+
+        let txn = pulsar
+            .new_transaction(Duration::from_secs(60))
+            .await
+            .unwrap();
+        let message = consumer.try_next().await.unwrap().unwrap();
+        consumer.ack_in_txn(&message, &txn).await.unwrap();
+
+        // the ack timeout check runs every 500ms
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(broker
+            .commands_of_type(CommandType::RedeliverUnacknowledgedMessages)
+            .is_empty());
+        let acks = broker.commands_of_type(CommandType::Ack);
+        assert_eq!(acks.len(), 1);
+        let ack = acks[0].command.ack.as_ref().unwrap();
+        assert_eq!(ack.txnid_least_bits, Some(txn.id().least_bits));
+        assert_eq!(ack.message_id[0].entry_id, 1);
+        txn.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_coordinator() {
+        let broker = MockBroker::start().await.unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        assert!(pulsar
+            .new_transaction(Duration::from_secs(60))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_txn_id() {
+        let broker = MockBroker::start_with_handler(|message| {
+            let new_txn = message.command.new_txn.as_ref();
+            match new_txn {
+                Some(new_txn) => Some(vec![RawMessage {
+                    command: proto::BaseCommand {
+                        r#type: CommandType::NewTxnResponse as i32,
+                        new_txn_response: Some(proto::CommandNewTxnResponse {
+                            request_id: new_txn.request_id,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    payload: None,
+                }]),
+                None => handler(message),
+            }
+        })
+        .await
+        .unwrap();
+        let pulsar: Pulsar<_> = Pulsar::builder(broker.url(), TokioExecutor)
+            .build()
+            .await
+            .unwrap();
+        let res = pulsar.new_transaction(Duration::from_secs(60)).await;
+        assert!(matches!(
+            res,
+            Err(Error::Connection(ConnectionError::UnexpectedResponse(_)))
+        ));
+    }
+}